@@ -1,11 +1,112 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 
 use super::types::*;
+use super::parser::{convert_pre_gates, split_instance_name};
+
+/// 実体化するモジュールの数の上限 (パラメータが増え続ける再帰で止まらなくなるのを防ぐ)
+const MAX_INSTANCES: usize = 4096;
+
+/// パラメータ付きモジュールを、使われている引数ごとに通常のモジュールとして実体化する
+/// 実体の名前は `add<8>` のようになり、以降のチェックや展開では通常のモジュールと同じように扱われる
+pub fn instantiate_modules(ast: &File) -> Result<File,Errs> {
+    let mut errors = Vec::new();
+    let mut generics: HashMap<String,&GenericModule> = HashMap::new();
+    for component in &ast.components {
+        if let Component::GenericModule(module) = component {
+            if generics.insert(module.name.clone(), module).is_some() {
+                errors.push(Diagnostic::error("defined-module-name-duplicated", format!("Defined module name Duplicated: {}",module.name)).with_span(&module.file, module.span));
+            }
+        }
+    }
+    if generics.is_empty() {
+        return Ok(ast.clone());
+    }
+    // 使われているモジュールを集める (モジュール名, 使われている場所, 使われている位置 `( file, span )`)
+    let mut queue = VecDeque::new();
+    // `add<1>` のように直接定義された実体は実体化しない
+    let mut instantiated = HashSet::new();
+    for component in &ast.components {
+        match component {
+            Component::Module(module) => {
+                instantiated.insert(module.name.clone());
+                if generics.contains_key(&module.name) {
+                    errors.push(Diagnostic::error("defined-module-name-duplicated", format!("Defined module name Duplicated: {}",module.name)).with_span(&module.file, module.span));
+                }
+                for gate in &module.gates {
+                    queue.push_back((gate.module_name.clone(),module.name.clone(),(module.file.clone(),gate.span)));
+                }
+            },
+            Component::Test(test) => queue.push_back((test.name.clone(),"test".to_string(),(test.file.clone(),test.span))),
+            Component::EquivalenceTest(test) => {
+                queue.push_back((test.name.clone(),"test".to_string(),(test.file.clone(),test.span)));
+                // 引数のないパラメータ付きモジュールの名前は組み込みの仕様として扱う
+                if !generics.contains_key(&test.reference) {
+                    queue.push_back((test.reference.clone(),"test".to_string(),(test.file.clone(),test.span)));
+                }
+            },
+            Component::Graphical(graphical) => queue.push_back((graphical.name.clone(),"graphical".to_string(),(graphical.file.clone(),graphical.span))),
+            _ => {},
+        }
+    }
+    let mut components = ast.components.clone();
+    let mut instance_count = 0;
+    while let Some((name,used_in,(file,span))) = queue.pop_front() {
+        let error = |code: &str, message: String| Diagnostic::error(code, message).with_span(&file, span);
+        let (base,args) = split_instance_name(&name);
+        let generic = match generics.get(base) {
+            Some(v) => v,
+            None => {
+                if !args.is_empty() {
+                    errors.push(error("module-not-parameterized", format!("Module is not parameterized: {} in {}",base,used_in)));
+                }
+                continue;
+            },
+        };
+        if args.is_empty() {
+            errors.push(error("missing-module-parameters", format!("Parameterized module used without parameters: {} in {}",base,used_in)));
+            continue;
+        }
+        if !instantiated.insert(name.clone()) {
+            continue;
+        }
+        instance_count += 1;
+        if instance_count>MAX_INSTANCES {
+            errors.push(error("too-many-instances", format!("Too many instances of parameterized modules (recursive instantiation?): {} in {}",name,used_in)));
+            break;
+        }
+        let values = args[1..args.len()-1].split(',').map(|v| v.parse::<usize>()).collect::<Result<Vec<_>,_>>().unwrap_or_default();
+        if values.len()!=generic.params.len() {
+            errors.push(error("wrong-number-of-parameters", format!("Wrong number of parameters: {} expects {} in {}",name,generic.params.len(),used_in)));
+            continue;
+        }
+        let params = generic.params.iter().cloned().zip(values).collect::<HashMap<_,_>>();
+        let (inputs,outputs,gates) = match convert_pre_gates(generic.inputs.clone(), generic.outputs.clone(), generic.gates.clone(), &params) {
+            Ok(v) => v,
+            Err(msg) => { errors.push(error("instantiation-failed", format!("{} in {} (used in {})",msg,name,used_in))); continue; },
+        };
+        for gate in &gates {
+            queue.push_back((gate.module_name.clone(),name.clone(),(generic.file.clone(),gate.span)));
+        }
+        components.push(Component::Module(Module {
+            func: generic.func,
+            sync: generic.sync,
+            export: generic.export,
+            name,
+            file: generic.file.clone(),
+            inputs,
+            outputs,
+            gates,
+            span: generic.span,
+        }));
+    }
+    if errors.is_empty() { Ok(File { components }) }
+    else { Err(errors) }
+}
 
 /// 全てのモジュールをnorのみで表す
 /// modules: 依存関係によりトポロジカルソートされたモジュール名一覧
-pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<String,CompiledModule>,Vec<String>> {
+pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<String,CompiledModule>,Errs> {
     let mut errors = Vec::new();
     let mut expanded_modules: HashMap<String,CompiledModule> = std::collections::HashMap::new(); // 全てのゲートがnorだけで構成されているmodule
 
@@ -18,6 +119,7 @@ pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<Stri
                 outputs: vec![0],
                 gates_sequential: vec![(CompiledGateInput::Input(0),CompiledGateInput::Input(1))],
                 gates_symmetry: Vec::new(),
+                gate_names: vec![String::new()],
             });
             continue;
         }
@@ -30,18 +132,31 @@ pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<Stri
             None
         }) {
             Some(v) => v,
-            None => {errors.push(format!("Undefined module used: {}",module_name));continue;}
+            None => {
+                // 使っているゲートの位置を付ける
+                let error = Diagnostic::error("undefined-module-used", format!("Undefined module used: {}",module_name));
+                let used_at = ast.components.iter().find_map(|component| match component {
+                    Component::Module(module) => module.gates.iter().find(|gate| &gate.module_name==module_name).map(|gate| (module.file.as_str(),gate.span)),
+                    _ => None,
+                });
+                errors.push(match used_at {
+                    Some((file,span)) => error.with_span(file, span),
+                    None => error,
+                });
+                continue;
+            }
         };
+        let error = |code: &str, message: String, span: Span| Diagnostic::error(code, message).with_span(&module.file, span);
         // 各ゲートのpointerを計算
         let mut gates_pointer = Vec::new();
         let mut gate_count = 0;
         for gate in module.gates.clone() {
             let expanding_gate = match expanded_modules.get(&gate.module_name) {
                 Some(v) => v.clone(),
-                None => {errors.push(format!("Undefined gate used: {}",gate.module_name));continue;}
+                None => {errors.push(error("undefined-module-used", format!("Undefined gate used: {}",gate.module_name), gate.span));continue;}
             };
             gates_pointer.push(gate_count);
-            gate_count += expanding_gate.gates_sequential.len() as u32;
+            gate_count += (expanding_gate.gates_sequential.len() + expanding_gate.gates_symmetry.len()) as u32;
         }
         // gateのoutput名前とindexの対応表を作る
         let mut output_map = HashMap::new();
@@ -49,7 +164,7 @@ pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<Stri
         for gate in module.gates.clone() {
             let expanding_gate = match expanded_modules.get(&gate.module_name) {
                 Some(v) => v.clone(),
-                None => {errors.push(format!("Undefined gate used: {}",gate.module_name));continue;}
+                None => {errors.push(error("undefined-module-used", format!("Undefined gate used: {}",gate.module_name), gate.span));continue;}
             };
             let mut output_index = 0;
             for output in expanding_gate.outputs.clone() {
@@ -66,42 +181,78 @@ pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<Stri
             input_index+=1;
         }
         // gateのinputsを解決しながら展開
+        // 展開元でsymmetryだったゲートと、syncモジュールの全てのゲートはsymmetryにする
         let mut expanded = Vec::new();
+        let mut symmetric = Vec::new();
+        let mut names = Vec::new();
+        let arrays = array_wires(&module);
         gate_index = 0;
         for gate in module.gates.clone() {
             let expanding_gate = match expanded_modules.get(&gate.module_name) {
                 Some(v) => v.clone(),
-                None => {errors.push(format!("Undefined gate used: {}",gate.module_name));continue;}
+                None => {errors.push(error("undefined-module-used", format!("Undefined gate used: {}",gate.module_name), gate.span));continue;}
             };
-            for egate in expanding_gate.gates_sequential.clone() {
+            let sequential_len = expanding_gate.gates_sequential.len();
+            // ゲートの名前は `出力名.モジュール名.展開元での名前` (norなら出力名のみ)
+            let label = gate.outputs.first().map(|output| wire_label(output, &arrays)).unwrap_or_else(|| gate.module_name.clone());
+            let egate_names = expanding_gate.gate_names.iter().map(|name| {
+                if name.is_empty() { label.clone() }
+                else { format!("{}.{}.{}",label,gate.module_name,name) }
+            }).collect::<Vec<_>>();
+            for (egate_index,egate) in expanding_gate.gates_sequential.into_iter().chain(expanding_gate.gates_symmetry).enumerate() {
                 let input0 = match egate.0 {
                     CompiledGateInput::NorGate(n) => CompiledGateInput::NorGate(n+gates_pointer[gate_index as usize]),
                     CompiledGateInput::Input(n) => match output_map.get(&gate.inputs[n as usize]) {
                         Some(v) => v.clone(),
-                        None => {errors.push(format!("Undefined gate used: {}",gate.inputs[n as usize]));continue;}
+                        None => {errors.push(error("undefined-id-used", format!("Undefined gate used: {}",gate.inputs[n as usize]), gate.span));continue;}
                     },
                 };
                 let input1 = match egate.1 {
                     CompiledGateInput::NorGate(n) => CompiledGateInput::NorGate(n+gates_pointer[gate_index as usize]),
                     CompiledGateInput::Input(n) => match output_map.get(&gate.inputs[n as usize]) {
                         Some(v) => v.clone(),
-                        None => {errors.push(format!("Undefined gate used: {}",gate.inputs[n as usize]));continue;}
+                        None => {errors.push(error("undefined-id-used", format!("Undefined gate used: {}",gate.inputs[n as usize]), gate.span));continue;}
                     },
                 };
                 expanded.push((input0,input1));
+                symmetric.push(module.sync || egate_index>=sequential_len);
+                names.push(egate_names.get(egate_index).cloned().unwrap_or_default());
             }
             gate_index+=1;
         }
+        // NCGBではsequential,symmetryの順に並ぶので、symmetryのゲートを後ろにまとめて番号を付け直す
+        let sequential_count = symmetric.iter().filter(|s| !**s).count() as u32;
+        let mut new_index = Vec::new();
+        let (mut sequential_index, mut symmetry_index) = (0, sequential_count);
+        for is_symmetric in &symmetric {
+            if *is_symmetric { new_index.push(symmetry_index); symmetry_index+=1; }
+            else { new_index.push(sequential_index); sequential_index+=1; }
+        }
+        let reindex = |input: CompiledGateInput| match input {
+            CompiledGateInput::NorGate(n) => CompiledGateInput::NorGate(new_index.get(n as usize).copied().unwrap_or(n)),
+            other => other,
+        };
+        let mut gates_sequential = Vec::new();
+        let mut gates_symmetry = Vec::new();
+        let mut sequential_names = Vec::new();
+        let mut symmetry_names = Vec::new();
+        for ((egate,is_symmetric),name) in expanded.into_iter().zip(symmetric).zip(names) {
+            let egate = (reindex(egate.0),reindex(egate.1));
+            if is_symmetric { gates_symmetry.push(egate); symmetry_names.push(name); }
+            else { gates_sequential.push(egate); sequential_names.push(name); }
+        }
+        let mut gate_names = sequential_names;
+        gate_names.extend(symmetry_names);
         // moduleのoutputを解決
         let mut outputs = Vec::new();
         for output in module.outputs.clone() {
             let output_solved = match output_map.get(&output) {
                 Some(v) => v.clone(),
-                None => {errors.push(format!("Undefined gate used in output: {}",output));continue;}
+                None => {errors.push(error("undefined-id-used", format!("Undefined gate used in output: {}",output), module.span));continue;}
             };
             let output_checked = match output_solved {
                 CompiledGateInput::Input(v) => v+gate_index,
-                CompiledGateInput::NorGate(v) => v,
+                CompiledGateInput::NorGate(v) => new_index.get(v as usize).copied().unwrap_or(v),
             };
             outputs.push(output_checked);
         }
@@ -111,8 +262,9 @@ pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<Stri
             name: module_name.clone(),
             inputs: module.inputs.len() as u32,
             outputs: outputs,
-            gates_sequential: expanded,
-            gates_symmetry: Vec::new(),
+            gates_sequential,
+            gates_symmetry,
+            gate_names,
         });
     }
 
@@ -121,12 +273,26 @@ pub fn module_expansion(ast: &File,modules: &Vec<String>) -> Result<HashMap<Stri
 }
 
 
-pub fn serialize_to_vec(module: CompiledModule) -> Vec<u32> {
+/// モジュールの配線のうち2bit以上の配列の名前 (inputとゲートの出力から調べる)
+pub fn array_wires(module: &Module) -> HashSet<String> {
+    module.inputs.iter().chain(module.gates.iter().flat_map(|gate| &gate.outputs))
+        .filter_map(|wire| wire.rsplit_once(':'))
+        .filter(|(_,index)| *index!="0")
+        .map(|(name,_)| name.to_string())
+        .collect()
+}
+
+/// ゲート名に使う配線の名前 (1bitの配線は `a:0` -> `a`, 配列の要素は `s:0` -> `s:0`)
+pub fn wire_label(wire: &str, arrays: &HashSet<String>) -> String {
+    match wire.rsplit_once(':') {
+        Some((name,"0")) if !arrays.contains(name) => name.to_string(),
+        _ => wire.to_string(),
+    }
+}
+
+/// NCGBのモジュール本体 (func,名前,入出力,ゲート) にする (ヘッダーとセクションはncgb.rsで付ける)
+pub fn serialize_module(module: &CompiledModule) -> Vec<u32> {
     let mut result = Vec::new();
-    // Add magic number
-    result.push(0x6247434e);
-    // Add data size
-    result.push(32); // 32bits (u32)
     // Add func module flag
     result.push(if module.func {1} else {0});
     // Add func name
@@ -163,4 +329,91 @@ pub fn serialize_to_vec(module: CompiledModule) -> Vec<u32> {
         });
     }
     result
-}
\ No newline at end of file
+}
+/// ゲートの名前をNCGBのシンボルセクションの中身にする (読まなくてもVMは動く)
+pub fn serialize_symbols(symbols: &SymbolTable) -> Vec<u32> {
+    fn push_str(result: &mut Vec<u32>, s: &str) {
+        let encoded = s.chars().map(|c| c as u32).collect::<Vec<u32>>();
+        result.push(encoded.len() as u32);
+        result.extend(encoded);
+    }
+    let mut result = Vec::new();
+    result.push(symbols.inputs.len() as u32);
+    for input in &symbols.inputs {
+        push_str(&mut result, input);
+    }
+    result.push(symbols.outputs.len() as u32);
+    for output in &symbols.outputs {
+        push_str(&mut result, output);
+    }
+    result.push(symbols.gates.len() as u32);
+    for gate in &symbols.gates {
+        push_str(&mut result, &gate.path);
+        push_str(&mut result, &gate.wire);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> IntermediateProducts {
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        assert!(products.errors.is_empty(), "{:?}", products.errors);
+        products
+    }
+
+    /// ゲートの入力を `g0` `i1` の形にする
+    fn wires(gates: &[CompiledGate]) -> Vec<(String,String)> {
+        let wire = |input: &CompiledGateInput| match input {
+            CompiledGateInput::NorGate(n) => format!("g{}",n),
+            CompiledGateInput::Input(n) => format!("i{}",n),
+        };
+        gates.iter().map(|(a,b)| (wire(a),wire(b))).collect()
+    }
+
+    /// syncモジュールのゲートは使われた先でもsymmetryになり、sequentialのゲートの後ろに並び替えられる
+    #[test]
+    fn sync_gates_are_symmetric() {
+        let products = compile("
+            using nor:2->1;
+            sync module sr (s r)->(q nq) {
+                q: nor <- r nq;
+                nq: nor <- s q;
+            }
+            module top (s r x)->(q nq z) {
+                y: nor <- x x;
+                q nq: sr <- s r;
+                z: nor <- y y;
+            }
+        ");
+        let sr = &products.expanded_modules["sr"];
+        assert!(sr.gates_sequential.is_empty());
+        assert_eq!(wires(&sr.gates_symmetry), [("i1".into(),"g1".into()), ("i0".into(),"g0".into())]);
+        let top = &products.expanded_modules["top"];
+        // y,z (sequential) の後に sr のq,nq (symmetry) が並び、番号は並び替えた後のもの
+        assert_eq!(wires(&top.gates_sequential), [("i2".into(),"i2".into()), ("g0".into(),"g0".into())]);
+        assert_eq!(wires(&top.gates_symmetry), [("i1".into(),"g3".into()), ("i0".into(),"g2".into())]);
+        assert_eq!(top.gate_names, ["y", "z", "q.sr.q", "q.sr.nq"]);
+        assert_eq!(top.outputs, [2, 3, 1]);
+    }
+
+    /// 1bitの配線は番号を付けず、配列の要素は0番目も含めて全て番号を付ける
+    #[test]
+    fn wire_labels() {
+        let products = compile("
+            using nor:2->1;
+            module not (x)->(a) { a: nor <- x x; }
+            module pair (x y)->(a b) { a: not <- x; b: not <- y; }
+            module top (a(2) b)->(s c) {
+                s(2): pair <- a;
+                c: not <- b;
+            }
+        ");
+        assert_eq!(products.expanded_modules["top"].gate_names, ["s:0.pair.a.not.a", "s:0.pair.b.not.a", "c.not.a"]);
+        let symbols = &products.symbol_tables["top"];
+        assert_eq!(symbols.inputs, ["a:0", "a:1", "b"]);
+        assert_eq!(symbols.outputs, ["s:0", "s:1", "c"]);
+    }
+}
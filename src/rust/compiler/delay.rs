@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use super::types::*;
+
+/// 全ての展開済みモジュールの遅延を計算する
+pub fn module_delays(modules: &HashMap<String,CompiledModule>) -> HashMap<String,ModuleDelay> {
+    modules.iter().map(|(name,module)| (name.clone(),module_delay(module))).collect()
+}
+
+/// outputの遅延と、最も遅延の大きいoutputまでの経路を計算する
+/// gates_sequentialは順番に更新されるので、前に並んでいるゲートからの入力は同じtickのうちに伝わる
+/// 後ろに並んでいるゲートやgates_symmetryからの入力は前のtickの値なので、そこで経路は途切れる
+pub fn module_delay(module: &CompiledModule) -> ModuleDelay {
+    let sequential_len = module.gates_sequential.len();
+    let gates_len = sequential_len + module.gates_symmetry.len();
+    // depth[i]: ゲートiの出力までの段数, from[i]: 経路上で一つ前のゲート
+    let mut depth = vec![1u32; gates_len];
+    let mut from: Vec<Option<usize>> = vec![None; gates_len];
+    for (index,gate) in module.gates_sequential.iter().enumerate() {
+        for input in [&gate.0,&gate.1] {
+            if let CompiledGateInput::NorGate(n) = input {
+                let n = *n as usize;
+                if n<index && depth[n]+1>depth[index] {
+                    depth[index] = depth[n]+1;
+                    from[index] = Some(n);
+                }
+            }
+        }
+    }
+    // inputをそのまま出力している場合は遅延0
+    let output_depths = module.outputs.iter().map(|output| depth.get(*output as usize).copied().unwrap_or(0)).collect::<Vec<_>>();
+    let mut critical_path = Vec::new();
+    let critical_output = module.outputs.iter().zip(&output_depths).filter(|(_,d)| **d>0).max_by_key(|(_,d)| **d);
+    if let Some((output,_)) = critical_output {
+        let mut current = Some(*output as usize);
+        while let Some(index) = current {
+            critical_path.push(gate_name(module,index));
+            current = from[index];
+        }
+        critical_path.reverse();
+    }
+    ModuleDelay {
+        output_depths,
+        critical_path,
+    }
+}
+
+/// モジュール名から始まるゲートの名前 `fAddr.c1.hAddr.s.xor.e`
+pub fn gate_name(module: &CompiledModule, index: usize) -> String {
+    match module.gate_names.get(index) {
+        Some(name) if !name.is_empty() => format!("{}.{}",module.name,name),
+        _ => format!("{}[{}]",module.name,index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    /// norだけで作った全加算器の遅延: sはxorを2段 (3+3), cはその途中のandの後にor (3+2+2)
+    #[test]
+    fn full_adder_delay() {
+        let source = "
+            using nor:2->1;
+            module not (x)->(a) { a: nor <- x x; }
+            module or (a b)->(o) { n: nor <- a b; o: not <- n; }
+            module and (a b)->(o) { na: not <- a; nb: not <- b; o: nor <- na nb; }
+            module xor (a b)->(o) { c: and <- a b; n: nor <- a b; o: nor <- c n; }
+            module hAddr (a b)->(c s) { c: and <- a b; s: xor <- a b; }
+            module fAddr (a b c0)->(s c) {
+                c1 s1: hAddr <- a b;
+                c2 s: hAddr <- s1 c0;
+                c: or <- c1 c2;
+            }
+        ";
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        assert!(products.errors.is_empty(), "{:?}", products.errors);
+        assert_eq!(products.module_delays["xor"].output_depths, [3]);
+        assert_eq!(products.module_delays["hAddr"].output_depths, [2, 3]);
+        let delay = &products.module_delays["fAddr"];
+        assert_eq!(delay.output_depths, [6, 7]);
+        assert_eq!(delay.critical_path.len(), 7);
+        assert_eq!(delay.critical_path.first().map(String::as_str), Some("fAddr.c1.hAddr.s.xor.c.and.na.not.a"));
+        assert_eq!(delay.critical_path.last().map(String::as_str), Some("fAddr.c.or.o.not.a"));
+    }
+}
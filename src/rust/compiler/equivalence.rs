@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use super::sat::{lit, Lit, SatResult, Solver};
+use super::types::*;
+
+/// SATソルバが諦めるまでの矛盾の回数
+const MAX_CONFLICTS: usize = 1_000_000;
+
+/// 二つのfuncモジュールが全ての入力で同じ出力を返すことを証明する
+/// 二つのNOR回路の出力のXORのORを取った回路 (miter) が1になる入力をSATソルバで探す
+/// @return 等価ならNone, 等価でなければ反例の入力
+pub fn prove_equivalence(left: &CompiledModule, right: &CompiledModule) -> Result<Option<Vec<bool>>,String> {
+    if left.inputs!=right.inputs || left.outputs.len()!=right.outputs.len() {
+        return Err(format!("Used module with unmatched type: {} is {}->{} but {} is {}->{}",left.name,left.inputs,left.outputs.len(),right.name,right.inputs,right.outputs.len()));
+    }
+    let mut aig = Aig::new(left.inputs);
+    let left_outputs = aig.add_module(left)?;
+    let right_outputs = aig.add_module(right)?;
+    // どれかの出力が異なれば1になる
+    let mut miter = Aig::FALSE;
+    for (l,r) in left_outputs.iter().zip(&right_outputs) {
+        let diff = aig.xor(*l, *r);
+        miter = aig.or(miter, diff);
+    }
+    if miter==Aig::FALSE {
+        return Ok(None);
+    }
+    let mut solver = aig.to_cnf(miter);
+    solver.add_clause(vec![miter]);
+    match solver.solve(MAX_CONFLICTS) {
+        Ok(SatResult::Unsat) => Ok(None),
+        Ok(SatResult::Sat(values)) => Ok(Some(values[1..=left.inputs as usize].to_vec())),
+        Err(msg) => Err(format!("{} while proving {} == {}",msg,left.name,right.name)),
+    }
+}
+
+/// AND-Inverter Graph (同じ形のノードは一つにまとめる)
+/// 変数0は定数false, 変数1..=inputsは入力, それ以降はANDノード
+struct Aig {
+    inputs: u32,
+    nodes: Vec<(Lit,Lit)>,
+    hash: HashMap<(Lit,Lit),Lit>,
+}
+
+impl Aig {
+    const FALSE: Lit = 0;
+    const TRUE: Lit = 1;
+
+    fn new(inputs: u32) -> Self {
+        Aig {
+            inputs,
+            nodes: vec![(0,0); inputs as usize + 1],
+            hash: HashMap::new(),
+        }
+    }
+
+    fn and(&mut self, a: Lit, b: Lit) -> Lit {
+        let (a,b) = if a<=b { (a,b) } else { (b,a) };
+        if a==Self::FALSE || a^1==b { return Self::FALSE; }
+        if a==Self::TRUE || a==b { return b; }
+        if let Some(v) = self.hash.get(&(a,b)) {
+            return *v;
+        }
+        let node = lit(self.nodes.len() as u32, false);
+        self.nodes.push((a,b));
+        self.hash.insert((a,b), node);
+        node
+    }
+
+    fn or(&mut self, a: Lit, b: Lit) -> Lit {
+        self.and(a^1, b^1)^1
+    }
+
+    fn nor(&mut self, a: Lit, b: Lit) -> Lit {
+        self.and(a^1, b^1)
+    }
+
+    fn xor(&mut self, a: Lit, b: Lit) -> Lit {
+        let x = self.and(a, b^1);
+        let y = self.and(a^1, b);
+        self.or(x, y)
+    }
+
+    /// モジュールのゲートを追加して、出力のリテラルを返す
+    /// 入力から出力までの値が一つに決まるように、ループのある回路は扱わない
+    fn add_module(&mut self, module: &CompiledModule) -> Result<Vec<Lit>,String> {
+        let gates = module.gates_sequential.iter().chain(&module.gates_symmetry).collect::<Vec<_>>();
+        let gates_len = gates.len();
+        // 0: 未訪問 1: 訪問中 2: 完了
+        let mut state = vec![0u8; gates_len];
+        let mut literals = vec![Self::FALSE; gates_len];
+        let input_lit = |n: u32| -> Result<Lit,String> {
+            if n<module.inputs { Ok(lit(n+1, false)) }
+            else { Err(format!("Index out of bounds in {}",module.name)) }
+        };
+        let mut outputs = Vec::new();
+        for output in &module.outputs {
+            let output = *output as usize;
+            if output>=gates_len {
+                outputs.push(input_lit((output-gates_len) as u32)?);
+                continue;
+            }
+            // 深い回路でもスタックが溢れないように、ゲートを後順で辿る
+            let mut stack = vec![(output,false)];
+            while let Some((index,visited)) = stack.pop() {
+                let (a,b) = gates[index];
+                if visited {
+                    let operand = |input: &CompiledGateInput| match input {
+                        CompiledGateInput::NorGate(n) => Ok(literals[*n as usize]),
+                        CompiledGateInput::Input(n) => input_lit(*n),
+                    };
+                    let (a,b) = (operand(a)?,operand(b)?);
+                    literals[index] = self.nor(a, b);
+                    state[index] = 2;
+                    continue;
+                }
+                match state[index] {
+                    2 => continue,
+                    1 => return Err(format!("Can not prove equivalence of modules with feedback loops: {}",module.name)),
+                    _ => {},
+                }
+                state[index] = 1;
+                stack.push((index,true));
+                for input in [a,b] {
+                    if let CompiledGateInput::NorGate(n) = input {
+                        let n = *n as usize;
+                        if n>=gates_len {
+                            return Err(format!("Index out of bounds in {}",module.name));
+                        }
+                        match state[n] {
+                            0 => stack.push((n,false)),
+                            1 => return Err(format!("Can not prove equivalence of modules with feedback loops: {}",module.name)),
+                            _ => {},
+                        }
+                    }
+                }
+            }
+            outputs.push(literals[output]);
+        }
+        Ok(outputs)
+    }
+
+    /// rootから辿れるANDノードをTseitin変換でCNFにする
+    fn to_cnf(&self, root: Lit) -> Solver {
+        let mut solver = Solver::new(self.nodes.len());
+        solver.add_clause(vec![Self::TRUE]);
+        let mut visited = vec![false; self.nodes.len()];
+        let mut stack = vec![(root/2) as usize];
+        while let Some(var) = stack.pop() {
+            if visited[var] || var<=self.inputs as usize {
+                continue;
+            }
+            visited[var] = true;
+            let (a,b) = self.nodes[var];
+            let v = lit(var as u32, false);
+            // v <-> a & b
+            solver.add_clause(vec![v^1, a]);
+            solver.add_clause(vec![v^1, b]);
+            solver.add_clause(vec![v, a^1, b^1]);
+            stack.push((a/2) as usize);
+            stack.push((b/2) as usize);
+        }
+        solver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compiled_modules(source: &str) -> HashMap<String,CompiledModule> {
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        assert!(products.errors.is_empty(), "{:?}", products.errors);
+        products.expanded_modules
+    }
+
+    /// ループのないモジュールの出力を直接計算する
+    fn evaluate(module: &CompiledModule, inputs: &[bool]) -> Vec<bool> {
+        let gates = module.gates_sequential.iter().chain(&module.gates_symmetry).collect::<Vec<_>>();
+        fn wire(gates: &[&CompiledGate], inputs: &[bool], input: &CompiledGateInput) -> bool {
+            match input {
+                CompiledGateInput::NorGate(n) => gate(gates, inputs, *n as usize),
+                CompiledGateInput::Input(n) => inputs[*n as usize],
+            }
+        }
+        fn gate(gates: &[&CompiledGate], inputs: &[bool], index: usize) -> bool {
+            let (a,b) = gates[index];
+            !(wire(gates, inputs, a) || wire(gates, inputs, b))
+        }
+        module.outputs.iter().map(|&output| {
+            let output = output as usize;
+            if output<gates.len() { gate(&gates, inputs, output) } else { inputs[output-gates.len()] }
+        }).collect()
+    }
+
+    const SOURCE: &str = "
+        using nor:2->1;
+        func buf (x)->(x) {}
+        func not (x)->(a) { a: nor <- x x; }
+        func notnot (x)->(b) { a: not <- x; b: not <- a; }
+        func or (x y)->(b) { a: nor <- x y; b: not <- a; }
+        func and (x y)->(c) { a: not <- x; b: not <- y; c: nor <- a b; }
+        func xor (x y)->(e) { a: not <- x; b: not <- y; c: nor <- a b; d: nor <- x y; e: nor <- c d; }
+        func xor2 (x y)->(e) { a: nor <- x y; b: nor <- x a; c: nor <- y a; d: nor <- b c; e: not <- d; }
+        func andor (x y z)->(c) { a: and <- x y; c: or <- a z; }
+        func orand (x y z)->(c) { a: or <- x z; b: or <- y z; c: and <- a b; }
+        func andand (x y z)->(c) { a: and <- x y; c: and <- a z; }
+    ";
+
+    #[test]
+    fn equivalent() {
+        let modules = compiled_modules(SOURCE);
+        assert_eq!(prove_equivalence(&modules["notnot"], &modules["buf"]), Ok(None));
+        // 構造の違う回路はSATソルバで証明する (xor2はNOR4つのxnorの否定)
+        assert_eq!(prove_equivalence(&modules["xor"], &modules["xor2"]), Ok(None));
+        assert_eq!(prove_equivalence(&modules["andor"], &modules["orand"]), Ok(None));
+    }
+
+    #[test]
+    fn counterexample() {
+        let modules = compiled_modules(SOURCE);
+        for (left,right) in [("and","or"),("andor","andand"),("xor","and")] {
+            let (left,right) = (&modules[left],&modules[right]);
+            let inputs = prove_equivalence(left, right).unwrap().expect("expected a counterexample");
+            assert_eq!(inputs.len(), left.inputs as usize);
+            assert_ne!(evaluate(left, &inputs), evaluate(right, &inputs), "{} == {} at {:?}", left.name, right.name, inputs);
+        }
+        assert!(prove_equivalence(&modules["not"], &modules["and"]).is_err());
+    }
+}
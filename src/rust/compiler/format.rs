@@ -0,0 +1,417 @@
+use nom::{
+    branch::alt,
+    character::complete::{char, multispace0},
+    combinator::{map, opt},
+    sequence::tuple,
+    IResult,
+};
+
+use super::parser::{
+    component, equivalence_test, export, func_keyword, gate, graphical_keyword, identifier,
+    img_size_auto, img_size_number, import, include, io_list_input, io_list_output, line_comment,
+    module_keyword, module_reference, mtype, param_list, pixel, right_arrow, skip_component, sync,
+    test_keyword, test_pattern, using,
+};
+use super::types::*;
+
+const INDENT: &str = "    ";
+/// トップレベルで残す連続した空行の数の上限
+const MAX_BLANK_LINES: usize = 2;
+
+/// ソースコードを標準の書き方に整形する
+/// コメントは残し、整形できない部分 (マクロを含むcomponentなど) は元のまま残す
+/// 構文エラーがある場合や、整形でASTが変わってしまう場合はErr
+/// circuitgame_binのfmtだけが使う
+#[allow(dead_code)]
+pub fn format(source: &str) -> Result<String, String> {
+    let (original, errors) = super::macros::expand_and_parse(source);
+    if let Some(error) = errors.first() {
+        return Err(format!("Can not format a file with syntax errors: {}", error));
+    }
+    let formatted = format_top_level(source);
+    let (result, errors) = super::macros::expand_and_parse(&formatted);
+    if !errors.is_empty() || without_spans(&original)!=without_spans(&result) {
+        return Err("Formatting changed the meaning of the file (this is a bug of the formatter)".to_string());
+    }
+    Ok(formatted)
+}
+
+/// spanを除いたAST (整形の前後で比べる)
+fn without_spans(ast: &File) -> serde_json::Value {
+    fn strip(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                map.remove("span");
+                map.values_mut().for_each(strip);
+            },
+            serde_json::Value::Array(values) => values.iter_mut().for_each(strip),
+            _ => {},
+        }
+    }
+    let mut value = serde_json::to_value(ast).unwrap_or_default();
+    strip(&mut value);
+    value
+}
+
+/// 空白を読み飛ばし、含まれていた改行の数を返す
+fn skip_whitespace(input: &str) -> (&str, usize) {
+    let rest = input.trim_start();
+    (rest, input[..input.len()-rest.len()].matches('\n').count())
+}
+
+fn format_top_level(source: &str) -> String {
+    let mut out = String::new();
+    let mut rest = source;
+    // 前の要素の後の改行の数 (コメントは改行まで読むので1から始まる)
+    let mut newlines = 0;
+    let mut first = true;
+    loop {
+        let (next, n) = skip_whitespace(rest);
+        newlines += n;
+        rest = next;
+        if rest.is_empty() {
+            break;
+        }
+        let (next, text, is_comment) = if let Ok((next, comment)) = line_comment(rest) {
+            (next, comment.trim_end().to_string(), true)
+        }
+        else if let Some((next, text)) = format_component(rest) {
+            (next, text, false)
+        }
+        else {
+            // マクロなどで整形できないcomponentはそのまま残す
+            let len = match component(rest) {
+                Ok((next, _)) => rest.len()-next.len(),
+                Err(_) => skip_component(rest),
+            };
+            let len = if len==0 { rest.chars().next().map_or(1, char::len_utf8) } else { len };
+            (&rest[len..], rest[..len].trim_end().to_string(), false)
+        };
+        if !first {
+            if newlines==0 {
+                // 同じ行に続くコメント
+                out.push(' ');
+            }
+            else {
+                out.push_str(&"\n".repeat(newlines.min(MAX_BLANK_LINES+1)));
+            }
+        }
+        out.push_str(&text);
+        first = false;
+        newlines = if is_comment { 1 } else { 0 };
+        rest = next;
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+type ComponentFormatter = fn(&str) -> IResult<&str, String>;
+
+fn format_component(input: &str) -> Option<(&str, String)> {
+    let formatters: [ComponentFormatter; 7] = [
+        |i| map(using, |_| "using nor:2->1;".to_string())(i),
+        |i| map(import, |import| format!("import {{ {} }} from \"{}\";", import.modules.join(" "), import.path))(i),
+        |i| map(include, |include| format!("!include \"{}\";", include.path))(i),
+        |i| map(equivalence_test, |test| format_equivalence_test(&test))(i),
+        format_module,
+        format_test,
+        format_graphical,
+    ];
+    formatters.iter().find_map(|formatter| formatter(input).ok())
+}
+
+/// ブロックの中の要素
+enum BodyItem<T> {
+    /// 要素と同じ行に続くコメント
+    Item(T, Option<String>),
+    Comment(String),
+    Blank,
+}
+
+/// `{` の後から `}` までを読む (空行は一つにまとめる)
+fn block_body<'a, T>(mut input: &'a str, item: impl Fn(&'a str) -> IResult<&'a str, T>) -> IResult<&'a str, Vec<BodyItem<T>>> {
+    let mut items = Vec::new();
+    let mut carry = 0;
+    loop {
+        let (rest, n) = skip_whitespace(input);
+        let newlines = n + carry;
+        input = rest;
+        carry = 0;
+        if let Some(rest) = input.strip_prefix('}') {
+            return Ok((rest, items));
+        }
+        if newlines>=2 && !items.is_empty() {
+            items.push(BodyItem::Blank);
+        }
+        if let Ok((rest, comment)) = line_comment(input) {
+            let comment = comment.trim_end().to_string();
+            match items.last_mut() {
+                Some(BodyItem::Item(_, trailing @ None)) if newlines==0 => *trailing = Some(comment),
+                _ => items.push(BodyItem::Comment(comment)),
+            }
+            carry = 1;
+            input = rest;
+            continue;
+        }
+        let (rest, value) = item(input)?;
+        items.push(BodyItem::Item(value, None));
+        input = rest;
+    }
+}
+
+/// ブロックを `header {` から `}` までの文字列にする
+fn format_block(header: String, lines: Vec<String>) -> String {
+    if lines.is_empty() {
+        format!("{} {{\n}}", header)
+    }
+    else {
+        format!("{} {{\n{}\n}}", header, lines.join("\n"))
+    }
+}
+
+/// 要素を一行ずつ文字列にする
+fn body_lines<T>(items: &[BodyItem<T>], line: impl Fn(usize, &T) -> String) -> Vec<String> {
+    let mut index = 0;
+    items.iter().map(|item| match item {
+        BodyItem::Item(value, trailing) => {
+            let text = format!("{}{}", INDENT, line(index, value));
+            index += 1;
+            match trailing {
+                Some(comment) => format!("{} {}", text, comment),
+                None => text,
+            }
+        },
+        BodyItem::Comment(comment) => format!("{}{}", INDENT, comment),
+        BodyItem::Blank => String::new(),
+    }).collect()
+}
+
+fn format_size_expr(expr: &SizeExpr) -> String {
+    // パーサーは左結合で読むので、括弧なしで書けば同じ式に戻る
+    match expr {
+        SizeExpr::Number { value } => value.to_string(),
+        SizeExpr::Param { name } => name.clone(),
+        SizeExpr::Add { left, right } => format!("{}+{}", format_size_expr(left), format_size_expr(right)),
+        SizeExpr::Sub { left, right } => format!("{}-{}", format_size_expr(left), format_size_expr(right)),
+        SizeExpr::Mul { left, right } => format!("{}*{}", format_size_expr(left), format_size_expr(right)),
+        SizeExpr::Div { left, right } => format!("{}/{}", format_size_expr(left), format_size_expr(right)),
+    }
+}
+
+fn format_declaration(declaration: &PreOutputs) -> String {
+    match declaration.arr_size {
+        SizeExpr::Number { value: 1 } => declaration.arr_name.clone(),
+        _ => format!("{}({})", declaration.arr_name, format_size_expr(&declaration.arr_size)),
+    }
+}
+
+fn format_slice(slice: &PreInputs) -> String {
+    let s = &slice.arr_slice;
+    if s.all {
+        return slice.arr_name.clone();
+    }
+    let start = format_size_expr(&s.start);
+    let end = format_size_expr(&s.end);
+    let step = format_size_expr(&s.step);
+    if start==end && step=="1" && s.lower_inclusive && s.upper_inclusive {
+        return format!("{}[{}]", slice.arr_name, start);
+    }
+    format!(
+        "{}{}{},{}{}{}",
+        slice.arr_name,
+        if s.lower_inclusive { '[' } else { '(' },
+        start,
+        end,
+        if step=="1" { String::new() } else { format!(",{}", step) },
+        if s.upper_inclusive { ']' } else { ')' },
+    )
+}
+
+fn join<T>(values: &[T], f: impl Fn(&T) -> String) -> String {
+    values.iter().map(f).collect::<Vec<_>>().join(" ")
+}
+
+fn format_module(input: &str) -> IResult<&str, String> {
+    let (input, (export, sync, func, _, name, params, _, inputs, _, _, _, outputs, _, _)) = tuple((
+        export,
+        sync,
+        alt((map(func_keyword, |_| true), map(module_keyword, |_| false))),
+        multispace0,
+        identifier,
+        opt(param_list),
+        multispace0,
+        io_list_input,
+        multispace0,
+        right_arrow,
+        multispace0,
+        io_list_output,
+        multispace0,
+        char('{'),
+    ))(input)?;
+    let (input, items) = block_body(input, gate)?;
+    let header = format!(
+        "{}{}{} {}{} ({})->({})",
+        if export { "export " } else { "" },
+        if sync { "sync " } else { "" },
+        if func { "func" } else { "module" },
+        name,
+        params.map(|params| format!("<{}>", params.join(" "))).unwrap_or_default(),
+        join(&inputs, format_declaration),
+        join(&outputs, format_slice),
+    );
+    Ok((input, format_block(header, format_gates(&items))))
+}
+
+/// ゲートを空行で区切られたまとまりごとに `出力: モジュール <- 入力;` の列を揃えて並べる
+fn format_gates(items: &[BodyItem<PreGate>]) -> Vec<String> {
+    let columns: Vec<(String, String, String)> = items.iter().filter_map(|item| match item {
+        BodyItem::Item(gate, _) => Some((
+            join(&gate.outputs, format_declaration),
+            if gate.module_params.is_empty() {
+                gate.module_name.clone()
+            }
+            else {
+                format!("{}<{}>", gate.module_name, join(&gate.module_params, format_size_expr))
+            },
+            join(&gate.inputs, format_slice),
+        )),
+        _ => None,
+    }).collect();
+    // 各ゲートが属するまとまりの列の幅
+    let mut widths = vec![(0, 0, 0); columns.len()];
+    let mut group_start = 0;
+    let mut index = 0;
+    for item in items.iter().chain(std::iter::once(&BodyItem::Blank)) {
+        match item {
+            BodyItem::Item(..) => index += 1,
+            BodyItem::Blank => {
+                let group = &columns[group_start..index];
+                let width = (
+                    group.iter().map(|c| c.0.chars().count()).max().unwrap_or(0),
+                    group.iter().map(|c| c.1.chars().count()).max().unwrap_or(0),
+                    group.iter().map(|c| c.2.chars().count()).max().unwrap_or(0),
+                );
+                widths[group_start..index].fill(width);
+                group_start = index;
+            },
+            BodyItem::Comment(_) => {},
+        }
+    }
+    body_lines(items, |i, _| {
+        let (outputs, module, inputs) = &columns[i];
+        let (outputs_width, module_width, inputs_width) = widths[i];
+        if inputs_width==0 {
+            format!("{:<ow$}: {};", outputs, module, ow = outputs_width)
+        }
+        else {
+            format!("{:<ow$}: {:<mw$} <- {:<iw$};", outputs, module, inputs, ow = outputs_width, mw = module_width, iw = inputs_width)
+        }
+    })
+}
+
+fn format_test_value(value: &Option<bool>) -> String {
+    match value {
+        Some(true) => "t",
+        Some(false) => "f",
+        None => "x",
+    }.to_string()
+}
+
+fn format_test(input: &str) -> IResult<&str, String> {
+    let (input, (_, _, name, _, _, _, type_sig, _, _)) = tuple((
+        test_keyword,
+        multispace0,
+        module_reference,
+        multispace0,
+        char(':'),
+        multispace0,
+        mtype,
+        multispace0,
+        char('{'),
+    ))(input)?;
+    let (input, items) = block_body(input, test_pattern)?;
+    let header = format!("test {}:{}->{}", name, type_sig.input_count, type_sig.output_count);
+    let lines = body_lines(&items, |_, pattern: &TestPattern| {
+        let ticks = match pattern.ticks {
+            TestTicks::Default => String::new(),
+            TestTicks::Count { count } => format!(" @{}", count),
+            TestTicks::Stable => " @stable".to_string(),
+        };
+        format!("{}{} -> {};", join(&pattern.inputs, format_test_value), ticks, join(&pattern.outputs, format_test_value))
+    });
+    Ok((input, format_block(header, lines)))
+}
+
+fn format_equivalence_test(test: &EquivalenceTest) -> String {
+    let mut text = format!("test {} == {}", test.name, test.reference);
+    if let Some(random) = test.random {
+        text += &format!(" random {}", random);
+    }
+    if test.seed!=0 {
+        text += &format!(" seed {}", test.seed);
+    }
+    if test.prove {
+        text += " prove";
+    }
+    text + ";"
+}
+
+fn format_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn format_graphical(input: &str) -> IResult<&str, String> {
+    let (input, (_, _, name, _, _, _, size, _, _)) = tuple((
+        graphical_keyword,
+        multispace0,
+        module_reference,
+        multispace0,
+        char(':'),
+        multispace0,
+        alt((img_size_auto, img_size_number)),
+        multispace0,
+        char('{'),
+    ))(input)?;
+    let (input, items) = block_body(input, pixel)?;
+    let size = match size {
+        ImgSize::Size { width, height } => format!("{}x{}", width, height),
+        ImgSize::Auto(()) => "auto".to_string(),
+    };
+    let lines = body_lines(&items, |_, pixel: &Pixel| format!(
+        "{},{}: {}{} <- {} {};",
+        pixel.coord.0,
+        pixel.coord.1,
+        if pixel.io_index.io_type=="input" { "i" } else { "o" },
+        pixel.io_index.index,
+        format_color(pixel.color.on),
+        format_color(pixel.color.off),
+    ));
+    Ok((input, format_block(format!("graphical {}: {}", name, size), lines)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "using nor:2->1;\n\n\n\n// 否定\nfunc not(x)->(a){a:nor<-x x;}\n\nmodule sr_latch (s r)->(q nq) {\n    // 出力\n    q: nor <- r nq; // リセット\n\n    nq: nor <- s q;\n}\ntest not:1->1 { t -> f; f->t; }\n";
+
+    #[test]
+    fn idempotent() {
+        for source in [MESSY, include_str!("../../../spec/sample.ncg")] {
+            let formatted = format(source).unwrap();
+            assert_eq!(format(&formatted).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let formatted = format(MESSY).unwrap();
+        for comment in ["// 否定", "// 出力", "// リセット"] {
+            assert_eq!(formatted.matches(comment).count(), 1, "{}", comment);
+        }
+        assert!(formatted.contains("q: nor <- r nq; // リセット\n"), "{}", formatted);
+        assert!(format("using nor:2->1; // 末尾\n").unwrap().ends_with("// 末尾\n"));
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use super::types::*;
+use super::macros;
+use super::parser::split_instance_name;
+use super::include::{resolve_includes, resolve_path, normalize_path, display_path, FileLoader};
+
+/// 名前空間の単位 (importされたファイルと、そのファイルがincludeしたファイル)
+struct Unit {
+    path: String,
+    components: Vec<Component>,
+}
+
+/// `import { a b } from "file";` を解決し、全てのファイルのモジュールを一つのASTにまとめる
+/// モジュールの名前空間はファイルごとに分かれ、名前が衝突したimport先のモジュールは `file::name` の形に修飾される
+/// @return `Ok(( merged ast, loaded file list ))` `Err(( error[], loaded file list ))` (file list is root first)
+pub fn resolve_imports(ast: File, root_path: &str, loader: FileLoader) -> Result<(File,Vec<String>),(Errs,Vec<String>)> {
+    let mut errors = Vec::new();
+    let mut files = Vec::new();
+    // 1, importされているファイルを全て読み込む
+    let mut units = Vec::new();
+    match resolve_includes(ast, root_path, true, loader) {
+        Ok((ast,loaded)) => { files.extend(loaded); units.push(Unit { path: normalize_path(root_path), components: ast.components }); },
+        Err((msg,loaded)) => { files.extend(loaded); return Err((msg,files)); },
+    }
+    let mut unit_index: HashMap<String,usize> = HashMap::new();
+    unit_index.insert(units[0].path.clone(), 0);
+    let mut current = 0;
+    while current < units.len() {
+        let imports = units[current].components.iter().filter_map(|component| match component {
+            Component::Import(import) => Some(import.clone()),
+            _ => None,
+        }).collect::<Vec<_>>();
+        for import in imports {
+            let imported_path = resolve_path(&units[current].path, &import.path);
+            if unit_index.contains_key(&imported_path) {
+                continue;
+            }
+            unit_index.insert(imported_path.clone(), units.len());
+            let source = match loader(&imported_path) {
+                Ok(v) => v,
+                Err(e) => {
+                    files.push(imported_path.clone());
+                    errors.push(Diagnostic::error("import-failed", format!("Failed to import {} in {}: {}",imported_path,display_path(&units[current].path),e)).with_span(unit_file(current, &units[current].path), import.span));
+                    continue;
+                },
+            };
+            // 構文エラーがあっても読めた部分は使う
+            let (imported_ast,parse_errors) = macros::expand_and_parse(&source);
+            errors.extend(parse_errors.into_iter().map(|e| Diagnostic { file: imported_path.clone(), ..e }));
+            match resolve_includes(imported_ast, &imported_path, false, loader) {
+                Ok((ast,loaded)) => {
+                    files.extend(loaded.into_iter().filter(|f| !files.contains(f)).collect::<Vec<_>>());
+                    units.push(Unit { path: imported_path, components: ast.components });
+                },
+                Err((msg,loaded)) => {
+                    files.extend(loaded.into_iter().filter(|f| !files.contains(f)).collect::<Vec<_>>());
+                    errors.extend(msg);
+                },
+            }
+        }
+        current += 1;
+    }
+    if !errors.is_empty() {
+        return Err((errors,files));
+    }
+    // importがなければ名前空間は一つなので何もしない
+    if units.len()==1 {
+        let components = units.pop().map(|unit| unit.components).unwrap_or_default();
+        return Ok((File { components },files));
+    }
+    // 2, 名前の衝突を調べて各モジュールの最終的な名前を決める
+    let qualifiers = unit_qualifiers(&units);
+    let mut defined_units: HashMap<String,Vec<usize>> = HashMap::new();
+    for (index,unit) in units.iter().enumerate() {
+        for (name,_) in unit_modules(unit) {
+            let list = defined_units.entry(name.to_string()).or_default();
+            if !list.contains(&index) {
+                list.push(index);
+            }
+        }
+    }
+    let final_name = |unit: usize, name: &str| -> String {
+        match defined_units.get(name) {
+            Some(list) if unit!=0 && list.len()>1 => format!("{}::{}",qualifiers[unit],name),
+            _ => name.to_string(),
+        }
+    };
+    // 3, ファイルごとのスコープ (名前 -> 最終的な名前) を作る
+    let mut scopes: Vec<HashMap<String,String>> = Vec::new();
+    for (index,unit) in units.iter().enumerate() {
+        let mut scope = HashMap::new();
+        scope.insert("nor".to_string(), "nor".to_string());
+        for (name,_) in unit_modules(unit) {
+            scope.insert(name.to_string(), final_name(index, name));
+        }
+        let mut imported_from: HashMap<String,String> = HashMap::new();
+        for component in &unit.components {
+            if let Component::Import(import) = component {
+                let imported_path = resolve_path(&unit.path, &import.path);
+                let imported = unit_index[&imported_path];
+                let error = |code: &str, message: String| Diagnostic::error(code, message).with_span(unit_file(index, &unit.path), import.span);
+                for name in &import.modules {
+                    let export = match unit_modules(&units[imported]).find(|(m,_)| m==name) {
+                        Some((_,export)) => export,
+                        None => { errors.push(error("imported-module-undefined", format!("Imported module is not defined: {} from {} in {}",name,imported_path,display_path(&unit.path)))); continue; },
+                    };
+                    if !export {
+                        errors.push(error("imported-module-not-exported", format!("Imported module is not exported: {} from {} in {}",name,imported_path,display_path(&unit.path))));
+                        continue;
+                    }
+                    if unit_modules(unit).any(|(m,_)| m==name) {
+                        errors.push(error("imported-module-conflict", format!("Imported module conflicts with a defined module: {} from {} in {}",name,imported_path,display_path(&unit.path))));
+                        continue;
+                    }
+                    match imported_from.get(name) {
+                        Some(other) if other!=&imported_path => {
+                            errors.push(error("module-imported-from-multiple-files", format!("Module imported from multiple files: {} from {} and {} in {}",name,other,imported_path,display_path(&unit.path))));
+                            continue;
+                        },
+                        _ => {},
+                    }
+                    imported_from.insert(name.clone(), imported_path.clone());
+                    scope.insert(name.clone(), final_name(imported, name));
+                }
+            }
+        }
+        scopes.push(scope);
+    }
+    // 4, スコープに従って名前を書き換えながら一つのASTにまとめる
+    let mut components = Vec::new();
+    for (index,unit) in units.into_iter().enumerate() {
+        let scope = &scopes[index];
+        // パラメータ付きモジュールの実体 `add<8>` は名前の部分だけを解決する
+        // at: 使われている場所 `( file, span )`
+        let resolve = |name: &str, used_in: &str, at: Option<(&str,Span)>, errors: &mut Errs| -> String {
+            let (base,args) = split_instance_name(name);
+            match scope.get(base) {
+                Some(v) => format!("{}{}",v,args),
+                None => {
+                    let error = Diagnostic::error("undefined-module-used", format!("Undefined module used: {} in {}",base,used_in));
+                    errors.push(match at {
+                        Some((file,span)) => error.with_span(file, span),
+                        None => error,
+                    });
+                    name.to_string()
+                },
+            }
+        };
+        for component in unit.components {
+            match component {
+                Component::Module(mut module) => {
+                    let used_in = if module.file.is_empty() { module.name.clone() } else { format!("{} ({})",module.name,module.file) };
+                    for gate in &mut module.gates {
+                        gate.module_name = resolve(&gate.module_name, &used_in, Some((&module.file,gate.span)), &mut errors);
+                    }
+                    module.name = final_name(index, &module.name);
+                    components.push(Component::Module(module));
+                },
+                Component::GenericModule(mut module) => {
+                    let used_in = if module.file.is_empty() { module.name.clone() } else { format!("{} ({})",module.name,module.file) };
+                    for gate in &mut module.gates {
+                        gate.module_name = resolve(&gate.module_name, &used_in, Some((&module.file,gate.span)), &mut errors);
+                    }
+                    module.name = final_name(index, &module.name);
+                    components.push(Component::GenericModule(module));
+                },
+                Component::Test(mut test) => {
+                    test.name = resolve(&test.name, &format!("test ({})",display_path(&unit.path)), Some((&test.file,test.span)), &mut errors);
+                    components.push(Component::Test(test));
+                },
+                Component::EquivalenceTest(mut test) => {
+                    test.name = resolve(&test.name, &format!("test ({})",display_path(&unit.path)), Some((&test.file,test.span)), &mut errors);
+                    // スコープにない名前は組み込みの仕様として残す
+                    if scope.contains_key(split_instance_name(&test.reference).0) {
+                        test.reference = resolve(&test.reference, &format!("test ({})",display_path(&unit.path)), Some((&test.file,test.span)), &mut errors);
+                    }
+                    components.push(Component::EquivalenceTest(test));
+                },
+                Component::Graphical(mut graphical) => {
+                    graphical.name = resolve(&graphical.name, &format!("graphical ({})",display_path(&unit.path)), Some((&graphical.file,graphical.span)), &mut errors);
+                    components.push(Component::Graphical(graphical));
+                },
+                // 解決済みのimportとimport先のusingは取り除く
+                Component::Import(import) if index==0 => components.push(Component::Import(import)),
+                Component::Using(using) if index==0 => components.push(Component::Using(using)),
+                _ => {},
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err((errors,files));
+    }
+    Ok((File { components },files))
+}
+
+/// エラーに付けるファイル名 (ルートファイルなら空)
+fn unit_file(index: usize, path: &str) -> &str {
+    if index==0 { "" } else { path }
+}
+
+/// ファイルで定義されたモジュールの (名前, exportされているか) の一覧
+fn unit_modules(unit: &Unit) -> impl Iterator<Item = (&str,bool)> {
+    unit.components.iter().filter_map(|component| match component {
+        Component::Module(module) => Some((module.name.as_str(),module.export)),
+        Component::GenericModule(module) => Some((module.name.as_str(),module.export)),
+        _ => None,
+    })
+}
+
+/// 修飾名に使うファイルの名前 (拡張子なし, 同じ名前のファイルが複数ある場合はパス)
+fn unit_qualifiers(units: &[Unit]) -> Vec<String> {
+    let stems = units.iter().map(|unit| {
+        std::path::Path::new(&unit.path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| unit.path.clone())
+    }).collect::<Vec<_>>();
+    stems.iter().enumerate().map(|(index,stem)| {
+        if stems.iter().filter(|s| s==&stem).count()>1 { units[index].path.clone() }
+        else { stem.clone() }
+    }).collect()
+}
@@ -0,0 +1,125 @@
+use super::types::*;
+use super::macros;
+
+/// ファイルの読み込み関数 (解決済みのパスを受け取って内容を返す)
+pub type FileLoader<'a> = &'a dyn Fn(&str) -> Result<String,String>;
+
+/// `!include` を再帰的に読み込み、一つのASTにまとめる
+/// 同じファイルは一度だけ読み込まれ、循環するincludeはエラーになる
+/// is_main: コンパイル対象のファイルかどうか (falseならimportされたファイル)
+/// @return `Ok(( merged ast, loaded file list ))` `Err(( error[], loaded file list ))` (file list is root first)
+pub fn resolve_includes(ast: File, root_path: &str, is_main: bool, loader: FileLoader) -> Result<(File,Vec<String>),(Errs,Vec<String>)> {
+    let root = normalize_path(root_path);
+    let mut errors = Vec::new();
+    let mut loaded = vec![root.clone()];
+    let mut stack = vec![root.clone()];
+    let components = expand_includes(ast, &root, is_main, loader, &mut stack, &mut loaded, &mut errors);
+    if errors.is_empty() { Ok((File { components },loaded)) }
+    else { Err((errors,loaded)) }
+}
+
+fn expand_includes(
+    ast: File,
+    path: &str,
+    is_main: bool,
+    loader: FileLoader,
+    stack: &mut Vec<String>,
+    loaded: &mut Vec<String>,
+    errors: &mut Errs,
+) -> Vec<Component> {
+    let mut components = Vec::new();
+    for component in ast.components {
+        match component {
+            Component::Include(include) => {
+                let included_path = resolve_path(path, &include.path);
+                let file = if is_main { "" } else { path };
+                // 読み込み中のファイルを再びincludeしようとしている場合は循環
+                if let Some(pos) = stack.iter().position(|p| p==&included_path) {
+                    errors.push(Diagnostic::error("include-cycle", format!("Include cycle detected: {} -> {}",stack[pos..].join(" -> "),included_path)).with_span(file, include.span));
+                    continue;
+                }
+                // 既に読み込まれたファイルは無視する
+                if loaded.contains(&included_path) {
+                    continue;
+                }
+                loaded.push(included_path.clone());
+                let source = match loader(&included_path) {
+                    Ok(v) => v,
+                    Err(e) => { errors.push(Diagnostic::error("include-failed", format!("Failed to include {} in {}: {}",included_path,display_path(path),e)).with_span(file, include.span)); continue; },
+                };
+                // 構文エラーがあっても読めた部分は使う
+                let (included_ast,parse_errors) = macros::expand_and_parse(&source);
+                errors.extend(parse_errors.into_iter().map(|e| Diagnostic { file: included_path.clone(), ..e }));
+                stack.push(included_path.clone());
+                components.extend(expand_includes(included_ast, &included_path, false, loader, stack, loaded, errors));
+                stack.pop();
+            },
+            Component::Module(mut module) => {
+                if !is_main {
+                    module.file = path.to_string();
+                }
+                components.push(Component::Module(module));
+            },
+            Component::GenericModule(mut module) => {
+                if !is_main {
+                    module.file = path.to_string();
+                }
+                components.push(Component::GenericModule(module));
+            },
+            Component::Test(mut test) => {
+                if !is_main {
+                    test.file = path.to_string();
+                }
+                components.push(Component::Test(test));
+            },
+            Component::EquivalenceTest(mut test) => {
+                if !is_main {
+                    test.file = path.to_string();
+                }
+                components.push(Component::EquivalenceTest(test));
+            },
+            Component::Graphical(mut graphical) => {
+                if !is_main {
+                    graphical.file = path.to_string();
+                }
+                components.push(Component::Graphical(graphical));
+            },
+            other => components.push(other),
+        }
+    }
+    components
+}
+
+/// include,importしているファイルからの相対パスを解決する
+pub fn resolve_path(base: &str, relative: &str) -> String {
+    let relative_path = std::path::Path::new(relative);
+    if relative_path.is_absolute() {
+        return normalize_path(relative);
+    }
+    let joined = match std::path::Path::new(base).parent() {
+        Some(dir) => dir.join(relative_path),
+        None => relative_path.to_path_buf(),
+    };
+    normalize_path(&joined.to_string_lossy())
+}
+
+/// `.` と `..` を取り除く (ファイルシステムにはアクセスしない)
+pub fn normalize_path(path: &str) -> String {
+    use std::path::Component as PathComponent;
+    let mut parts: Vec<PathComponent> = Vec::new();
+    for part in std::path::Path::new(path).components() {
+        match part {
+            PathComponent::CurDir => {},
+            PathComponent::ParentDir => match parts.last() {
+                Some(PathComponent::Normal(_)) => { parts.pop(); },
+                _ => parts.push(part),
+            },
+            _ => parts.push(part),
+        }
+    }
+    parts.iter().collect::<std::path::PathBuf>().to_string_lossy().to_string()
+}
+
+pub fn display_path(path: &str) -> &str {
+    if path.is_empty() { "<input>" } else { path }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use super::delay::gate_name;
+use super::types::*;
+
+/// 全ての展開済みモジュールのフィードバックループを調べる
+pub fn module_loops(modules: &HashMap<String,CompiledModule>) -> HashMap<String,ModuleLoops> {
+    modules.iter().map(|(name,module)| (name.clone(),module_loop(module))).collect()
+}
+
+/// ゲートの入力をたどるグラフの強連結成分 (Tarjanのアルゴリズム) からフィードバックループを見つける
+/// 2つ以上のゲートからなる強連結成分と、自分自身を入力にするゲートがループになる
+/// ループがなければ出力はinputだけで決まるので、組み合わせ回路として純粋な関数にできる
+pub fn module_loop(module: &CompiledModule) -> ModuleLoops {
+    let gates = module.gates_sequential.iter().chain(&module.gates_symmetry).collect::<Vec<_>>();
+    // ゲートiの入力になっているゲート
+    let inputs_of = |index: usize| [&gates[index].0,&gates[index].1].into_iter().filter_map(|input| match input {
+        CompiledGateInput::NorGate(n) if (*n as usize)<gates.len() => Some(*n as usize),
+        _ => None,
+    });
+    // 大きなモジュールでも再帰が深くならないように、スタックを使って深さ優先探索する
+    let mut order = vec![usize::MAX; gates.len()];
+    let mut lowlink = vec![0; gates.len()];
+    let mut on_stack = vec![false; gates.len()];
+    let mut stack = Vec::new();
+    let mut counter = 0;
+    let mut components = Vec::new();
+    for root in 0..gates.len() {
+        if order[root]!=usize::MAX {
+            continue;
+        }
+        // ( ゲート, 次に見る入力の番号 )
+        let mut frames = vec![(root,0)];
+        order[root] = counter;
+        lowlink[root] = counter;
+        counter += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        while let Some(&mut (index, ref mut next)) = frames.last_mut() {
+            if let Some(input) = inputs_of(index).nth(*next) {
+                *next += 1;
+                if order[input]==usize::MAX {
+                    order[input] = counter;
+                    lowlink[input] = counter;
+                    counter += 1;
+                    stack.push(input);
+                    on_stack[input] = true;
+                    frames.push((input,0));
+                }
+                else if on_stack[input] {
+                    lowlink[index] = lowlink[index].min(order[input]);
+                }
+                continue;
+            }
+            frames.pop();
+            if let Some(&(parent,_)) = frames.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[index]);
+            }
+            if lowlink[index]==order[index] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack[member] = false;
+                    component.push(member);
+                    if member==index {
+                        break;
+                    }
+                }
+                let is_loop = component.len()>1 || inputs_of(index).any(|input| input==index);
+                if is_loop {
+                    component.sort();
+                    components.push(component);
+                }
+            }
+        }
+    }
+    components.sort();
+    let loops = components.iter().map(|component| component.iter().map(|index| gate_name(module,*index)).collect::<Vec<_>>()).collect::<Vec<_>>();
+    ModuleLoops {
+        combinational: loops.is_empty(),
+        loops,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn module_loops(source: &str) -> std::collections::HashMap<String,super::ModuleLoops> {
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        assert!(products.errors.is_empty(), "{:?}", products.errors);
+        products.module_loops
+    }
+
+    #[test]
+    fn classify_loops() {
+        let loops = module_loops("
+            using nor:2->1;
+            module not (x)->(a) { a: nor <- x x; }
+            module xor (a b)->(o) { na: not <- a; nb: not <- b; c: nor <- na nb; n: nor <- a b; o: nor <- c n; }
+            module ring ()->(c) { a: not <- c; b: not <- a; c: not <- b; }
+            module sr_latch (s r)->(q nq) { q: nor <- r nq; nq: nor <- s q; }
+            module two (s r x)->(q y) { q nq: sr_latch <- s r; y: nor <- x y; o: xor <- q x; }
+        ");
+        // ループのない回路
+        assert!(loops["xor"].combinational);
+        assert!(loops["xor"].loops.is_empty());
+        // 組み合わせ回路のゲートだけでできたループ (発振する)
+        assert!(!loops["ring"].combinational);
+        assert_eq!(loops["ring"].loops, [["ring.a.not.a", "ring.b.not.a", "ring.c.not.a"]]);
+        // ラッチ
+        assert!(!loops["sr_latch"].combinational);
+        assert_eq!(loops["sr_latch"].loops, [["sr_latch.q", "sr_latch.nq"]]);
+        // 別々のループと自分自身を入力にするゲート
+        assert_eq!(loops["two"].loops, [vec!["two.q.sr_latch.q", "two.q.sr_latch.nq"], vec!["two.y"]]);
+    }
+}
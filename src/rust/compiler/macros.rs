@@ -0,0 +1,763 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::types::*;
+use super::parser;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{take_while, take_while1},
+    character::complete::{char, digit1, multispace0, none_of},
+    combinator::{map, map_res, opt, recognize, value},
+    multi::{many0, separated_list0},
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
+};
+
+// マクロ: `!` <macro_expr> `:` <type> の形で書かれ、評価結果がソースコードに展開される
+//   !(for i (range 4) (format "c{} s{}: fAddr <- a[{}] b[{}] c{};" (+ i 1) i i i i)) : gates
+// 展開結果の型
+//   - none          : 何も展開しない (letやdefnで定義だけ行う)
+//   - number, int   : 数値
+//   - str, string   : 文字列をそのまま展開
+//   - ids           : 配列の要素を空白区切りで展開 (io listやgateの入出力)
+//   - gates, lines  : 配列の要素を空白区切りで展開 (gateやtest patternの列)
+//   - bools         : 数値の配列を t f の列として展開
+
+#[derive(Debug, Clone)]
+enum MacroExpr {
+    Call { name: String, args: Vec<MacroExpr> },
+    Chain(Vec<MacroExpr>),
+    Variable(String),
+    Number(i64),
+    Str(String),
+    Vector(Vec<MacroExpr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MacroValue {
+    None,
+    Number(i64),
+    Str(String),
+    Vector(Vec<MacroValue>),
+}
+
+/// マクロの変数とdefnで定義された関数 (ファイルごと)
+#[derive(Debug, Default)]
+struct MacroEnv {
+    vars: HashMap<String,MacroValue>,
+    funcs: HashMap<String,Rc<(Vec<String>,MacroExpr)>>,
+    /// スコープを抜けるときに戻す定義 (forの変数,関数の引数,スコープの中のletとdefn)
+    shadowed: Vec<Binding>,
+    /// defnで定義された関数の呼び出しの深さ
+    depth: usize,
+    /// 評価した式の数と作った値の大きさの合計
+    work: usize,
+}
+
+/// 上書きされる前の定義 (Noneなら未定義だった)
+#[derive(Debug)]
+enum Binding {
+    Var(String,Option<MacroValue>),
+    Func(String,Option<Rc<(Vec<String>,MacroExpr)>>),
+}
+
+/// defnで定義された関数の呼び出しの深さの上限 (再帰でスタックが溢れないように)
+const MAX_CALL_DEPTH: usize = 200;
+/// range,bits,concat,forで作るベクタの長さの上限
+const MAX_VECTOR_LEN: usize = 1 << 16;
+/// str,join,formatで作る文字列の長さの上限
+const MAX_STRING_LEN: usize = 1 << 20;
+/// 一つのファイルのマクロの評価にかけられる仕事量の上限 (ネストしたforなどで終わらなくならないように)
+const MAX_MACRO_WORK: usize = 1 << 22;
+
+impl MacroEnv {
+    /// トップレベルのマクロを評価する (トップレベルのletとdefnは以降のマクロでも使える)
+    fn eval_top(&mut self, expr: &MacroExpr) -> Result<MacroValue,String> {
+        let result = eval(expr, self);
+        self.shadowed.clear();
+        result
+    }
+    fn set_var(&mut self, name: &str, value: MacroValue) {
+        let old = self.vars.insert(name.to_string(), value);
+        self.shadowed.push(Binding::Var(name.to_string(), old));
+    }
+    fn set_func(&mut self, name: &str, func: Rc<(Vec<String>,MacroExpr)>) {
+        let old = self.funcs.insert(name.to_string(), func);
+        self.shadowed.push(Binding::Func(name.to_string(), old));
+    }
+    /// markの後の定義を取り消してスコープを抜ける
+    fn restore(&mut self, mark: usize) {
+        while self.shadowed.len()>mark {
+            match self.shadowed.pop() {
+                Some(Binding::Var(name,Some(old))) => { self.vars.insert(name, old); },
+                Some(Binding::Var(name,None)) => { self.vars.remove(&name); },
+                Some(Binding::Func(name,Some(old))) => { self.funcs.insert(name, old); },
+                Some(Binding::Func(name,None)) => { self.funcs.remove(&name); },
+                None => break,
+            }
+        }
+    }
+    fn spend(&mut self, amount: usize) -> Result<(),String> {
+        self.work = self.work.saturating_add(amount);
+        if self.work>MAX_MACRO_WORK {
+            return Err(format!("Macro expansion is too large (max {} steps per file)",MAX_MACRO_WORK));
+        }
+        Ok(())
+    }
+}
+
+/// 展開されたマクロの位置 `( 展開後の開始, 展開後の終了, 元の開始, 元の終了 )`
+type MacroRegion = (usize,usize,usize,usize);
+
+/// マクロを展開してからパースする
+/// spanは展開前のsourceでの位置に直す
+/// 評価できなかったマクロは何も展開せずにエラーにし、残りはそのままパースを続ける
+/// @return `( 読めた部分のAST, マクロと構文のエラー[] )`
+pub fn expand_and_parse(source: &str) -> (File,Errs) {
+    let line_starts = std::iter::once(0).chain(source.match_indices('\n').map(|(i,_)| i+1)).collect::<Vec<_>>();
+    let span_at = |start: usize, end: usize| {
+        let line = line_starts.partition_point(|&s| s<=start);
+        let column = source[line_starts[line-1]..start].chars().count() + 1;
+        Span { start, end, line, column }
+    };
+    let (expanded,regions,mut errors) = expand_macros(source, &span_at);
+    let to_source = |span: Span| {
+        let start = original_position(&regions, span.start, false);
+        let end = original_position(&regions, span.end, true).max(start);
+        span_at(start, end)
+    };
+    let (mut ast,mut parse_errors) = parser::parser(&expanded);
+    parser::map_spans(&mut ast, &to_source);
+    for error in &mut parse_errors {
+        error.span = error.span.map(to_source);
+    }
+    errors.extend(parse_errors);
+    (ast,errors)
+}
+
+/// 展開後の位置を展開前の位置に直す (マクロの中の位置はマクロの先頭か末尾にする)
+fn original_position(regions: &[MacroRegion], pos: usize, is_end: bool) -> usize {
+    let (mut expanded_end, mut original_end) = (0,0);
+    for &(expanded_start,region_end,original_start,region_original_end) in regions {
+        if pos<=expanded_start {
+            break;
+        }
+        if pos<region_end {
+            return if is_end { region_original_end } else { original_start };
+        }
+        (expanded_end,original_end) = (region_end,region_original_end);
+    }
+    pos - expanded_end + original_end
+}
+
+/// ソースコード中のマクロを全て評価し、展開したソースコードと展開した位置の一覧とエラーを返す
+/// 行番号がずれないよう、展開結果の改行が元のマクロより少ない場合は改行で埋める
+/// span_at: 展開前の `( 開始, 終了 )` からspanを作る関数
+fn expand_macros(source: &str, span_at: &dyn Fn(usize,usize) -> Span) -> (String,Vec<MacroRegion>,Errs) {
+    let mut regions = Vec::new();
+    let mut result = String::new();
+    let mut env = MacroEnv::default();
+    let mut errors = Vec::new();
+    let mut rest = source;
+    while let Some(pos) = find_macro_start(rest) {
+        result.push_str(&rest[..pos]);
+        let original_start = source.len()-rest.len()+pos;
+        let (remainder, (expr, macro_type)) = match macro_define(&rest[pos..]) {
+            Ok(v) => v,
+            Err(_) => {
+                // 括弧の対応が取れるところまでを読めなかったマクロとして飛ばす (中身を構文エラーにしないように)
+                let macro_text = &rest[pos..pos+malformed_macro_len(&rest[pos..])];
+                let line = macro_text.lines().next().unwrap_or("");
+                errors.push(Diagnostic::error("invalid-macro", format!("Invalid macro: {}",line.trim())).with_span("", span_at(original_start, original_start+macro_text.len())));
+                let expanded_start = result.len();
+                result.push_str(&"\n".repeat(macro_text.matches('\n').count()));
+                regions.push((expanded_start,result.len(),original_start,original_start+macro_text.len()));
+                rest = &rest[pos+macro_text.len()..];
+                continue;
+            },
+        };
+        let macro_text = &rest[pos..rest.len()-remainder.len()];
+        let text = match env.eval_top(&expr).and_then(|v| render(&v, &macro_type)) {
+            Ok(text) => text,
+            Err(e) => {
+                errors.push(Diagnostic::error("macro-evaluation-failed", format!("Macro evaluation failed: {}",e)).with_span("", span_at(original_start, original_start+macro_text.len())));
+                String::new()
+            },
+        };
+        let missing_lines = macro_text.matches('\n').count().saturating_sub(text.matches('\n').count());
+        let expanded_start = result.len();
+        result.push_str(&text);
+        result.push_str(&"\n".repeat(missing_lines));
+        regions.push((expanded_start,result.len(),original_start,original_start+macro_text.len()));
+        rest = remainder;
+    }
+    result.push_str(rest);
+    (result,regions,errors)
+}
+
+/// 読めなかったマクロ `!(...) : type` の長さ (括弧の対応が取れなければ行末まで)
+fn malformed_macro_len(input: &str) -> usize {
+    let line_end = input.find('\n').unwrap_or(input.len());
+    let body = input[1..].trim_start();
+    if !body.starts_with(['(', '{', '[']) {
+        return line_end;
+    }
+    let body_start = input.len()-body.len();
+    // 開いている括弧 (対応しない閉じ括弧は無視する)
+    let mut open = Vec::new();
+    let mut in_string = false;
+    let mut end = None;
+    for (i, c) in body.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {},
+            '(' => open.push(')'),
+            '{' => open.push('}'),
+            '[' => open.push(']'),
+            ')' | '}' | ']' if open.last()==Some(&c) => {
+                open.pop();
+                if open.is_empty() {
+                    end = Some(body_start+i+1);
+                    break;
+                }
+            },
+            _ => {},
+        }
+    }
+    let Some(end) = end else { return line_end };
+    // 続く `: type` も含める
+    let after = &input[end..];
+    let type_part = after.trim_start_matches([' ', '\t']);
+    match type_part.strip_prefix(':') {
+        Some(type_name) => {
+            let type_name = type_name.trim_start_matches([' ', '\t']);
+            let name_len = type_name.find(|c: char| !(c.is_ascii_alphanumeric() || c=='_')).unwrap_or(type_name.len());
+            input.len()-type_name.len()+name_len
+        },
+        None => end,
+    }
+}
+
+/// コメントと文字列を飛ばしながら次のマクロの開始位置を探す (`!include` はマクロではない)
+fn find_macro_start(input: &str) -> Option<usize> {
+    let bytes = input.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i+1)==Some(&b'/') => {
+                i = input[i..].find('\n').map(|n| i+n).unwrap_or(bytes.len());
+            },
+            // 色指定 (#rrggbb) ではない # はコメント
+            b'#' if !parser::is_color(&input[i..]) => {
+                i = input[i..].find('\n').map(|n| i+n).unwrap_or(bytes.len());
+            },
+            b'"' => {
+                i = input[i+1..].find('"').map(|n| i+n+2).unwrap_or(bytes.len());
+            },
+            b'!' => {
+                let keyword = input[i+1..].split(|c: char| !c.is_alphanumeric()).next().unwrap_or("");
+                if !matches!(keyword, "include"|"Include"|"INCLUDE") {
+                    return Some(i);
+                }
+                i += 1;
+            },
+            _ => { i += 1; },
+        }
+    }
+    None
+}
+
+// マクロのパーサー
+
+fn macro_define(input: &str) -> IResult<&str, (MacroExpr,String)> {
+    map(
+        tuple((
+            char('!'),
+            macro_expr,
+            multispace0,
+            char(':'),
+            multispace0,
+            macro_type,
+        )),
+        |(_,expr,_,_,_,macro_type)| (expr,macro_type),
+    )(input)
+}
+
+fn macro_type(input: &str) -> IResult<&str, String> {
+    map(
+        recognize(pair(
+            take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+        )),
+        String::from,
+    )(input)
+}
+
+fn macro_separator(input: &str) -> IResult<&str, ()> {
+    map(
+        take_while1(|c: char| c.is_whitespace() || c == ','),
+        |_| (),
+    )(input)
+}
+
+fn macro_expr(input: &str) -> IResult<&str, MacroExpr> {
+    alt((
+        macro_func_call,
+        macro_chain,
+        macro_literal,
+    ))(input)
+}
+
+fn macro_func_name(input: &str) -> IResult<&str, String> {
+    alt((
+        macro_variable_name,
+        map(
+            take_while1(|c: char| "+-*/%<>=!&|".contains(c)),
+            String::from,
+        ),
+    ))(input)
+}
+
+fn macro_func_call(input: &str) -> IResult<&str, MacroExpr> {
+    map(
+        delimited(
+            pair(char('('), multispace0),
+            pair(
+                macro_func_name,
+                many0(preceded(macro_separator, macro_expr)),
+            ),
+            pair(opt(macro_separator), char(')')),
+        ),
+        |(name,args)| MacroExpr::Call { name, args },
+    )(input)
+}
+
+fn macro_chain(input: &str) -> IResult<&str, MacroExpr> {
+    map(
+        delimited(
+            pair(char('{'), opt(macro_separator)),
+            separated_list0(macro_separator, macro_func_call),
+            pair(opt(macro_separator), char('}')),
+        ),
+        MacroExpr::Chain,
+    )(input)
+}
+
+fn macro_literal(input: &str) -> IResult<&str, MacroExpr> {
+    alt((
+        macro_number,
+        map(macro_variable_name, MacroExpr::Variable),
+        macro_string,
+        macro_vector,
+    ))(input)
+}
+
+fn macro_vector(input: &str) -> IResult<&str, MacroExpr> {
+    map(
+        delimited(
+            pair(char('['), opt(macro_separator)),
+            separated_list0(macro_separator, macro_expr),
+            pair(opt(macro_separator), char(']')),
+        ),
+        MacroExpr::Vector,
+    )(input)
+}
+
+fn macro_number(input: &str) -> IResult<&str, MacroExpr> {
+    map_res(
+        recognize(pair(opt(char('-')), digit1)),
+        |s: &str| s.parse::<i64>().map(MacroExpr::Number),
+    )(input)
+}
+
+fn macro_variable_name(input: &str) -> IResult<&str, String> {
+    map(
+        recognize(pair(
+            take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+        )),
+        String::from,
+    )(input)
+}
+
+fn macro_string(input: &str) -> IResult<&str, MacroExpr> {
+    map(
+        delimited(
+            char('"'),
+            many0(alt((
+                preceded(char('\\'), alt((
+                    value('\0', char('0')),
+                    value('\'', char('\'')),
+                    value('"', char('"')),
+                    value('\\', char('\\')),
+                    value('\n', char('n')),
+                    value('\r', char('r')),
+                    value('\u{0B}', char('v')),
+                    value('\t', char('t')),
+                    value('\u{08}', char('b')),
+                    value('\u{0C}', char('f')),
+                ))),
+                none_of("\"\\"),
+            ))),
+            char('"'),
+        ),
+        |chars| MacroExpr::Str(chars.into_iter().collect()),
+    )(input)
+}
+
+// マクロの評価
+
+fn eval(expr: &MacroExpr, env: &mut MacroEnv) -> Result<MacroValue,String> {
+    env.spend(1)?;
+    match expr {
+        MacroExpr::Number(n) => Ok(MacroValue::Number(*n)),
+        MacroExpr::Str(s) => Ok(MacroValue::Str(s.clone())),
+        MacroExpr::Vector(members) => Ok(MacroValue::Vector(
+            members.iter().map(|m| eval(m, env)).collect::<Result<Vec<_>,_>>()?
+        )),
+        MacroExpr::Chain(calls) => {
+            let mut result = MacroValue::None;
+            for call in calls {
+                result = eval(call, env)?;
+            }
+            Ok(result)
+        },
+        MacroExpr::Variable(name) => match env.vars.get(name) {
+            Some(v) => {
+                let v = v.clone();
+                env.spend(value_size(&v))?;
+                Ok(v)
+            },
+            None if env.funcs.contains_key(name) => call(name, &[], env),
+            None => Err(format!("Undefined macro variable: {}",name)),
+        },
+        MacroExpr::Call { name, args } => call(name, args, env),
+    }
+}
+
+fn call(name: &str, args: &[MacroExpr], env: &mut MacroEnv) -> Result<MacroValue,String> {
+    // 引数を評価しない特殊な関数
+    match name {
+        "let" => {
+            let (var, value) = match args {
+                [MacroExpr::Variable(var), value] => (var, value),
+                _ => return Err("let expects (let name value)".to_string()),
+            };
+            let value = eval(value, env)?;
+            env.set_var(var, value.clone());
+            return Ok(value);
+        },
+        "defn" => {
+            let (func_name, params, body) = match args {
+                [MacroExpr::Variable(func_name), MacroExpr::Vector(params), body] => (func_name, params, body),
+                _ => return Err("defn expects (defn name [params] body)".to_string()),
+            };
+            let params = params.iter().map(|p| match p {
+                MacroExpr::Variable(p) => Ok(p.clone()),
+                _ => Err(format!("Invalid parameter of {}",func_name)),
+            }).collect::<Result<Vec<_>,_>>()?;
+            env.set_func(func_name, Rc::new((params, body.clone())));
+            return Ok(MacroValue::None);
+        },
+        "for" => {
+            let (var, list, body) = match args {
+                [MacroExpr::Variable(var), list, body] => (var, list, body),
+                _ => return Err("for expects (for name list body)".to_string()),
+            };
+            let list = match eval(list, env)? {
+                MacroValue::Vector(v) => v,
+                v => return Err(format!("for expects a vector but got {}",type_name(&v))),
+            };
+            if list.len()>MAX_VECTOR_LEN {
+                return Err(format!("for is too long (max {})",MAX_VECTOR_LEN));
+            }
+            let mut result = Vec::with_capacity(list.len());
+            for item in list {
+                let mark = env.shadowed.len();
+                env.set_var(var, item);
+                let value = eval(body, env);
+                env.restore(mark);
+                result.push(value?);
+            }
+            return Ok(MacroValue::Vector(result));
+        },
+        "if" => {
+            let (cond, then, otherwise) = match args {
+                [cond, then, otherwise] => (cond, then, otherwise),
+                _ => return Err("if expects (if cond then else)".to_string()),
+            };
+            return if truthy(&eval(cond, env)?) { eval(then, env) } else { eval(otherwise, env) };
+        },
+        _ => {},
+    }
+    let values = args.iter().map(|a| eval(a, env)).collect::<Result<Vec<_>,_>>()?;
+    // ユーザー定義の関数
+    if let Some(func) = env.funcs.get(name).cloned() {
+        let (params, body) = &*func;
+        if params.len()!=values.len() {
+            return Err(format!("{} expects {} arguments but got {}",name,params.len(),values.len()));
+        }
+        if env.depth>=MAX_CALL_DEPTH {
+            return Err(format!("Macro calls are nested too deeply in {} (max {})",name,MAX_CALL_DEPTH));
+        }
+        let mark = env.shadowed.len();
+        for (param,value) in params.iter().zip(values) {
+            env.set_var(param, value);
+        }
+        env.depth += 1;
+        let result = eval(body, env);
+        env.depth -= 1;
+        env.restore(mark);
+        return result;
+    }
+    let result = builtin(name, values)?;
+    if let MacroValue::Str(s) = &result {
+        if s.len()>MAX_STRING_LEN {
+            return Err(format!("{} is too long (max {} bytes)",name,MAX_STRING_LEN));
+        }
+    }
+    env.spend(value_size(&result))?;
+    Ok(result)
+}
+
+fn builtin(name: &str, args: Vec<MacroValue>) -> Result<MacroValue,String> {
+    use MacroValue::*;
+    let numbers = || args.iter().map(|a| match a {
+        Number(n) => Ok(*n),
+        v => Err(format!("{} expects numbers but got {}",name,type_name(v))),
+    }).collect::<Result<Vec<i64>,String>>();
+    let bool_value = |b: bool| Number(if b { 1 } else { 0 });
+    match name {
+        "+" => numbers()?.iter().try_fold(0i64, |acc,n| acc.checked_add(*n)).map(Number).ok_or("Overflow".to_string()),
+        "*" => numbers()?.iter().try_fold(1i64, |acc,n| acc.checked_mul(*n)).map(Number).ok_or("Overflow".to_string()),
+        "-" => match numbers()?.as_slice() {
+            [n] => Ok(Number(-n)),
+            [first, rest @ ..] => rest.iter().try_fold(*first, |acc,n| acc.checked_sub(*n)).map(Number).ok_or("Overflow".to_string()),
+            [] => Err("- expects at least one argument".to_string()),
+        },
+        "/"|"%" => match numbers()?.as_slice() {
+            [_, 0] => Err("Division by zero".to_string()),
+            [a, b] => Ok(Number(if name=="/" { a.div_euclid(*b) } else { a.rem_euclid(*b) })),
+            _ => Err(format!("{} expects 2 arguments",name)),
+        },
+        "pow" => match numbers()?.as_slice() {
+            [a, b] if *b>=0 => a.checked_pow(*b as u32).map(Number).ok_or("Overflow".to_string()),
+            _ => Err("pow expects (pow base exponent)".to_string()),
+        },
+        "min"|"max" => {
+            let n = numbers()?;
+            let result = if name=="min" { n.iter().min() } else { n.iter().max() };
+            result.map(|v| Number(*v)).ok_or(format!("{} expects at least one argument",name))
+        },
+        "="|"=="|"!=" => match args.as_slice() {
+            [a, b] => Ok(bool_value((a==b) == (name!="!="))),
+            _ => Err(format!("{} expects 2 arguments",name)),
+        },
+        "<"|">"|"<="|">=" => match numbers()?.as_slice() {
+            [a, b] => Ok(bool_value(match name { "<" => a<b, ">" => a>b, "<=" => a<=b, _ => a>=b })),
+            _ => Err(format!("{} expects 2 arguments",name)),
+        },
+        "and" => Ok(bool_value(args.iter().all(truthy))),
+        "or" => Ok(bool_value(args.iter().any(truthy))),
+        "not" => match args.as_slice() {
+            [a] => Ok(bool_value(!truthy(a))),
+            _ => Err("not expects 1 argument".to_string()),
+        },
+        "range" => {
+            let (start, end, step) = match numbers()?.as_slice() {
+                [end] => (0, *end, 1),
+                [start, end] => (*start, *end, 1),
+                [start, end, step] if *step!=0 => (*start, *end, *step),
+                _ => return Err("range expects (range [start] end [step])".to_string()),
+            };
+            let mut result = Vec::new();
+            let mut i = start;
+            while (step>0 && i<end) || (step<0 && i>end) {
+                if result.len()>=MAX_VECTOR_LEN {
+                    return Err(format!("range is too long (max {})",MAX_VECTOR_LEN));
+                }
+                result.push(Number(i));
+                i = match i.checked_add(step) {
+                    Some(v) => v,
+                    Option::None => break,
+                };
+            }
+            Ok(Vector(result))
+        },
+        "bits" => match numbers()?.as_slice() {
+            [_, width] if *width>MAX_VECTOR_LEN as i64 => Err(format!("bits is too long (max {})",MAX_VECTOR_LEN)),
+            [v, width] if *width>=0 => Ok(Vector((0..*width).map(|i| Number(v.checked_shr(i as u32).unwrap_or(0) & 1)).collect())),
+            _ => Err("bits expects (bits value width)".to_string()),
+        },
+        "len" => match args.as_slice() {
+            [Vector(v)] => Ok(Number(v.len() as i64)),
+            [Str(s)] => Ok(Number(s.chars().count() as i64)),
+            _ => Err("len expects a vector or a string".to_string()),
+        },
+        "get" => match args.as_slice() {
+            [Vector(v), Number(i)] => v.get(*i as usize).cloned().ok_or(format!("Index out of bounds: {}",i)),
+            [Str(s), Number(i)] => s.chars().nth(*i as usize).map(|c| Str(c.to_string())).ok_or(format!("Index out of bounds: {}",i)),
+            _ => Err("get expects (get vector index)".to_string()),
+        },
+        "rev" => match args.as_slice() {
+            [Vector(v)] => Ok(Vector(v.iter().rev().cloned().collect())),
+            _ => Err("rev expects a vector".to_string()),
+        },
+        "concat" => {
+            let mut result = Vec::new();
+            for arg in args {
+                match arg {
+                    Vector(v) => result.extend(v),
+                    v => result.push(v),
+                }
+                if result.len()>MAX_VECTOR_LEN {
+                    return Err(format!("concat is too long (max {})",MAX_VECTOR_LEN));
+                }
+            }
+            Ok(Vector(result))
+        },
+        "str" => Ok(Str(args.iter().map(to_text).collect::<Result<Vec<_>,_>>()?.concat())),
+        "join" => match args.as_slice() {
+            [Str(sep), Vector(v)] => Ok(Str(v.iter().map(to_text).collect::<Result<Vec<_>,_>>()?.join(sep))),
+            _ => Err("join expects (join separator vector)".to_string()),
+        },
+        "format" => {
+            let (fmt, rest) = match args.split_first() {
+                Some((Str(fmt), rest)) => (fmt, rest),
+                _ => return Err("format expects a format string".to_string()),
+            };
+            let pieces = fmt.split("{}").collect::<Vec<_>>();
+            if pieces.len()-1 != rest.len() {
+                return Err(format!("format expects {} arguments but got {}",pieces.len()-1,rest.len()));
+            }
+            let mut result = pieces[0].to_string();
+            for (arg,piece) in rest.iter().zip(&pieces[1..]) {
+                result.push_str(&to_text(arg)?);
+                result.push_str(piece);
+            }
+            Ok(Str(result))
+        },
+        "bools" => match args.as_slice() {
+            [Vector(v)] => Ok(Str(v.iter().map(|b| if truthy(b) { "t" } else { "f" }).collect::<Vec<_>>().join(" "))),
+            _ => Err("bools expects a vector".to_string()),
+        },
+        _ => Err(format!("Undefined macro function: {}",name)),
+    }
+}
+
+fn truthy(value: &MacroValue) -> bool {
+    match value {
+        MacroValue::None => false,
+        MacroValue::Number(n) => *n!=0,
+        MacroValue::Str(s) => !s.is_empty(),
+        MacroValue::Vector(v) => !v.is_empty(),
+    }
+}
+
+/// 値を作るのにかかる仕事量 (文字列の長さかベクタの要素数)
+fn value_size(value: &MacroValue) -> usize {
+    match value {
+        MacroValue::Str(s) => s.len(),
+        MacroValue::Vector(v) => v.len(),
+        _ => 0,
+    }
+}
+
+fn type_name(value: &MacroValue) -> &'static str {
+    match value {
+        MacroValue::None => "none",
+        MacroValue::Number(_) => "number",
+        MacroValue::Str(_) => "string",
+        MacroValue::Vector(_) => "vector",
+    }
+}
+
+fn to_text(value: &MacroValue) -> Result<String,String> {
+    match value {
+        MacroValue::Number(n) => Ok(n.to_string()),
+        MacroValue::Str(s) => Ok(s.clone()),
+        v => Err(format!("Cannot convert {} to string",type_name(v))),
+    }
+}
+
+/// 評価結果を型注釈に従ってソースコードに変換する
+fn render(value: &MacroValue, macro_type: &str) -> Result<String,String> {
+    fn flatten(value: &MacroValue, out: &mut Vec<MacroValue>) {
+        match value {
+            MacroValue::Vector(v) => v.iter().for_each(|m| flatten(m, out)),
+            MacroValue::None => {},
+            v => out.push(v.clone()),
+        }
+    }
+    let mut items = Vec::new();
+    flatten(value, &mut items);
+    match macro_type {
+        "none" => Ok(String::new()),
+        "number"|"int" => match value {
+            MacroValue::Number(n) => Ok(n.to_string()),
+            v => Err(format!("Expected number but got {}",type_name(v))),
+        },
+        "str"|"string" => match value {
+            MacroValue::Str(_)|MacroValue::Number(_) => to_text(value),
+            v => Err(format!("Expected string but got {}",type_name(v))),
+        },
+        "ids"|"gates"|"lines" => Ok(items.iter().map(to_text).collect::<Result<Vec<_>,_>>()?.join(" ")),
+        "bools" => Ok(items.iter().map(|b| if truthy(b) { "t" } else { "f" }).collect::<Vec<_>>().join(" ")),
+        _ => Err(format!("Unknown macro type: {}",macro_type)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `#` の後に非ASCII文字があってもコメントとして読み飛ばす
+    #[test]
+    fn non_ascii_comment() {
+        let (ast,errors) = expand_and_parse("# ああ comment\n#あ\nmodule not (x)->(a) { a: nor <- x x; }\n");
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(ast.components.len(), 1);
+    }
+
+    /// 評価できなかったマクロは位置付きのエラーになり、残りのcomponentは読める
+    #[test]
+    fn macro_error_keeps_parsing() {
+        let source = "module not (x)->(a) { a: nor <- x x; }\n!(undefined_func 1) : none\nmodule buf (x)->(a) { b: not <- x; a: not <- b; }\n";
+        let (ast,errors) = expand_and_parse(source);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].code, "macro-evaluation-failed");
+        assert_eq!(errors[0].span.map(|s| (s.line,s.column)), Some((2,1)));
+        assert_eq!(ast.components.len(), 2);
+    }
+
+    /// 読めないマクロは括弧の対応が取れるところまで飛ばし、中身を構文エラーにしない
+    #[test]
+    fn malformed_macro_is_skipped() {
+        let source = "module not (x)->(a) { a: nor <- x x; }\n!(for i (range 4)\n  (format \"{}: nor <- x x;\" i) ]) : gates\nmodule buf (x)->(a) { b: not <- x; a: not <- b; }\n";
+        let (ast,errors) = expand_and_parse(source);
+        assert_eq!(errors.iter().map(|e| e.code.as_str()).collect::<Vec<_>>(), ["invalid-macro"], "{:?}", errors);
+        assert_eq!(errors[0].span.map(|s| (s.line,s.column)), Some((2,1)));
+        assert_eq!(ast.components.len(), 2);
+        assert_eq!(malformed_macro_len("!(a (b) \")\") : gates x"), "!(a (b) \")\") : gates".len());
+        assert_eq!(malformed_macro_len("!(a (b)\nc"), "!(a (b)".len());
+    }
+
+    /// ネストしたforで展開が大きくなりすぎる場合は終わらなくなる前にエラーになる
+    #[test]
+    fn expansion_is_bounded() {
+        let (_,errors) = expand_and_parse("!(for i (range 60000) (for j (range 60000) (format \"a{}\" j))) : none\n");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("too large"), "{}", errors[0].message);
+    }
+
+    /// forと関数の中のletはスコープを抜けると消え、トップレベルのletは次のマクロでも使える
+    #[test]
+    fn scoped_bindings() {
+        let mut env = MacroEnv::default();
+        let define = macro_define("!{(let x 1) (for i (range 3) (let x i)) (defn f [y] (let x y))} : none").unwrap().1.0;
+        env.eval_top(&define).unwrap();
+        let use_x = macro_define("!(+ x (f 5) x) : number").unwrap().1.0;
+        assert_eq!(env.eval_top(&use_x), Ok(MacroValue::Number(7)));
+    }
+}
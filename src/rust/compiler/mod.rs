@@ -1,9 +1,20 @@
-use compile::serialize_to_vec;
 use types::IntermediateProducts;
 
 mod parser;
+mod macros;
+mod include;
+mod import;
 mod modulecheck;
 mod compile;
+mod optimize;
+mod delay;
+mod loops;
+mod symbols;
+mod sat;
+mod equivalence;
+mod ncgb;
+#[cfg(feature = "native")]
+pub mod format;
 pub mod types;
 
 #[cfg(feature = "web")]
@@ -12,25 +23,87 @@ pub fn compile(input: &str,module: &str) -> Result<Vec<u32>,String> {
 }
 
 pub fn serialize(products: IntermediateProducts,module: &str) -> Result<Vec<u32>,String> {
-    if products.errors.len()>0 {
-        return Err(products.errors.join("\n"));
+    serialize_modules(&products, &[module.to_string()], &NcgbOptions::default())
+}
+
+/// 複数のモジュールを一つのNCGBにする
+pub fn serialize_modules(products: &IntermediateProducts,modules: &[String],options: &NcgbOptions) -> Result<Vec<u32>,String> {
+    if !products.errors.is_empty() {
+        return Err(products.errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"));
+    }
+    ncgb::serialize_container(products, modules, options)
+}
+
+pub use ncgb::NcgbOptions;
+
+/// コンパイルの設定
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// funcモジュールのゲートを最適化するかどうか (falseなら展開したままのゲートを残す, デバッグ向け)
+    pub optimize: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions { optimize: true }
+    }
+}
+
+pub use include::FileLoader;
+
+/// マクロを展開してパースする (include,importは解決しない, circuitgame_lsp向け)
+#[cfg(feature = "native")]
+#[allow(dead_code)]
+pub fn parse(input: &str) -> (types::File, types::Errs) {
+    macros::expand_and_parse(input)
+}
+/// inputとinclude,importされている全てのファイルの一覧 (パスは正規化済み, ルートが最初)
+/// include,importを解決するだけでコンパイルはしない (watchでファイルが変わるたびに呼ぶ)
+#[cfg(feature = "native")]
+#[allow(dead_code)]
+pub fn source_files(input: &str, input_path: &str, loader: FileLoader) -> Vec<String> {
+    let (ast,_) = macros::expand_and_parse(input);
+    match import::resolve_imports(ast, input_path, loader) {
+        Ok((_,files)) | Err((_,files)) => files,
     }
-    let expanded_module = match products.expanded_modules.get(module) {
-        Some(v) => v.clone(),
-        None => {return Err(format!("An undefined module was specified: {}",module));}
-    };
-    let serialized = serialize_to_vec(expanded_module);
-    Ok(serialized)
 }
+/// `.` と `..` を取り除いたパス (source_filesの一覧と比べる)
+#[cfg(feature = "native")]
+#[allow(dead_code)]
+pub fn normalize_path(path: &str) -> String {
+    include::normalize_path(path)
+}
+pub use equivalence::prove_equivalence;
 
+/// includeを解決できない環境 (web) 向け
+#[cfg(feature = "web")]
 pub fn intermediate_products(input: &str) -> types::IntermediateProducts {
+    intermediate_products_with_loader(input, "", &|path: &str| Err(format!("File loading is not supported in this environment: {}",path)))
+}
+
+/// input_path: inputのパス (includeの相対パスの基準)
+/// loader: include,importされたファイルを読み込む関数
+pub fn intermediate_products_with_loader(input: &str, input_path: &str, loader: FileLoader) -> types::IntermediateProducts {
+    intermediate_products_with_options(input, input_path, loader, &CompileOptions::default())
+}
+
+pub fn intermediate_products_with_options(input: &str, input_path: &str, loader: FileLoader, options: &CompileOptions) -> types::IntermediateProducts {
     use modulecheck::*;
     use compile::*;
-    let mut products = types::IntermediateProducts { source: input.to_string() ,warns: Vec::new(), errors: Vec::new(), ast: types::File { components: Vec::new() }, defined_non_func_module_list: Vec::new(), defined_func_module_list: Vec::new() , module_type_list: Vec::new(), module_dependency: Vec::new(), module_dependency_sorted: Vec::new(), expanded_modules: std::collections::HashMap::new() };
-    // 0, パース
-    products.ast = match parser::parser(input).map_err(|err| format!("[Parser error]\n{}", err)) {
+    let mut products = types::IntermediateProducts { source: input.to_string(), source_files: vec![input_path.to_string()], warns: Vec::new(), errors: Vec::new(), ast: types::File { components: Vec::new() }, defined_non_func_module_list: Vec::new(), defined_func_module_list: Vec::new() , module_type_list: Vec::new(), module_dependency: Vec::new(), module_dependency_sorted: Vec::new(), expanded_modules: std::collections::HashMap::new(), module_optimizations: std::collections::HashMap::new(), module_delays: std::collections::HashMap::new(), module_loops: std::collections::HashMap::new(), symbol_tables: std::collections::HashMap::new() };
+    // 0, マクロを展開してパース (構文エラーがあっても、読めた部分で以降のチェックを続ける)
+    let (ast,parse_errors) = macros::expand_and_parse(input);
+    products.ast = ast;
+    products.errors.extend(parse_errors);
+    // 0.5, include,importを解決して一つのASTにまとめる
+    products.ast = match import::resolve_imports(products.ast.clone(), input_path, loader) {
+        Ok((ast,files)) => {products.source_files = files;ast},
+        Err((msg,files)) => {products.source_files = files;products.errors.extend(msg);return products;},
+    };
+    // 0.6, パラメータ付きモジュールを実体化
+    products.ast = match instantiate_modules(&products.ast) {
         Ok(ast) => ast,
-        Err(msg) => {products.errors.push(msg);return products;},
+        Err(msg) => {products.errors.extend(msg);return products;},
     };
     // println!("{:#?}",products.ast);
     // 1, モジュール定義の一覧を作成
@@ -39,7 +112,7 @@ pub fn intermediate_products(input: &str) -> types::IntermediateProducts {
     products.defined_func_module_list = modules_info.1;
     products.module_type_list = modules_info.2;
     // 2, モジュールの名前に重複がないかを確認
-    match check_module_name_duplicates(&products.module_type_list) {
+    match check_module_name_duplicates(&products.ast,&products.module_type_list) {
         Ok(()) => {},
         Err(msg) => {products.errors.extend(msg);return products;},
     };
@@ -60,7 +133,14 @@ pub fn intermediate_products(input: &str) -> types::IntermediateProducts {
         Ok(v) => {v},
         Err(msg) => {products.errors.extend(msg);return products;},
     };
+    // 6.5, funcモジュールのゲートを最適化
+    products.module_optimizations = optimize::optimize_modules(&mut products.expanded_modules, options.optimize);
     // 7, 各モジュールの遅延を計算
+    products.module_delays = delay::module_delays(&products.expanded_modules);
+    // 7.2, フィードバックループを検出
+    products.module_loops = loops::module_loops(&products.expanded_modules);
+    // 7.5, ゲートの名前の対応表を作成
+    products.symbol_tables = symbols::symbol_tables(&products.ast, &products.expanded_modules);
     // 8, testを実行
     products
 }
\ No newline at end of file
@@ -18,9 +18,6 @@ pub fn collect_modules(ast: &File) -> (Vec<String>,Vec<String>,Vec<ModuleType>)
     // ASTの中で定義されたモジュールを集める
     for component in &ast.components {
         match component {
-            Component::Include(include) => {
-                println!("Include: {}", include.path);
-            },
             // Component::Graphical(include) => {
             //     println!("Graphical: {:#?}", include);
             // },
@@ -43,20 +40,43 @@ pub fn collect_modules(ast: &File) -> (Vec<String>,Vec<String>,Vec<ModuleType>)
     return (non_func_modules,func_modules,modules);
 }
 
-pub fn check_module_name_duplicates(modules: &Vec<ModuleType>) -> Result<(),Vec<String>> {
+/// importされたファイルのモジュールは名前が衝突しないよう修飾されているので、重複は同じファイル内での重複になる
+pub fn check_module_name_duplicates(ast: &File, modules: &Vec<ModuleType>) -> Result<(),Errs> {
     let mut module_names = std::collections::HashSet::new();
     let mut errors = Vec::new();
     for module in modules {
         if !module_names.insert(&module.name) {
-            errors.push(format!("Defined module name Duplicated: {}",module.name));
+            let defined = ast.components.iter().filter_map(|component| match component {
+                Component::Module(m) if m.name==module.name => Some(m),
+                _ => None,
+            }).collect::<Vec<_>>();
+            let files = defined.iter().filter(|m| !m.file.is_empty()).map(|m| m.file.clone()).collect::<Vec<_>>();
+            let message = if files.is_empty() {
+                format!("Defined module name Duplicated: {}",module.name)
+            }
+            else {
+                format!("Defined module name Duplicated: {} ({})",module.name,files.join(", "))
+            };
+            // 二つ目の定義の位置を示す
+            let error = Diagnostic::error("defined-module-name-duplicated", message);
+            errors.push(match (defined.first(),defined.get(1)) {
+                (Some(first),Some(second)) => error.with_span(&second.file, second.span).with_note(format!("first defined at line {}",first.span.line)),
+                _ => error,
+            });
         }
     }
     if errors.len()==0 { Ok(()) }
     else { Err(errors) }
 }
 
-pub fn check_module_gates(ast: &File, module_types: &Vec<ModuleType>) -> Result<(),Vec<String>> {
-    let mut errors: Vec<String> = Vec::new();
+/// エラーメッセージ用のモジュール名 (includeされたファイルならファイル名も付ける)
+fn module_label(module: &Module) -> String {
+    if module.file.is_empty() { module.name.clone() }
+    else { format!("{} ({})",module.name,module.file) }
+}
+
+pub fn check_module_gates(ast: &File, module_types: &Vec<ModuleType>) -> Result<(),Errs> {
+    let mut errors: Errs = Vec::new();
     // moduleの一覧を作る
     let mut modules = Vec::new();
     for component in &ast.components {
@@ -73,26 +93,26 @@ pub fn check_module_gates(ast: &File, module_types: &Vec<ModuleType>) -> Result<
         let mut id_names = std::collections::HashSet::new();
         for input in &module.inputs {
             if !id_names.insert(input) {
-                errors.push(format!("Defined id Duplicated: Input {} in {}",input,module.name));
+                errors.push(Diagnostic::error("defined-id-duplicated", format!("Defined id Duplicated: Input {} in {}",input,module_label(module))).with_span(&module.file, module.span));
             }
         }
         for gates in &module.gates {
             for output in &gates.outputs {
                 if !id_names.insert(output) {
-                    errors.push(format!("Defined id Duplicated: Gate-Out {} in {}",output,module.name));
+                    errors.push(Diagnostic::error("defined-id-duplicated", format!("Defined id Duplicated: Gate-Out {} in {}",output,module_label(module))).with_span(&module.file, gates.span));
                 }
             }
         }
         // 宣言されていない名前が使われていないかの確認
         for output in &module.outputs {
             if !id_names.contains(output) {
-                errors.push(format!("Undefined id used: Output {} in {}",output,module.name));
+                errors.push(Diagnostic::error("undefined-id-used", format!("Undefined id used: Output {} in {}",output,module_label(module))).with_span(&module.file, module.span));
             }
         }
         for gates in &module.gates {
             for input in &gates.inputs {
                 if !id_names.contains(input) {
-                    errors.push(format!("Undefined id used: Gate-In {} in {}",input,module.name));
+                    errors.push(Diagnostic::error("undefined-id-used", format!("Undefined id used: Gate-In {} in {}",input,module_label(module))).with_span(&module.file, gates.span));
                 }
             }
         }
@@ -113,7 +133,7 @@ pub fn check_module_gates(ast: &File, module_types: &Vec<ModuleType>) -> Result<
                 }
                 for input in &gates.inputs {
                     if !id_names.contains(input) {
-                        errors.push(format!("In a function module, a value cannot be used before it is declared: {} in {}",input,module.name));
+                        errors.push(Diagnostic::error("used-before-declared", format!("In a function module, a value cannot be used before it is declared: {} in {}",input,module_label(module))).with_span(&module.file, gates.span));
                     }
                 }
             }
@@ -126,10 +146,10 @@ pub fn check_module_gates(ast: &File, module_types: &Vec<ModuleType>) -> Result<
                 Some(mtype) => { // 使われているモジュールが定義されている場合
                     // moduleのinput,outputの型を確認
                     if gate.inputs.len()!=mtype.input_count||gate.outputs.len()!=mtype.output_count {
-                        errors.push(format!("Used module with unmatched type: {} expected {}->{} but got {}->{}, in {}",gate.module_name,mtype.input_count,mtype.output_count,gate.inputs.len(),gate.outputs.len(),module.name));
+                        errors.push(Diagnostic::error("unmatched-type", format!("Used module with unmatched type: {} expected {}->{} but got {}->{}, in {}",gate.module_name,mtype.input_count,mtype.output_count,gate.inputs.len(),gate.outputs.len(),module_label(module))).with_span(&module.file, gate.span));
                     }
                 },
-                None => { errors.push(format!("Undefined module used: {} in {}",gate.module_name,module.name)); break; },
+                None => { errors.push(Diagnostic::error("undefined-module-used", format!("Undefined module used: {} in {}",gate.module_name,module_label(module))).with_span(&module.file, gate.span)); break; },
             }
             // func_moduleのみの処理
             if module.func {
@@ -137,7 +157,7 @@ pub fn check_module_gates(ast: &File, module_types: &Vec<ModuleType>) -> Result<
                 match modules.iter().find(|m| m.name==gate.module_name).map(|m| &m.func) {
                     Some(func) => {
                         if !func {
-                            errors.push(format!("Function modules cannot call non-function modules: {} used in {}",gate.module_name,module.name));
+                            errors.push(Diagnostic::error("func-calls-non-func", format!("Function modules cannot call non-function modules: {} used in {}",gate.module_name,module_label(module))).with_span(&module.file, gate.span));
                         }
                     },
                     None => {
@@ -172,7 +192,7 @@ pub fn module_dependency(ast: &File) -> Vec<NodeDepends> {
 
 pub fn sort_dependency(dependency_vec: &Vec<NodeDepends>, modules: &Vec<ModuleType>) -> ResultwithWarn<Vec<String>> {
     use std::collections::{HashMap, HashSet};
-    let mut warns: Vec<String> = Vec::new();
+    let mut warns: Warns = Vec::new();
     // 依存関係のグラフを作成
     let mut dependency_graph: HashMap<String, HashSet<String>> = HashMap::new();
     let mut in_degree: HashMap<String, usize> = HashMap::new();
@@ -198,7 +218,7 @@ pub fn sort_dependency(dependency_vec: &Vec<NodeDepends>, modules: &Vec<ModuleTy
     s.sort();
     // 複数のルートモジュールがある場合は警告
     if s.len() > 1 {
-        warns.push(format!("Multiple modules are not used by other modules: {}", s.join(", ")));
+        warns.push(Diagnostic::warning("unused-modules", format!("Multiple modules are not used by other modules: {}", s.join(", "))));
     }
 
     let mut l = Vec::new();
@@ -269,7 +289,7 @@ pub fn sort_dependency(dependency_vec: &Vec<NodeDepends>, modules: &Vec<ModuleTy
                 cycle.join(" -> "),
                 cycle[0])); // ループを閉じる
         }
-        return Err((vec![error_msg], warns));
+        return Err((vec![Diagnostic::error("dependency-cycle", error_msg)], warns));
     }
 
     Ok((l, warns))
@@ -0,0 +1,114 @@
+// NCGB: コンパイルしたモジュールのバイナリ (u32の列, ファイルにはリトルエンディアンで書く)
+//
+// バージョン2 (コンテナ):
+//   MAGIC  VERSION  flags  モジュール数
+//   モジュールごとに: 長さ  本体 (compile::serialize_module)  セクション数  セクション (タグ 長さ 中身)...
+//   flagsにFLAG_CHECKSUMがあれば、最後にそれより前の全てのCRC-32
+// バージョン1 (単一モジュール): MAGIC  32  本体  [SYMBOL_SECTION シンボル]
+//   (VERSIONの位置にデータサイズの32が入っているので区別できる, vm::deserializerは両方読める)
+
+use super::compile::{serialize_module, serialize_symbols};
+use super::types::*;
+use crate::vm::deserializer::{crc32, MAGIC, VERSION, FLAG_CHECKSUM, SYMBOL_SECTION, GRAPHICAL_SECTION, TEST_SECTION};
+
+/// NCGBに含めるもの
+#[derive(Debug, Clone, Copy)]
+pub struct NcgbOptions {
+    pub symbols: bool,
+    pub graphical: bool,
+    pub tests: bool,
+    pub checksum: bool,
+}
+
+impl Default for NcgbOptions {
+    fn default() -> Self {
+        NcgbOptions { symbols: true, graphical: true, tests: true, checksum: true }
+    }
+}
+
+/// 複数のモジュールを一つのNCGBにする
+pub fn serialize_container(products: &IntermediateProducts, modules: &[String], options: &NcgbOptions) -> Result<Vec<u32>,String> {
+    let mut result = vec![MAGIC, VERSION, if options.checksum { FLAG_CHECKSUM } else { 0 }, modules.len() as u32];
+    for name in modules {
+        let module = products.expanded_modules.get(name).ok_or(format!("An undefined module was specified: {}",name))?;
+        let mut sections = Vec::new();
+        if options.symbols {
+            if let Some(symbols) = products.symbol_tables.get(name) {
+                sections.push((SYMBOL_SECTION, serialize_symbols(symbols)));
+            }
+        }
+        for component in &products.ast.components {
+            match component {
+                Component::Graphical(graphical) if options.graphical && graphical.name==*name => {
+                    sections.push((GRAPHICAL_SECTION, serialize_graphical(graphical)));
+                },
+                Component::Test(test) if options.tests && test.name==*name => {
+                    sections.push((TEST_SECTION, serialize_test(test)));
+                },
+                _ => {},
+            }
+        }
+        let mut record = serialize_module(module);
+        record.push(sections.len() as u32);
+        for (tag, data) in sections {
+            record.push(tag);
+            record.push(data.len() as u32);
+            record.extend(data);
+        }
+        result.push(record.len() as u32);
+        result.extend(record);
+    }
+    if options.checksum {
+        result.push(crc32(&result));
+    }
+    Ok(result)
+}
+
+fn color(c: (u8,u8,u8)) -> u32 {
+    ((c.0 as u32) << 16) | ((c.1 as u32) << 8) | c.2 as u32
+}
+
+/// 幅 高さ (autoなら0 0) ピクセル数 ピクセル (x y 入力0/出力1 番号 onの色 offの色)...
+fn serialize_graphical(graphical: &Graphical) -> Vec<u32> {
+    let mut result = match graphical.size {
+        ImgSize::Size { width, height } => vec![width, height],
+        ImgSize::Auto(()) => vec![0, 0],
+    };
+    result.push(graphical.pixels.len() as u32);
+    for pixel in &graphical.pixels {
+        result.extend([
+            pixel.coord.0,
+            pixel.coord.1,
+            if pixel.io_index.io_type=="input" { 0 } else { 1 },
+            pixel.io_index.index,
+            color(pixel.color.on),
+            color(pixel.color.off),
+        ]);
+    }
+    result
+}
+
+/// パターン数 パターン (tickの種類 tick数 入力数 入力... 出力数 出力...)...
+/// tickの種類は 0:指定なし 1:`@n` 2:`@stable`, 値は 0:f 1:t 2:x
+fn serialize_test(test: &Test) -> Vec<u32> {
+    fn value(v: &Option<bool>) -> u32 {
+        match v {
+            Some(false) => 0,
+            Some(true) => 1,
+            None => 2,
+        }
+    }
+    let mut result = vec![test.patterns.len() as u32];
+    for pattern in &test.patterns {
+        result.extend(match pattern.ticks {
+            TestTicks::Default => [0, 0],
+            TestTicks::Count { count } => [1, count],
+            TestTicks::Stable => [2, 0],
+        });
+        result.push(pattern.inputs.len() as u32);
+        result.extend(pattern.inputs.iter().map(value));
+        result.push(pattern.outputs.len() as u32);
+        result.extend(pattern.outputs.iter().map(value));
+    }
+    result
+}
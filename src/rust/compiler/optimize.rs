@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+
+use super::types::*;
+
+/// 全ての展開済みモジュールを最適化する (optimize: falseならゲート数を数えるだけ)
+/// @return 最適化前後のゲート数
+pub fn optimize_modules(modules: &mut HashMap<String,CompiledModule>, optimize: bool) -> HashMap<String,ModuleOptimization> {
+    modules.iter_mut().map(|(name,module)| {
+        let before = module.gates_sequential.len() + module.gates_symmetry.len();
+        if let Some(optimized) = optimize.then(|| optimize_module(module)).flatten() {
+            *module = optimized;
+        }
+        let after = module.gates_sequential.len() + module.gates_symmetry.len();
+        (name.clone(),ModuleOptimization { before, after })
+    }).collect()
+}
+
+/// ゲートの出力の値
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Signal {
+    Const(bool),
+    Input(u32),
+    /// 最適化後のゲートのインデックス
+    Gate(usize),
+}
+
+/// 最適化中のゲートの一覧
+struct Netlist {
+    gates: Vec<(Signal,Signal)>,
+    names: Vec<String>,
+    /// 同じ入力のゲートを一つにまとめる
+    hash: HashMap<(Signal,Signal),usize>,
+    /// not (nor(x,x)) のゲートの入力
+    not_of: HashMap<usize,Signal>,
+}
+
+impl Netlist {
+    fn is_not_of(&self, a: Signal, b: Signal) -> bool {
+        matches!(a, Signal::Gate(n) if self.not_of.get(&n)==Some(&b))
+    }
+
+    /// 簡単化せずにゲートを追加する (同じゲートがあればそれを使う)
+    fn gate(&mut self, a: Signal, b: Signal, name: &str) -> Signal {
+        let key = if a<=b { (a,b) } else { (b,a) };
+        if let Some(n) = self.hash.get(&key) {
+            return Signal::Gate(*n);
+        }
+        let index = self.gates.len();
+        self.gates.push(key);
+        self.names.push(name.to_string());
+        self.hash.insert(key, index);
+        if a==b {
+            self.not_of.insert(index, a);
+        }
+        Signal::Gate(index)
+    }
+
+    fn not(&mut self, a: Signal, name: &str) -> Signal {
+        match a {
+            Signal::Const(v) => Signal::Const(!v),
+            // 二重否定を取り除く
+            Signal::Gate(n) if self.not_of.contains_key(&n) => self.not_of[&n],
+            _ => self.gate(a, a, name),
+        }
+    }
+
+    /// 定数の伝播と簡単化をしてからnorゲートを追加する
+    fn nor(&mut self, a: Signal, b: Signal, name: &str) -> Signal {
+        match (a,b) {
+            (Signal::Const(true),_) | (_,Signal::Const(true)) => Signal::Const(false),
+            (Signal::Const(false),x) | (x,Signal::Const(false)) => self.not(x, name),
+            _ if a==b => self.not(a, name),
+            // nor(x, not x) は常にfalse
+            _ if self.is_not_of(a, b) || self.is_not_of(b, a) => Signal::Const(false),
+            _ => self.gate(a, b, name),
+        }
+    }
+}
+
+/// funcモジュールのゲートを最適化する
+/// 共通部分式の削除, 二重否定の削除, 定数の伝播, outputに届かないゲートの削除を行う
+/// 全てのゲートが前にあるゲートだけを使っていれば1tickで出力が決まるので、ゲートを入れ替えても結果は変わらない
+/// @return 最適化できない,またはゲートが減らなければNone
+fn optimize_module(module: &CompiledModule) -> Option<CompiledModule> {
+    if !module.func || !module.gates_symmetry.is_empty() {
+        return None;
+    }
+    let forward_only = module.gates_sequential.iter().enumerate().all(|(index,gate)| {
+        [&gate.0,&gate.1].iter().all(|input| match input {
+            CompiledGateInput::NorGate(n) => (*n as usize)<index,
+            CompiledGateInput::Input(n) => *n<module.inputs,
+        })
+    });
+    if !forward_only {
+        return None;
+    }
+    let gates_len = module.gates_sequential.len();
+    let mut netlist = Netlist { gates: Vec::new(), names: Vec::new(), hash: HashMap::new(), not_of: HashMap::new() };
+    let mut signals = Vec::with_capacity(gates_len);
+    for (index,gate) in module.gates_sequential.iter().enumerate() {
+        let name = module.gate_names.get(index).map(|v| v.as_str()).unwrap_or("");
+        let signal = |input: &CompiledGateInput| match input {
+            CompiledGateInput::NorGate(n) => signals[*n as usize],
+            CompiledGateInput::Input(n) => Signal::Input(*n),
+        };
+        let (a,b) = (signal(&gate.0),signal(&gate.1));
+        signals.push(netlist.nor(a, b, name));
+    }
+    let mut outputs = Vec::new();
+    for output in &module.outputs {
+        let output = *output as usize;
+        outputs.push(match signals.get(output) {
+            Some(v) => *v,
+            None if output<gates_len+module.inputs as usize => Signal::Input((output-gates_len) as u32),
+            None => return None,
+        });
+    }
+    // 定数を出力するゲートを作る (入力がなければ作れない)
+    for output in outputs.iter_mut() {
+        if let Signal::Const(value) = *output {
+            if module.inputs==0 {
+                return None;
+            }
+            let input = Signal::Input(0);
+            let not_input = netlist.gate(input, input, "");
+            let zero = netlist.gate(input, not_input, "");
+            *output = if value { netlist.gate(zero, zero, "") } else { zero };
+        }
+    }
+    // outputから辿れるゲートだけを残す
+    let mut used = vec![false; netlist.gates.len()];
+    let mut stack = outputs.iter().filter_map(|s| match s { Signal::Gate(n) => Some(*n), _ => None }).collect::<Vec<_>>();
+    while let Some(n) = stack.pop() {
+        if used[n] { continue; }
+        used[n] = true;
+        for input in [netlist.gates[n].0,netlist.gates[n].1] {
+            if let Signal::Gate(m) = input {
+                stack.push(m);
+            }
+        }
+    }
+    let mut new_index = vec![0u32; netlist.gates.len()];
+    let mut count = 0;
+    for (index,used) in used.iter().enumerate() {
+        if *used {
+            new_index[index] = count;
+            count += 1;
+        }
+    }
+    if count as usize>=gates_len {
+        return None;
+    }
+    let convert = |signal: Signal| match signal {
+        Signal::Gate(n) => CompiledGateInput::NorGate(new_index[n]),
+        Signal::Input(n) => CompiledGateInput::Input(n),
+        Signal::Const(_) => unreachable!(),
+    };
+    let mut gates_sequential = Vec::new();
+    let mut gate_names = Vec::new();
+    for (index,gate) in netlist.gates.iter().enumerate() {
+        if used[index] {
+            gates_sequential.push((convert(gate.0),convert(gate.1)));
+            gate_names.push(netlist.names[index].clone());
+        }
+    }
+    let outputs = outputs.iter().map(|signal| match signal {
+        Signal::Gate(n) => new_index[*n],
+        Signal::Input(n) => count + *n,
+        Signal::Const(_) => unreachable!(),
+    }).collect();
+    Some(CompiledModule {
+        func: module.func,
+        name: module.name.clone(),
+        inputs: module.inputs,
+        outputs,
+        gates_sequential,
+        gates_symmetry: Vec::new(),
+        gate_names,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileOptions;
+
+    /// 最適化しない場合とした場合のモジュール
+    fn compile(source: &str, name: &str) -> (CompiledModule,CompiledModule) {
+        let source = format!("using nor:2->1;\nfunc not (x)->(a) {{ a: nor <- x x; }}\n{}", source);
+        let compile = |optimize: bool| {
+            let products = crate::compiler::intermediate_products_with_options(&source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)), &CompileOptions { optimize });
+            assert!(products.errors.is_empty(), "{:?}", products.errors);
+            products.expanded_modules[name].clone()
+        };
+        (compile(false),compile(true))
+    }
+
+    fn gates(module: &CompiledModule) -> usize {
+        module.gates_sequential.len() + module.gates_symmetry.len()
+    }
+
+    /// 前のゲートだけを使う回路の全ての入力に対する出力
+    fn truth_table(module: &CompiledModule) -> Vec<Vec<bool>> {
+        (0..1u32<<module.inputs).map(|n| {
+            let inputs = (0..module.inputs).map(|i| n>>i&1==1).collect::<Vec<_>>();
+            let mut values = Vec::new();
+            for (a,b) in &module.gates_sequential {
+                let value = |input: &CompiledGateInput| match input {
+                    CompiledGateInput::NorGate(n) => values[*n as usize],
+                    CompiledGateInput::Input(n) => inputs[*n as usize],
+                };
+                let output = !(value(a) || value(b));
+                values.push(output);
+            }
+            module.outputs.iter().map(|&o| values.get(o as usize).copied().unwrap_or_else(|| inputs[o as usize-values.len()])).collect()
+        }).collect()
+    }
+
+    fn check(source: &str, name: &str, before: usize, after: usize) {
+        let (unoptimized,optimized) = compile(source, name);
+        assert_eq!((gates(&unoptimized),gates(&optimized)), (before,after), "{}", name);
+        assert_eq!(truth_table(&unoptimized), truth_table(&optimized), "{}", name);
+    }
+
+    #[test]
+    fn common_subexpressions() {
+        check("func cse (x y)->(a b) { a: nor <- x y; b: nor <- y x; }", "cse", 2, 1);
+    }
+
+    #[test]
+    fn double_not() {
+        check("func notnot (x y)->(c) { a: not <- x; b: not <- a; c: nor <- b y; }", "notnot", 3, 1);
+    }
+
+    #[test]
+    fn constant_propagation() {
+        // zは常にfalseなのでcはyの否定になる
+        check("func constant (x y)->(c) { n: not <- x; z: nor <- x n; c: nor <- z y; }", "constant", 3, 1);
+        // 定数の出力は入力から作り直す
+        check("func zero (x)->(z) { n: not <- x; z: nor <- x n; o: not <- z; }", "zero", 3, 2);
+    }
+
+    #[test]
+    fn dead_gates() {
+        check("func dead (x y)->(a) { a: nor <- x y; b: nor <- a x; c: not <- b; }", "dead", 3, 1);
+    }
+}
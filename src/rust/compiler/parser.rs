@@ -5,16 +5,28 @@ use nom::{
     branch::alt,
     bytes::complete::{tag, take_while1},
     character::complete::{char, digit1, multispace0, multispace1, not_line_ending},
-    combinator::{eof, map, map_res, opt, recognize, value},
-    multi::{many0, separated_list0},
-    sequence::{delimited, terminated, tuple, preceded},
+    combinator::{eof, map, map_res, not, opt, recognize, value},
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{delimited, pair, terminated, tuple, preceded},
     IResult,
 };
 
 // Parser implementations
 
+/// パース結果にソース中の位置を付ける
+/// 位置はここでは入力の残りの長さで記録し、parserで先頭からの位置に直す
+fn spanned<'a, O, F>(mut f: F) -> impl FnMut(&'a str) -> IResult<&'a str, (O, Span)>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>,
+{
+    move |input: &'a str| {
+        let (rest, output) = f(input)?;
+        Ok((rest, (output, Span { start: input.len(), end: rest.len(), line: 0, column: 0 })))
+    }
+}
 
-fn identifier(input: &str) -> IResult<&str, String> {
+
+pub(super) fn identifier(input: &str) -> IResult<&str, String> {
     map(
         take_while1(|c: char| c.is_alphanumeric() || c == '_'),
         String::from,
@@ -25,6 +37,76 @@ fn natural_number(input: &str) -> IResult<&str, usize> {
     map_res(digit1, |s: &str| s.parse::<usize>())(input)
 }
 
+fn size_atom(input: &str) -> IResult<&str, SizeExpr> {
+    alt((
+        map(natural_number, |value| SizeExpr::Number { value }),
+        map(identifier, |name| SizeExpr::Param { name }),
+    ))(input)
+}
+
+fn size_term(input: &str) -> IResult<&str, SizeExpr> {
+    let (input, first) = size_atom(input)?;
+    let (input, rest) = many0(tuple((
+        delimited(multispace0, alt((char('*'), char('/'))), multispace0),
+        size_atom,
+    )))(input)?;
+    let expr = rest.into_iter().fold(first, |left, (op, right)| match op {
+        '*' => SizeExpr::Mul { left: Box::new(left), right: Box::new(right) },
+        _ => SizeExpr::Div { left: Box::new(left), right: Box::new(right) },
+    });
+    Ok((input, expr))
+}
+
+/// 配列のサイズやスライスに書ける式: 自然数, パラメータ, + - * / (括弧なし, * / が優先)
+fn size_expr(input: &str) -> IResult<&str, SizeExpr> {
+    let (input, first) = size_term(input)?;
+    let (input, rest) = many0(tuple((
+        delimited(multispace0, alt((char('+'), char('-'))), multispace0),
+        size_term,
+    )))(input)?;
+    let expr = rest.into_iter().fold(first, |left, (op, right)| match op {
+        '+' => SizeExpr::Add { left: Box::new(left), right: Box::new(right) },
+        _ => SizeExpr::Sub { left: Box::new(left), right: Box::new(right) },
+    });
+    Ok((input, expr))
+}
+
+/// パラメータ付きモジュールの定義 `<N M>`
+pub(super) fn param_list(input: &str) -> IResult<&str, Vec<String>> {
+    delimited(
+        tuple((char('<'), multispace0)),
+        separated_list1(value_separator, identifier),
+        tuple((multispace0, char('>'))),
+    )(input)
+}
+
+/// パラメータ付きモジュールの引数 `<8>` `<N-1 2>`
+fn module_params(input: &str) -> IResult<&str, Vec<SizeExpr>> {
+    delimited(
+        tuple((char('<'), multispace0)),
+        separated_list1(value_separator, size_expr),
+        tuple((multispace0, char('>'))),
+    )(input)
+}
+
+/// モジュールの参照 (パラメータ付きモジュールなら引数は数字のみ) `add` `add<8>`
+pub(super) fn module_reference(input: &str) -> IResult<&str, String> {
+    map(
+        tuple((
+            identifier,
+            opt(delimited(
+                tuple((char('<'), multispace0)),
+                separated_list1(value_separator, natural_number),
+                tuple((multispace0, char('>'))),
+            )),
+        )),
+        |(name, args)| match args {
+            Some(args) => instance_name(&name, &args),
+            None => name,
+        },
+    )(input)
+}
+
 fn hex_digit(input: &str) -> IResult<&str, char> {
     alt((
         char('0'),
@@ -67,7 +149,7 @@ fn file_path(input: &str) -> IResult<&str, String> {
     ))(input)
 }
 
-fn right_arrow(input: &str) -> IResult<&str, &str> {
+pub(super) fn right_arrow(input: &str) -> IResult<&str, &str> {
     alt((
         tag("->"),
         tag("=>"),
@@ -93,6 +175,38 @@ fn include_keyword(input: &str) -> IResult<&str, &str> {
     ))(input)
 }
 
+fn import_keyword(input: &str) -> IResult<&str, &str> {
+    alt((
+        tag("import"),
+        tag("Import"),
+        tag("IMPORT"),
+    ))(input)
+}
+
+fn from_keyword(input: &str) -> IResult<&str, &str> {
+    alt((
+        tag("from"),
+        tag("From"),
+        tag("FROM"),
+    ))(input)
+}
+
+fn export_keyword(input: &str) -> IResult<&str, &str> {
+    alt((
+        tag("export"),
+        tag("Export"),
+        tag("EXPORT"),
+    ))(input)
+}
+
+fn sync_keyword(input: &str) -> IResult<&str, &str> {
+    alt((
+        tag("sync"),
+        tag("Sync"),
+        tag("SYNC"),
+    ))(input)
+}
+
 fn using_keyword(input: &str) -> IResult<&str, &str> {
     alt((
         tag("using"),
@@ -104,7 +218,7 @@ fn using_keyword(input: &str) -> IResult<&str, &str> {
     ))(input)
 }
 
-fn module_keyword(input: &str) -> IResult<&str, &str> {
+pub(super) fn module_keyword(input: &str) -> IResult<&str, &str> {
     alt((
         tag("module"),
         tag("Module"),
@@ -115,7 +229,7 @@ fn module_keyword(input: &str) -> IResult<&str, &str> {
     ))(input)
 }
 
-fn func_keyword(input: &str) -> IResult<&str, &str> {
+pub(super) fn func_keyword(input: &str) -> IResult<&str, &str> {
     alt((
         tag("func"),
         tag("Func"),
@@ -126,7 +240,7 @@ fn func_keyword(input: &str) -> IResult<&str, &str> {
     ))(input)
 }
 
-fn graphical_keyword(input: &str) -> IResult<&str, &str> {
+pub(super) fn graphical_keyword(input: &str) -> IResult<&str, &str> {
     alt((
         tag("graphical"),
         tag("Graphical"),
@@ -134,7 +248,7 @@ fn graphical_keyword(input: &str) -> IResult<&str, &str> {
     ))(input)
 }
 
-fn test_keyword(input: &str) -> IResult<&str, &str> {
+pub(super) fn test_keyword(input: &str) -> IResult<&str, &str> {
     alt((
         tag("test"),
         tag("Test"),
@@ -146,7 +260,7 @@ fn number(input: &str) -> IResult<&str, u32> {
     map_res(digit1, str::parse)(input)
 }
 
-fn mtype(input: &str) -> IResult<&str, MType> {
+pub(super) fn mtype(input: &str) -> IResult<&str, MType> {
     map(
         tuple((
             number,
@@ -168,7 +282,7 @@ fn line_comment_start(input: &str) -> IResult<&str, &str> {
     ))(input)
 }
 
-fn line_comment(input: &str) -> IResult<&str, &str> {
+pub(super) fn line_comment(input: &str) -> IResult<&str, &str> {
     recognize(
         tuple((
             line_comment_start,
@@ -210,7 +324,7 @@ fn value_separator(input: &str) -> IResult<&str, ()> {
     ))(input)
 }
 
-fn using(input: &str) -> IResult<&str, Using> {
+pub(super) fn using(input: &str) -> IResult<&str, Using> {
     map(
         tuple((
             using_keyword,
@@ -236,21 +350,61 @@ fn using(input: &str) -> IResult<&str, Using> {
     )(input)
 }
 
-fn include(input: &str) -> IResult<&str, Include> {
+pub(super) fn include(input: &str) -> IResult<&str, Include> {
     map(
-        tuple((
+        spanned(tuple((
             char('!'),
             include_keyword,
             multispace0,
             file_path,
             char(';'),
-        )),
-        |(_,_,_,path,_)| Include {
+        ))),
+        |((_,_,_,path,_), span)| Include {
             path,
+            span,
         },
     )(input)
 }
 
+pub(super) fn import(input: &str) -> IResult<&str, Import> {
+    map(
+        spanned(tuple((
+            import_keyword,
+            multispace0,
+            char('{'),
+            multispace0,
+            separated_list0(value_separator, identifier),
+            multispace0,
+            char('}'),
+            multispace0,
+            from_keyword,
+            multispace0,
+            file_path,
+            multispace0,
+            char(';'),
+        ))),
+        |((_,_,_,_,modules,_,_,_,_,_,path,_,_), span)| Import {
+            modules,
+            path,
+            span,
+        },
+    )(input)
+}
+
+pub(super) fn export(input: &str) -> IResult<&str, bool> {
+    map(
+        opt(tuple((export_keyword, multispace1))),
+        |export| export.is_some(),
+    )(input)
+}
+
+
+pub(super) fn sync(input: &str) -> IResult<&str, bool> {
+    map(
+        opt(tuple((sync_keyword, multispace1))),
+        |sync| sync.is_some(),
+    )(input)
+}
 
 fn id_list_output(input: &str) -> IResult<&str, Vec<PreOutputs>> {
     separated_list0(value_separator, array_declaration)(input)
@@ -259,7 +413,7 @@ fn id_list_input(input: &str) -> IResult<&str, Vec<PreInputs>> {
     separated_list0(value_separator, array_slice)(input)
 }
 
-fn io_list_input(input: &str) -> IResult<&str, Vec<PreOutputs>> {
+pub(super) fn io_list_input(input: &str) -> IResult<&str, Vec<PreOutputs>> {
     delimited(
         char('('),
         delimited(multispace0, separated_list0(value_separator, array_declaration), multispace0),
@@ -267,7 +421,7 @@ fn io_list_input(input: &str) -> IResult<&str, Vec<PreOutputs>> {
     )(input)
 }
 
-fn io_list_output(input: &str) -> IResult<&str, Vec<PreInputs>> {
+pub(super) fn io_list_output(input: &str) -> IResult<&str, Vec<PreInputs>> {
     delimited(
         char('('),
         delimited(multispace0, separated_list0(value_separator, array_slice), multispace0),
@@ -287,11 +441,11 @@ fn gate_separator(input: &str) -> IResult<&str, &str> {
 
 // 配列の宣言をパースします：
 //  - "identifier"        -> これは identifier(1) と解釈されます。
-//  - "identifier(n)"     -> nは自然数。
+//  - "identifier(n)"     -> nは自然数、またはパラメータを含む式。
 fn array_declaration(input: &str) -> IResult<&str, PreOutputs> {
     let (input, arr_name) = identifier(input)?;
-    let (input, arr_size_) = opt(delimited(char('('), natural_number, char(')')))(input)?;
-    let arr_size = arr_size_.unwrap_or_else(|| 1);
+    let (input, arr_size_) = opt(delimited(char('('), size_expr, char(')')))(input)?;
+    let arr_size = arr_size_.unwrap_or(SizeExpr::Number { value: 1 });
     Ok((input,PreOutputs{arr_name,arr_size}))
     // Ok((input,(0..size).map(|i| format!("{}:{}",arr_name,i)).collect()))
 }
@@ -303,27 +457,37 @@ fn array_declaration(input: &str) -> IResult<&str, PreOutputs> {
 //   - [x,y)  : 左側閉、右側開
 //   - (x,y]  : 左側開、右側閉
 // さらに、1つの数字の場合は id[x] として id[x,x] とみなします。
+// id(x) は配列全体の宣言と同じ形なので id[0,x) とみなします。
+// 数字の代わりにパラメータを含む式も書けます。
 fn slice_specifier(input: &str) -> IResult<&str, ArrSlice> {
     // 開き括弧のパース: '[' または '('
     let (input, opening) = alt((char('['), char('(')))(input)?;
-    let lower_inclusive = opening == '[';
+    let mut lower_inclusive = opening == '[';
 
     // 最初の数字をパース
-    let (input, first_num) = natural_number(input)?;
+    let (input, first_num) = size_expr(input)?;
 
     // オプションでカンマと第二の数字をパース
-    let (input, second_opt) = opt(preceded(char(','), natural_number))(input)?;
+    let (input, second_opt) = opt(preceded(char(','), size_expr))(input)?;
 
-    let (input, third_opt) = opt(preceded(char(','), natural_number))(input)?;
+    let (input, third_opt) = opt(preceded(char(','), size_expr))(input)?;
 
     // 閉じ括弧のパース: ']' または ')'
     let (input, closing) = alt((char(']'), char(')')))(input)?;
     let upper_inclusive = closing == ']';
 
-    // 第二の数字がなければ、最初の数字を使用 (id[x] == id[x,x])
-    let second_num = second_opt.unwrap_or(first_num);
+    let (first_num, second_num) = match second_opt {
+        Some(second_num) => (first_num, second_num),
+        // id(x) == id[0,x)
+        None if !lower_inclusive && !upper_inclusive => {
+            lower_inclusive = true;
+            (SizeExpr::Number { value: 0 }, first_num)
+        },
+        // 第二の数字がなければ、最初の数字を使用 (id[x] == id[x,x])
+        None => (first_num.clone(), first_num),
+    };
     // 第三の数字がなければ、1を使用
-    let third_num = third_opt.unwrap_or(1);
+    let third_num = third_opt.unwrap_or(SizeExpr::Number { value: 1 });
 
     let slice = ArrSlice {
         all: false,
@@ -351,9 +515,9 @@ fn array_slice(input: &str) -> IResult<&str, PreInputs> {
     // スライス記法がない場合はデフォルトで [0,0] (両端含む) とする
     let slice = slice_opt.unwrap_or(ArrSlice {
         all: true,
-        start: 0,
-        end: 0,
-        step: 1,
+        start: SizeExpr::Number { value: 0 },
+        end: SizeExpr::Number { value: 0 },
+        step: SizeExpr::Number { value: 1 },
         lower_inclusive: true,
         upper_inclusive: true,
     });
@@ -363,34 +527,40 @@ fn array_slice(input: &str) -> IResult<&str, PreInputs> {
     }))
 }
 
-fn gate(input: &str) -> IResult<&str, PreGate> {
+pub(super) fn gate(input: &str) -> IResult<&str, PreGate> {
     map(
-        tuple((
+        spanned(tuple((
             id_list_output,
             multispace0,
             gate_separator,
             multispace0,
             identifier,
+            opt(module_params),
             multispace0,
             opt(tuple((left_arrow, multispace0))),
             id_list_input,
             multispace0,
             char(';'),
-        )),
-        |(outputs, _, _, _, module_name, _, _, inputs, _, _)| PreGate {
+        ))),
+        |((outputs, _, _, _, module_name, module_params, _, _, inputs, _, _), span)| PreGate {
             outputs,
             module_name,
+            module_params: module_params.unwrap_or_default(),
             inputs,
+            span,
         },
     )(input)
 }
 
-fn module(input: &str) -> IResult<&str, Module> {
-    map(
-        tuple((
+fn module(input: &str) -> IResult<&str, Component> {
+    map_res(
+        spanned(tuple((
+            export,
+            sync,
             module_keyword,
             multispace0,
             identifier,
+            opt(param_list),
             multispace0,
             io_list_input,
             multispace0,
@@ -413,26 +583,22 @@ fn module(input: &str) -> IResult<&str, Module> {
                     |_| Vec::new()
                 )
             )),
-        )),
-        |(_, _, name, _, inputs_pre, _, _, _, outputs_pre, _, gates_pre)| {
-            let (inputs,outputs,gates) = convert_pre_gates(inputs_pre,outputs_pre,gates_pre);
-            Module {
-                func: false,
-                name,
-                inputs,
-                outputs,
-                gates: gates,
-            }
+        ))),
+        |((export, sync, _, _, name, params, _, inputs_pre, _, _, _, outputs_pre, _, gates_pre), span)| {
+            build_module(ParsedModule { func: false, sync, export, name, params: params.unwrap_or_default(), inputs_pre, outputs_pre, gates_pre, span })
         },
     )(input)
 }
 
-fn func_module(input: &str) -> IResult<&str, Module> {
-    map(
-        tuple((
+fn func_module(input: &str) -> IResult<&str, Component> {
+    map_res(
+        spanned(tuple((
+            export,
+            sync,
             func_keyword,
             multispace0,
             identifier,
+            opt(param_list),
             multispace0,
             io_list_input,
             multispace0,
@@ -455,20 +621,68 @@ fn func_module(input: &str) -> IResult<&str, Module> {
                     |_| Vec::new()
                 )
             )),
-        )),
-        |(_, _, name, _, inputs_pre, _, _, _, outputs_pre, _, gates_pre)| {
-            let (inputs,outputs,gates) = convert_pre_gates(inputs_pre,outputs_pre,gates_pre);
-            Module {
-                func: true,
-                name,
-                inputs,
-                outputs,
-                gates: gates,
-            }
+        ))),
+        |((export, sync, _, _, name, params, _, inputs_pre, _, _, _, outputs_pre, _, gates_pre), span)| {
+            build_module(ParsedModule { func: true, sync, export, name, params: params.unwrap_or_default(), inputs_pre, outputs_pre, gates_pre, span })
         },
     )(input)
 }
 
+/// パースしたモジュールの定義 (パラメータ付きかどうかを決める前)
+struct ParsedModule {
+    func: bool,
+    sync: bool,
+    export: bool,
+    name: String,
+    params: Vec<String>,
+    inputs_pre: Vec<PreOutputs>,
+    outputs_pre: Vec<PreInputs>,
+    gates_pre: Vec<PreGate>,
+    span: Span,
+}
+
+/// パラメータがなければ通常のモジュールに変換し、あればパラメータ付きモジュールのまま残す
+/// パラメータが全て数字 `add<1>` なら、その引数の実体を直接定義する (再帰の終端などに使う)
+fn build_module(module: ParsedModule) -> Result<Component, String> {
+    let ParsedModule { func, sync, export, name, params, inputs_pre, outputs_pre, gates_pre, span } = module;
+    let args = params.iter().map(|p| p.parse::<usize>()).collect::<Result<Vec<usize>, _>>();
+    let name = match args {
+        Ok(args) if !args.is_empty() => instance_name(&name, &args),
+        _ if params.iter().any(|p| p.starts_with(|c: char| c.is_ascii_digit())) => {
+            return Err(format!("Invalid parameter name in {}", name));
+        },
+        _ => name,
+    };
+    if params.is_empty() || name.contains('<') {
+        let (inputs,outputs,gates) = convert_pre_gates(inputs_pre,outputs_pre,gates_pre,&HashMap::new())?;
+        Ok(Component::Module(Module {
+            func,
+            sync,
+            export,
+            name,
+            file: String::new(),
+            inputs,
+            outputs,
+            gates: gates,
+            span,
+        }))
+    }
+    else {
+        Ok(Component::GenericModule(GenericModule {
+            func,
+            sync,
+            export,
+            name,
+            file: String::new(),
+            params,
+            inputs: inputs_pre,
+            outputs: outputs_pre,
+            gates: gates_pre,
+            span,
+        }))
+    }
+}
+
 fn true_value(input: &str) -> IResult<&str, bool> {
     alt((
         value(true, char('t')),
@@ -495,31 +709,64 @@ fn bool_value(input: &str) -> IResult<&str, bool> {
     ))(input)
 }
 
-fn bool_list(input: &str) -> IResult<&str, Vec<bool>> {
-    separated_list0(value_separator, bool_value)(input)
+/// don't care `x` `X` `-` (`->` の `-` は除く)
+fn dont_care_value(input: &str) -> IResult<&str, ()> {
+    alt((
+        value((), char('x')),
+        value((), char('X')),
+        value((), terminated(char('-'), not(char('>')))),
+    ))(input)
+}
+
+fn test_value(input: &str) -> IResult<&str, Option<bool>> {
+    alt((
+        map(bool_value, Some),
+        map(dont_care_value, |_| None),
+    ))(input)
+}
+
+fn test_value_list(input: &str) -> IResult<&str, Vec<Option<bool>>> {
+    separated_list0(value_separator, test_value)(input)
+}
+
+/// 進めるtick数 `@3` `@stable`
+fn test_ticks(input: &str) -> IResult<&str, TestTicks> {
+    preceded(
+        tuple((char('@'), multispace0)),
+        alt((
+            map(number, |count| TestTicks::Count { count }),
+            value(TestTicks::Stable, alt((tag("stable"), tag("Stable"), tag("STABLE")))),
+        )),
+    )(input)
 }
 
-fn test_pattern(input: &str) -> IResult<&str, TestPattern> {
+pub(super) fn test_pattern(input: &str) -> IResult<&str, TestPattern> {
     map(
         tuple((
-            bool_list,
+            test_value_list,
+            multispace0,
+            opt(test_ticks),
             multispace0,
             right_arrow,
             multispace0,
-            bool_list,
+            test_value_list,
             multispace0,
             char(';'),
         )),
-        |(inputs, _, _, _, outputs, _, _)| TestPattern { inputs, outputs },
+        |(inputs, _, ticks, _, _, _, outputs, _, _)| TestPattern {
+            inputs,
+            ticks: ticks.unwrap_or(TestTicks::Default),
+            outputs,
+        },
     )(input)
 }
 
 fn test(input: &str) -> IResult<&str, Test> {
     map(
-        tuple((
+        spanned(tuple((
             test_keyword,
             multispace0,
-            identifier,
+            module_reference,
             multispace0,
             char(':'),
             multispace0,
@@ -540,23 +787,53 @@ fn test(input: &str) -> IResult<&str, Test> {
                     |_| Vec::new()
                 )
             )),
-        )),
-        |(_, _, name, _, _, _, type_sig, _, patterns)| Test {
+        ))),
+        |((_, _, name, _, _, _, type_sig, _, patterns), span)| Test {
             name,
+            file: String::new(),
             type_sig,
             patterns,
+            span,
         },
     )(input)
 }
 
-fn img_size_auto(input: &str) -> IResult<&str, ImgSize> {
+pub(super) fn equivalence_test(input: &str) -> IResult<&str, EquivalenceTest> {
+    map(
+        spanned(tuple((
+            test_keyword,
+            multispace0,
+            module_reference,
+            multispace0,
+            tag("=="),
+            multispace0,
+            module_reference,
+            opt(preceded(tuple((multispace1, tag("random"), multispace1)), natural_number)),
+            opt(preceded(tuple((multispace1, tag("seed"), multispace1)), natural_number)),
+            opt(preceded(multispace1, tag("prove"))),
+            multispace0,
+            char(';'),
+        ))),
+        |((_, _, name, _, _, _, reference, random, seed, prove, _, _), span)| EquivalenceTest {
+            name,
+            file: String::new(),
+            reference,
+            random,
+            seed: seed.unwrap_or(0),
+            prove: prove.is_some(),
+            span,
+        },
+    )(input)
+}
+
+pub(super) fn img_size_auto(input: &str) -> IResult<&str, ImgSize> {
     map(
         tag("auto"),
         |_| ImgSize::Auto(()),
     )(input)
 }
 
-fn img_size_number(input: &str) -> IResult<&str, ImgSize> {
+pub(super) fn img_size_number(input: &str) -> IResult<&str, ImgSize> {
     map(
         tuple((
             number,
@@ -595,10 +872,10 @@ fn img_color(input: &str) -> IResult<&str, (u8,u8,u8)> {
 
 fn graphical(input: &str) -> IResult<&str, Graphical> {
     map(
-        tuple((
+        spanned(tuple((
             graphical_keyword,
             multispace0,
-            identifier,
+            module_reference,
             multispace0,
             char(':'),
             multispace0,
@@ -619,11 +896,13 @@ fn graphical(input: &str) -> IResult<&str, Graphical> {
                     |_| Vec::new()
                 )
             )),
-        )),
-        |(_, _,name,_,_,_,size, _, pixels)| Graphical {
+        ))),
+        |((_, _,name,_,_,_,size, _, pixels), span)| Graphical {
             name,
+            file: String::new(),
             size,
             pixels,
+            span,
         },
     )(input)
 }
@@ -645,9 +924,9 @@ fn img_io_name(input: &str) -> IResult<&str, IoIndex> {
 }
 
 
-fn pixel(input: &str) -> IResult<&str, Pixel> {
+pub(super) fn pixel(input: &str) -> IResult<&str, Pixel> {
     map(
-        tuple((
+        spanned(tuple((
             number,
             value_separator,
             number,
@@ -661,215 +940,367 @@ fn pixel(input: &str) -> IResult<&str, Pixel> {
             value_separator,
             img_color,
             char(';'),
-        )),
-        |(x,_,y,_,_,_,io_index,_,_,color_on,_,color_off,_)| Pixel {
+        ))),
+        |((x,_,y,_,_,_,io_index,_,_,color_on,_,color_off,_), span)| Pixel {
             coord: (x,y),
             io_index,
             color: PixelColor {
                 on: color_on,
                 off: color_off,
-            }
+            },
+            span,
         },
     )(input)
 }
 
 
-fn component(input: &str) -> IResult<&str, Component> {
-    alt((
-        map(using, Component::Using),
-        // map(import, Component::Import),
-        map(module, Component::Module),
-        map(graphical, Component::Graphical),
-        map(func_module, Component::Module),
-        map(test, Component::Test),
-        map(include, Component::Include),
-    ))(input)
+type ComponentParser = fn(&str) -> IResult<&str, Component>;
+
+pub(super) fn component(input: &str) -> IResult<&str, Component> {
+    let parsers: [ComponentParser; 8] = [
+        |i| map(using, Component::Using)(i),
+        |i| map(import, Component::Import)(i),
+        module,
+        |i| map(graphical, Component::Graphical)(i),
+        func_module,
+        |i| map(test, Component::Test)(i),
+        |i| map(equivalence_test, Component::EquivalenceTest)(i),
+        |i| map(include, Component::Include)(i),
+    ];
+    // 全て失敗した場合は、一番先まで読めたもののエラーを返す (エラーの位置を分かりやすくする)
+    let mut deepest: Option<nom::error::Error<&str>> = None;
+    for parser in parsers {
+        match parser(input) {
+            Ok(v) => return Ok(v),
+            Err(nom::Err::Error(e)) => {
+                if deepest.as_ref().is_none_or(|d| e.input.len()<d.input.len()) {
+                    deepest = Some(e);
+                }
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    Err(nom::Err::Error(deepest.unwrap_or(nom::error::Error::new(input, nom::error::ErrorKind::Alt))))
 }
 
-fn file(input: &str) -> IResult<&str, File> {
-    map(
-        terminated(
-            many0(delimited(separator, component, separator)),
-            tuple((separator, eof)),
-        ),
-        |components| File { components },
-    )(input)
+/// 行の先頭がcomponentの始まりに見えるか (閉じ括弧が足りない場合に次のcomponentから再開するため)
+fn looks_like_component_start(input: &str) -> bool {
+    let keyword = alt((
+        using_keyword,
+        import_keyword,
+        export_keyword,
+        module_keyword,
+        func_keyword,
+        graphical_keyword,
+        test_keyword,
+        recognize(pair(char('!'), include_keyword)),
+    ))(input);
+    match keyword {
+        Ok((rest, _)) => rest.starts_with(|c: char| c.is_whitespace() || c=='{'),
+        Err(_) => false,
+    }
 }
 
+/// `#` の後に16進数が6文字続くかどうか (色指定 `#rrggbb` でない `#` はコメント)
+/// `#` の後は非ASCII文字かもしれないので文字単位で見る
+pub(super) fn is_color(input: &str) -> bool {
+    input.get(1..7).is_some_and(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
 
-pub fn parser(input: &str) -> Result<File, String> {
-    match file(input) {
-        Ok(("", ast)) => Ok(ast),
-        Ok((remainder, _)) => {
-            // Find the context of the error
-            let error_pos = input.len() - remainder.len();
-            let start = input[..error_pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
-            let end = remainder.find('\n').map(|i| error_pos + i).unwrap_or(input.len());
-            let line = input[start..end].trim();
-            // Calculate line number
-            let line_number = input[..error_pos].matches('\n').count() + 1;
-            // Create detailed error message
-            let mut error = format!("Parsing error at line {}:\n", line_number);
-            error.push_str(&format!("{}\n", line));
-            // Add pointer to the error position
-            error.push_str(&format!("{}^\n", " ".repeat(error_pos - start)));
-            error.push_str("Unexpected content found. The parser was unable to continue from this point.\n");
-            error.push_str("Common causes:\n");
-            error.push_str("- Missing semicolon at the end of a statement\n");
-            error.push_str("- Invalid syntax or typo in module/gate definition\n");
-            error.push_str("- Unmatched braces or parentheses\n");
-            Err(error)
-        },
-        Err(e) => {
-            let error_desc = match e {
-                nom::Err::Error(e) | nom::Err::Failure(e) => {
-                    // Convert nom error into more user-friendly message
-                    let pos = input.len() - e.input.len();
-                    let start = input[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
-                    let end = e.input.find('\n').map(|i| pos + i).unwrap_or(input.len());
-                    let line = input[start..end].trim();
-                    let line_number = input[..pos].matches('\n').count() + 1;
-                    format!("Syntax error at line {}:\n{}\n{}^\nInvalid syntax found here.\n",
-                        line_number,
-                        line,
-                        " ".repeat(pos - start)
-                    )
-                },
-                nom::Err::Incomplete(_) => "Incomplete input: the file appears to be truncated.".to_string(),
-            };
-            Err(error_desc)
-        },
+/// パースできなかったcomponentを読み飛ばす長さ
+/// 括弧の外の `;` か、括弧を閉じる `}` の直後まで進む (次のcomponentらしい行があればそこで止まる)
+pub(super) fn skip_component(input: &str) -> usize {
+    let mut depth = 0usize;
+    let mut line_start = false;
+    let mut chars = input.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if line_start && !c.is_whitespace() {
+            line_start = false;
+            if looks_like_component_start(&input[i..]) {
+                return i;
+            }
+        }
+        match c {
+            '\n' => line_start = true,
+            // コメントの中の `;` `}` は無視する
+            '/' if input[i..].starts_with("//") => {
+                while chars.next_if(|(_, c)| *c!='\n').is_some() {}
+            },
+            '#' if !is_color(&input[i..]) => {
+                while chars.next_if(|(_, c)| *c!='\n').is_some() {}
+            },
+            '"' => {
+                while chars.next_if(|(_, c)| *c!='"' && *c!='\n').is_some() {}
+                chars.next_if(|(_, c)| *c=='"');
+            },
+            '{' => depth += 1,
+            '}' => {
+                depth = depth.saturating_sub(1);
+                if depth==0 {
+                    return i + 1;
+                }
+            },
+            ';' if depth==0 => return i + 1,
+            _ => {},
+        }
     }
+    input.len()
 }
 
+/// spanは入力の先頭からのバイト位置 (行と列はmacros::expand_and_parseで付ける)
+/// 構文エラーがあっても `;` `}` の区切りまで読み飛ばして続け、読めたcomponentだけのASTと全てのエラーを返す
+pub fn parser(input: &str) -> (File, Errs) {
+    let error_span = |start: usize, end: usize| Span { start, end, line: 0, column: 0 };
+    let mut components = Vec::new();
+    let mut errors = Vec::new();
+    let mut rest = input;
+    loop {
+        if let Ok((remainder, _)) = separator(rest) {
+            rest = remainder;
+        }
+        if rest.is_empty() {
+            break;
+        }
+        match component(rest) {
+            Ok((remainder, component)) => {
+                components.push(component);
+                rest = remainder;
+            },
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                let start = input.len() - rest.len();
+                let pos = input.len() - e.input.len();
+                let skipped = skip_component(rest).max(rest.chars().next().map_or(1, |c| c.len_utf8()));
+                let end = (start + skipped).max(pos);
+                errors.push(if pos==start {
+                    Diagnostic::error("unexpected-content", "Unexpected content found. The parser skipped to the next `;` or `}`.".to_string())
+                        .with_span("", error_span(pos, end))
+                        .with_note("Missing semicolon at the end of a statement".to_string())
+                        .with_note("Invalid syntax or typo in module/gate definition".to_string())
+                        .with_note("Unmatched braces or parentheses".to_string())
+                }
+                else {
+                    Diagnostic::error("syntax-error", "Invalid syntax found here.".to_string()).with_span("", error_span(pos, end))
+                });
+                rest = &rest[skipped..];
+            },
+            Err(nom::Err::Incomplete(_)) => {
+                errors.push(Diagnostic::error("incomplete-input", "Incomplete input: the file appears to be truncated.".to_string()));
+                break;
+            },
+        }
+    }
+    let mut ast = File { components };
+    // 残りの長さで記録された位置を先頭からの位置に直す
+    map_spans(&mut ast, &|span| Span { start: input.len() - span.start, end: input.len() - span.end, ..span });
+    (ast, errors)
+}
+
+/// AST中の全てのspanを変換する
+pub fn map_spans(ast: &mut File, f: &dyn Fn(Span) -> Span) {
+    for component in &mut ast.components {
+        match component {
+            Component::Module(module) => {
+                module.span = f(module.span);
+                for gate in &mut module.gates {
+                    gate.span = f(gate.span);
+                }
+            },
+            Component::GenericModule(module) => {
+                module.span = f(module.span);
+                for gate in &mut module.gates {
+                    gate.span = f(gate.span);
+                }
+            },
+            Component::Test(test) => test.span = f(test.span),
+            Component::EquivalenceTest(test) => test.span = f(test.span),
+            Component::Include(include) => include.span = f(include.span),
+            Component::Import(import) => import.span = f(import.span),
+            Component::Graphical(graphical) => {
+                graphical.span = f(graphical.span);
+                for pixel in &mut graphical.pixels {
+                    pixel.span = f(pixel.span);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// パラメータ付きモジュールの実体の名前 `add<8>` `mux<4,2>`
+pub fn instance_name(name: &str, args: &[usize]) -> String {
+    format!("{}<{}>", name, args.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(","))
+}
+
+/// モジュールの参照を名前と引数部分に分ける `add<8>` -> (`add`, `<8>`)
+pub fn split_instance_name(name: &str) -> (&str, &str) {
+    match name.find('<') {
+        Some(pos) => (&name[..pos], &name[pos..]),
+        None => (name, ""),
+    }
+}
+
+/// パラメータの値を使って式を評価する
+pub fn eval_size(expr: &SizeExpr, params: &HashMap<String, usize>) -> Result<usize, String> {
+    match expr {
+        SizeExpr::Number { value } => Ok(*value),
+        SizeExpr::Param { name } => params.get(name).copied().ok_or_else(|| format!("Undefined parameter used: {}", name)),
+        SizeExpr::Add { left, right } => eval_size(left, params)?
+            .checked_add(eval_size(right, params)?)
+            .ok_or_else(|| "Overflow in size expression".to_string()),
+        SizeExpr::Sub { left, right } => eval_size(left, params)?
+            .checked_sub(eval_size(right, params)?)
+            .ok_or_else(|| "Negative value in size expression".to_string()),
+        SizeExpr::Mul { left, right } => eval_size(left, params)?
+            .checked_mul(eval_size(right, params)?)
+            .ok_or_else(|| "Overflow in size expression".to_string()),
+        SizeExpr::Div { left, right } => eval_size(left, params)?
+            .checked_div(eval_size(right, params)?)
+            .ok_or_else(|| "Division by zero in size expression".to_string()),
+    }
+}
+
+/// スライスを添字の一覧に変換する
+fn slice_indices(slice: &ArrSlice, size: usize, params: &HashMap<String, usize>) -> Result<Vec<usize>, String> {
+    if slice.all {
+        // 宣言されていない名前もそのまま残し、後のチェックで未定義として扱う
+        return Ok(if size > 0 { (0..size).collect() } else { vec![0] });
+    }
+    let start = eval_size(&slice.start, params)?;
+    let end = eval_size(&slice.end, params)?;
+    let step = eval_size(&slice.step, params)?;
+    if step == 0 {
+        return Err("Slice step must be greater than 0".to_string());
+    }
+    let lower = if slice.lower_inclusive { start } else { start + 1 };
+    // 右側開区間で end=0 なら空
+    let upper = if slice.upper_inclusive { Some(end) } else { end.checked_sub(1) };
+    match upper {
+        Some(upper) if lower <= upper => Ok((lower..=upper).step_by(step).collect()),
+        _ => Ok(vec![]),
+    }
+}
+
+/// (inputs, outputs, gates)
+pub type ConvertedModule = (Vec<String>, Vec<String>, Vec<Gate>);
+
 /// Convert pre-module and gate data into final representations.
 ///
 /// # Parameters
 /// - `module_inputs_pre`: PreOutputs for module-level inputs.
 /// - `module_outputs_pre`: PreInputs for module-level outputs.
 /// - `gates_pre`: PreGate data to be converted into Gate structures.
+/// - `params`: Values of the module parameters (empty for non-parameterized modules).
 ///
 /// # Returns
 /// A tuple containing:
 /// - Converted module inputs as Vec<String>
 /// - Converted module outputs as Vec<String>
 /// - Converted gates as Vec<Gate>
+///
+/// or an error if a size expression can not be evaluated.
 pub fn convert_pre_gates(
     module_inputs_pre: Vec<PreOutputs>,
     module_outputs_pre: Vec<PreInputs>,
     gates_pre: Vec<PreGate>,
-) -> (Vec<String>, Vec<String>, Vec<Gate>) {
+    params: &HashMap<String, usize>,
+) -> Result<ConvertedModule, String> {
     // Step 1: Build the output_sizes mapping using module_inputs_pre and gates_pre outputs.
     let mut output_sizes: HashMap<String, usize> = HashMap::new();
     // Insert sizes from module_inputs_pre.
     for po in &module_inputs_pre {
-        output_sizes.insert(po.arr_name.clone(), po.arr_size);
+        output_sizes.insert(po.arr_name.clone(), eval_size(&po.arr_size, params)?);
     }
     // Insert sizes from each gate's outputs.
     for gate in &gates_pre {
         for po in &gate.outputs {
             // Only insert if not already present.
-            output_sizes.entry(po.arr_name.clone()).or_insert(po.arr_size);
+            if !output_sizes.contains_key(&po.arr_name) {
+                output_sizes.insert(po.arr_name.clone(), eval_size(&po.arr_size, params)?);
+            }
         }
     }
 
     // Step 2: Convert module_inputs_pre into a vector of strings.
     let module_inputs: Vec<String> = module_inputs_pre
-        .into_iter()
+        .iter()
         .flat_map(|po| {
-            (0..po.arr_size)
+            (0..output_sizes[&po.arr_name])
                 .map(|i| format!("{}:{}", po.arr_name, i))
                 .collect::<Vec<String>>()
         })
         .collect();
 
     // Convert each gate's outputs and inputs.
-    let gates: Vec<Gate> = gates_pre
-        .into_iter()
-        .map(|pre_gate| {
-            // Convert outputs using the output_sizes mapping.
-            let outputs: Vec<String> = pre_gate
-                .outputs
-                .into_iter()
-                .flat_map(|po| {
-                    // Retrieve size from the mapping.
-                    let size = *output_sizes.get(&po.arr_name).unwrap_or(&po.arr_size);
-                    (0..size)
-                        .map(|i| format!("{}:{}", po.arr_name, i))
-                        .collect::<Vec<String>>()
-                })
-                .collect();
-
-            // Convert inputs using the output_sizes mapping.
-            let inputs: Vec<String> = pre_gate
-                .inputs
-                .into_iter()
-                .flat_map(|pi| {
-                    let name = pi.arr_name;
-                    let slice = pi.arr_slice;
-                    // Determine the effective size from output_sizes.
-                    let size = *output_sizes.get(&name).unwrap_or(&100);
-                    let (lower, upper) = if slice.all {
-                        if size > 0 {
-                            (0, size - 1)
-                        } else {
-                            (0, 0)
-                        }
-                    } else {
-                        let lower = if slice.lower_inclusive { slice.start } else { slice.start + 1 };
-                        let upper = if slice.upper_inclusive { slice.end } else { slice.end - 1 };
-                        (lower, upper)
-                    };
-                    if lower <= upper {
-                        let step = slice.step;
-                        (lower..=upper)
-                            .step_by(step as usize)
-                            .map(|i| format!("{}:{}", name, i))
-                            .collect::<Vec<String>>()
-                    } else {
-                        vec![]
-                    }
-                })
-                .collect();
-
-            Gate {
-                module_name: pre_gate.module_name,
-                outputs,
-                inputs,
+    let mut gates: Vec<Gate> = Vec::new();
+    for pre_gate in gates_pre {
+        // Convert outputs using the output_sizes mapping.
+        let outputs: Vec<String> = pre_gate
+            .outputs
+            .into_iter()
+            .flat_map(|po| {
+                // Retrieve size from the mapping.
+                let size = output_sizes[&po.arr_name];
+                (0..size)
+                    .map(|i| format!("{}:{}", po.arr_name, i))
+                    .collect::<Vec<String>>()
+            })
+            .collect();
+
+        // Convert inputs using the output_sizes mapping.
+        let mut inputs: Vec<String> = Vec::new();
+        for pi in pre_gate.inputs {
+            // Determine the effective size from output_sizes.
+            let size = *output_sizes.get(&pi.arr_name).unwrap_or(&100);
+            for i in slice_indices(&pi.arr_slice, size, params)? {
+                inputs.push(format!("{}:{}", pi.arr_name, i));
             }
-        })
-        .collect();
+        }
+
+        // Evaluate the parameters of the gate's module.
+        let module_name = if pre_gate.module_params.is_empty() {
+            pre_gate.module_name
+        } else {
+            let args = pre_gate
+                .module_params
+                .iter()
+                .map(|expr| eval_size(expr, params))
+                .collect::<Result<Vec<usize>, String>>()?;
+            instance_name(&pre_gate.module_name, &args)
+        };
+
+        gates.push(Gate {
+            module_name,
+            outputs,
+            inputs,
+            span: pre_gate.span,
+        });
+    }
 
     // Step 3: Convert module_outputs_pre into a vector of strings using output_sizes.
-    let module_outputs: Vec<String> = module_outputs_pre
-        .into_iter()
-        .flat_map(|pi| {
-            let name = pi.arr_name;
-            let slice = pi.arr_slice;
-            // Retrieve size from the mapping.
-            let size = *output_sizes.get(&name).unwrap_or(&0);
-            let (lower, upper) = if slice.all {
-                if size > 0 {
-                    (0, size - 1)
-                } else {
-                    (0, 0)
-                }
-            } else {
-                let lower = if slice.lower_inclusive { slice.start } else { slice.start + 1 };
-                let upper = if slice.upper_inclusive { slice.end } else { slice.end - 1 };
-                (lower, upper)
-            };
-            if lower <= upper {
-                let step = slice.step;
-                (lower..=upper)
-                    .step_by(step as usize)
-                    .map(|i| format!("{}:{}", name, i))
-                    .collect::<Vec<String>>()
-            } else {
-                vec![]
-            }
-        })
-        .collect();
+    let mut module_outputs: Vec<String> = Vec::new();
+    for pi in module_outputs_pre {
+        // Retrieve size from the mapping.
+        let size = *output_sizes.get(&pi.arr_name).unwrap_or(&0);
+        for i in slice_indices(&pi.arr_slice, size, params)? {
+            module_outputs.push(format!("{}:{}", pi.arr_name, i));
+        }
+    }
+
+    Ok((module_inputs, module_outputs, gates))
+}
 
-    (module_inputs, module_outputs, gates)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 読み飛ばしている途中の `#` のコメントの中の `;` `}` では止まらない (`//` と同じ)
+    #[test]
+    fn skip_hash_comment() {
+        let broken = |comment: &str| format!("module broken (x)->(a) {{\n    a: nor <- x x\n    {} note; fix }} later\n    b: nor <- x x;\n}}\nmodule not (x)->(a) {{ a: nor <- x x; }}\n", comment);
+        let (ast,errors) = parser(&broken("#"));
+        let (_,slash_errors) = parser(&broken("//"));
+        assert_eq!(errors.len(), slash_errors.len(), "{:?}", errors);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(ast.components.len(), 1);
+    }
+}
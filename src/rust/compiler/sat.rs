@@ -0,0 +1,425 @@
+/// リテラル: `変数*2` が正, `変数*2+1` が否定
+pub type Lit = u32;
+
+pub fn lit(var: u32, negative: bool) -> Lit {
+    var*2 + negative as u32
+}
+
+fn var_of(lit: Lit) -> usize {
+    (lit/2) as usize
+}
+
+/// 充足可能性の判定結果
+pub enum SatResult {
+    /// 各変数の値
+    Sat(Vec<bool>),
+    Unsat,
+}
+
+/// activityの大きい順に変数を取り出す二分ヒープ
+/// 各変数は高々一つしか入らず, 位置を覚えておいてactivityが上がった変数をその場で動かす
+struct VarHeap {
+    heap: Vec<usize>,
+    /// 変数のheap内の位置 (入っていなければNone)
+    positions: Vec<Option<usize>>,
+}
+
+impl VarHeap {
+    /// 全ての変数が入った状態 (activityは全て0なのでそのままヒープになっている)
+    fn new(vars: usize) -> Self {
+        VarHeap { heap: (0..vars).collect(), positions: (0..vars).map(Some).collect() }
+    }
+
+    fn insert(&mut self, var: usize, activity: &[f64]) {
+        if self.positions[var].is_some() {
+            return;
+        }
+        self.positions[var] = Some(self.heap.len());
+        self.heap.push(var);
+        self.sift_up(self.heap.len()-1, activity);
+    }
+
+    /// varのactivityが上がった
+    fn increased(&mut self, var: usize, activity: &[f64]) {
+        if let Some(position) = self.positions[var] {
+            self.sift_up(position, activity);
+        }
+    }
+
+    fn pop(&mut self, activity: &[f64]) -> Option<usize> {
+        let top = *self.heap.first()?;
+        let last = self.heap.pop()?;
+        self.positions[top] = None;
+        if !self.heap.is_empty() {
+            self.heap[0] = last;
+            self.positions[last] = Some(0);
+            self.sift_down(0, activity);
+        }
+        Some(top)
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a,b);
+        self.positions[self.heap[a]] = Some(a);
+        self.positions[self.heap[b]] = Some(b);
+    }
+
+    fn sift_up(&mut self, mut position: usize, activity: &[f64]) {
+        while position>0 {
+            let parent = (position-1)/2;
+            if activity[self.heap[parent]]>=activity[self.heap[position]] {
+                break;
+            }
+            self.swap(parent,position);
+            position = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut position: usize, activity: &[f64]) {
+        loop {
+            let mut largest = position;
+            for child in [position*2+1, position*2+2] {
+                if child<self.heap.len() && activity[self.heap[child]]>activity[self.heap[largest]] {
+                    largest = child;
+                }
+            }
+            if largest==position {
+                break;
+            }
+            self.swap(largest,position);
+            position = largest;
+        }
+    }
+}
+
+/// 小さなCDCLソルバ (2-watched literals, 1UIP学習, VSIDS, リスタート)
+pub struct Solver {
+    clauses: Vec<Vec<Lit>>,
+    watches: Vec<Vec<usize>>,
+    /// 0: 未割り当て 1: true -1: false
+    values: Vec<i8>,
+    levels: Vec<usize>,
+    reasons: Vec<Option<usize>>,
+    trail: Vec<Lit>,
+    trail_lim: Vec<usize>,
+    propagated: usize,
+    activity: Vec<f64>,
+    activity_inc: f64,
+    heap: VarHeap,
+    phase: Vec<bool>,
+    /// 空の節が追加された
+    conflict_at_root: bool,
+}
+
+impl Solver {
+    pub fn new(vars: usize) -> Self {
+        Solver {
+            clauses: Vec::new(),
+            watches: vec![Vec::new(); vars*2],
+            values: vec![0; vars],
+            levels: vec![0; vars],
+            reasons: vec![None; vars],
+            trail: Vec::new(),
+            trail_lim: Vec::new(),
+            propagated: 0,
+            activity: vec![0.0; vars],
+            activity_inc: 1.0,
+            heap: VarHeap::new(vars),
+            phase: vec![false; vars],
+            conflict_at_root: false,
+        }
+    }
+
+    fn lit_value(&self, lit: Lit) -> i8 {
+        let value = self.values[var_of(lit)];
+        if lit&1==1 { -value } else { value }
+    }
+
+    fn assign(&mut self, lit: Lit, reason: Option<usize>) {
+        let var = var_of(lit);
+        self.values[var] = if lit&1==1 { -1 } else { 1 };
+        self.levels[var] = self.trail_lim.len();
+        self.reasons[var] = reason;
+        self.phase[var] = lit&1==0;
+        self.trail.push(lit);
+    }
+
+    pub fn add_clause(&mut self, mut clause: Vec<Lit>) {
+        clause.sort_unstable();
+        clause.dedup();
+        // 常に真になる節は無視する
+        if clause.windows(2).any(|w| w[0]^1==w[1]) {
+            return;
+        }
+        match clause.len() {
+            0 => self.conflict_at_root = true,
+            1 => match self.lit_value(clause[0]) {
+                0 => self.assign(clause[0], None),
+                -1 => self.conflict_at_root = true,
+                _ => {},
+            },
+            _ => {
+                let index = self.clauses.len();
+                self.watches[(clause[0]^1) as usize].push(index);
+                self.watches[(clause[1]^1) as usize].push(index);
+                self.clauses.push(clause);
+            },
+        }
+    }
+
+    /// 単位伝播, 矛盾した節を返す
+    fn propagate(&mut self) -> Option<usize> {
+        while self.propagated<self.trail.len() {
+            // falseになったリテラル (= trailのリテラルの否定) を監視している節を調べる
+            let false_lit = self.trail[self.propagated]^1;
+            self.propagated += 1;
+            let watching = std::mem::take(&mut self.watches[(false_lit^1) as usize]);
+            let mut kept = Vec::with_capacity(watching.len());
+            let mut conflict = None;
+            for (position,&index) in watching.iter().enumerate() {
+                if conflict.is_some() {
+                    kept.extend_from_slice(&watching[position..]);
+                    break;
+                }
+                // 監視しているリテラルを先頭の二つに置く
+                if self.clauses[index][0]==false_lit {
+                    self.clauses[index].swap(0,1);
+                }
+                let first = self.clauses[index][0];
+                if self.lit_value(first)==1 {
+                    kept.push(index);
+                    continue;
+                }
+                // 新しく監視するリテラルを探す
+                let replacement = (2..self.clauses[index].len()).find(|&i| self.lit_value(self.clauses[index][i])!=-1);
+                match replacement {
+                    Some(i) => {
+                        self.clauses[index].swap(1,i);
+                        let new_watch = self.clauses[index][1];
+                        self.watches[(new_watch^1) as usize].push(index);
+                    },
+                    None => {
+                        kept.push(index);
+                        if self.lit_value(first)==-1 {
+                            conflict = Some(index);
+                        }
+                        else {
+                            self.assign(first, Some(index));
+                        }
+                    },
+                }
+            }
+            self.watches[(false_lit^1) as usize] = kept;
+            if conflict.is_some() {
+                return conflict;
+            }
+        }
+        None
+    }
+
+    fn bump(&mut self, var: usize) {
+        self.activity[var] += self.activity_inc;
+        if self.activity[var]>1e100 {
+            for a in &mut self.activity {
+                *a *= 1e-100;
+            }
+            self.activity_inc *= 1e-100;
+        }
+        // 全ての変数に同じ倍率を掛けても順序は変わらないので, 上がった変数だけ動かす
+        self.heap.increased(var, &self.activity);
+    }
+
+    /// 1UIPで学習節を作る @return `( learnt clause, backjump level )`
+    fn analyze(&mut self, mut conflict: usize) -> (Vec<Lit>,usize) {
+        let level = self.trail_lim.len();
+        let mut seen = vec![false; self.values.len()];
+        let mut learnt = vec![0];
+        let mut counter = 0;
+        let mut index = self.trail.len();
+        let mut uip;
+        loop {
+            let clause = self.clauses[conflict].clone();
+            for &lit in &clause {
+                let var = var_of(lit);
+                if seen[var] || self.levels[var]==0 || (self.values[var]!=0 && self.lit_value(lit)==1) {
+                    continue;
+                }
+                seen[var] = true;
+                self.bump(var);
+                if self.levels[var]==level { counter += 1; }
+                else { learnt.push(lit); }
+            }
+            // trailを遡って次に調べる変数を探す
+            loop {
+                index -= 1;
+                if seen[var_of(self.trail[index])] { break; }
+            }
+            uip = self.trail[index];
+            counter -= 1;
+            if counter==0 { break; }
+            conflict = match self.reasons[var_of(uip)] {
+                Some(v) => v,
+                None => break,
+            };
+            seen[var_of(uip)] = false;
+        }
+        learnt[0] = uip^1;
+        let backjump = learnt[1..].iter().map(|&l| self.levels[var_of(l)]).max().unwrap_or(0);
+        // 二番目に大きいレベルのリテラルを二番目に置く (監視用)
+        if learnt.len()>1 {
+            let max_index = (1..learnt.len()).max_by_key(|&i| self.levels[var_of(learnt[i])]).unwrap_or(1);
+            learnt.swap(1,max_index);
+        }
+        self.activity_inc *= 1.05;
+        (learnt,backjump)
+    }
+
+    fn backtrack(&mut self, level: usize) {
+        if self.trail_lim.len()<=level {
+            return;
+        }
+        let start = self.trail_lim[level];
+        for &lit in &self.trail[start..] {
+            let var = var_of(lit);
+            self.values[var] = 0;
+            self.reasons[var] = None;
+            self.heap.insert(var, &self.activity);
+        }
+        self.trail.truncate(start);
+        self.trail_lim.truncate(level);
+        self.propagated = start;
+    }
+
+    fn decide(&mut self) -> Option<Lit> {
+        // 割り当て済みの変数は取り出したままにする (backtrackで戻す)
+        while let Some(var) = self.heap.pop(&self.activity) {
+            if self.values[var]==0 {
+                return Some(lit(var as u32, !self.phase[var]));
+            }
+        }
+        None
+    }
+
+    /// conflict_limit: 諦めるまでの矛盾の回数
+    pub fn solve(&mut self, conflict_limit: usize) -> Result<SatResult,String> {
+        if self.conflict_at_root || self.propagate().is_some() {
+            return Ok(SatResult::Unsat);
+        }
+        let mut conflicts = 0;
+        let mut restart_limit = 100.0;
+        let mut conflicts_since_restart = 0;
+        loop {
+            match self.propagate() {
+                Some(conflict) => {
+                    conflicts += 1;
+                    conflicts_since_restart += 1;
+                    if self.trail_lim.is_empty() {
+                        return Ok(SatResult::Unsat);
+                    }
+                    if conflicts>conflict_limit {
+                        return Err(format!("SAT solver gave up after {} conflicts",conflict_limit));
+                    }
+                    let (learnt,backjump) = self.analyze(conflict);
+                    self.backtrack(backjump);
+                    if learnt.len()==1 {
+                        self.assign(learnt[0], None);
+                    }
+                    else {
+                        let index = self.clauses.len();
+                        self.watches[(learnt[0]^1) as usize].push(index);
+                        self.watches[(learnt[1]^1) as usize].push(index);
+                        let first = learnt[0];
+                        self.clauses.push(learnt);
+                        self.assign(first, Some(index));
+                    }
+                },
+                None => {
+                    if conflicts_since_restart as f64>restart_limit {
+                        conflicts_since_restart = 0;
+                        restart_limit *= 1.5;
+                        self.backtrack(0);
+                        continue;
+                    }
+                    match self.decide() {
+                        Some(decision) => {
+                            self.trail_lim.push(self.trail.len());
+                            self.assign(decision, None);
+                        },
+                        None => return Ok(SatResult::Sat(self.values.iter().map(|v| *v==1).collect())),
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 全ての節がモデルで充足されている
+    fn satisfies(clauses: &[Vec<Lit>], model: &[bool]) -> bool {
+        clauses.iter().all(|clause| clause.iter().any(|&l| model[var_of(l)]==(l&1==0)))
+    }
+
+    /// pigeons羽の鳩をholes個の巣に一羽ずつ入れる (pigeons>holesなら充足不能)
+    fn pigeonhole(pigeons: u32, holes: u32) -> (usize,Vec<Vec<Lit>>) {
+        let var = |p: u32, h: u32| p*holes+h;
+        let mut clauses = Vec::new();
+        for p in 0..pigeons {
+            clauses.push((0..holes).map(|h| lit(var(p,h), false)).collect());
+        }
+        for h in 0..holes {
+            for p in 0..pigeons {
+                for q in p+1..pigeons {
+                    clauses.push(vec![lit(var(p,h), true), lit(var(q,h), true)]);
+                }
+            }
+        }
+        ((pigeons*holes) as usize, clauses)
+    }
+
+    fn solve(vars: usize, clauses: &[Vec<Lit>]) -> Solver {
+        let mut solver = Solver::new(vars);
+        for clause in clauses {
+            solver.add_clause(clause.clone());
+        }
+        solver
+    }
+
+    #[test]
+    fn satisfiable() {
+        // (x | y) & !x & (!y | z)
+        let clauses = vec![vec![lit(0,false), lit(1,false)], vec![lit(0,true)], vec![lit(1,true), lit(2,false)]];
+        let mut solver = solve(3, &clauses);
+        match solver.solve(100) {
+            Ok(SatResult::Sat(model)) => assert_eq!(model, [false, true, true]),
+            _ => panic!("expected sat"),
+        }
+        let (vars,clauses) = pigeonhole(4, 4);
+        let mut solver = solve(vars, &clauses);
+        match solver.solve(10_000) {
+            Ok(SatResult::Sat(model)) => assert!(satisfies(&clauses, &model)),
+            _ => panic!("expected sat"),
+        }
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        // xとyの全ての組み合わせを禁止する
+        let clauses = (0..4).map(|n| vec![lit(0, n&1==1), lit(1, n&2==2)]).collect::<Vec<_>>();
+        assert!(matches!(solve(2, &clauses).solve(100), Ok(SatResult::Unsat)));
+        // 学習とリスタートを何度も通っても, ヒープには各変数が高々一つしか入らない
+        let (vars,clauses) = pigeonhole(6, 5);
+        let mut solver = solve(vars, &clauses);
+        assert!(matches!(solver.solve(100_000), Ok(SatResult::Unsat)));
+        assert!(solver.heap.heap.len()<=vars);
+        assert!(solver.heap.heap.iter().enumerate().all(|(i,&v)| solver.heap.positions[v]==Some(i)));
+    }
+
+    #[test]
+    fn gives_up() {
+        let (vars,clauses) = pigeonhole(8, 7);
+        assert!(solve(vars, &clauses).solve(10).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+use super::compile::{array_wires, wire_label};
+use super::types::*;
+
+/// 展開済みモジュールのシンボルテーブルを作る
+pub fn symbol_tables(ast: &File, modules: &HashMap<String,CompiledModule>) -> HashMap<String,SymbolTable> {
+    let mut io_names = HashMap::new();
+    for component in &ast.components {
+        if let Component::Module(module) = component {
+            io_names.insert(module.name.clone(), (&module.inputs,&module.outputs,array_wires(module)));
+        }
+    }
+    modules.iter().map(|(name,module)| {
+        let (inputs,outputs) = match io_names.get(name) {
+            Some((inputs,outputs,arrays)) => (inputs.iter().map(|v| wire_label(v, arrays)).collect(),outputs.iter().map(|v| wire_label(v, arrays)).collect()),
+            None => (Vec::new(),Vec::new()),
+        };
+        (name.clone(),SymbolTable {
+            inputs,
+            outputs,
+            gates: module.gate_names.iter().map(|name| gate_symbol(name)).collect(),
+        })
+    }).collect()
+}
+
+/// `c1.hAddr.s.xor.e` を階層 `c1.hAddr.s.xor` とワイヤ名 `e` に分ける
+fn gate_symbol(name: &str) -> GateSymbol {
+    match name.rsplit_once('.') {
+        Some((path,wire)) => GateSymbol { path: path.to_string(), wire: wire.to_string() },
+        None => GateSymbol { path: String::new(), wire: name.to_string() },
+    }
+}
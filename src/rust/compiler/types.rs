@@ -2,10 +2,64 @@ use serde::Serialize;
 
 /// Errのレベル: 一つ目は停止 二つ目は警告
 /// `Ok(( result, warn[] ))` `Err(( error[], warn[] ))`
-pub type Warns = Vec<String>;
-pub type Errs = Vec<String>;
+pub type Warns = Vec<Diagnostic>;
+pub type Errs = Vec<Diagnostic>;
 pub type ResultwithWarn<T> = Result<(T,Warns),(Errs,Warns)>;
 
+/// ソース中の位置 (マクロ展開前のソースのバイト位置, 行と列は1から)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// エラーと警告
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    /// 種類ごとの名前 `undefined-id-used`
+    pub code: String,
+    pub severity: Severity,
+    /// spanのあるファイル (ルートファイルなら空)
+    pub file: String,
+    pub span: Option<Span>,
+    pub message: String,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &str, message: String) -> Self {
+        Diagnostic { code: code.to_string(), severity: Severity::Error, file: String::new(), span: None, message, notes: Vec::new() }
+    }
+    pub fn warning(code: &str, message: String) -> Self {
+        Diagnostic { severity: Severity::Warning, ..Diagnostic::error(code, message) }
+    }
+    pub fn with_span(self, file: &str, span: Span) -> Self {
+        Diagnostic { file: file.to_string(), span: Some(span), ..self }
+    }
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.span {
+            Some(span) if self.file.is_empty() => write!(f, "{} (line {}:{})", self.message, span.line, span.column),
+            Some(span) => write!(f, "{} ({}:{}:{})", self.message, self.file, span.line, span.column),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
 // パーサー系
 
 #[derive(Debug, Clone, Serialize)]
@@ -18,14 +72,25 @@ pub struct File {
 pub enum Component {
     Using(Using),
     Module(Module),
+    GenericModule(GenericModule),
     Graphical(Graphical),
     Test(Test),
+    EquivalenceTest(EquivalenceTest),
     Include(Include),
+    Import(Import),
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Include {
     pub path: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Import {
+    pub modules: Vec<String>,
+    pub path: String,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -36,10 +101,33 @@ pub struct Using {
 #[derive(Debug, Clone, Serialize)]
 pub struct Module {
     pub func: bool,
+    /// 全てのゲートを前のtickの値から同時に更新するかどうか (gates_symmetryにコンパイルされる)
+    pub sync: bool,
+    /// importで他のファイルから使えるかどうか
+    pub export: bool,
     pub name: String,
+    /// 定義されたファイル (ルートファイルなら空)
+    pub file: String,
     pub inputs: Vec<String>,
     pub outputs: Vec<String>,
     pub gates: Vec<Gate>,
+    pub span: Span,
+}
+
+/// パラメータ付きモジュール `func add<N> (a(N) b(N) cin)->(s(N) cout)`
+/// 使われている引数ごとに `add<8>` のような名前の通常のモジュールとして実体化される
+#[derive(Debug, Clone, Serialize)]
+pub struct GenericModule {
+    pub func: bool,
+    pub sync: bool,
+    pub export: bool,
+    pub name: String,
+    pub file: String,
+    pub params: Vec<String>,
+    pub inputs: Vec<PreOutputs>,
+    pub outputs: Vec<PreInputs>,
+    pub gates: Vec<PreGate>,
+    pub span: Span,
 }
 
 
@@ -53,15 +141,37 @@ pub enum ImgSize {
 #[derive(Debug, Clone, Serialize)]
 pub struct Graphical {
     pub name: String,
+    /// 定義されたファイル (ルートファイルなら空)
+    pub file: String,
     pub size: ImgSize,
     pub pixels: Vec<Pixel>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Test {
     pub name: String,
+    /// 定義されたファイル (ルートファイルなら空)
+    pub file: String,
     pub type_sig: MType,
     pub patterns: Vec<TestPattern>,
+    pub span: Span,
+}
+
+/// 二つのfuncモジュール (または組み込みの仕様) の出力が一致するかを比べるテスト `test add4 == ref_add4;`
+#[derive(Debug, Clone, Serialize)]
+pub struct EquivalenceTest {
+    pub name: String,
+    /// 定義されたファイル (ルートファイルなら空)
+    pub file: String,
+    /// 比べる相手のモジュール, 同じ名前のモジュールがなければ組み込みの仕様 (add, eq)
+    pub reference: String,
+    /// ランダムに比べる回数 (Noneなら入力が少なければ全通り、多ければランダム)
+    pub random: Option<usize>,
+    pub seed: usize,
+    /// 入力を試す代わりにSATソルバで等価性を証明する `test add64 == ref_add64 prove;`
+    pub prove: bool,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -69,13 +179,14 @@ pub struct Gate {
     pub outputs: Vec<String>,
     pub module_name: String,
     pub inputs: Vec<String>,
+    pub span: Span,
 }
 
 
 #[derive(Debug, Clone, Serialize)]
 pub struct PreOutputs {
     pub arr_name: String,
-    pub arr_size: usize,
+    pub arr_size: SizeExpr,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -87,9 +198,9 @@ pub struct PreInputs {
 pub struct ArrSlice {
     pub all: bool,
     // all=trueなら以下の内容を無視
-    pub start: usize,
-    pub end: usize,
-    pub step: usize,
+    pub start: SizeExpr,
+    pub end: SizeExpr,
+    pub step: SizeExpr,
     pub lower_inclusive: bool,
     pub upper_inclusive: bool,
 }
@@ -98,7 +209,22 @@ pub struct ArrSlice {
 pub struct PreGate {
     pub outputs: Vec<PreOutputs>,
     pub module_name: String,
+    /// パラメータ付きモジュールの引数 (通常のモジュールなら空)
+    pub module_params: Vec<SizeExpr>,
     pub inputs: Vec<PreInputs>,
+    pub span: Span,
+}
+
+/// 配列のサイズやスライスに使う式 (パラメータを含められる)
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum SizeExpr {
+    Number { value: usize },
+    Param { name: String },
+    Add { left: Box<SizeExpr>, right: Box<SizeExpr> },
+    Sub { left: Box<SizeExpr>, right: Box<SizeExpr> },
+    Mul { left: Box<SizeExpr>, right: Box<SizeExpr> },
+    Div { left: Box<SizeExpr>, right: Box<SizeExpr> },
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -106,6 +232,7 @@ pub struct Pixel {
     pub coord: (u32,u32),
     pub io_index: IoIndex,
     pub color: PixelColor,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -128,8 +255,23 @@ pub struct MType {
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TestPattern {
-    pub inputs: Vec<bool>,
-    pub outputs: Vec<bool>,
+    /// None (`x`) は全ての組み合わせに展開される
+    pub inputs: Vec<Option<bool>>,
+    /// inputを設定してから出力を確認するまでに進めるtick数
+    pub ticks: TestTicks,
+    /// None (`x`) は確認しない
+    pub outputs: Vec<Option<bool>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum TestTicks {
+    /// 1tick (symmetryのゲートがあれば状態が変わらなくなるまで)
+    Default,
+    /// `@n`
+    Count { count: u32 },
+    /// `@stable` 状態が変わらなくなるまで
+    Stable,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -163,6 +305,51 @@ pub struct CompiledModule {
     pub outputs: Vec<u32>,
     pub gates_sequential: Vec<CompiledGate>,
     pub gates_symmetry: Vec<CompiledGate>,
+    /// 各ゲートの階層的な名前 `c1.hAddr.s.xor.e` (gates_sequential,gates_symmetryの順)
+    pub gate_names: Vec<String>,
+}
+
+/// 展開済みモジュールのゲートの名前
+#[derive(Debug, Clone, Serialize)]
+pub struct GateSymbol {
+    /// ゲートがあるインスタンスの階層 `c1.hAddr.s.xor` (モジュール直下のゲートなら空)
+    pub path: String,
+    /// ゲートの出力のワイヤ名 `e`
+    pub wire: String,
+}
+
+/// 展開済みモジュールのシンボルテーブル (VMの状態やトランスパイルしたコードに名前を付けるため)
+#[derive(Debug, Clone, Serialize)]
+pub struct SymbolTable {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// gates_sequential,gates_symmetryの順
+    pub gates: Vec<GateSymbol>,
+}
+
+/// 最適化前後のゲート数
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleOptimization {
+    pub before: usize,
+    pub after: usize,
+}
+
+/// モジュールの遅延 (NORゲートの段数)
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleDelay {
+    /// 各outputの遅延
+    pub output_depths: Vec<u32>,
+    /// 最も遅延の大きいoutputまでの経路 (inputに近い順のゲート名)
+    pub critical_path: Vec<String>,
+}
+
+/// モジュールのフィードバックループ (展開後のNORゲートのグラフの強連結成分)
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleLoops {
+    /// ループがなく、出力がinputだけで決まるかどうか
+    pub combinational: bool,
+    /// ループごとのゲート名
+    pub loops: Vec<Vec<String>>,
 }
 
 // compileの返り値
@@ -170,8 +357,9 @@ pub struct CompiledModule {
 #[derive(Debug, Clone, Serialize)]
 pub struct IntermediateProducts {
     pub source                      : String,
-    pub warns                       : Vec<String>,
-    pub errors                      : Vec<String>,
+    pub source_files                : Vec<String>,
+    pub warns                       : Warns,
+    pub errors                      : Errs,
     pub ast                         : File,
     pub defined_non_func_module_list: Vec<String>,
     pub defined_func_module_list    : Vec<String>,
@@ -179,4 +367,8 @@ pub struct IntermediateProducts {
     pub module_dependency           : Vec<NodeDepends>,
     pub module_dependency_sorted    : Vec<String>,
     pub expanded_modules            : std::collections::HashMap<String,CompiledModule>,
+    pub module_optimizations        : std::collections::HashMap<String,ModuleOptimization>,
+    pub module_delays               : std::collections::HashMap<String,ModuleDelay>,
+    pub module_loops                : std::collections::HashMap<String,ModuleLoops>,
+    pub symbol_tables               : std::collections::HashMap<String,SymbolTable>,
 }
\ No newline at end of file
@@ -71,7 +71,7 @@ pub fn export_compile(input: &str,module: &str) -> Vec<u32> {
 pub fn export_transpile(input: &str,output_modules_pattern: &str) -> String {
     let result = compiler::intermediate_products(&input);
     if result.errors.len()>0 {
-        return format!("// Error:\n{}",result.errors.join("\n"));
+        return format!("// Error:\n{}",result.errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"));
     }
     let mut output_modules = Vec::new();
     let regex_pattern = regex::Regex::new(&format!("^({})$",output_modules_pattern)).unwrap();
@@ -208,6 +208,48 @@ pub fn export_VMgetTick_as_str(resource_id: u32) -> String {
         None=> return  format!("Resource not found: {}",resource_id)
     }
 }
+#[wasm_bindgen(js_name=VMdumpState)]
+pub fn export_VMdump_state(resource_id: u32) -> String {
+    let mut vmres = match VM_resource.lock() {
+        Ok(v)=>v,
+        Err(_)=> return format!("Mutex error")
+    };
+    match vmres.get_resource(resource_id) {
+        Some(module)=> {
+            module.dump_state()
+        },
+        None=> return  format!("Resource not found: {}",resource_id)
+    }
+}
+#[wasm_bindgen(js_name=VMstartTrace)]
+pub fn export_VMstart_trace(resource_id: u32,wires: bool) -> Result<(),String> {
+    let mut vmres = match VM_resource.lock() {
+        Ok(v)=>v,
+        Err(_)=> return Err(format!("Mutex error"))
+    };
+    match vmres.get_resource(resource_id) {
+        Some(module)=> {
+            Ok( module.start_trace(wires) )
+        },
+        None=> return  Err(format!("Resource not found: {}",resource_id))
+    }
+}
+#[wasm_bindgen(js_name=VMgetVCD)]
+pub fn export_VMget_vcd(resource_id: u32) -> Result<String,String> {
+    let mut vmres = match VM_resource.lock() {
+        Ok(v)=>v,
+        Err(_)=> return Err(format!("Mutex error"))
+    };
+    match vmres.get_resource(resource_id) {
+        Some(module)=> {
+            match &module.trace {
+                Some(trace) => Ok(trace.to_vcd()),
+                None => Err(format!("Trace is not started: {}",resource_id)),
+            }
+        },
+        None=> return  Err(format!("Resource not found: {}",resource_id))
+    }
+}
 #[wasm_bindgen(js_name=VMnext)]
 pub fn export_VMnext(resource_id: u32,n: u32) -> Result<u128,String> {
     let mut vmres = match VM_resource.lock() {
@@ -0,0 +1,16 @@
+mod compiler;
+mod test;
+mod vm;
+// LSPはnativeのコマンドの部分 (出力,VM,watchなど) を使わないので、lspだけを読み込む
+mod native {
+    pub mod lsp;
+}
+
+#[cfg(not(feature = "web"))]
+fn main() {
+    native::lsp::main();
+}
+
+#[cfg(feature = "web")]
+fn main() {
+}
@@ -2,63 +2,265 @@ mod compiler;
 mod test;
 mod vm;
 mod transpiler;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 mod native;
 
+use native::common::{OutputMode, ProcessOptions};
+use native::watch::WatchOptions;
+
 // コマンドライン引数
 #[derive(Parser, Debug)]
+#[command(about = "Neknaj Circuit Game")]
 struct Opt {
+    /// Print only errors
+    #[arg(short = 'q', long = "quiet", global = true)]
+    quiet: bool,
+    /// Output format of the result
+    #[arg(long = "format", global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Same as `--format json`
+    #[arg(long = "json", global = true, conflicts_with = "format")]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// 結果の出力形式
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// Colored text
+    Text,
+    /// A single JSON object with the diagnostics and the result of each test pattern
+    Json,
+    /// JUnit XML with a testcase for each test pattern
+    Junit,
+}
+
+/// 出力先の指定
+#[derive(Args, Debug)]
+struct OutputArgs {
     /// Input file
-    #[arg(short = 'i', long = "input", value_name = "Input File Path")]
-    input: Option<String>,
-    #[arg(short = 'o', long = "output", value_name = "Output File Path")]
+    input: String,
+    /// Output file, the type is inferred from the extension or given as `path:type`
+    #[arg(short = 'o', long = "output", value_name = "PATH")]
     output: Vec<String>,
-    #[arg(short = 'd', long = "docOut", value_name = "Document output File Path")]
+    /// Document output file
+    #[arg(short = 'd', long = "doc", value_name = "PATH")]
     doc_output: Option<String>,
-    #[arg(short = 'm', long = "module", value_name = "Name of module to compile")]
-    module: Option<String>,
-    #[arg(short = 's', long = "server", value_name = "Open server for API")]
-    server: Option<String>,
-    #[arg(short = 'w', long = "watch", value_name = "File Watch")]
-    watch: Option<bool>,
-    #[arg(long = "vm", value_name = "Run VM")]
-    run_vm: Option<bool>,
+    /// Names of modules to output (regex)
+    #[arg(short = 'm', long = "module", value_name = "PATTERN", default_value = "")]
+    module: String,
+    /// Write only the gates to NCGB outputs, without symbols, graphical layouts and tests
+    #[arg(long = "strip")]
+    strip: bool,
+    /// Keep the NOR gates of func modules as expanded, without optimizing them
+    #[arg(long = "no-optimize")]
+    no_optimize: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compile and write the outputs
+    Build {
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Compile and run the tests
+    Test {
+        /// Input file
+        input: String,
+        /// Write a VCD waveform of each failing sequential test to this directory
+        #[arg(long = "vcd", value_name = "DIR")]
+        vcd: Option<String>,
+        /// Also record every named internal wire in the VCD
+        #[arg(long = "vcd-wires", requires = "vcd")]
+        vcd_wires: bool,
+    },
+    /// Compile and report errors without writing anything
+    Check {
+        /// Input file
+        input: String,
+    },
+    /// Write the document of the modules
+    Doc {
+        /// Input file
+        input: String,
+        /// Document output file
+        #[arg(short = 'o', long = "output", value_name = "PATH")]
+        output: String,
+    },
+    /// Run a module on the VM (switch the inputs with the number keys)
+    Run {
+        /// Input file
+        input: String,
+        /// Name of the module to run
+        #[arg(short = 'm', long = "module")]
+        module: String,
+        /// Record the inputs and outputs on every tick and write them to this VCD file
+        #[arg(long = "vcd", value_name = "PATH")]
+        vcd: Option<String>,
+        /// Also record every named internal wire in the VCD
+        #[arg(long = "vcd-wires", requires = "vcd")]
+        vcd_wires: bool,
+    },
+    /// Compile and test again whenever the input or included files change
+    Watch {
+        #[command(flatten)]
+        output: OutputArgs,
+        /// Also run the first output module on the VM
+        #[arg(long = "vm")]
+        vm: bool,
+    },
+    /// Send the results to the web tool over WebSocket
+    Serve {
+        #[command(flatten)]
+        output: OutputArgs,
+        /// WebSocket port
+        #[arg(short = 'p', long = "port", default_value_t = 8080)]
+        port: u16,
+        /// Also run the first output module on the VM
+        #[arg(long = "vm")]
+        vm: bool,
+        /// Do not recompile when the files change
+        #[arg(long = "no-watch")]
+        no_watch: bool,
+    },
+    /// Format source files in place
+    Fmt {
+        /// Files to format
+        #[arg(required = true)]
+        files: Vec<String>,
+        /// Exit with a non-zero status if any file is not formatted, without rewriting it
+        #[arg(long = "check")]
+        check: bool,
+    },
+    /// Check the structure of NCGB files and print a summary of each module
+    Verify {
+        /// NCGB files to check
+        #[arg(required_unless_present = "fuzz")]
+        files: Vec<String>,
+        /// Also load this many random or corrupted buffers and check that nothing panics
+        #[arg(long = "fuzz", value_name = "CASES")]
+        fuzz: Option<u32>,
+        /// Seed for `--fuzz`
+        #[arg(long = "seed", default_value_t = 0)]
+        seed: u64,
+    },
+}
+
+impl OutputArgs {
+    fn options(self, mode: OutputMode, test: bool) -> (String, ProcessOptions) {
+        let ncgb = if self.strip {
+            compiler::NcgbOptions { symbols: false, graphical: false, tests: false, checksum: true }
+        }
+        else {
+            compiler::NcgbOptions::default()
+        };
+        let compile = compiler::CompileOptions { optimize: !self.no_optimize };
+        (self.input, ProcessOptions { output_modules_pattern: self.module, output_path: self.output, doc_output_path: self.doc_output, test, ncgb, compile, mode, ..Default::default() })
+    }
 }
 
 #[cfg(not(feature = "web"))]
 #[tokio::main]
 async fn main() {
-    use colored::*;
-    // 引数を処理
-    let opt = Opt::parse();
-    let input_path = match opt.input {
-        Some(v) => v,
-        None => {
-            println!("{}:{} No input path specified in command line arguments","[error]".red(),"arguments".cyan());
-            return;
-        },
+    // コンパイルエラーや失敗したテストがあれば0以外で終了する
+    if !run(Opt::parse()).await {
+        std::process::exit(1);
+    }
+}
+
+/// コマンドを実行する
+/// 戻り値: 成功したかどうか (終了コードになる)
+#[cfg(not(feature = "web"))]
+async fn run(opt: Opt) -> bool {
+    let mode = match (opt.format, opt.json, opt.quiet) {
+        (_, true, _)|(Format::Json, _, _) => OutputMode::Json,
+        (Format::Junit, _, _) => OutputMode::Junit,
+        (Format::Text, _, true) => OutputMode::Quiet,
+        (Format::Text, _, false) => OutputMode::Text,
     };
-    let server = match &opt.server {
-        Some(v)=> match v {
-            v if v=="false" => false,
-            _ => true,
+    match opt.command {
+        Command::Build { output } => {
+            let (input, options) = output.options(mode, false);
+            native::common::process_input(&input, &options).success
         },
-        None => false,
-    };
-    let watch = match opt.watch {
-        Some(v) => v,
-        None => server,
-    };
-    if server|watch|opt.run_vm.unwrap_or(false) {
-        native::watch::main(input_path, opt.output, opt.doc_output, opt.module.unwrap_or("".to_string()), opt.run_vm.unwrap_or(false),watch,server,opt.server).await;
-    }
-    else {
-        let _ = native::common::process_input(&input_path, opt.module.unwrap_or("".to_string()),opt.output,opt.doc_output );
+        Command::Test { input, vcd, vcd_wires } => {
+            native::common::process_input(&input, &ProcessOptions { test: true, mode, vcd_dir: vcd, vcd_wires, ..Default::default() }).success
+        },
+        Command::Check { input } => {
+            native::common::process_input(&input, &ProcessOptions { mode, ..Default::default() }).success
+        },
+        Command::Doc { input, output } => {
+            native::common::process_input(&input, &ProcessOptions { doc_output_path: Some(output), mode, ..Default::default() }).success
+        },
+        Command::Run { input, module, vcd, vcd_wires } => {
+            let options = ProcessOptions { output_modules_pattern: module, mode, vm_vcd_path: vcd, vcd_wires, ..Default::default() };
+            // コンパイルエラーならVMを動かさずに終了する
+            let compiled = native::common::process_input(&input, &options);
+            if !compiled.success {
+                false
+            }
+            else {
+                native::watch::main(input, options, WatchOptions { run_vm: true, watch: false, server: false, server_port: None, compiled: Some(compiled) }).await;
+                true
+            }
+        },
+        Command::Watch { output, vm } => {
+            let (input, options) = output.options(mode, true);
+            native::watch::main(input, options, WatchOptions { run_vm: vm, watch: true, server: false, server_port: None, compiled: None }).await;
+            true
+        },
+        Command::Serve { output, port, vm, no_watch } => {
+            let (input, options) = output.options(mode, true);
+            native::watch::main(input, options, WatchOptions { run_vm: vm, watch: !no_watch, server: true, server_port: Some(port.to_string()), compiled: None }).await;
+            true
+        },
+        Command::Fmt { files, check } => native::fmt::main(&files, check, mode),
+        Command::Verify { files, fuzz, seed } => native::verify::main(&files, fuzz, seed, mode),
     }
-    return;
 }
 
-#[cfg(not(feature = "web"))]
 #[cfg(feature = "web")]
 fn main() {
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// コマンドを実行して、終了コードが0になるかどうかを返す
+    async fn succeeds(args: &[&str]) -> bool {
+        run(Opt::try_parse_from(std::iter::once("circuitgame").chain(args.iter().copied())).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn exit_codes() {
+        let dir = std::env::temp_dir().join(format!("circuitgame_cli_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, source: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, source).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let ok = file("ok.ncg", "using nor:2->1;\n\nfunc not (x)->(a) {\n    a: nor <- x x;\n}\n\ntest not:1->1 {\n    t -> f;\n    f -> t;\n}\n");
+        let error = file("error.ncg", "using nor:2->1;\nfunc not (x)->(a) { a: foo <- x; }\n");
+        let failing = file("failing.ncg", "using nor:2->1;\nfunc not (x)->(a) { a: nor <- x x; }\ntest not:1->1 { t -> t; }\n");
+        let missing = dir.join("missing.ncg").to_string_lossy().to_string();
+        let ncgb = dir.join("not.ncgb").to_string_lossy().to_string();
+
+        assert!(succeeds(&["-q", "check", &ok]).await);
+        assert!(!succeeds(&["-q", "check", &error]).await);
+        assert!(!succeeds(&["-q", "check", &missing]).await);
+        assert!(succeeds(&["-q", "test", &ok]).await);
+        assert!(!succeeds(&["-q", "test", &failing]).await);
+        assert!(!succeeds(&["-q", "test", &error]).await);
+        assert!(succeeds(&["-q", "build", &ok, "-m", "not", "-o", &ncgb]).await);
+        assert!(!succeeds(&["-q", "build", &error, "-m", "not", "-o", &ncgb]).await);
+        assert!(succeeds(&["-q", "verify", &ncgb]).await);
+        assert!(!succeeds(&["-q", "verify", &ok]).await);
+        assert!(succeeds(&["-q", "fmt", "--check", &ok]).await);
+        assert!(!succeeds(&["-q", "fmt", "--check", &failing]).await);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
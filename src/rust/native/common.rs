@@ -5,70 +5,257 @@ use crate::vm::deserializer::deserialize_from_vec;
 use super::super::test;
 use super::super::vm;
 use super::super::compiler;
+use compiler::types::{Diagnostic, Severity};
+use super::report::{self, Report, ReportedDiagnostic};
 
-// 入力処理を別関数として分離
-pub fn process_input(input_path: &str,output_modules_pattern: String, output_path: Vec<String>, doc_output_path: Option<String>) -> Vec<Vec<u32>> {
-    println!("< {} >\n","Neknaj Circuit Game".bold());
+/// CLIのメッセージの出し方
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputMode {
+    /// 色付きのテキスト
+    #[default]
+    Text,
+    /// エラーだけを表示する
+    Quiet,
+    /// 最後に結果をまとめたJSONを一つだけ表示する (スクリプト向け)
+    Json,
+    /// 最後にテストの結果をJUnit XMLで表示する (CI向け)
+    Junit,
+}
+
+/// process_inputで行う処理
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// 出力するモジュールの名前 (正規表現)
+    pub output_modules_pattern: String,
+    pub output_path: Vec<String>,
+    pub doc_output_path: Option<String>,
+    /// テストを実行するかどうか
+    pub test: bool,
+    /// NCGBの出力に含めるもの
+    pub ncgb: compiler::NcgbOptions,
+    pub compile: compiler::CompileOptions,
+    pub mode: OutputMode,
+    /// 失敗した順序回路のテストの波形 (VCD) を書き出すディレクトリ
+    pub vcd_dir: Option<String>,
+    /// VMの波形 (VCD) を書き出すファイル
+    pub vm_vcd_path: Option<String>,
+    /// VCDに名前の付いた全てのゲートも含めるかどうか
+    pub vcd_wires: bool,
+}
+
+/// process_inputの結果
+pub struct ProcessResult {
+    /// output_modules_patternに一致したモジュールのNCGB
+    pub binaries: Vec<Vec<u32>>,
+    /// コンパイルエラー、失敗したテスト、出力の失敗がなかったかどうか
+    pub success: bool,
+}
+
+/// メッセージをOutputModeに合わせて表示し、JSON,JUnitの出力のために集める
+struct Reporter<'a> {
+    mode: OutputMode,
+    input_path: &'a str,
+    source: String,
+    /// 診断がどのモジュールのものかを調べるためのAST
+    ast: Option<compiler::types::File>,
+    report: Report,
+}
 
-    println!("{}:{} input  file: {}","[info]".green(),"input ".cyan(),input_path);
-    println!("{}:{} output file: {:?}","[info]".green(),"output".cyan(),output_path);
+impl Reporter<'_> {
+    fn info(&self, stage: &str, message: String) {
+        if self.mode==OutputMode::Text {
+            println!("{}:{} {}","[info]".green(),stage.cyan(),message);
+        }
+    }
+    fn warn(&mut self, stage: &str, code: &str, message: String) {
+        self.report(stage, &Diagnostic::warning(code, message));
+    }
+    fn error(&mut self, stage: &str, code: &str, message: String) {
+        self.report(stage, &Diagnostic::error(code, message));
+    }
+    fn report(&mut self, stage: &str, diagnostic: &Diagnostic) {
+        let error = diagnostic.severity==Severity::Error;
+        let show = match self.mode {
+            OutputMode::Text => true,
+            OutputMode::Quiet => error,
+            OutputMode::Json|OutputMode::Junit => false,
+        };
+        if show {
+            print_diagnostic(stage, diagnostic, &self.source, self.input_path);
+        }
+        let module = self.ast.as_ref().and_then(|ast| report::owner(ast, diagnostic));
+        // ルートファイルの診断はfileが空なので、CIでファイルを特定できるように入力のパスを入れる
+        let mut diagnostic = diagnostic.clone();
+        if diagnostic.file.is_empty() {
+            diagnostic.file = self.report.input.clone();
+        }
+        self.report.diagnostics.push(ReportedDiagnostic { stage: stage.to_string(), module, diagnostic });
+    }
+}
+
+/// 一つのモジュールだけを出力できる形式で、出力するモジュールを選ぶ
+fn single_output_module<'a>(reporter: &mut Reporter, stage: &str, output_modules: &'a [String], output: &str) -> Option<&'a String> {
+    if output_modules.len()>1 {
+        reporter.warn(stage, "multiple-output-modules", format!("This output type doesn't support multiple modules, only the first module was exported to {}",output));
+    }
+    if output_modules.is_empty() {
+        reporter.warn(stage, "no-output-module", format!("No module is specified to output: {}",output));
+    }
+    output_modules.first()
+}
+
+/// 入力ファイルをコンパイルし、設定に応じてテスト、ファイルの出力、ドキュメントの出力を行う
+pub fn process_input(input_path: &str, options: &ProcessOptions) -> ProcessResult {
+    let mut reporter = Reporter { mode: options.mode, input_path, source: String::new(), ast: None, report: Report { input: input_path.to_string(), ..Default::default() } };
+    let binaries = process(input_path, options, &mut reporter);
+    let report = reporter.report;
+    match options.mode {
+        OutputMode::Json => println!("{}", report.to_json()),
+        OutputMode::Junit => println!("{}", report.to_junit()),
+        _ => {},
+    }
+    ProcessResult { binaries, success: report.success() }
+}
+
+fn process(input_path: &str, options: &ProcessOptions, reporter: &mut Reporter) -> Vec<Vec<u32>> {
+    if options.mode==OutputMode::Text {
+        println!("< {} >\n","Neknaj Circuit Game".bold());
+    }
+    reporter.info("input ", format!("input  file: {}",input_path));
+    reporter.info("output", format!("output file: {:?}",options.output_path));
     // inputを読み込み
     let input = match std::fs::read_to_string(input_path) {
         Ok(v) => v,
         Err(e) => {
-            match e.kind() {
-                std::io::ErrorKind::NotFound => println!("{}:{} File not found","[error]".red(),"arguments".cyan()),
-                std::io::ErrorKind::PermissionDenied => println!("{}:{} Permission denied","[error]".red(),"arguments".cyan()),
-                _ => println!("{}:{} {}","[error]".red(),"arguments".cyan(),e),
+            let message = match e.kind() {
+                std::io::ErrorKind::NotFound => format!("File not found: {}",input_path),
+                std::io::ErrorKind::PermissionDenied => format!("Permission denied: {}",input_path),
+                _ => format!("{}: {}",e,input_path),
             };
+            reporter.error("arguments", "input-read-failed", message);
             return Vec::new();
         }
     };
+    reporter.source = input.clone();
 
     // inputを処理
-    let result = compiler::intermediate_products(&input);
+    let result = compiler::intermediate_products_with_options(&input, input_path, &read_source, &options.compile);
+    reporter.ast = Some(result.ast.clone());
 
     for i in &result.warns {
-        println!("{}:{} {}","[warn]".yellow(),"compile".cyan(),i);
+        reporter.report("compile", i);
     }
     for i in &result.errors {
-        println!("{}:{} {}","[error]".red(),"compile".cyan(),i);
+        reporter.report("compile", i);
     }
-    println!("sortedDependency {:?}",&result.module_dependency_sorted);
+    reporter.info("compile", format!("sortedDependency {:?}",&result.module_dependency_sorted));
 
-    if result.errors.len() > 0 {
+    if !result.errors.is_empty() {
         return Vec::new();
     }
 
-    let test_result = test::test(result.clone());
-    for i in &test_result.warns {
-        println!("{}:{} {}","[warn]".yellow(),"test".cyan(),i);
-    }
-    for i in &test_result.errors {
-        println!("{}:{} {}","[error]".red(),"test".cyan(),i);
+    if options.test {
+        let test_result = test::test(result.clone());
+        for i in &test_result.warns {
+            reporter.report("test", i);
+        }
+        for i in &test_result.errors {
+            reporter.report("test", i);
+        }
+        for name in &test_result.test_list {
+            let Some(patterns) = test_result.test_result.get(name) else { continue };
+            let failed = patterns.iter().filter(|p| !p.accept).count();
+            if failed>0 {
+                // 失敗したパターンごとの警告はtestが出すので、ここではテストごとにまとめてエラーにする
+                reporter.error("test", "failing-test", format!("Test failed: {} ({} of {} patterns)",name,failed,patterns.len()));
+                if let Some(reported) = reporter.report.diagnostics.last_mut() {
+                    reported.module = Some(name.clone());
+                }
+            }
+            else if patterns.is_empty() {
+                reporter.info("test", format!("Test passed: {}",name));
+            }
+            else {
+                reporter.info("test", format!("Test passed: {} ({} patterns)",name,patterns.len()));
+            }
+            reporter.report.tests.push((name.clone(), patterns.clone()));
+        }
+        // 失敗した順序回路のテストの波形を書き出す (組み合わせ回路は入力だけで出力が決まるので波形にしない)
+        // funcでないモジュールか、`@n` `@stable` で時間を進めるテストか、フィードバックループのあるモジュールを順序回路とする
+        if let Some(vcd_dir) = &options.vcd_dir {
+            for name in &test_result.test_list {
+                let failed = test_result.test_result.get(name).is_some_and(|patterns| patterns.iter().any(|p| !p.accept));
+                // 波形にするのは真理値表のテストだけ (等価性のテストの名前はモジュール名でない)
+                let Some(test) = result.ast.components.iter().find_map(|component| match component {
+                    compiler::types::Component::Test(test) if &test.name==name => Some(test),
+                    _ => None,
+                }) else { continue };
+                let timed = test.patterns.iter().any(|p| !matches!(p.ticks, compiler::types::TestTicks::Default));
+                let sequential = !result.defined_func_module_list.contains(name) || timed || result.module_loops.get(name).is_some_and(|loops| !loops.combinational);
+                if !failed || !sequential {
+                    continue;
+                }
+                let path = std::path::Path::new(vcd_dir).join(format!("{}.vcd",name.replace(|c: char| !c.is_ascii_alphanumeric() && c!='_', "_")));
+                let path = path.to_string_lossy().to_string();
+                let vcd = match test::trace(&result, name, options.vcd_wires) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        reporter.error("vcd", "trace-failed", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = std::fs::create_dir_all(vcd_dir).and_then(|_| write_text_file(&path, &vcd)) {
+                    reporter.error("output", "output-write-failed", e.to_string());
+                } else {
+                    reporter.info("vcd", format!("Output completed: {}",path));
+                    reporter.report.outputs.push(path);
+                }
+            }
+        }
     }
 
     let mut output_modules = Vec::new();
-    let regex_pattern = regex::Regex::new(&format!("^({})$",output_modules_pattern)).unwrap();
-    for test_str in result.defined_func_module_list.clone() {
-        if regex_pattern.is_match(&test_str) {
-            output_modules.push(test_str.clone());
+    let regex_pattern = match regex::Regex::new(&format!("^({})$",options.output_modules_pattern)) {
+        Ok(v) => v,
+        Err(e) => {
+            reporter.error("arguments", "invalid-module-pattern", format!("Invalid module name pattern: {}",e));
+            return Vec::new();
         }
-    }
-    for test_str in result.defined_non_func_module_list.clone() {
-        if regex_pattern.is_match(&test_str) {
+    };
+    for test_str in result.defined_func_module_list.iter().chain(&result.defined_non_func_module_list) {
+        if regex_pattern.is_match(test_str) {
             output_modules.push(test_str.clone());
         }
     }
-    println!("{}:{} {}","[info]".green(),"modules".cyan(),format!("Module exports: {}",output_modules.join(", ")));
+    reporter.info("modules", format!("Module exports: {}",output_modules.join(", ")));
+
+    let mut binaries = Vec::new();
+    for module_name in &output_modules {
+        match compiler::serialize(result.clone(), module_name.as_str()) {
+            Ok(v) => binaries.push(v),
+            Err(v) => {
+                reporter.error("serialize", "serialize-failed", v);
+                return Vec::new();
+            }
+        };
+    }
+    let binary_of = |module_name: &String| &binaries[output_modules.iter().position(|m| m==module_name).unwrap_or(0)];
+    // トランスパイラ向けに読み込む (ループのあるモジュールは純粋な関数として出力しない)
+    let transpile_module = |module_name: &String| {
+        let mut module = deserialize_from_vec(binary_of(module_name)).unwrap();
+        if result.module_loops.get(module_name).is_some_and(|loops| !loops.combinational) {
+            module.func = false;
+        }
+        module
+    };
 
-    for output in output_path {
+    for output in &options.output_path {
         // outputのtypeを決定する
         let out_type = match output.split(":").nth(1) {
             // 明示されている場合
             Some(t) => t,
             // 拡張子から推定
-            None => match &output {
+            None => match output {
                 name if name.ends_with(".bin")  => "ncgb",
                 name if name.ends_with(".ncgb") => "ncgb",
                 name if name.ends_with(".c")    => "c",
@@ -79,185 +266,134 @@ pub fn process_input(input_path: &str,output_modules_pattern: String, output_pat
                 name if name.ends_with(".rs")   => "rs",
                 // output_typeの推定に失敗
                 _ => {
-                    println!("{}:{} {}","[error]".red(),"output".cyan(),format!("Could not infer output type for {}",output));
+                    reporter.error("output", "unknown-output-type", format!("Could not infer output type for {}",output));
                     continue;
                 },
             },
         };
         // typeに基づいてoutput
-        match out_type {
+        let (stage, data) = match out_type {
             "ncgb" => {
-                if output_modules.len()>1 {
-                    println!("{}:{} {}","[error]".red(),"output".cyan(),format!("NCGb output doesn't support multiple modules: {}",output));
-                    println!("{}:{} {}","[info]".green(),"output".cyan(),format!("Only the first module was exported {}",output));
-                }
-                if output_modules.len()==0 {
-                    println!("{}:{} {}","[warn]".green(),"output".cyan(),format!("No module is specified to output: {}",output));
+                if output_modules.is_empty() {
+                    reporter.warn("output", "no-output-module", format!("No module is specified to output: {}",output));
+                    continue;
                 }
-                if let Some(module_name) = output_modules.get(0) {
-                    let binary = match compiler::serialize(result.clone(), module_name.as_str()) {
-                        Ok(v) => v,
-                        Err(v) => {
-                            println!("{}:{} {}","[error]".red(),"serialize".cyan(),v);
-                            return Vec::new()
-                        }
-                    };
-                    if let Err(e) = write_binary_file(output.as_str(), binary.clone()) {
-                        println!("{}:{} {}","[error]".red(),"output".cyan(),e);
-                    } else {
-                        println!("{}:{} Output completed: {}","[info]".green(),"output".cyan(),output);
+                // 全てのモジュールを一つのコンテナにまとめる
+                let binary = match compiler::serialize_modules(&result, &output_modules, &options.ncgb) {
+                    Ok(v) => v,
+                    Err(v) => {
+                        reporter.error("serialize", "serialize-failed", v);
+                        continue;
                     }
+                };
+                if let Err(e) = write_binary_file(output.as_str(), binary) {
+                    reporter.error("output", "output-write-failed", e.to_string());
+                } else {
+                    reporter.info("output", format!("Output completed: {}",output));
+                    reporter.report.outputs.push(output.clone());
                 }
+                continue;
             },
             "c"|"cheader" => {
-                if output_modules.len()>1 {
-                    println!("{}:{} {}","[error]".red(),"transpile".cyan(),format!("NCGb output doesn't support multiple modules: {}",output));
-                    println!("{}:{} {}","[info]".green(),"transpile".cyan(),format!("Only the first module was exported to {}",output));
-                }
-                if output_modules.len()==0 {
-                    println!("{}:{} {}","[warn]".green(),"transpile".cyan(),format!("No module is specified to output: {}",output));
-                }
-                if let Some(module_name) = output_modules.get(0) {
-                    let binary = match compiler::serialize(result.clone(), module_name.as_str()) {
-                        Ok(v) => v,
-                        Err(v) => {
-                            println!("{}:{} {}","[error]".red(),"serialize".cyan(),v);
-                            return Vec::new()
-                        }
-                    };
-                    match crate::transpiler::c_transpiler::transpile(deserialize_from_vec(&binary).unwrap(),out_type=="cheader") {
-                        Ok(data) => {
-                            if let Err(e) = write_text_file(output.as_str(), &data) {
-                                println!("{}:{} {}","[error]".red(),"output".cyan(),e);
-                            } else {
-                                println!("{}:{} Output completed: {}","[info]".green(),"transpile".cyan(),output);
-                            }
-                        },
-                        Err(err) => {
-                            println!("{}:{} {}","[error]".red(),"transpile".cyan(),err);
-                        }
-                    }
-                }
+                let Some(module_name) = single_output_module(reporter, "transpile", &output_modules, output) else { continue };
+                ("transpile", crate::transpiler::c_transpiler::transpile(transpile_module(module_name),out_type=="cheader"))
             },
             "ts"|"dts" => {
-                let mut modules = Vec::new();
-                for module_name in &output_modules {
-                    let binary = match compiler::serialize(result.clone(), module_name.as_str()) {
-                        Ok(v) => v,
-                        Err(v) => {
-                            println!("{}:{} {}","[error]".red(),"serialize".cyan(),v);
-                            return Vec::new()
-                        }
-                    };
-                    modules.push(deserialize_from_vec(&binary).unwrap());
-                }
-                match crate::transpiler::ts_transpiler::transpile(modules,out_type=="dts") {
-                    Ok(data) => {
-                        if let Err(e) = write_text_file(output.as_str(), &data) {
-                            println!("{}:{} {}","[error]".red(),"output".cyan(),e);
-                        } else {
-                            println!("{}:{} Output completed: {}","[info]".green(),"transpile".cyan(),output);
-                        }
-                    },
-                    Err(err) => {
-                        println!("{}:{} {}","[error]".red(),"transpile".cyan(),err);
-                    }
-                }
+                let modules = output_modules.iter().map(transpile_module).collect();
+                ("transpile", crate::transpiler::ts_transpiler::transpile(modules,out_type=="dts"))
             },
             "rs" => {
-                let mut modules = Vec::new();
-                for module_name in &output_modules {
-                    let binary = match compiler::serialize(result.clone(), module_name.as_str()) {
-                        Ok(v) => v,
-                        Err(v) => {
-                            println!("{}:{} {}","[error]".red(),"serialize".cyan(),v);
-                            return Vec::new()
-                        }
-                    };
-                    modules.push(deserialize_from_vec(&binary).unwrap());
-                }
-                match crate::transpiler::rs_transpiler::transpile(modules) {
-                    Ok(data) => {
-                        if let Err(e) = write_text_file(output.as_str(), &data) {
-                            println!("{}:{} {}","[error]".red(),"output".cyan(),e);
-                        } else {
-                            println!("{}:{} Output completed: {}","[info]".green(),"transpile".cyan(),output);
-                        }
-                    },
-                    Err(err) => {
-                        println!("{}:{} {}","[error]".red(),"transpile".cyan(),err);
-                    }
-                }
+                let modules = output_modules.iter().map(transpile_module).collect();
+                ("transpile", crate::transpiler::rs_transpiler::transpile(modules))
             },
             "js" => {
-                if output_modules.len()>1 {
-                    println!("{}:{} {}","[error]".red(),"transpile".cyan(),format!("NCGb output doesn't support multiple modules: {}",output));
-                    println!("{}:{} {}","[info]".green(),"transpile".cyan(),format!("Only the first module was exported to {}",output));
-                }
-                if output_modules.len()==0 {
-                    println!("{}:{} {}","[warn]".green(),"transpile".cyan(),format!("No module is specified to output: {}",output));
-                }
-                if let Some(module_name) = output_modules.get(0) {
-                    let binary = match compiler::serialize(result.clone(), module_name.as_str()) {
-                        Ok(v) => v,
-                        Err(v) => {
-                            println!("{}:{} {}","[error]".red(),"serialize".cyan(),v);
-                            return Vec::new()
-                        }
-                    };
-                    match crate::transpiler::js_transpiler::transpile(deserialize_from_vec(&binary).unwrap()) {
-                        Ok(data) => {
-                            if let Err(e) = write_text_file(output.as_str(), &data) {
-                                println!("{}:{} {}","[error]".red(),"output".cyan(),e);
-                            } else {
-                                println!("{}:{} Output completed: {}","[info]".green(),"transpile".cyan(),output);
-                            }
-                        },
-                        Err(err) => {
-                            println!("{}:{} {}","[error]".red(),"transpile".cyan(),err);
-                        }
-                    }
-                }
+                let Some(module_name) = single_output_module(reporter, "transpile", &output_modules, output) else { continue };
+                ("transpile", crate::transpiler::js_transpiler::transpile(transpile_module(module_name)))
             },
             _ => {
-                println!("{}:{} {}","[error]".red(),"output".cyan(),format!("Unsupported output type was specified: {} for {}",out_type,output));
+                reporter.error("output", "unknown-output-type", format!("Unsupported output type was specified: {} for {}",out_type,output));
+                continue;
             },
         };
-    }
-    match document(result.clone()) {
-        Ok(doc_str)=>{
-            match doc_output_path {
-                Some(v)=> {
-                    if let Err(e) = write_text_file(v.as_str(), &doc_str) {
-                        println!("{}:{} {}","[error]".red(),"output".cyan(),e);
-                    } else {
-                        println!("{}:{} document output completed","[info]".green(),"output".cyan());
-                    }
-                },
-                None => {
-                    println!("{}:{} No document output path specified in command line arguments","[info]".green(),"output".cyan());
+        match data {
+            Ok(data) => {
+                if let Err(e) = write_text_file(output.as_str(), &data) {
+                    reporter.error("output", "output-write-failed", e.to_string());
+                } else {
+                    reporter.info(stage, format!("Output completed: {}",output));
+                    reporter.report.outputs.push(output.clone());
                 }
+            },
+            Err(err) => {
+                reporter.error(stage, "transpile-failed", err);
             }
-        },
-        Err(v)=>{
-            println!("{}:{} {}","[error]".red(),"document".cyan(),v);
         }
-    };
-
-    let mut binaries = Vec::new();
-    for module_name in &output_modules {
-        let binary = match compiler::serialize(result.clone(), module_name.as_str()) {
-            Ok(v) => v,
+    }
+    if let Some(doc_output_path) = &options.doc_output_path {
+        match document(result.clone()) {
+            Ok(doc_str) => {
+                if let Err(e) = write_text_file(doc_output_path, &doc_str) {
+                    reporter.error("output", "output-write-failed", e.to_string());
+                } else {
+                    reporter.info("output", "document output completed".to_string());
+                    reporter.report.outputs.push(doc_output_path.clone());
+                }
+            },
             Err(v) => {
-                println!("{}:{} {}","[error]".red(),"serialize".cyan(),v);
-                return Vec::new()
+                reporter.error("document", "document-failed", v);
             }
         };
-        binaries.push(binary);
     }
+
     binaries
 }
 
+/// エラーと警告を、位置が分かればソースの該当行と一緒に表示する
+/// source: ルートファイルの内容 (includeされたファイルは読み直す)
+pub fn print_diagnostic(stage: &str, diagnostic: &compiler::types::Diagnostic, source: &str, input_path: &str) {
+    use compiler::types::Severity;
+    let label = match diagnostic.severity {
+        Severity::Error => "[error]".red(),
+        Severity::Warning => "[warn]".yellow(),
+    };
+    println!("{}:{} {} {}",label,stage.cyan(),diagnostic.message,format!("[{}]",diagnostic.code).dimmed());
+    if let Some(span) = diagnostic.span {
+        let (path,text) = if diagnostic.file.is_empty() {
+            (input_path.to_string(),Some(source.to_string()))
+        }
+        else {
+            (diagnostic.file.clone(),read_source(&diagnostic.file).ok())
+        };
+        println!("  {} {}:{}:{}","-->".blue(),path,span.line,span.column);
+        if let Some(line) = text.as_deref().and_then(|text| text.lines().nth(span.line.saturating_sub(1))) {
+            let number = span.line.to_string();
+            let padding = " ".repeat(number.len());
+            // spanが複数行にわたる場合は最初の行の終わりまで下線を引く
+            let start = span.column.saturating_sub(1).min(line.chars().count());
+            let width = (span.end.saturating_sub(span.start)).min(line.chars().count()-start).max(1);
+            println!("  {} {}",padding,"|".blue());
+            println!("  {} {} {}",number.blue(),"|".blue(),line);
+            println!("  {} {} {}{}",padding,"|".blue()," ".repeat(start),"^".repeat(width).bold());
+        }
+    }
+    for note in &diagnostic.notes {
+        println!("  {} {}","=".blue(),note);
+    }
+}
+
+/// includeされたファイルを読み込む
+pub fn read_source(path: &str) -> Result<String,String> {
+    std::fs::read_to_string(path).map_err(|e| e.to_string())
+}
+
+/// inputとincludeされている全てのファイルの一覧
+pub fn source_files(input_path: &str) -> Vec<String> {
+    match std::fs::read_to_string(input_path) {
+        Ok(input) => compiler::source_files(&input, input_path, &read_source),
+        Err(_) => vec![compiler::normalize_path(input_path)],
+    }
+}
+
 fn write_binary_file(filename: &str, data: Vec<u32>) -> std::io::Result<()> {use std::fs::File;
     use byteorder::{LittleEndian, WriteBytesExt};
     // ファイルの作成
@@ -287,15 +423,57 @@ fn write_text_file(file_path: &str, content: &str) -> std::io::Result<()> {
 
 use tokio::time::{sleep, Duration, self, Instant};
 use tokio::sync::broadcast;
-pub async fn runVM(data: Vec<u32>, vmset_tx: broadcast::Sender<u32>, ws_tx: broadcast::Sender<String>) -> Result<(),String> {
+/// runVMでVCDに追記する間隔 (tick)
+const VCD_WRITE_INTERVAL: u128 = 1000;
+
+/// runVMで動かしているVMと、値の変化を追記していくVCDファイル
+/// Ctrl-Cやファイルの変更でrunVMが止められてdropされたときにも、残りの変化を書き出す
+struct RunningVM {
+    module: crate::vm::types::Module,
+    vcd: Option<std::fs::File>,
+}
+
+impl RunningVM {
+    /// 記録した変化をVCDに追記して記録から取り除く (長く動かしてもメモリが増え続けないように)
+    fn write_vcd(&mut self, finish: bool) {
+        use std::io::Write;
+        if let (Some(file), Some(trace)) = (&mut self.vcd, &mut self.module.trace) {
+            let vcd = trace.drain_vcd(finish);
+            if let Err(e) = file.write_all(vcd.as_bytes()).and_then(|_| file.flush()) {
+                println!("failed to write VCD: {}",e);
+            }
+        }
+    }
+}
+
+impl Drop for RunningVM {
+    fn drop(&mut self) {
+        self.write_vcd(true);
+    }
+}
+
+/// vcd_path: 値の変化を記録して書き出すVCDファイル (vcd_wiresなら名前の付いた全てのゲートも記録する)
+pub async fn runVM(data: Vec<u32>, vmset_tx: broadcast::Sender<u32>, ws_tx: broadcast::Sender<String>, vcd_path: Option<String>, vcd_wires: bool) -> Result<(),String> {
     let mut rx = vmset_tx.subscribe();
     use crate::vm::types::Module;
-    let mut vm_module = match Module::new(data) {
+    let vm_module = match Module::new(data) {
         Ok(v) => v,
         Err(_) => {
             println!("failed to init VM");
             return Err(format!("failed to init VM"));}
     };
+    let mut running = RunningVM { module: vm_module, vcd: None };
+    // 入力を切り替えたとき以外はほとんどのゲートが変わらないので、変わったゲートだけを評価する
+    running.module.set_event_driven(true);
+    if let Some(path) = &vcd_path {
+        running.module.start_trace(vcd_wires);
+        running.vcd = match std::fs::File::create(path) {
+            Ok(v) => Some(v),
+            Err(e) => return Err(format!("failed to create VCD: {}",e)),
+        };
+        // ヘッダーと初期値を先に書いておく
+        running.write_vcd(false);
+    }
 
     println!("");
     println!("VM start");
@@ -303,25 +481,54 @@ pub async fn runVM(data: Vec<u32>, vmset_tx: broadcast::Sender<u32>, ws_tx: broa
     println!("\n\n");
     loop {
         // まずVMを1ステップ実行
-        let _ = vm_module.next(1);
+        let _ = running.module.next(1);
         // outputをプリント
         println!("\x1B[4A\x1B[2K");
-        println!("tick   {}",vm_module.get_tick());
-        println!("input  {}",vm_module.get_input().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(" "));
-        println!("output {}",vm_module.get_output().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(" "));
+        println!("tick   {}",running.module.get_tick());
+        println!("input  {}",running.module.get_input().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(" "));
+        println!("output {}",running.module.get_output().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(" "));
         // let _ = ws_tx.send(format!("tick:{},input:{:?},output:{:?}",
-        //     vm_module.get_tick(),
-        //     vm_module.get_input().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(""),
-        //     vm_module.get_output().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(""),
+        //     running.module.get_tick(),
+        //     running.module.get_input().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(""),
+        //     running.module.get_output().unwrap().iter().map(|&b| if b {"t"}else{"f"}).collect::<Vec<_>>().join(""),
         // ));
         // メッセージの確認（ノンブロッキング）
         if let Ok(index) = rx.try_recv() {
             // println!("Received VM setting: index={}",index);
             // ここでメッセージに基づく処理を実装
             // 例：VMの設定を更新するなど
-            let _ = vm_module.inv(index);
+            let _ = running.module.inv(index);
+        }
+        // 終了するまで待たずにGTKWaveで読み直せるように、一定の間隔で追記する
+        if vcd_path.is_some() && running.module.get_tick().is_multiple_of(VCD_WRITE_INTERVAL) {
+            running.write_vcd(false);
         }
         // 必要に応じて短い待機を入れる
         tokio::time::sleep(Duration::from_millis(1)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// runVMが止められてdropされたときに、まだ書き出していない変化と最後のtickを追記する
+    #[test]
+    fn running_vm_flushes_vcd_on_drop() {
+        let source = "using nor:2->1;\nmodule sr_latch (s r)->(q nq) { q: nor <- r nq; nq: nor <- s q; }\n";
+        let products = compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        let data = compiler::serialize(products, "sr_latch").unwrap();
+        let path = std::env::temp_dir().join(format!("circuitgame_drop_{}.vcd", std::process::id()));
+        {
+            let mut running = RunningVM { module: crate::vm::types::Module::new(data).unwrap(), vcd: Some(std::fs::File::create(&path).unwrap()) };
+            running.module.start_trace(false);
+            running.write_vcd(false);
+            running.module.set(0, true).unwrap();
+            running.module.next(2).unwrap();
+        }
+        let vcd = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(vcd.starts_with("$version Neknaj Circuit Game $end\n"), "{}", vcd);
+        assert!(vcd.ends_with("$dumpvars\n0!\n0\"\n0#\n0$\n$end\n#0\n1!\n#1\n1#\n#2\n"), "{}", vcd);
+    }
+}
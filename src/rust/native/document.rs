@@ -16,11 +16,23 @@ pub fn document(product: IntermediateProducts) -> Result<String,String> {
         }
     }
     let table_body = modules.iter().map(|module| {
-        match product.expanded_modules.get(&module.name) {
-            Some(emod) => format!("| {} | {} -> {} | {} |",module.name,module.inputs.len(),module.outputs.len(),emod.gates_sequential.len()),
-            None => format!("| error |")
+        match (product.module_optimizations.get(&module.name),product.module_delays.get(&module.name)) {
+            (Some(optimization),Some(delay)) => format!("| {} | {} -> {} | {} | {} | {} | {} |",module.name,module.inputs.len(),module.outputs.len(),optimization.before,optimization.after,delay.output_depths.iter().max().unwrap_or(&0),delay.output_depths.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(" ")),
+            _ => format!("| error |")
         }
     }).collect::<Vec<_>>().join("\n");
-    let table = format!("| name | type | size |\n| -- | -- | -- |\n{}",table_body);
-    Ok(format!("{}",table))
+    let table = format!("| name | type | size | optimized | depth | output depths |\n| -- | -- | -- | -- | -- | -- |\n{}",table_body);
+    // 各モジュールのクリティカルパス
+    let critical_paths = modules.iter().filter_map(|module| {
+        product.module_delays.get(&module.name).filter(|delay| !delay.critical_path.is_empty()).map(|delay| {
+            format!("### {}\n\n{}",module.name,delay.critical_path.iter().map(|name| format!("1. `{}`",name)).collect::<Vec<_>>().join("\n"))
+        })
+    }).collect::<Vec<_>>().join("\n\n");
+    // 各モジュールのフィードバックループ (ループのあるモジュールは順序回路)
+    let feedback_loops = modules.iter().filter_map(|module| {
+        product.module_loops.get(&module.name).filter(|loops| !loops.combinational).map(|loops| {
+            format!("### {}\n\n{}",module.name,loops.loops.iter().map(|gates| format!("1. {}",gates.iter().map(|name| format!("`{}`",name)).collect::<Vec<_>>().join(" "))).collect::<Vec<_>>().join("\n"))
+        })
+    }).collect::<Vec<_>>().join("\n\n");
+    Ok(format!("{}\n\n## Critical path\n\n{}\n\n## Feedback loops\n\n{}\n",table,critical_paths,feedback_loops))
 }
\ No newline at end of file
@@ -0,0 +1,60 @@
+use colored::*;
+
+use super::super::compiler::format::format;
+use super::common::OutputMode;
+
+/// ファイルを標準の書き方に整形する
+/// check: 書き換えずに、整形されていないファイルがあれば失敗にする
+/// 戻り値: 全て成功したかどうか
+pub fn main(files: &[String], check: bool, mode: OutputMode) -> bool {
+    let mut errors = Vec::new();
+    let mut changed = Vec::new();
+    for path in files {
+        let source = match std::fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(format!("{}: {}",path,e));
+                continue;
+            }
+        };
+        let formatted = match format(&source) {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(format!("{}: {}",path,e));
+                continue;
+            }
+        };
+        if formatted==source {
+            continue;
+        }
+        if check {
+            errors.push(format!("{} is not formatted",path));
+        }
+        else if let Err(e) = std::fs::write(path, formatted) {
+            errors.push(format!("{}: {}",path,e));
+            continue;
+        }
+        changed.push(path.clone());
+    }
+    match mode {
+        OutputMode::Json => {
+            println!("{}", serde_json::json!({
+                "success": errors.is_empty(),
+                "errors": errors,
+                // --checkでは整形されていないファイル、それ以外では書き換えたファイル
+                "files": changed,
+            }));
+        },
+        _ => {
+            for error in &errors {
+                println!("{}:{} {}","[error]".red(),"fmt".cyan(),error);
+            }
+            if mode==OutputMode::Text && !check {
+                for path in &changed {
+                    println!("{}:{} formatted {}","[info]".green(),"fmt".cyan(),path);
+                }
+            }
+        },
+    }
+    errors.is_empty()
+}
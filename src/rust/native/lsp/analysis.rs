@@ -0,0 +1,162 @@
+use crate::compiler::types::*;
+
+/// ドキュメント中でモジュールの名前が書かれている場所 (バイト位置)
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    /// モジュールの定義の名前かどうか
+    pub definition: bool,
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c=='_'
+}
+
+/// textの [start,end) にある識別子の範囲の一覧
+fn identifiers(text: &str, start: usize, end: usize) -> Vec<(usize,usize)> {
+    let mut result = Vec::new();
+    let mut current: Option<usize> = None;
+    for (i,c) in text[start..end].char_indices() {
+        match (is_identifier_char(c),current) {
+            (true,None) => current = Some(start+i),
+            (false,Some(s)) => { result.push((s,start+i)); current = None; },
+            _ => {},
+        }
+    }
+    if let Some(s) = current {
+        result.push((s,end));
+    }
+    result
+}
+
+/// spanがtextの範囲に収まっていればその範囲 (マクロの中などで位置がずれていればNone)
+fn span_range(text: &str, span: Span) -> Option<(usize,usize)> {
+    if span.start<=span.end && span.end<=text.len() && text.is_char_boundary(span.start) && text.is_char_boundary(span.end) {
+        Some((span.start,span.end))
+    }
+    else {
+        None
+    }
+}
+
+/// モジュール定義の名前の範囲: 最初の `(` か `<` より前の最後の識別子
+pub fn definition_name(text: &str, span: Span) -> Option<(usize,usize)> {
+    let (start,end) = span_range(text, span)?;
+    let head_end = text[start..end].find(['(','<']).map_or(end, |i| start+i);
+    identifiers(text, start, head_end).pop()
+}
+
+/// ゲートで使われているモジュールの名前の範囲: 区切り (`:` `=` `<-`) の後の最初の識別子
+fn gate_module_name(text: &str, span: Span) -> Option<(usize,usize)> {
+    let (start,end) = span_range(text, span)?;
+    let separator = start + text[start..end].find([':','=','<'])?;
+    identifiers(text, separator, end).into_iter().next()
+}
+
+/// test,graphicalの名前の範囲: キーワードの次の識別子
+fn component_name(text: &str, span: Span, head_end: char) -> Option<(usize,usize)> {
+    let (start,end) = span_range(text, span)?;
+    let head_end = text[start..end].find(head_end).map_or(end, |i| start+i);
+    identifiers(text, start, head_end).get(1).copied()
+}
+
+/// equivalence testの比べる相手の名前の範囲: `==` の次の識別子
+fn reference_name(text: &str, span: Span) -> Option<(usize,usize)> {
+    let (start,end) = span_range(text, span)?;
+    let separator = start + text[start..end].find("==")?;
+    identifiers(text, separator, end).into_iter().next()
+}
+
+/// ルートのドキュメントのASTから、モジュールの名前が書かれている場所を全て集める
+pub fn references(ast: &File, text: &str) -> Vec<Reference> {
+    let mut result: Vec<Reference> = Vec::new();
+    let mut push = |range: Option<(usize,usize)>, definition: bool| {
+        if let Some((start,end)) = range {
+            // 配列を展開したゲートは同じspanを持つので一つにまとめる
+            if !result.iter().any(|r| r.start==start) {
+                result.push(Reference { name: text[start..end].to_string(), start, end, definition });
+            }
+        }
+    };
+    for component in &ast.components {
+        match component {
+            Component::Module(module) => {
+                push(definition_name(text, module.span), true);
+                for gate in &module.gates {
+                    push(gate_module_name(text, gate.span), false);
+                }
+            },
+            Component::GenericModule(module) => {
+                push(definition_name(text, module.span), true);
+                for gate in &module.gates {
+                    push(gate_module_name(text, gate.span), false);
+                }
+            },
+            Component::Test(test) => push(component_name(text, test.span, ':'), false),
+            Component::EquivalenceTest(test) => {
+                push(component_name(text, test.span, '='), false);
+                push(reference_name(text, test.span), false);
+            },
+            Component::Graphical(graphical) => push(component_name(text, graphical.span, ':'), false),
+            _ => {},
+        }
+    }
+    result
+}
+
+/// offsetを含むモジュールで使える信号の名前
+pub fn enclosing_wires(ast: &File, offset: usize) -> Vec<String> {
+    let contains = |span: &Span| span.start<=offset && offset<=span.end;
+    let mut wires: Vec<String> = Vec::new();
+    for component in &ast.components {
+        match component {
+            Component::Module(module) if contains(&module.span) => {
+                let names = module.inputs.iter().chain(&module.outputs).chain(module.gates.iter().flat_map(|g| &g.outputs));
+                // 配列を展開した名前 `a:0` は配列の名前にする
+                wires.extend(names.map(|name| name.split(':').next().unwrap_or(name).to_string()));
+            },
+            Component::GenericModule(module) if contains(&module.span) => {
+                wires.extend(module.inputs.iter().map(|i| i.arr_name.clone()));
+                wires.extend(module.gates.iter().flat_map(|g| g.outputs.iter().map(|o| o.arr_name.clone())));
+            },
+            _ => {},
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    wires.retain(|w| seen.insert(w.clone()));
+    wires
+}
+
+/// バイト位置を行と列 (UTF-16) にする
+pub fn position(text: &str, offset: usize) -> (u32,u32) {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i+1);
+    let character = before[line_start..].encode_utf16().count();
+    (line as u32, character as u32)
+}
+
+/// 行と列 (UTF-16) をバイト位置にする
+pub fn offset(text: &str, line: u32, character: u32) -> usize {
+    let mut line_start = 0;
+    for _ in 0..line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i+1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i,c) in text[line_start..].char_indices() {
+        if units>=character as usize || c=='\n' {
+            return line_start+i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
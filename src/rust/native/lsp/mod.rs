@@ -0,0 +1,419 @@
+mod analysis;
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::compiler;
+use crate::compiler::types::*;
+use crate::test;
+
+/// 開かれているドキュメント
+struct Document {
+    path: String,
+    text: String,
+    /// include,importを解決しないで読んだAST (名前の位置を探すのに使う)
+    ast: File,
+    products: IntermediateProducts,
+    /// 保存時に実行したテストの結果 (編集すると位置がずれるので消す)
+    test_diagnostics: Vec<Diagnostic>,
+}
+
+struct Server {
+    documents: HashMap<String,Document>,
+}
+
+/// .ncgファイルのLanguage Server (標準入出力でLSPを話す)
+// circuitgame_lspからだけ呼ぶので、circuitgame_binでは使われない
+#[allow(dead_code)]
+pub fn main() {
+    let stdin = std::io::stdin();
+    let mut reader = stdin.lock();
+    let mut server = Server { documents: HashMap::new() };
+    while let Some(message) = read_message(&mut reader) {
+        let method = match message.get("method").and_then(|m| m.as_str()) {
+            Some(v) => v.to_string(),
+            // クライアントからのresponseは使わない
+            None => continue,
+        };
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        match message.get("id").cloned() {
+            Some(id) => {
+                let response = match server.request(&method, &params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code,message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+                };
+                send(&response);
+            },
+            None => {
+                if method=="exit" {
+                    return;
+                }
+                server.notification(&method, &params);
+            },
+        }
+    }
+}
+
+/// `Content-Length` ヘッダ付きのメッセージを読む (入力が終われば None)
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).ok()?==0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(v) = line.strip_prefix("Content-Length:") {
+                length = v.trim().parse::<usize>().ok();
+            }
+        }
+        let Some(length) = length else { continue; };
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).ok()?;
+        // 壊れたメッセージは読み飛ばす
+        if let Ok(v) = serde_json::from_slice(&body) {
+            return Some(v);
+        }
+    }
+}
+
+/// 開かれていないファイルはディスクから読む
+fn read_source(path: &str) -> Result<String,String> {
+    std::fs::read_to_string(path).map_err(|e| e.to_string())
+}
+
+fn send(message: &Value) {
+    let body = message.to_string();
+    let mut stdout = std::io::stdout().lock();
+    let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = stdout.flush();
+}
+
+/// `file:///a/b%20c.ncg` -> `/a/b c.ncg`
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i<bytes.len() {
+        if bytes[i]==b'%' && i+2<bytes.len() {
+            if let Ok(v) = u8::from_str_radix(&path[i+1..i+3], 16) {
+                decoded.push(v);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn path_to_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    for c in path.chars() {
+        match c {
+            ' ' => uri.push_str("%20"),
+            '%' => uri.push_str("%25"),
+            '#' => uri.push_str("%23"),
+            _ => uri.push(c),
+        }
+    }
+    uri
+}
+
+fn range(text: &str, start: usize, end: usize) -> Value {
+    let (start_line,start_character) = analysis::position(text, start);
+    let (end_line,end_character) = analysis::position(text, end);
+    json!({
+        "start": { "line": start_line, "character": start_character },
+        "end": { "line": end_line, "character": end_character },
+    })
+}
+
+/// モジュールの名前から修飾 `file::` とパラメータ `<8>` を取り除く
+fn base_name(name: &str) -> &str {
+    let name = name.rsplit("::").next().unwrap_or(name);
+    name.split('<').next().unwrap_or(name)
+}
+
+fn parse_text_position(params: &Value) -> Option<(String,u32,u32)> {
+    let uri = params["textDocument"]["uri"].as_str()?.to_string();
+    let line = params["position"]["line"].as_u64()? as u32;
+    let character = params["position"]["character"].as_u64()? as u32;
+    Some((uri,line,character))
+}
+
+/// コンパイルとテストの診断をLSPのDiagnosticにする
+fn diagnostics(document: &Document) -> Vec<Value> {
+    let products = &document.products;
+    products.errors.iter().chain(&products.warns).chain(&document.test_diagnostics).map(|diagnostic| {
+        // 他のファイルの診断はファイルの先頭に位置付きのメッセージで表示する
+        let (range,message) = match diagnostic.span {
+            Some(span) if diagnostic.file.is_empty() => (range(&document.text, span.start, span.end), diagnostic.message.clone()),
+            _ => (range(&document.text, 0, 0), diagnostic.to_string()),
+        };
+        let message = std::iter::once(message).chain(diagnostic.notes.iter().cloned()).collect::<Vec<_>>().join("\n");
+        json!({
+            "range": range,
+            "severity": match diagnostic.severity { Severity::Error => 1, Severity::Warning => 2 },
+            "code": diagnostic.code,
+            "source": "circuitgame",
+            "message": message,
+        })
+    }).collect()
+}
+
+impl Server {
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.update(&uri, text, true);
+            },
+            "textDocument/didChange" => {
+                // 全文の同期だけに対応している
+                let text = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str());
+                if let Some(text) = text {
+                    self.update(&uri, text.to_string(), false);
+                }
+            },
+            "textDocument/didSave" => {
+                if let Some(document) = self.documents.get(&uri) {
+                    let text = document.text.clone();
+                    self.update(&uri, text, true);
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                send(&json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": { "uri": uri, "diagnostics": [] } }));
+            },
+            _ => {},
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value,(i32,String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": false } },
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": [] },
+                    "renameProvider": true,
+                },
+                "serverInfo": { "name": "circuitgame_lsp" },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Value::Null)),
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Value::Null)),
+            "textDocument/completion" => Ok(self.completion(params).unwrap_or(json!([]))),
+            "textDocument/rename" => self.rename(params),
+            _ => Err((-32601,format!("Method not found: {}",method))),
+        }
+    }
+
+    /// ドキュメントをコンパイルし直して診断を送る
+    /// run_tests: テストも実行する (保存時と開いた時)
+    fn update(&mut self, uri: &str, text: String, run_tests: bool) {
+        let path = uri_to_path(uri);
+        // 開かれているファイルは編集中の内容を読む
+        let loader = |p: &str| -> Result<String,String> {
+            match self.documents.values().find(|d| d.path==p) {
+                Some(document) => Ok(document.text.clone()),
+                None => read_source(p),
+            }
+        };
+        let products = compiler::intermediate_products_with_loader(&text, &path, &loader);
+        let test_diagnostics = if run_tests && products.errors.is_empty() {
+            let test_products = test::test(products.clone());
+            test_products.errors.into_iter().chain(test_products.warns).collect()
+        }
+        else {
+            Vec::new()
+        };
+        let (ast,_) = compiler::parse(&text);
+        let document = Document { path, text, ast, products, test_diagnostics };
+        self.publish_diagnostics(uri, &document);
+        self.documents.insert(uri.to_string(), document);
+    }
+
+    fn publish_diagnostics(&self, uri: &str, document: &Document) {
+        let diagnostics = diagnostics(document);
+        send(&json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": { "uri": uri, "diagnostics": diagnostics } }));
+    }
+
+    /// カーソルの位置にあるモジュールの名前
+    fn reference_at(&self, params: &Value) -> Option<(&Document,String,analysis::Reference)> {
+        let (uri,line,character) = parse_text_position(params)?;
+        let document = self.documents.get(&uri)?;
+        let offset = analysis::offset(&document.text, line, character);
+        let reference = analysis::references(&document.ast, &document.text).into_iter().find(|r| r.start<=offset && offset<=r.end)?;
+        Some((document,uri,reference))
+    }
+
+    fn definition(&self, params: &Value) -> Option<Value> {
+        let (document,uri,reference) = self.reference_at(params)?;
+        // このファイルで定義されたモジュール
+        if let Some(definition) = analysis::references(&document.ast, &document.text).into_iter().find(|r| r.definition && r.name==reference.name) {
+            return Some(json!({ "uri": uri, "range": range(&document.text, definition.start, definition.end) }));
+        }
+        // include,importされたファイルで定義されたモジュール
+        for component in &document.products.ast.components {
+            let (name,file,span) = match component {
+                Component::Module(m) => (&m.name,&m.file,m.span),
+                Component::GenericModule(m) => (&m.name,&m.file,m.span),
+                _ => continue,
+            };
+            if file.is_empty() || base_name(name)!=reference.name {
+                continue;
+            }
+            let text = match self.documents.values().find(|d| &d.path==file) {
+                Some(d) => d.text.clone(),
+                None => read_source(file).ok()?,
+            };
+            let (start,end) = analysis::definition_name(&text, span).unwrap_or((span.start,span.start));
+            return Some(json!({ "uri": path_to_uri(file), "range": range(&text, start, end) }));
+        }
+        None
+    }
+
+    fn hover(&self, params: &Value) -> Option<Value> {
+        let (document,_,reference) = self.reference_at(params)?;
+        let products = &document.products;
+        let mut lines = Vec::new();
+        for module_type in &products.module_type_list {
+            if base_name(&module_type.name)!=reference.name {
+                continue;
+            }
+            let kind = if products.defined_func_module_list.contains(&module_type.name) { "func" } else { "module" };
+            let mut line = format!("**{}** `{}` : {}->{}",kind,module_type.name,module_type.mtype.input_count,module_type.mtype.output_count);
+            if let Some(module) = products.expanded_modules.get(&module_type.name) {
+                let gates = module.gates_sequential.len() + module.gates_symmetry.len();
+                match products.module_optimizations.get(&module_type.name) {
+                    Some(optimization) if optimization.before!=optimization.after => line.push_str(&format!("  \nNOR gates: {} (optimized from {})",gates,optimization.before)),
+                    _ => line.push_str(&format!("  \nNOR gates: {}",gates)),
+                }
+            }
+            lines.push(line);
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        Some(json!({
+            "contents": { "kind": "markdown", "value": lines.join("\n\n") },
+            "range": range(&document.text, reference.start, reference.end),
+        }))
+    }
+
+    fn completion(&self, params: &Value) -> Option<Value> {
+        let (uri,line,character) = parse_text_position(params)?;
+        let document = self.documents.get(&uri)?;
+        let offset = analysis::offset(&document.text, line, character);
+        let mut items = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        for module_type in &document.products.module_type_list {
+            let name = base_name(&module_type.name);
+            if seen.insert(name.to_string()) {
+                let detail = if name==module_type.name { format!("{}->{}",module_type.mtype.input_count,module_type.mtype.output_count) } else { "module".to_string() };
+                items.push(json!({ "label": name, "kind": 3, "detail": detail }));
+            }
+        }
+        // 実体化されていないパラメータ付きモジュール
+        for component in &document.ast.components {
+            if let Component::GenericModule(module) = component {
+                if seen.insert(module.name.clone()) {
+                    items.push(json!({ "label": module.name, "kind": 3, "detail": format!("<{}>",module.params.join(",")) }));
+                }
+            }
+        }
+        for wire in analysis::enclosing_wires(&document.ast, offset) {
+            if seen.insert(wire.clone()) {
+                items.push(json!({ "label": wire, "kind": 6, "detail": "wire" }));
+            }
+        }
+        Some(Value::Array(items))
+    }
+
+    fn rename(&self, params: &Value) -> Result<Value,(i32,String)> {
+        let new_name = params["newName"].as_str().unwrap_or("");
+        if new_name.is_empty() || !new_name.chars().all(|c| c.is_alphanumeric() || c=='_') {
+            return Err((-32602,format!("Invalid module name: {}",new_name)));
+        }
+        let Some((document,uri,reference)) = self.reference_at(params) else {
+            return Ok(Value::Null);
+        };
+        let references = analysis::references(&document.ast, &document.text);
+        if !references.iter().any(|r| r.definition && r.name==reference.name) {
+            return Err((-32602,format!("Only modules defined in this file can be renamed: {}",reference.name)));
+        }
+        let edits = references.iter().filter(|r| r.name==reference.name).map(|r| json!({
+            "range": range(&document.text, r.start, r.end),
+            "newText": new_name,
+        })).collect::<Vec<_>>();
+        let mut changes = serde_json::Map::new();
+        changes.insert(uri, Value::Array(edits));
+        Ok(json!({ "changes": changes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 開いたときと同じように、コンパイルできればテストも実行する
+    fn document(text: &str, loader: compiler::FileLoader) -> Document {
+        let products = compiler::intermediate_products_with_loader(text, "main.ncg", loader);
+        let test_diagnostics = if products.errors.is_empty() {
+            let test_products = test::test(products.clone());
+            test_products.errors.into_iter().chain(test_products.warns).collect()
+        }
+        else {
+            Vec::new()
+        };
+        let (ast,_) = compiler::parse(text);
+        Document { path: "main.ncg".to_string(), text: text.to_string(), ast, products, test_diagnostics }
+    }
+
+    fn no_files(path: &str) -> Result<String,String> {
+        Err(format!("File loading is not supported in this test: {}",path))
+    }
+
+    fn position(value: &Value) -> (u64,u64) {
+        (value["line"].as_u64().unwrap(),value["character"].as_u64().unwrap())
+    }
+
+    #[test]
+    fn diagnostics_mapping() {
+        // 同じファイルの診断はspanの位置 (行と文字) に付ける
+        let local = diagnostics(&document("using nor:2->1;\nmodule a (x)->(y) {\n    y: foo <- x;\n}\n", &no_files));
+        assert_eq!(local.len(), 1);
+        assert_eq!((position(&local[0]["range"]["start"]),position(&local[0]["range"]["end"])), ((2,4),(2,16)));
+        assert_eq!((&local[0]["severity"],&local[0]["code"],&local[0]["source"]), (&json!(1),&json!("undefined-module-used"),&json!("circuitgame")));
+        assert_eq!(local[0]["message"], "Undefined module used: foo in a");
+        // noteはメッセージの後に行を分けて付ける
+        let duplicate = diagnostics(&document("using nor:2->1;\nmodule a (x)->(y) { y: nor <- x x; }\nmodule a (x)->(y) { y: nor <- x x; }\n", &no_files));
+        assert_eq!(duplicate[0]["severity"], 1);
+        assert_eq!(position(&duplicate[0]["range"]["start"]), (2,0));
+        assert!(duplicate[0]["message"].as_str().unwrap().ends_with("\nfirst defined at line 2"), "{}", duplicate[0]["message"]);
+        // 位置のない警告はファイルの先頭に, 失敗したテストはtestの範囲に付ける
+        let warns = diagnostics(&document("using nor:2->1;\nfunc not (x)->(y) { y: nor <- x x; }\ntest not:1->1 {\n    t -> t;\n}\n", &no_files));
+        assert_eq!(warns.iter().map(|w| (&w["severity"],w["code"].as_str().unwrap())).collect::<Vec<_>>(), [(&json!(2),"no-test-provided"),(&json!(2),"test-failed")]);
+        assert_eq!((position(&warns[0]["range"]["start"]),position(&warns[0]["range"]["end"])), ((0,0),(0,0)));
+        assert_eq!(warns[0]["message"], "No test provided for module: nor");
+        assert_eq!((position(&warns[1]["range"]["start"]),position(&warns[1]["range"]["end"])), ((2,0),(4,1)));
+        // 他のファイルの診断はファイルの先頭に, ファイル名と位置の付いたメッセージで付ける
+        let lib = |path: &str| if path.ends_with("lib.ncg") { Ok("module b (x)->(y) {\n    y: bar <- x;\n}\n".to_string()) } else { no_files(path) };
+        let included = diagnostics(&document("using nor:2->1;\n!include \"lib.ncg\";\n", &lib));
+        assert_eq!(included.len(), 1);
+        assert_eq!((position(&included[0]["range"]["start"]),position(&included[0]["range"]["end"])), ((0,0),(0,0)));
+        assert!(included[0]["message"].as_str().unwrap().ends_with("(lib.ncg:2:5)"), "{}", included[0]["message"]);
+    }
+}
@@ -1,4 +1,8 @@
 pub mod common;
 pub mod document;
+pub mod fmt;
+pub mod report;
+pub mod verify;
 
-pub mod watch;
\ No newline at end of file
+pub mod watch;
+pub mod lsp;
@@ -0,0 +1,191 @@
+use serde::Serialize;
+
+use super::super::compiler::types::{Component, Diagnostic, File, Severity};
+use super::super::test::types::TestPattern;
+
+/// 一つの入力ファイルを処理した結果 (`--format json` `--format junit` の出力)
+#[derive(Debug, Default)]
+pub struct Report {
+    pub input: String,
+    pub diagnostics: Vec<ReportedDiagnostic>,
+    /// 実行したテストの結果 (テストの定義順)
+    pub tests: Vec<(String, Vec<TestPattern>)>,
+    /// 書き出したファイル
+    pub outputs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportedDiagnostic {
+    pub stage: String,
+    /// 診断の位置を含むモジュールやテストの名前
+    pub module: Option<String>,
+    #[serde(flatten)]
+    pub diagnostic: Diagnostic,
+}
+
+/// 診断の位置を含むcomponentの名前
+pub fn owner(ast: &File, diagnostic: &Diagnostic) -> Option<String> {
+    let span = diagnostic.span?;
+    ast.components.iter().find_map(|component| {
+        let (name, file, component_span) = match component {
+            Component::Module(v) => (&v.name, &v.file, v.span),
+            Component::GenericModule(v) => (&v.name, &v.file, v.span),
+            Component::Test(v) => (&v.name, &v.file, v.span),
+            Component::EquivalenceTest(v) => (&v.name, &v.file, v.span),
+            Component::Graphical(v) => (&v.name, &v.file, v.span),
+            _ => return None,
+        };
+        (*file==diagnostic.file && component_span.start<=span.start && span.start<component_span.end).then(|| name.clone())
+    })
+}
+
+fn bits(values: &[bool]) -> String {
+    values.iter().map(|&b| if b { "t" } else { "f" }).collect::<Vec<_>>().join(" ")
+}
+
+/// 確認しなかったビットを `x` にする
+fn masked_bits(values: &[bool], ignored: &[bool]) -> String {
+    values.iter().enumerate().map(|(i, &b)| match (ignored.get(i), b) {
+        (Some(true), _) => "x",
+        (_, true) => "t",
+        (_, false) => "f",
+    }).collect::<Vec<_>>().join(" ")
+}
+
+/// パターンを `t f -> t` の形にする
+pub fn pattern_text(pattern: &TestPattern) -> String {
+    format!("{} -> {}", bits(&pattern.input), masked_bits(&pattern.expect, &pattern.ignored))
+}
+
+/// 失敗したパターンの説明
+pub fn failure_text(pattern: &TestPattern) -> String {
+    format!("{} -> expected {} but got {} after {} ticks", bits(&pattern.input), masked_bits(&pattern.expect, &pattern.ignored), bits(&pattern.output), pattern.ticks)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+impl Report {
+    pub fn success(&self) -> bool {
+        self.diagnostics.iter().all(|d| d.diagnostic.severity!=Severity::Error)
+            && self.tests.iter().all(|(_, patterns)| patterns.iter().all(|p| p.accept))
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let tests = self.tests.iter().map(|(name, patterns)| serde_json::json!({
+            "name": name,
+            "passed": patterns.iter().all(|p| p.accept),
+            "failed": patterns.iter().filter(|p| !p.accept).count(),
+            "patterns": patterns,
+        })).collect::<Vec<_>>();
+        serde_json::json!({
+            "input": self.input,
+            "success": self.success(),
+            "diagnostics": self.diagnostics,
+            "tests": tests,
+            "outputs": self.outputs,
+        })
+    }
+
+    /// テストのパターンごとにtestcaseを作り、テストに属さないエラーはstageごとのtestcaseにする
+    pub fn to_junit(&self) -> String {
+        let mut cases = Vec::new();
+        let is_error = |d: &&ReportedDiagnostic| d.diagnostic.severity==Severity::Error;
+        for (name, patterns) in &self.tests {
+            let classname = escape_xml(name);
+            for (i, pattern) in patterns.iter().enumerate() {
+                let case_name = escape_xml(&format!("#{} {}", i, pattern_text(pattern)));
+                if pattern.accept {
+                    cases.push(format!("    <testcase classname=\"{}\" name=\"{}\"/>", classname, case_name));
+                }
+                else {
+                    cases.push(format!(
+                        "    <testcase classname=\"{}\" name=\"{}\">\n      <failure message=\"{}\"/>\n    </testcase>",
+                        classname, case_name, escape_xml(&failure_text(pattern)),
+                    ));
+                }
+            }
+            if patterns.is_empty() {
+                cases.push(format!("    <testcase classname=\"{}\" name=\"{}\"/>", classname, classname));
+            }
+        }
+        // テストの失敗はパターンのtestcaseで表しているので除く
+        for reported in self.diagnostics.iter().filter(is_error).filter(|d| d.diagnostic.code!="failing-test") {
+            let classname = escape_xml(reported.module.as_deref().unwrap_or(&reported.stage));
+            cases.push(format!(
+                "    <testcase classname=\"{}\" name=\"{}\">\n      <error type=\"{}\" message=\"{}\"/>\n    </testcase>",
+                classname, escape_xml(&reported.stage), escape_xml(&reported.diagnostic.code), escape_xml(&reported.diagnostic.to_string()),
+            ));
+        }
+        let failures = self.tests.iter().flat_map(|(_, patterns)| patterns).filter(|p| !p.accept).count();
+        let errors = self.diagnostics.iter().filter(is_error).filter(|d| d.diagnostic.code!="failing-test").count();
+        let warnings = self.diagnostics.iter()
+            .filter(|d| d.diagnostic.severity==Severity::Warning)
+            .map(|d| escape_xml(&format!("[warn]:{} {} [{}]", d.stage, d.diagnostic, d.diagnostic.code)))
+            .collect::<Vec<_>>();
+        let name = escape_xml(&self.input);
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n{}{}  </testsuite>\n</testsuites>",
+            name, cases.len(), failures, errors,
+            name, cases.len(), failures, errors,
+            cases.iter().map(|c| format!("{}\n", c)).collect::<String>(),
+            if warnings.is_empty() { String::new() } else { format!("    <system-out>{}</system-out>\n", warnings.join("\n")) },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::compiler::types::Span;
+
+    fn pattern(accept: bool, input: &[bool], expect: &[bool], output: &[bool]) -> TestPattern {
+        TestPattern { accept, ticks: 1, input: input.to_vec(), expect: expect.to_vec(), ignored: vec![false; expect.len()], output: output.to_vec() }
+    }
+
+    /// notのテストが一つ失敗し、andにコンパイルエラー、警告が一つある結果
+    fn report() -> Report {
+        let span = Span { start: 0, end: 1, line: 2, column: 5 };
+        let reported = |stage: &str, module: Option<&str>, diagnostic: Diagnostic| ReportedDiagnostic { stage: stage.to_string(), module: module.map(String::from), diagnostic };
+        Report {
+            input: "a&b.ncg".to_string(),
+            diagnostics: vec![
+                reported("compile", Some("and"), Diagnostic::error("undefined-id-used", "Undefined gate used: <x>".to_string()).with_span("", span)),
+                reported("compile", None, Diagnostic::warning("unused-modules", "Multiple modules are not used by other modules: or".to_string())),
+                reported("test", Some("not"), Diagnostic::error("failing-test", "Test failed: not (1 of 2 patterns)".to_string())),
+            ],
+            tests: vec![("not".to_string(), vec![pattern(true, &[true], &[false], &[false]), pattern(false, &[false], &[true], &[false])])],
+            outputs: vec!["not.ncgb".to_string()],
+        }
+    }
+
+    #[test]
+    fn json_shape() {
+        let json = report().to_json();
+        assert_eq!(json["input"], "a&b.ncg");
+        assert_eq!(json["success"], false);
+        assert_eq!(json["outputs"], serde_json::json!(["not.ncgb"]));
+        // 診断はstageとmoduleに元の診断の項目を並べる
+        let diagnostic = &json["diagnostics"][0];
+        assert_eq!((&diagnostic["stage"],&diagnostic["module"],&diagnostic["code"],&diagnostic["severity"]), (&serde_json::json!("compile"),&serde_json::json!("and"),&serde_json::json!("undefined-id-used"),&serde_json::json!("Error")));
+        assert_eq!(diagnostic["span"]["line"], 2);
+        assert_eq!(json["diagnostics"][1]["module"], serde_json::Value::Null);
+        // テストごとに全てのパターンの結果
+        let test = &json["tests"][0];
+        assert_eq!((&test["name"],&test["passed"],&test["failed"]), (&serde_json::json!("not"),&serde_json::json!(false),&serde_json::json!(1)));
+        assert_eq!(test["patterns"][1], serde_json::json!({ "accept": false, "ticks": 1, "input": [false], "expect": [true], "ignored": [false], "output": [false] }));
+    }
+
+    #[test]
+    fn junit_shape() {
+        let junit = report().to_junit();
+        // パターンごとのtestcaseと、テストに属さないエラーのtestcase (failing-testはパターンで表すので除く)
+        assert!(junit.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"a&amp;b.ncg\" tests=\"3\" failures=\"1\" errors=\"1\">\n  <testsuite name=\"a&amp;b.ncg\" tests=\"3\" failures=\"1\" errors=\"1\">\n"), "{}", junit);
+        assert!(junit.contains("    <testcase classname=\"not\" name=\"#0 t -&gt; f\"/>\n"), "{}", junit);
+        assert!(junit.contains("    <testcase classname=\"not\" name=\"#1 f -&gt; t\">\n      <failure message=\"f -&gt; expected t but got f after 1 ticks\"/>\n    </testcase>\n"), "{}", junit);
+        assert!(junit.contains("    <testcase classname=\"and\" name=\"compile\">\n      <error type=\"undefined-id-used\" message=\"Undefined gate used: &lt;x&gt; (line 2:5)\"/>\n    </testcase>\n"), "{}", junit);
+        assert!(!junit.contains("failing-test"), "{}", junit);
+        assert!(junit.ends_with("    <system-out>[warn]:compile Multiple modules are not used by other modules: or [unused-modules]</system-out>\n  </testsuite>\n</testsuites>"), "{}", junit);
+    }
+}
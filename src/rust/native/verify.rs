@@ -0,0 +1,121 @@
+use colored::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::super::vm::deserializer::deserialize_modules;
+use super::super::vm::fuzz::{check_case, fuzz_case};
+use super::super::vm::types::Module;
+use super::common::OutputMode;
+
+/// NCGBファイルをu32の列として読む
+fn read_ncgb(path: &str) -> Result<Vec<u32>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if bytes.len() % 4 != 0 {
+        return Err(format!("{}: The file size is not a multiple of 4 bytes", path));
+    }
+    Ok(bytes.chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+}
+
+fn summary(module: &Module) -> serde_json::Value {
+    let mut sections = Vec::new();
+    if module.symbols.is_some() { sections.push("symbols"); }
+    if module.layout.is_some() { sections.push("graphical"); }
+    if !module.test_vectors.is_empty() { sections.push("tests"); }
+    serde_json::json!({
+        "name": module.name,
+        "func": module.func,
+        "inputs": module.inputs,
+        "outputs": module.outputs.len(),
+        "gates": module.gates_sequential.len() + module.gates_symmetry.len(),
+        "sections": sections,
+    })
+}
+
+/// NCGBファイルの構造を確かめて、モジュールごとの概要を出力する
+/// fuzz: 指定した回数だけ、ランダムなデータや与えたファイルを壊したデータを読ませて、
+///       読めたモジュールがVMで実行できるか (読み込みやVMがpanicしないか) を確かめる
+/// 戻り値: 全て成功したかどうか
+pub fn main(files: &[String], fuzz: Option<u32>, seed: u64, mode: OutputMode) -> bool {
+    let mut errors = Vec::new();
+    let mut results = Vec::new();
+    let mut samples = Vec::new();
+    for path in files {
+        let data = match read_ncgb(path) {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        match deserialize_modules(&data) {
+            Ok(modules) => {
+                if mode==OutputMode::Text {
+                    for module in &modules {
+                        println!(
+                            "{}:{} {} {}: {} inputs, {} outputs, {} gates{}",
+                            "[info]".green(), "verify".cyan(), path, module.name, module.inputs, module.outputs.len(),
+                            module.gates_sequential.len() + module.gates_symmetry.len(),
+                            if module.func { " (func)" } else { "" },
+                        );
+                    }
+                }
+                results.push(serde_json::json!({
+                    "path": path,
+                    "modules": modules.iter().map(summary).collect::<Vec<_>>(),
+                }));
+                samples.push(data);
+            },
+            Err(e) => errors.push(format!("{}: {}", path, e)),
+        }
+    }
+    let fuzz_result = fuzz.map(|count| {
+        let (accepted, failures) = run_fuzz(&samples, count, seed);
+        errors.extend(failures.iter().cloned());
+        serde_json::json!({ "cases": count, "seed": seed, "accepted": accepted, "failures": failures })
+    });
+    match mode {
+        OutputMode::Json => {
+            println!("{}", serde_json::json!({
+                "success": errors.is_empty(),
+                "errors": errors,
+                "files": results,
+                "fuzz": fuzz_result,
+            }));
+        },
+        _ => {
+            for error in &errors {
+                println!("{}:{} {}", "[error]".red(), "verify".cyan(), error);
+            }
+            if let (Some(result), OutputMode::Text) = (&fuzz_result, mode) {
+                println!("{}:{} {} cases (seed {}), {} accepted", "[info]".green(), "verify".cyan(), result["cases"], seed, result["accepted"]);
+            }
+        },
+    }
+    errors.is_empty()
+}
+
+fn run_fuzz(samples: &[Vec<u32>], count: u32, seed: u64) -> (u32, Vec<String>) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut accepted = 0;
+    let mut failures = Vec::new();
+    // panicのメッセージは失敗として報告するので、標準エラー出力には出さない
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    for case in 0..count {
+        let data = fuzz_case(&mut rng, samples);
+        let case_seed = rng.gen();
+        let describe = |reason: String| format!("fuzz case {} (seed {}): {}: {:08x?}", case, seed, reason, data);
+        match std::panic::catch_unwind(|| check_case(&data, case_seed)) {
+            Ok(Ok(true)) => accepted += 1,
+            Ok(Ok(false)) => {},
+            Ok(Err(e)) => failures.push(describe(format!("accepted but failed to run: {}", e))),
+            Err(panic) => {
+                let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                failures.push(describe(format!("panicked: {}", message)));
+            },
+        }
+    }
+    std::panic::set_hook(hook);
+    (accepted, failures)
+}
@@ -19,7 +19,21 @@ use std::path::Path;
 use std::collections::HashMap;
 
 
-pub async fn main(input_path: String, output_path: Vec<String>, doc_output_path: Option<String>, output_modules_pattern: String, run_vm: bool, watch: bool, server: bool, server_port: Option<String>) {
+/// watch,serve,runでの動かし方
+pub struct WatchOptions {
+    /// output_modules_patternに一致した最初のモジュールをVMで動かす
+    pub run_vm: bool,
+    /// 入力ファイルが変わったらコンパイルし直す
+    pub watch: bool,
+    /// WebSocketで結果をwebのツールに送る
+    pub server: bool,
+    pub server_port: Option<String>,
+    /// 既にコンパイルした結果 (最初の一回はコンパイルし直さずにこれを使う)
+    pub compiled: Option<super::common::ProcessResult>,
+}
+
+pub async fn main(input_path: String, options: super::common::ProcessOptions, watch_options: WatchOptions) {
+    let WatchOptions { run_vm, watch, server, .. } = watch_options;
     // tokioのbroadcastチャンネルを使用
     let (ws_tx, _ws_rx) = broadcast::channel::<String>(100); // websocket送信
     let (fc_tx, _fc_rx) = broadcast::channel::<String>(100); // ncg処理 (file change 通知)
@@ -29,7 +43,7 @@ pub async fn main(input_path: String, output_path: Vec<String>, doc_output_path:
             // WebSocketサーバーを起動
             let ws_tx_clone = ws_tx.clone();
             let input_path_clone = input_path.clone();
-            start_websocket_server(ws_tx_clone,input_path_clone,server_port).await
+            start_websocket_server(ws_tx_clone,input_path_clone,watch_options.server_port.clone()).await
         }
         else {
             Err("disabled".to_string())
@@ -40,7 +54,7 @@ pub async fn main(input_path: String, output_path: Vec<String>, doc_output_path:
         Err(v) => {
             use colored::*;
             if !(watch|run_vm) {
-                let _ = super::common::process_input(&input_path, output_modules_pattern,output_path,doc_output_path );
+                let _ = super::common::process_input(&input_path, &options);
                 println!("{}:{} {}","[error]".red(),"webSock".cyan(),v);
                 return;
             }
@@ -70,7 +84,7 @@ pub async fn main(input_path: String, output_path: Vec<String>, doc_output_path:
     let ws_tx_clone = ws_tx.clone();
     let fc_tx_clone = fc_tx.clone();
     let vmset_tx_clone = vmset_tx.clone();
-    tokio::spawn({ncg_tool(input_path,fc_tx_clone,vmset_tx_clone,ws_tx_clone,output_modules_pattern,output_path,doc_output_path,server_msg, run_vm,watch,server)});
+    tokio::spawn({ncg_tool(input_path,fc_tx_clone,vmset_tx_clone,ws_tx_clone,options,server_msg,watch_options)});
 
     tokio::signal::ctrl_c().await.unwrap();
     println!("Exit");
@@ -83,8 +97,10 @@ async fn watch_file(path: String, ws_tx: broadcast::Sender<String>, fc_tx: broad
             let _ = watcher_ws_tx.blocking_send(event);
         }
     })?;
-    watcher.watch(Path::new(&path), RecursiveMode::Recursive)?;
-    println!("Watching for changes in: {}", path);
+    // inputとincludeされている全てのファイルを監視する
+    let mut watched_files = Vec::new();
+    watch_source_files(&mut watcher, &path, &mut watched_files)?;
+    println!("Watching for changes in: {}", watched_files.join(", "));
     // デバウンス用の状態管理
     let mut last_events: HashMap<String, Instant> = HashMap::new();
     let debounce_duration = Duration::from_millis(1); // 1msのデバウンス時間
@@ -112,6 +128,8 @@ async fn watch_file(path: String, ws_tx: broadcast::Sender<String>, fc_tx: broad
             };
             let _ = ws_tx.send(format!("file:{}",input));
         }
+        // includeが追加された場合に備えて監視対象を更新
+        let _ = watch_source_files(&mut watcher, &path, &mut watched_files);
         // 古いエントリを削除
         last_events.retain(|_, time| now.duration_since(*time) < debounce_duration);
     }
@@ -119,6 +137,24 @@ async fn watch_file(path: String, ws_tx: broadcast::Sender<String>, fc_tx: broad
     Ok(())
 }
 
+/// まだ監視していないinclude先のファイルを監視対象に追加する
+fn watch_source_files(watcher: &mut notify::RecommendedWatcher, path: &str, watched_files: &mut Vec<String>) -> NotifyResult<()> {
+    // 一覧のパスは正規化されているので、inputのパスも揃えて比べる (`./a.ncg` など)
+    let root = super::super::compiler::normalize_path(path);
+    for file in super::common::source_files(path) {
+        if watched_files.contains(&file) {
+            continue;
+        }
+        // ルートのinputは監視できなければエラー, includeされたファイルは存在しない場合もある
+        match watcher.watch(Path::new(&file), RecursiveMode::Recursive) {
+            Ok(()) => watched_files.push(file),
+            Err(e) if file==root => return Err(e),
+            Err(_) => {},
+        }
+    }
+    Ok(())
+}
+
 
 async fn key_watch(ws_tx: broadcast::Sender<String>,vmset_tx: broadcast::Sender<u32>) {
     // デバウンス用の状態管理
@@ -234,27 +270,36 @@ async fn handle_connection(
 
 
 
-async fn ncg_tool(input_path: String, fc_tx: broadcast::Sender<String>, vmset_tx: broadcast::Sender<u32>, ws_tx: broadcast::Sender<String>,output_modules_pattern: String, output_path: Vec<String>, doc_output_path: Option<String>, server_msg: Result<String,String>, run_vm: bool, watch: bool, server: bool) {
+async fn ncg_tool(input_path: String, fc_tx: broadcast::Sender<String>, vmset_tx: broadcast::Sender<u32>, ws_tx: broadcast::Sender<String>,options: super::common::ProcessOptions, server_msg: Result<String,String>, watch_options: WatchOptions) {
+    let WatchOptions { run_vm, watch, server, mut compiled, .. } = watch_options;
     let mut rx = fc_tx.subscribe();  // メッセージ受信用のreceiverを作成
     loop {
         // inputを処理
-        // 画面クリア
-        print!("\x1B[2J\x1B[1;1H");  // ANSIエスケープシーケンスでクリア
         use colored::*;
-        println!("{}:{} {}","[info]".green(),"ncg".cyan(),format!("watch: {} server: {} vm: {}",match watch{true=>"on".cyan(),false=>"off".blue()},match server{true=>"on".cyan(),false=>"off".blue()},match run_vm{true=>"on".cyan(),false=>"off".blue()}));
-        if server {
-            match &server_msg {
-                Ok(msg) => {
+        // 画面クリアと状態の表示はテキストの出力のときだけ (JSONなどの出力に混ざらないように)
+        if options.mode==super::common::OutputMode::Text {
+            // 既にコンパイルした結果の表示は消さない
+            if compiled.is_none() {
+                print!("\x1B[2J\x1B[1;1H");  // ANSIエスケープシーケンスでクリア
+            }
+            println!("{}:{} {}","[info]".green(),"ncg".cyan(),format!("watch: {} server: {} vm: {}",match watch{true=>"on".cyan(),false=>"off".blue()},match server{true=>"on".cyan(),false=>"off".blue()},match run_vm{true=>"on".cyan(),false=>"off".blue()}));
+            if server {
+                if let Ok(msg) = &server_msg {
                     println!("{}:{} {}","[info]".green(),"webSock".cyan(),msg);
                 }
-                Err(msg) => {
-                    println!("{}:{} {}","[error]".red(),"webSock".cyan(),msg);
-                }
+            }
+            println!("");
+        }
+        if server && matches!(options.mode, super::common::OutputMode::Text|super::common::OutputMode::Quiet) {
+            if let Err(msg) = &server_msg {
+                println!("{}:{} {}","[error]".red(),"webSock".cyan(),msg);
             }
         }
-        println!("");
 
-        let binaries = super::common::process_input(&input_path,output_modules_pattern.clone(),output_path.clone(),doc_output_path.clone());
+        let binaries = match compiled.take() {
+            Some(result) => result.binaries,
+            None => super::common::process_input(&input_path,&options).binaries,
+        };
 
         let vmset_tx_clone = vmset_tx.clone();
         let ws_tx_clone = ws_tx.clone();
@@ -268,7 +313,7 @@ async fn ncg_tool(input_path: String, fc_tx: broadcast::Sender<String>, vmset_tx
                 if run_vm {
                     if let Some(binary) = binaries.get(0) {
                         tokio::select! {
-                            vm_res = super::common::runVM(binary.clone(), vmset_tx_clone,ws_tx_clone) => {
+                            vm_res = super::common::runVM(binary.clone(), vmset_tx_clone,ws_tx_clone,options.vm_vcd_path.clone(),options.vcd_wires) => {
                                 match vm_res {
                                     Ok(_) => {},
                                     Err(_) => { sleep(Duration::from_secs(100)).await; },
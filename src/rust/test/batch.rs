@@ -0,0 +1,239 @@
+use super::test::{run_ticks, MAX_STABLE_TICKS};
+use crate::compiler::types::TestTicks;
+use crate::vm::bitslice::{BitslicedModule, LANES};
+use crate::vm::types::Module;
+
+/// 一つのパターンを動かした結果
+pub struct PatternRun {
+    /// 進めたtick数
+    pub ticks: u64,
+    /// 状態が止まらなかった理由
+    pub unsettled: Option<String>,
+    pub output: Vec<bool>,
+}
+
+/// パターンを順に動かす (前のパターンの状態から次のパターンを始める)
+/// 64個ずつbitslicedのVMで同時に動かし、順に動かした場合と結果が変わらないことを確かめられなければ一つずつ動かす
+/// vmは最後のパターンを動かした後の状態になる
+pub fn run_patterns(vm: &mut Module, patterns: &[(Vec<bool>, TestTicks)]) -> Result<Vec<PatternRun>,String> {
+    let mut result = Vec::new();
+    let mut sliced = BitslicedModule::new(vm);
+    let feed_forward = sliced.feed_forward();
+    for chunk in patterns.chunks(LANES) {
+        match run_chunk(vm, &mut sliced, feed_forward, chunk)? {
+            Some(runs) => result.extend(runs),
+            None => {
+                for (inputs, ticks) in chunk {
+                    result.push(run_pattern(vm, inputs, ticks)?);
+                }
+            },
+        }
+    }
+    Ok(result)
+}
+
+/// 一つずつ動かす
+pub fn run_pattern(vm: &mut Module, inputs: &[bool], ticks: &TestTicks) -> Result<PatternRun,String> {
+    for (index, input) in inputs.iter().enumerate() {
+        let _ = vm.set(index as u32, *input);
+    }
+    let (ticks, unsettled) = run_ticks(vm, ticks)?;
+    Ok(PatternRun { ticks, unsettled, output: vm.get_output()? })
+}
+
+/// maskのビットが立っているレーンの番号
+fn lanes(mut mask: u64) -> impl Iterator<Item=usize> {
+    std::iter::from_fn(move || {
+        if mask==0 {
+            return None;
+        }
+        let lane = mask.trailing_zeros() as usize;
+        mask &= mask-1;
+        Some(lane)
+    })
+}
+
+/// レーンごとに `run_ticks` と同じだけ進める
+/// 全てのレーンを同時に始めるので、n回目のnextは全ての動いているレーンにとってn回目になる
+/// @return `( レーンごとのtick数, `@stable` で止まらなかったレーン, 全てのレーンのnextの回数の合計 )`
+fn run_lanes(vm: &mut BitslicedModule, ticks: &[&TestTicks]) -> Result<(Vec<u64>,u64,u128),String> {
+    let symmetry_len = vm.gates_symmetry.len() as u64;
+    let mut reported = vec![0; ticks.len()];
+    let mut unsettled = 0u64;
+    let (mut default, mut stable, mut count) = (0u64, 0u64, 0u64);
+    for (lane, ticks) in ticks.iter().enumerate() {
+        match ticks {
+            TestTicks::Default => default |= 1 << lane,
+            TestTicks::Stable => stable |= 1 << lane,
+            TestTicks::Count { count: 0 } => {},
+            TestTicks::Count { .. } => count |= 1 << lane,
+        }
+    }
+    let mut active = default | stable | count;
+    let mut total = 0u128;
+    let mut n = 0u64;
+    while active!=0 {
+        let changed = vm.next(active)?;
+        total += active.count_ones() as u128;
+        n += 1;
+        let mut done = 0u64;
+        // 指定なし: 1tick進めた後、symmetryのゲートの数まで状態が変わらなくなるまで進める
+        let d = active & default;
+        if n==1 {
+            if symmetry_len==0 { done |= d; }
+            for lane in lanes(d) { reported[lane] = 1; }
+        }
+        else {
+            let settled = d & !changed;
+            for lane in lanes(settled) { reported[lane] = n-1; }
+            done |= settled;
+            if n==symmetry_len+1 {
+                for lane in lanes(d & changed) { reported[lane] = n; }
+                done |= d;
+            }
+        }
+        // `@stable`
+        let s = active & stable;
+        let settled = s & !changed;
+        for lane in lanes(settled) { reported[lane] = n; }
+        done |= settled;
+        if n==MAX_STABLE_TICKS {
+            unsettled |= s & changed;
+            done |= s;
+        }
+        // `@n`
+        for lane in lanes(active & count) {
+            if let TestTicks::Count { count } = ticks[lane] {
+                if n==*count as u64 {
+                    reported[lane] = n;
+                    done |= 1 << lane;
+                }
+            }
+        }
+        active &= !done;
+    }
+    Ok((reported, unsettled, total))
+}
+
+/// 64個までのパターンを同時に動かす
+/// 各レーンは一つ前のパターンが終わった状態から始める必要があるので、
+/// 1回目で全てのレーンの終わりの状態を求め、2回目でそれを一つずらして始め、
+/// 2回目の終わりの状態が次のレーンの始めの状態と一致していれば順に動かした場合と同じになる
+/// feed_forwardの回路で全てのレーンを指定なしか `@n` (n>=1) で進めるなら、状態とtick数がinputだけで決まるので1回目だけでよい
+/// @return 一致しなければNone
+fn run_chunk(vm: &mut Module, sliced: &mut BitslicedModule, feed_forward: bool, chunk: &[(Vec<bool>, TestTicks)]) -> Result<Option<Vec<PatternRun>>,String> {
+    let ticks = chunk.iter().map(|(_, ticks)| ticks).collect::<Vec<_>>();
+    // inputごとに全てのレーンの値をまとめておく
+    let mut inputs = vec![0u64; sliced.inputs as usize];
+    for (lane, (values, _)) in chunk.iter().enumerate() {
+        for (word, value) in inputs.iter_mut().zip(values) {
+            if *value { *word |= 1 << lane; }
+        }
+    }
+    let lanes_mask = if chunk.len()==LANES { !0 } else { (1u64 << chunk.len()) - 1 };
+    let set_inputs = |sliced: &mut BitslicedModule| {
+        for (index, word) in inputs.iter().enumerate() {
+            sliced.set_lanes(index as u32, lanes_mask, *word)?;
+        }
+        Ok::<(),String>(())
+    };
+    // 1回目: 全てのレーンをvmの今の状態から始める
+    sliced.load_all(&vm.cond);
+    set_inputs(sliced)?;
+    let (mut reported, mut unsettled, mut total) = run_lanes(sliced, &ticks)?;
+    // `@stable` のtick数は始めの状態によって変わるので、終わりの状態がinputだけで決まっても2回目が必要
+    let independent = feed_forward && ticks.iter().all(|ticks| matches!(ticks, TestTicks::Default|TestTicks::Count { count: 1.. }));
+    if !independent {
+        let first = sliced.cond.clone();
+        // 2回目: 一つ前のレーンの終わりの状態から始める (レーン0はvmの今の状態)
+        for (wire, value) in sliced.cond.iter_mut().zip(&first) {
+            *wire = value << 1;
+        }
+        sliced.load(0, &vm.cond);
+        set_inputs(sliced)?;
+        (reported, unsettled, total) = run_lanes(sliced, &ticks)?;
+        // 最後のレーン以外の終わりの状態が1回目と一致するか
+        let mask = (1u64 << (chunk.len()-1)) - 1;
+        if sliced.cond.iter().zip(&first).any(|(second, first)| (second ^ first) & mask != 0) {
+            return Ok(None);
+        }
+    }
+    // 止まらなかったパターンがあれば、理由 (振動の周期など) を調べるために一つずつ動かす
+    let default = lanes(lanes_mask).filter(|lane| matches!(ticks[*lane], TestTicks::Default)).fold(0u64, |mask, lane| mask | 1 << lane);
+    if unsettled!=0 || sliced.unstable() & default!=0 {
+        return Ok(None);
+    }
+    let mut runs = Vec::new();
+    for (lane, ticks) in reported.into_iter().enumerate() {
+        runs.push(PatternRun { ticks, unsettled: None, output: sliced.get_output(lane)? });
+    }
+    vm.set_state(sliced.state(chunk.len()-1));
+    vm.tick += total;
+    Ok(Some(runs))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    /// ランダムな回路 (バージョン1の形式)
+    /// sequential: 前の状態が残るループのある回路, sync: 全てsymmetryのゲート, どちらでもなければfeed_forwardの回路
+    fn random_module(rng: &mut StdRng, sequential: bool, sync: bool) -> Module {
+        let inputs = rng.gen_range(1..6u32);
+        let gates = rng.gen_range(1..24u32);
+        let mut data = vec![0x6247434e, 32, 0, 1, 'r' as u32, inputs];
+        let outputs = rng.gen_range(1..6);
+        data.push(outputs);
+        data.extend((0..outputs).map(|_| rng.gen_range(0..gates+inputs)));
+        data.extend(if sync { [0, gates] } else { [gates, 0] });
+        for index in 0..gates {
+            for _ in 0..2 {
+                if sequential || sync {
+                    data.push(rng.gen_range(0..gates+inputs));
+                    continue;
+                }
+                // feed_forwardなら前のゲートかinputだけを入力にする
+                let wire = rng.gen_range(0..index+inputs);
+                data.push(if wire<index { wire } else { gates+wire-index });
+            }
+        }
+        Module::new(data).unwrap()
+    }
+
+    /// stableでなければ `@stable` と `@0` を使わない (feed_forwardの回路で1回目だけで済ませる場合を試す)
+    fn random_ticks(rng: &mut StdRng, stable: bool) -> TestTicks {
+        match rng.gen_range(0..6) {
+            0..=2 => TestTicks::Default,
+            3 if stable => TestTicks::Stable,
+            3 => TestTicks::Count { count: 1 },
+            _ => TestTicks::Count { count: rng.gen_range(if stable { 0 } else { 1 }..4) },
+        }
+    }
+
+    /// bitslicedでまとめて動かしても、一つずつ順に動かした場合と同じ結果と状態になる
+    #[test]
+    fn bitsliced_matches_serial() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for case in 0..300 {
+            let (sequential, sync) = match case%3 { 0 => (true, false), 1 => (false, true), _ => (false, false) };
+            let module = random_module(&mut rng, sequential, sync);
+            if !sequential && !sync {
+                assert!(BitslicedModule::new(&module).feed_forward(), "case {}", case);
+            }
+            let patterns = (0..rng.gen_range(1..2*LANES)).map(|_| {
+                ((0..module.inputs).map(|_| rng.gen()).collect::<Vec<bool>>(), random_ticks(&mut rng, case%6!=2))
+            }).collect::<Vec<_>>();
+            let mut batched = module.clone();
+            let runs = run_patterns(&mut batched, &patterns).unwrap();
+            let mut serial = module.clone();
+            for (index, ((inputs, ticks), run)) in patterns.iter().zip(runs).enumerate() {
+                let expected = run_pattern(&mut serial, inputs, ticks).unwrap();
+                assert_eq!((run.ticks, &run.unsettled, &run.output), (expected.ticks, &expected.unsettled, &expected.output), "case {} pattern {}", case, index);
+            }
+            assert_eq!(batched.cond, serial.cond, "case {}", case);
+            assert_eq!(batched.tick, serial.tick, "case {}", case);
+        }
+    }
+}
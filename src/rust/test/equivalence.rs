@@ -0,0 +1,210 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::types::*;
+use super::test::run_ticks;
+use super::batch::run_patterns;
+use crate::vm::bitslice::LANES;
+use crate::compiler::types::{Component, Diagnostic, EquivalenceTest, ModuleType, TestTicks};
+
+/// 全通り比べる入力のビット数の上限 (これより多ければランダムに比べる)
+const MAX_EXHAUSTIVE_INPUTS: usize = 16;
+/// ランダムに比べる回数の既定値
+const DEFAULT_RANDOM_COUNT: usize = 10000;
+/// 報告する反例の数の上限
+const MAX_COUNTEREXAMPLES: usize = 8;
+
+/// 組み込みの仕様 (入力はLSBから順)
+enum Spec {
+    /// 前半と後半 (と奇数ならcarry) の和
+    Add,
+    /// 前半と後半が等しいか
+    Eq,
+}
+
+/// 比べる相手
+enum Reference {
+    Module(Box<crate::vm::types::Module>),
+    Spec(Spec),
+}
+
+pub fn test_equivalences(
+    product: &crate::compiler::types::IntermediateProducts,
+    module_type_list: &[ModuleType],
+)-> ResultwithWarn<std::collections::HashMap<String,Vec<TestPattern>>> {
+    let mut errors = Vec::new();
+    let mut warns = Vec::new();
+    let mut result_map = std::collections::HashMap::<String,Vec<TestPattern>>::new();
+    for component in &product.ast.components {
+        if let Component::EquivalenceTest(test) = component {
+            match test_equivalence(product, module_type_list, test) {
+                Ok((patterns,warn)) => {
+                    warns.extend(warn.into_iter().map(|msg| Diagnostic::warning("test-failed", msg).with_span(&test.file, test.span)));
+                    // 同じモジュールの真理値表や他の等価性のテストと別のキーにする
+                    result_map.insert(super::testcheck::test_label(component).unwrap_or_default(), patterns);
+                },
+                Err(msg) => errors.push(Diagnostic::error("invalid-equivalence-test", msg).with_span(&test.file, test.span)),
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err((errors,warns));
+    }
+    Ok((result_map,warns))
+}
+
+/// @return `( 反例のpattern[], warn[] )`
+fn test_equivalence(
+    product: &crate::compiler::types::IntermediateProducts,
+    module_type_list: &[ModuleType],
+    test: &EquivalenceTest,
+) -> Result<(Vec<TestPattern>,Vec<String>),String> {
+    let module_type = match module_type_list.iter().find(|m| m.name==test.name) {
+        Some(v) => v.mtype.clone(),
+        None => return Err(format!("Undefined module used: {}",test.name)),
+    };
+    if !product.defined_func_module_list.contains(&test.name) {
+        return Err(format!("Equivalence tests are only for function modules: {}",test.name));
+    }
+    let mut vm = crate::vm::types::Module::new(crate::compiler::serialize(product.clone(), &test.name)?)?;
+    // 比べる相手を用意する
+    let mut reference = match module_type_list.iter().find(|m| m.name==test.reference) {
+        Some(reference_type) => {
+            if !product.defined_func_module_list.contains(&test.reference) {
+                return Err(format!("Equivalence tests are only for function modules: {}",test.reference));
+            }
+            if reference_type.mtype.input_count!=module_type.input_count||reference_type.mtype.output_count!=module_type.output_count {
+                return Err(format!("Used module with unmatched type: {} is {}->{} but {} is {}->{}",test.name,module_type.input_count,module_type.output_count,test.reference,reference_type.mtype.input_count,reference_type.mtype.output_count));
+            }
+            Reference::Module(Box::new(crate::vm::types::Module::new(crate::compiler::serialize(product.clone(), &test.reference)?)?))
+        },
+        None => {
+            let spec = match test.reference.as_str() {
+                "add" => Spec::Add,
+                "eq" => Spec::Eq,
+                _ => return Err(format!("Undefined module used: {} in test {}",test.reference,test.name)),
+            };
+            check_spec_type(&spec, &test.reference, module_type.input_count, module_type.output_count)?;
+            Reference::Spec(spec)
+        },
+    };
+    if test.prove {
+        return prove(product, test, &mut vm, &mut reference);
+    }
+    // 全通りかランダムに入力を作る
+    let inputs_len = module_type.input_count;
+    let (count,random) = match test.random {
+        Some(count) => (count,true),
+        None if inputs_len<=MAX_EXHAUSTIVE_INPUTS => (1usize<<inputs_len,false),
+        None => (DEFAULT_RANDOM_COUNT,true),
+    };
+    let mut rng = StdRng::seed_from_u64(test.seed as u64);
+    let mut patterns = Vec::new();
+    let mut warns = Vec::new();
+    // 64個ずつまとめて動かす (組み合わせ回路なので前の入力に関係なく同じ結果になる)
+    'chunks: for start in (0..count).step_by(LANES) {
+        let inputs = (start..count.min(start+LANES)).map(|n| {
+            (0..inputs_len).map(|bit| if random { rng.gen::<bool>() } else { (n>>bit)&1==1 }).collect::<Vec<bool>>()
+        }).collect::<Vec<_>>();
+        let outputs = run_all(&mut vm, &inputs)?;
+        let expects = match &mut reference {
+            Reference::Module(reference) => run_all(reference, &inputs)?,
+            Reference::Spec(spec) => inputs.iter().map(|inputs| eval_spec(spec, inputs, module_type.output_count)).collect(),
+        };
+        for ((inputs,output),expect) in inputs.into_iter().zip(outputs).zip(expects) {
+            if output!=expect {
+                warns.push(format!("Test failed: module {} differs from {}: input {:?}, expected {:?} but got {:?}",test.name,test.reference,inputs,expect,output));
+                patterns.push(TestPattern {
+                    accept: false,
+                    ticks: 1,
+                    ignored: vec![false;expect.len()],
+                    input: inputs,
+                    expect,
+                    output,
+                });
+                if patterns.len()>=MAX_COUNTEREXAMPLES {
+                    break 'chunks;
+                }
+            }
+        }
+    }
+    Ok((patterns,warns))
+}
+
+/// SATソルバで等価性を証明する, 等価でなければ反例を一つ報告する
+fn prove(
+    product: &crate::compiler::types::IntermediateProducts,
+    test: &EquivalenceTest,
+    vm: &mut crate::vm::types::Module,
+    reference: &mut Reference,
+) -> Result<(Vec<TestPattern>,Vec<String>),String> {
+    let reference = match reference {
+        Reference::Module(reference) => reference,
+        Reference::Spec(_) => return Err(format!("Built-in specs can not be proved: {} in test {}",test.reference,test.name)),
+    };
+    if test.random.is_some() {
+        return Err(format!("random can not be used with prove in test {}",test.name));
+    }
+    let (left,right) = match (product.expanded_modules.get(&test.name),product.expanded_modules.get(&test.reference)) {
+        (Some(left),Some(right)) => (left,right),
+        _ => return Err(format!("An undefined module was specified: {}",test.name)),
+    };
+    let inputs = match crate::compiler::prove_equivalence(left, right)? {
+        Some(v) => v,
+        None => return Ok((Vec::new(),Vec::new())),
+    };
+    let output = run(vm, &inputs)?;
+    let expect = run(reference, &inputs)?;
+    let warn = format!("Test failed: module {} differs from {}: input {:?}, expected {:?} but got {:?}",test.name,test.reference,inputs,expect,output);
+    let pattern = TestPattern {
+        accept: false,
+        ticks: 1,
+        ignored: vec![false;expect.len()],
+        input: inputs,
+        expect,
+        output,
+    };
+    Ok((vec![pattern],vec![warn]))
+}
+
+/// 入力を順に設定してそれぞれの出力を得る
+fn run_all(vm: &mut crate::vm::types::Module, inputs: &[Vec<bool>]) -> Result<Vec<Vec<bool>>,String> {
+    let patterns = inputs.iter().map(|inputs| (inputs.clone(),TestTicks::Default)).collect::<Vec<_>>();
+    Ok(run_patterns(vm, &patterns)?.into_iter().map(|run| run.output).collect())
+}
+
+/// 入力を設定して出力を得る
+fn run(vm: &mut crate::vm::types::Module, inputs: &[bool]) -> Result<Vec<bool>,String> {
+    for (index,input) in inputs.iter().enumerate() {
+        vm.set(index as u32, *input)?;
+    }
+    run_ticks(vm, &TestTicks::Default)?;
+    vm.get_output()
+}
+
+fn check_spec_type(spec: &Spec, name: &str, input_count: usize, output_count: usize) -> Result<(),String> {
+    let ok = match spec {
+        Spec::Add => input_count>=2 && output_count>=1,
+        Spec::Eq => input_count.is_multiple_of(2) && output_count==1,
+    };
+    if ok { Ok(()) }
+    else { Err(format!("Used module with unmatched type: {}->{} can not be compared with {}",input_count,output_count,name)) }
+}
+
+fn eval_spec(spec: &Spec, inputs: &[bool], output_count: usize) -> Vec<bool> {
+    let width = inputs.len()/2;
+    let (a,b) = (&inputs[..width],&inputs[width..width*2]);
+    match spec {
+        Spec::Add => {
+            // 奇数なら最後の入力はcarry
+            let mut carry = inputs.len()%2==1 && inputs[inputs.len()-1];
+            (0..output_count).map(|bit| {
+                let x = a.get(bit).copied().unwrap_or(false);
+                let y = b.get(bit).copied().unwrap_or(false);
+                let sum = x^y^carry;
+                carry = (x&&y)||(carry&&(x^y));
+                sum
+            }).collect()
+        },
+        Spec::Eq => vec![a==b],
+    }
+}
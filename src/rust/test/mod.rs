@@ -1,6 +1,8 @@
 mod testcheck;
 mod test;
-mod types;
+mod equivalence;
+mod batch;
+pub mod types;
 
 pub fn test(products: crate::compiler::types::IntermediateProducts) -> types::TestProducts {
     let mut test_products = types::TestProducts { warns: Vec::new(), errors: Vec::new(), test_list: Vec::new(), test_result: std::collections::HashMap::new() };
@@ -8,18 +10,26 @@ pub fn test(products: crate::compiler::types::IntermediateProducts) -> types::Te
     // 1, テスト定義の一覧を作成
     test_products.test_list = collect_tests(&products.ast);
     // 2, テストの名前に不足がないかを確認 -> warn
-    let check_res = check_test_missing(&test_products.test_list, &products);
+    let check_res = check_test_missing(&collect_tested_modules(&products.ast), &products);
     test_products.warns.extend(check_res.0);
     test_products.errors.extend(check_res.1);
-    // 3, テストの名前に重複がないかを確認 -> err
-    match check_test_name_duplicates(&test_products.test_list) {
-        Ok(()) => {},
-        Err(msg) => {test_products.errors.extend(msg);return test_products;},
-    };
+    // 3, 同じテストが重複していないかを確認 -> err (他のテストは実行する)
+    test_products.errors.extend(check_test_name_duplicates(&products.ast));
     // 4, テストを実行
     test_products.test_result = match test::test_gates(&products,&products.module_type_list) {
         Ok(res) => {test_products.warns.extend(res.1);res.0},
         Err(res) => {test_products.errors.extend(res.0);test_products.warns.extend(res.1);return test_products;},
     };
+    // 5, 他のモジュールや組み込みの仕様と比べるテストを実行
+    match equivalence::test_equivalences(&products,&products.module_type_list) {
+        Ok(res) => {test_products.warns.extend(res.1);test_products.test_result.extend(res.0);},
+        Err(res) => {test_products.errors.extend(res.0);test_products.warns.extend(res.1);return test_products;},
+    };
     test_products
+}
+
+/// testのパターンを順に動かした波形のVCD (circuitgame_bin向け)
+#[allow(dead_code)]
+pub fn trace(products: &crate::compiler::types::IntermediateProducts, name: &str, wires: bool) -> Result<String,String> {
+    test::trace_gates(products, name, wires)
 }
\ No newline at end of file
@@ -1,4 +1,12 @@
 use super::types::*;
+use super::batch::{run_pattern, run_patterns};
+use crate::vm::types::Stability;
+use crate::compiler::types::{Diagnostic, TestTicks};
+
+/// `@stable` で状態が変わらなくなるのを待つtick数の上限
+pub const MAX_STABLE_TICKS: u64 = 10000;
+/// 一つのpatternのinputに書けるxの数の上限 (2^n 通りに展開される)
+const MAX_DONT_CARE_INPUTS: usize = 16;
 
 pub fn test_gates(
     product: &crate::compiler::types::IntermediateProducts,
@@ -11,6 +19,9 @@ pub fn test_gates(
     for component in &product.ast.components {
         match component {
             crate::compiler::types::Component::Test(test)=>{ // Testのみ処理
+                // testの位置を付けて報告する
+                let error = |code: &str, msg: String| Diagnostic::error(code, msg).with_span(&test.file, test.span);
+                let warning = |code: &str, msg: String| Diagnostic::warning(code, msg).with_span(&test.file, test.span);
                 // moduleの型を取得
                 let module_type = match module_type_list.iter().find(|m| m.name==test.name).map(|m| &m.mtype) {
                     Some(mtype) => mtype.clone(),
@@ -19,56 +30,70 @@ pub fn test_gates(
                 // patternがtypeに合致するかを確認
                 for pattern in &test.patterns {
                     if pattern.inputs.len()!=module_type.input_count||pattern.outputs.len()!=module_type.output_count {
-                        errors.push(format!("Used module with unmatched type: {} expected {}->{} but got {}->{}",&test.name,module_type.input_count,module_type.output_count,pattern.inputs.len(),pattern.outputs.len()));
+                        errors.push(error("unmatched-type", format!("Used module with unmatched type: {} expected {}->{} but got {}->{}",&test.name,module_type.input_count,module_type.output_count,pattern.inputs.len(),pattern.outputs.len())));
                     }
                 }
                 // vmに入れて出力を確認する
                 let binary = match crate::compiler::serialize(product.clone(), &test.name.as_str()) {
                     Ok(v)=>v,
-                    Err(v)=>{ errors.push(v);break; }
+                    Err(v)=>{ errors.push(error("test-compile-failed", v));break; }
                 };
                 let mut vm = match crate::vm::types::Module::new(binary) {
                     Ok(v) => v,
-                    Err(v)=>{ errors.push(v);break; }
+                    Err(v)=>{ errors.push(error("test-compile-failed", v));break; }
                 };
-                let mut test_result = Vec::new();
-                // それぞれのpatternを試す
+                if vm.gates_sequential.len()+vm.gates_symmetry.len()>=crate::vm::event::EVENT_DRIVEN_MIN_GATES {
+                    vm.set_event_driven(true);
+                }
+                // それぞれのpatternを試す (inputのxは全ての組み合わせに展開する)
+                let mut expanded = Vec::new();
                 for pattern in &test.patterns {
-                    // 入力を設定する
-                    let mut input_index = 0;
-                    for input in &pattern.inputs {
-                        let _ = vm.set(input_index, *input);
-                        input_index+=1;
-                    }
-                    // vmを1ステップ進める
-                    let _ = vm.next(1);
-                    // 出力を取得する
-                    let output = match vm.get_output() {
-                        Ok(v) => v,
-                        Err(v)=>{ errors.push(v);break; }
+                    match expand_inputs(&pattern.inputs) {
+                        Ok(v) => expanded.extend(v.into_iter().map(|inputs| (pattern, inputs))),
+                        Err(v) => { errors.push(error("invalid-test-pattern", format!("{} in test {}",v,&test.name)));break; }
                     };
+                }
+                // 64パターンずつまとめて動かす
+                let runs = match run_patterns(&mut vm, &expanded.iter().map(|(pattern,inputs)| (inputs.clone(),pattern.ticks.clone())).collect::<Vec<_>>()) {
+                    Ok(v) => v,
+                    Err(v)=>{ errors.push(error("test-run-failed", v));break; }
+                };
+                let mut test_result = Vec::new();
+                for ((pattern,inputs),run) in expanded.into_iter().zip(runs) {
+                    // outputのxは確認しない
+                    let ignored = pattern.outputs.iter().map(|v| v.is_none()).collect::<Vec<_>>();
+                    let expect = pattern.outputs.iter().map(|v| v.unwrap_or(false)).collect::<Vec<_>>();
+                    // 状態が止まらなければ途中の出力になるので失敗にする
+                    let test_failed = run.unsettled.is_some();
+                    if let Some(reason) = &run.unsettled {
+                        warns.push(warning("test-failed", format!("Test failed: module {} input {:?}, {}",&test.name,inputs,reason)));
+                    }
+                    let ticks = run.ticks;
+                    let output = run.output;
                     // 出力の一致を確認する
-                    let mut test_failed = false;
+                    let mut output_failed = false;
                     let mut out_index = 0;
                     for out in &output {
                         let expect = match pattern.outputs.get(out_index) {
                             Some(v) => *v,
-                            None => { errors.push(format!("Index out of bounds"));break; }
+                            None => { errors.push(error("unmatched-type", "Index out of bounds".to_string()));break; }
                         };
-                        if *out!=expect {
-                            test_failed = true;
+                        if expect.is_some_and(|expect| *out!=expect) {
+                            output_failed = true;
                         }
                         out_index+=1;
                     }
                     //
-                    if test_failed {
-                        warns.push(format!("Test failed: module {} input {:?}, expected {:?} but got {:?}",&test.name,pattern.inputs.clone(),pattern.outputs.clone(),output.clone()));
+                    if output_failed {
+                        warns.push(warning("test-failed", format!("Test failed: module {} input {:?}, expected {} but got {:?}",&test.name,inputs,format_expect(&pattern.outputs),output.clone())));
                     }
                     let test_pattern = TestPattern {
-                        input : pattern.inputs.clone(),
-                        expect: pattern.outputs.clone(),
-                        output: output.clone(),
-                        accept: !test_failed,
+                        ticks,
+                        input : inputs,
+                        expect,
+                        ignored,
+                        output,
+                        accept: !test_failed && !output_failed,
                     };
                     test_result.push(test_pattern);
                 }
@@ -81,4 +106,179 @@ pub fn test_gates(
         return Err((errors,warns));
     }
     Ok((result_map,warns))
-}
\ No newline at end of file
+}
+
+/// testのパターンを一つずつ順に動かし、値の変化をVCDにする (失敗したテストを波形で調べる用)
+/// wiresなら名前の付いた全てのゲートも記録する
+pub fn trace_gates(product: &crate::compiler::types::IntermediateProducts, name: &str, wires: bool) -> Result<String,String> {
+    let test = product.ast.components.iter().find_map(|component| match component {
+        crate::compiler::types::Component::Test(test) if test.name==name => Some(test),
+        _ => None,
+    }).ok_or(format!("Test not found: {}",name))?;
+    let binary = crate::compiler::serialize(product.clone(), name)?;
+    let mut vm = crate::vm::types::Module::new(binary)?;
+    vm.start_trace(wires);
+    for pattern in &test.patterns {
+        for inputs in expand_inputs(&pattern.inputs)? {
+            run_pattern(&mut vm, &inputs, &pattern.ticks)?;
+        }
+    }
+    Ok(vm.take_trace().map(|trace| trace.to_vcd()).unwrap_or_default())
+}
+
+/// vmをtestの指定に従って進める
+/// `@n` 以外で状態が止まらなければ、途中の出力を比べないように止まらなかった理由を返す
+/// @return `( 進めたtick数, 止まらなかった理由 )`
+pub fn run_ticks(vm: &mut crate::vm::types::Module, ticks: &TestTicks) -> Result<(u64,Option<String>),String> {
+    match ticks {
+        TestTicks::Count { count } => {
+            vm.next(*count)?;
+            Ok((*count as u64,None))
+        },
+        TestTicks::Default => {
+            vm.next(1)?;
+            // symmetryのゲートは1tickに1段ずつしか伝わらないので、状態が変わらなくなるまで進める
+            let mut ticks = 1;
+            for _ in 0..vm.gates_symmetry.len() {
+                let before = vm.get_gates();
+                vm.next(1)?;
+                if vm.get_gates()==before {
+                    break;
+                }
+                ticks += 1;
+            }
+            if vm.is_stable() {
+                return Ok((ticks,None));
+            }
+            // 続けたらどうなるかを調べて理由にする (vmの状態は変えない)
+            let reason = match vm.clone().run_until_stable(MAX_STABLE_TICKS)? {
+                Stability::Stable { ticks: more } => format!("did not settle within {} ticks (use @stable to wait {} more ticks)",ticks,more-1),
+                stability => unsettled_reason(vm, &stability),
+            };
+            Ok((ticks,Some(reason)))
+        },
+        TestTicks::Stable => match vm.run_until_stable(MAX_STABLE_TICKS)? {
+            Stability::Stable { ticks } => Ok((ticks,None)),
+            stability => Ok((stability.ticks(),Some(unsettled_reason(vm, &stability)))),
+        },
+    }
+}
+
+/// 振動しているなら周期と値が変わるゲートの名前
+fn unsettled_reason(vm: &crate::vm::types::Module, stability: &Stability) -> String {
+    /// 表示するゲートの名前の数の上限
+    const MAX_TOGGLING_NAMES: usize = 8;
+    match stability {
+        Stability::Oscillating { period, toggling, .. } => {
+            let mut names = toggling.iter().take(MAX_TOGGLING_NAMES).map(|index| vm.gate_name(*index)).collect::<Vec<_>>();
+            if toggling.len()>MAX_TOGGLING_NAMES {
+                names.push(format!("and {} more",toggling.len()-MAX_TOGGLING_NAMES));
+            }
+            format!("did not settle: oscillates with period {} ({})",period,names.join(", "))
+        },
+        _ => format!("did not settle within {} ticks",MAX_STABLE_TICKS),
+    }
+}
+
+/// inputのx (None) を全ての組み合わせに展開する (左のxが上位ビット)
+fn expand_inputs(inputs: &[Option<bool>]) -> Result<Vec<Vec<bool>>,String> {
+    let dont_care = inputs.iter().filter(|v| v.is_none()).count();
+    if dont_care>MAX_DONT_CARE_INPUTS {
+        return Err(format!("Too many don't-care inputs: {} (max {})",dont_care,MAX_DONT_CARE_INPUTS));
+    }
+    Ok((0..1u32<<dont_care).map(|n| {
+        let mut bit = dont_care;
+        inputs.iter().map(|v| match v {
+            Some(v) => *v,
+            None => { bit-=1; (n>>bit)&1==1 },
+        }).collect()
+    }).collect())
+}
+
+/// 期待する出力の表示 `[true, x]`
+fn format_expect(outputs: &[Option<bool>]) -> String {
+    format!("[{}]",outputs.iter().map(|v| match v {
+        Some(v) => v.to_string(),
+        None => "x".to_string(),
+    }).collect::<Vec<_>>().join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_tests(source: &str) -> crate::test::types::TestProducts {
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        assert!(products.errors.is_empty(), "{:?}", products.errors);
+        crate::test::test(products)
+    }
+
+    /// `@n` はちょうどnだけ、`@stable` は状態が変わらなくなるまで進める (状態はパターンの間で引き継ぐ)
+    #[test]
+    fn tick_counts() {
+        let result = run_tests("
+            using nor:2->1;
+            module sr_latch (s r)->(q nq) {
+                q: nor <- r nq;
+                nq: nor <- s q;
+            }
+            test sr_latch:2->2 {
+                t f @stable -> t f;
+                f f @stable -> t f;
+                f t @stable -> f t;
+                f f @3 -> f t;
+            }
+            sync module chain (x)->(c) { a: nor <- x x; b: nor <- a a; c: nor <- b b; }
+            test chain:1->1 {
+                t @1 -> t;
+                t @stable -> f;
+            }
+        ");
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let latch = &result.test_result["sr_latch"];
+        assert!(latch.iter().all(|p| p.accept), "{:?}", latch);
+        assert_eq!(latch[3].ticks, 3);
+        // syncでは1tickに1段ずつ伝わるので、1tick後はまだ途中の値
+        let chain = &result.test_result["chain"];
+        assert!(chain.iter().all(|p| p.accept), "{:?}", chain);
+        assert_eq!(chain.iter().map(|p| p.ticks).collect::<Vec<_>>(), [1, 2]);
+    }
+
+    /// 入力の `x` `-` は全ての組み合わせに展開し、出力の `x` は確かめない
+    #[test]
+    fn dont_care_values() {
+        let result = run_tests("
+            using nor:2->1;
+            func and (a b)->(o) { na: nor <- a a; nb: nor <- b b; o: nor <- na nb; }
+            test and:2->1 {
+                f x -> f;
+                X f -> f;
+                t t -> t;
+                - t -> x;
+            }
+        ");
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let patterns = &result.test_result["and"];
+        assert!(patterns.iter().all(|p| p.accept), "{:?}", patterns);
+        assert_eq!(patterns.iter().map(|p| p.input.clone()).collect::<Vec<_>>(), [
+            vec![false, false], vec![false, true],
+            vec![false, false], vec![true, false],
+            vec![true, true],
+            vec![false, true], vec![true, true],
+        ]);
+        assert_eq!(patterns.iter().map(|p| p.ignored[0]).collect::<Vec<_>>(), [false, false, false, false, false, true, true]);
+    }
+
+    /// 入力の `x` は16個まで (2^16通り)
+    #[test]
+    fn dont_care_limit() {
+        assert_eq!(expand_inputs(&[None; MAX_DONT_CARE_INPUTS]).map(|inputs| inputs.len()), Ok(1<<MAX_DONT_CARE_INPUTS));
+        assert!(expand_inputs(&[None; MAX_DONT_CARE_INPUTS+1]).is_err());
+        let result = run_tests(&format!("
+            using nor:2->1;
+            func wide (a(17))->(o) {{ o: nor <- a[0] a[16]; }}
+            test wide:17->1 {{ {} -> x; }}
+        ", vec!["x"; 17].join(" ")));
+        assert_eq!(result.errors.iter().map(|e| e.code.as_str()).collect::<Vec<_>>(), ["invalid-test-pattern"], "{:?}", result.errors);
+    }
+}
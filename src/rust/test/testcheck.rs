@@ -1,46 +1,112 @@
-pub fn collect_tests(ast: &crate::compiler::types::File) -> Vec<String> {
+/// テストの名前 (結果のキー)
+/// 真理値表のテストはモジュール名、等価性のテストは書かれた形 `add == ref_add random 100 prove` にして、同じモジュールの別のテストと区別する
+pub fn test_label(component: &crate::compiler::types::Component) -> Option<String> {
     use crate::compiler::types::*;
+    match component {
+        Component::Test(test) => Some(test.name.clone()),
+        Component::EquivalenceTest(test) => {
+            let mut label = format!("{} == {}",test.name,test.reference);
+            if let Some(random) = test.random {
+                label += &format!(" random {}",random);
+            }
+            if test.seed!=0 {
+                label += &format!(" seed {}",test.seed);
+            }
+            if test.prove {
+                label += " prove";
+            }
+            Some(label)
+        },
+        _ => None, // テストでなければ何もしない
+    }
+}
+
+/// テストの名前の一覧 (定義順, 重複は一つにまとめる)
+pub fn collect_tests(ast: &crate::compiler::types::File) -> Vec<String> {
     let mut tests = Vec::new();
-    for component in &ast.components {
-        match component {
-            Component::Test(test)=>{
-                tests.push(test.name.clone());
-            },
-            _ => {} // テストでなければ何もしない
+    for label in ast.components.iter().filter_map(test_label) {
+        if !tests.contains(&label) {
+            tests.push(label);
         }
     }
     tests
 }
 
-pub fn check_test_name_duplicates(modules: &Vec<String>) -> Result<(),Vec<String>> {
-    let mut module_names = std::collections::HashSet::new();
+/// テストされているモジュールの一覧
+pub fn collect_tested_modules(ast: &crate::compiler::types::File) -> Vec<String> {
+    use crate::compiler::types::*;
+    ast.components.iter().filter_map(|component| match component {
+        Component::Test(test) => Some(test.name.clone()),
+        Component::EquivalenceTest(test) => Some(test.name.clone()),
+        _ => None,
+    }).collect()
+}
+
+/// 同じテストが二度書かれていないかを確認する
+/// 一つのモジュールに真理値表は一つまでだが、等価性のテストは相手や方法が違えばいくつでも書ける
+pub fn check_test_name_duplicates(ast: &crate::compiler::types::File) -> super::types::Errs {
+    use crate::compiler::types::*;
+    let mut labels = std::collections::HashSet::new();
     let mut errors = Vec::new();
-    for module in modules {
-        if !module_names.insert(module) {
-            errors.push(format!("Multiple tests are provided for one module: {}",module));
+    for component in &ast.components {
+        let (Some(label), Some((file, span))) = (test_label(component), match component {
+            Component::Test(test) => Some((&test.file, test.span)),
+            Component::EquivalenceTest(test) => Some((&test.file, test.span)),
+            _ => None,
+        }) else { continue };
+        if !labels.insert(label.clone()) {
+            let message = match component {
+                Component::Test(_) => format!("Multiple truth tables are provided for one module: {}",label),
+                _ => format!("Equivalence test is provided twice: {}",label),
+            };
+            errors.push(Diagnostic::error("multiple-tests-for-module", message).with_span(file, span));
         }
     }
-    if errors.len()==0 { Ok(()) }
-    else { Err(errors) }
+    errors
 }
 
-pub fn check_test_missing(provided_tests: &Vec<String>,product: &crate::compiler::types::IntermediateProducts) -> (Vec<String>,Vec<String>) {
+pub fn check_test_missing(provided_tests: &Vec<String>,product: &crate::compiler::types::IntermediateProducts) -> (super::types::Warns,super::types::Errs) {
+    use crate::compiler::types::Diagnostic;
     let mut warns = Vec::new();
     let mut errors = Vec::new();
     for module in &product.defined_func_module_list {
         if !provided_tests.contains(module) {
-            warns.push(format!("No test provided for module: {}",module));
+            warns.push(Diagnostic::warning("no-test-provided", format!("No test provided for module: {}",module)));
         }
     }
+    // 非funcモジュールは状態を持つので、`@n` `@stable` で時間を進めながらテストする
     for module in provided_tests {
-        if !product.defined_func_module_list.contains(module) {
-            if !product.defined_non_func_module_list.contains(module) {
-                errors.push(format!("Undefined module used: {}",module));
-            }
-            else {
-                errors.push(format!("Testing non-function modules: {}",module));
-            }
+        if !product.defined_func_module_list.contains(module) && !product.defined_non_func_module_list.contains(module) {
+            errors.push(Diagnostic::error("undefined-module-used", format!("Undefined module used: {}",module)));
         }
     }
     (warns,errors)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    /// 真理値表と、相手や方法の違う等価性のテストは同じモジュールに書けて、結果は別に残る
+    #[test]
+    fn equivalence_tests_coexist() {
+        let source = "
+            using nor:2->1;
+            func not (x)->(a) { a: nor <- x x; }
+            func not3 (x)->(a) { b: nor <- x x; c: nor <- b b; a: nor <- c c; }
+            test not:1->1 { t -> f; f -> t; }
+            test not == not3;
+            test not == not3 random 4;
+            test not == not3 prove;
+            test not3 == not;
+        ";
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        let result = super::super::test(products.clone());
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.test_list, ["not", "not == not3", "not == not3 random 4", "not == not3 prove", "not3 == not"]);
+        assert!(result.test_list.iter().all(|name| result.test_result.contains_key(name)));
+        assert_eq!(result.test_result["not"].len(), 2);
+        // 同じテストを二度書いたときだけエラーにして、他のテストは実行する
+        let products = crate::compiler::intermediate_products_with_loader(&format!("{}test not == not3;", source), "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        let result = super::super::test(products);
+        assert_eq!(result.errors.iter().map(|e| e.code.as_str()).collect::<Vec<_>>(), ["multiple-tests-for-module"]);
+        assert_eq!(result.test_result.len(), 5);
+    }
+}
@@ -2,24 +2,26 @@ use serde::Serialize;
 
 /// Errのレベル: 一つ目は停止 二つ目は警告
 /// `Ok(( result, warn[] ))` `Err(( error[], warn[] ))`
-pub type Warns = Vec<String>;
-pub type Errs = Vec<String>;
-pub type ResultwithWarn<T> = Result<(T,Warns),(Errs,Warns)>;
+pub use crate::compiler::types::{Warns, Errs, ResultwithWarn};
 
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TestPattern {
     pub accept: bool,
+    /// 進めたtick数
+    pub ticks : u64,
     pub input : Vec<bool>,
     pub expect: Vec<bool>,
+    /// 確認しなかった (xが書かれた) outputのビット
+    pub ignored: Vec<bool>,
     pub output: Vec<bool>,
 }
 
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TestProducts {
-    pub warns      : Vec<String>,
-    pub errors     : Vec<String>,
+    pub warns      : Warns,
+    pub errors     : Errs,
     pub test_list  : Vec<String>,
     pub test_result: std::collections::HashMap<String,Vec<TestPattern>>,
 }
\ No newline at end of file
@@ -7,27 +7,29 @@ pub fn transpile(module: Module,header: bool) -> Result<String,String> {
     if module.gates_symmetry.len()>0 {
         return Err(format!("C transpiler does not support symmetry module: {}",module.name));
     }
+    // パラメータ付きモジュールの `add<4>` などはそのまま識別子にできない
+    let name = super::identifier(&module.name);
     // ヘッダーを作る
     let out_struct = format!(
         "typedef struct {{\n    bool outputs[{}OutputsLen];\n}} {}Result;",
-        module.name,
-        module.name,
+        name,
+        name,
     );
     let out_func_h = format!(
         "{}Result {}({});",
-        module.name,
-        module.name,
+        name,
+        name,
         vec!["int";module.inputs as usize].join(","),
     );
     let out_header = format!(
         "{}\n\n#ifndef TRANSPILE_{}_HEADER\n#define TRANSPILE_{}_HEADER\n\n{}\n\n{}\n\n{}\n\n{}\n\n#endif",
         "// Generated by Neknaj Circuit Game",
-        module.name,
-        module.name,
+        name,
+        name,
         "#include <stdbool.h>",
         format!(
             "#define {}OutputsLen {}",
-            module.name,
+            name,
             module.outputs.len(),
         ),
         out_struct,
@@ -37,14 +39,14 @@ pub fn transpile(module: Module,header: bool) -> Result<String,String> {
     // 本体の関数を作る
     let out_func_head = format!(
         "{}Result {}({})",
-        module.name,
-        module.name,
+        name,
+        name,
         (0..module.inputs as usize).map(|i| format!("int b{}",i+module.gates_sequential.len())).collect::<Vec<String>>().join(", "),
     );
-    let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("    int b{} = !( b{} | b{} );",index,value.0,value.1)).collect::<Vec<String>>().join("\n");
+    let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("    int b{} = !( b{} | b{} );{}",index,value.0,value.1,super::gate_comment(&module,index))).collect::<Vec<String>>().join("\n");
     let out_func_return = format!(
         "    {}Result result = {{{{ {} }}}};\n    return result;",
-        module.name,module.outputs.iter().map(|value| format!("b{}",value)).collect::<Vec<String>>().join(", "),
+        name,module.outputs.iter().map(|value| format!("b{}",value)).collect::<Vec<String>>().join(", "),
     );
     let out_func = format!(
         "{} {{\n{}\n{}\n}}",
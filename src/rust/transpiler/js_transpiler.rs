@@ -8,17 +8,18 @@ pub fn transpile(module: Module) -> Result<String,String> {
         return Err(format!("JS transpiler does not support non-func module: {}",module.name));
     }
     else {
+        let name = super::identifier(&module.name);
         let out_const = format!(
             "export const {}OutputsLen = {};",
-            module.name,
+            name,
             module.outputs.len(),
         );
         let out_func_head = format!(
             "export function {}({})",
-            module.name,
+            name,
             (0..module.inputs as usize).map(|i| format!("b{}",i+module.gates_sequential.len())).collect::<Vec<String>>().join(", "),
         );
-        let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("    const b{} = !( b{} || b{} );",index,value.0,value.1)).collect::<Vec<String>>().join("\n");
+        let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("    const b{} = !( b{} || b{} );{}",index,value.0,value.1,super::gate_comment(&module,index))).collect::<Vec<String>>().join("\n");
         let out_func_return = format!(
             "    return {{ outputs: [ {} ] }};",
             module.outputs.iter().map(|value| format!("b{}",value)).collect::<Vec<String>>().join(", "),
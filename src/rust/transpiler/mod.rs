@@ -1,4 +1,112 @@
 pub mod c_transpiler;
 pub mod ts_transpiler;
 pub mod js_transpiler;
-pub mod rs_transpiler;
\ No newline at end of file
+pub mod rs_transpiler;
+
+/// シンボルがあれば、ゲートの名前のコメント ` // c1.hAddr.s.xor.e`
+fn gate_comment(module: &crate::vm::types::Module, index: usize) -> String {
+    match module.symbols {
+        Some(_) => format!(" // {}",module.gate_name(index)),
+        None => String::new(),
+    }
+}
+
+/// モジュール名を出力先の言語の識別子にする (`add<4>` -> `add_4`, `gates::helper` -> `gates_helper`)
+/// 英数字と `_` 以外の並びを一つの `_` にし、数字で始まる場合は `_` を付ける
+fn identifier(name: &str) -> String {
+    let mut ident = String::new();
+    let mut separated = false;
+    for c in name.chars() {
+        if !(c.is_alphanumeric() || c=='_') {
+            separated = true;
+            continue;
+        }
+        if separated && !ident.is_empty() {
+            ident.push('_');
+        }
+        separated = false;
+        ident.push(c);
+    }
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// パラメータ付きモジュールの実体を全てのトランスパイラで識別子にする
+    #[test]
+    fn generic_instance_names() {
+        let source = "
+            using nor:2->1;
+            func not (x)->(a) { a: nor <- x x; }
+            func nots<1> (x)->(t) { t: not <- x; }
+            func nots<N> (x(N))->(h t) {
+                h(N-1): nots<N-1> <- x[0,N-1);
+                t: not <- x[N-1];
+            }
+            test nots<2>:2->2 { t f -> f t; }
+        ";
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        assert!(products.errors.is_empty(), "{:?}", products.errors);
+        let module = crate::vm::deserializer::deserialize_from_vec(&crate::compiler::serialize(products, "nots<2>").unwrap()).unwrap();
+        assert_eq!(module.name, "nots<2>");
+        let outputs = [
+            c_transpiler::transpile(module.clone(), true).unwrap(),
+            c_transpiler::transpile(module.clone(), false).unwrap(),
+            js_transpiler::transpile(module.clone()).unwrap(),
+            ts_transpiler::transpile(vec![module.clone()], false).unwrap(),
+            rs_transpiler::transpile(vec![module]).unwrap(),
+        ];
+        for output in &outputs {
+            assert!(output.contains("nots_2"), "{}", output);
+            assert!(!output.contains("nots<2>"), "{}", output);
+        }
+        assert!(outputs[0].contains("#ifndef TRANSPILE_nots_2_HEADER"));
+        assert!(outputs[4].contains("pub struct nots_2 {"));
+    }
+
+    /// importで修飾された名前 `gates::helper` も識別子にする
+    #[test]
+    fn qualified_names() {
+        let source = "
+            using nor:2->1;
+            import { top } from \"gates.ncg\";
+            func helper (x)->(a) { a: nor <- x x; }
+            test top:1->1 { t -> f; }
+        ";
+        let loader = |path: &str| match path {
+            "gates.ncg" => Ok("
+                using nor:2->1;
+                func helper (x)->(a) { a: nor <- x x; }
+                export func top (x)->(a) { a: helper <- x; }
+            ".to_string()),
+            _ => Err(format!("File not found: {}",path)),
+        };
+        let products = crate::compiler::intermediate_products_with_loader(source, "main.ncg", &loader);
+        assert!(products.errors.is_empty(), "{:?}", products.errors);
+        let module = crate::vm::deserializer::deserialize_from_vec(&crate::compiler::serialize(products, "gates::helper").unwrap()).unwrap();
+        let outputs = [
+            c_transpiler::transpile(module.clone(), false).unwrap(),
+            js_transpiler::transpile(module.clone()).unwrap(),
+            ts_transpiler::transpile(vec![module.clone()], false).unwrap(),
+            rs_transpiler::transpile(vec![module]).unwrap(),
+        ];
+        for output in &outputs {
+            assert!(output.contains("gates_helper"), "{}", output);
+            assert!(!output.contains("gates::helper"), "{}", output);
+        }
+    }
+
+    #[test]
+    fn identifiers() {
+        assert_eq!(identifier("add<4>"), "add_4");
+        assert_eq!(identifier("add<4,8>"), "add_4_8");
+        assert_eq!(identifier("gates::helper"), "gates_helper");
+        assert_eq!(identifier("lib/gates.ncg::helper<2>"), "lib_gates_ncg_helper_2");
+        assert_eq!(identifier("half_"), "half_");
+    }
+}
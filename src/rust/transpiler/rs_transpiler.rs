@@ -27,8 +27,8 @@ pub fn transpile(modules: Vec<Module>) -> Result<String, String> {
                 .enumerate()
                 .map(|(index, gate)| {
                     format!(
-                        "            let b{} = !( b{} || b{} );",
-                        index, gate.0, gate.1
+                        "            let b{} = !( b{} || b{} );{}",
+                        index, gate.0, gate.1, super::gate_comment(module, index)
                     )
                 })
                 .collect::<Vec<String>>()
@@ -50,15 +50,17 @@ pub fn transpile(modules: Vec<Module>) -> Result<String, String> {
             "".to_string()
         };
 
+        // Generic instances like `add<4>` are not valid identifiers.
+        let name = super::identifier(&module.name);
         // Create the struct definition.
         let struct_def = format!(
             "    pub struct {} {{\n        b: Vec<bool>,\n    }}",
-            module.name
+            name
         );
         // Start the impl block with constants.
         let consts = format!(
             "    impl {} {{\n        pub const INPUTS_LEN: usize = {};\n        pub const OUTPUTS_LEN: usize = {};\n        pub const GATES_SEQ_LEN: usize = {};",
-            module.name,
+            name,
             module.inputs,
             module.outputs.len(),
             module.gates_sequential.len()
@@ -87,8 +89,8 @@ pub fn transpile(modules: Vec<Module>) -> Result<String, String> {
                 .enumerate()
                 .map(|(index, gate)| {
                     format!(
-                        "            self.b[{}] = !( self.b[{}] || self.b[{}] );",
-                        index, gate.0, gate.1
+                        "            self.b[{}] = !( self.b[{}] || self.b[{}] );{}",
+                        index, gate.0, gate.1, super::gate_comment(module, index)
                     )
                 })
                 .collect::<Vec<String>>()
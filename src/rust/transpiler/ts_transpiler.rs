@@ -17,7 +17,7 @@ pub fn transpile(modules: Vec<Module>,header: bool) -> Result<String,String> {
                     (0..module.inputs as usize).map(|i| format!("b{}: boolean",i+module.gates_sequential.len())).collect::<Vec<String>>().join(", "),
                     module.outputs.len(),
                 );
-                let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("            const b{} = !( b{} || b{} );",index,value.0,value.1)).collect::<Vec<String>>().join("\n");
+                let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("            const b{} = !( b{} || b{} );{}",index,value.0,value.1,super::gate_comment(module,index))).collect::<Vec<String>>().join("\n");
                 let out_func_return = format!(
                     "            return [ {} ];",
                     module.outputs.iter().map(|value| format!("b{}",value)).collect::<Vec<String>>().join(", "),
@@ -34,7 +34,7 @@ pub fn transpile(modules: Vec<Module>,header: bool) -> Result<String,String> {
                 "".to_string()
             };
             // 本体の関数を作る
-            let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("            this.b[{}] = !( this.b[{}] || this.b[{}] );",index,value.0,value.1)).collect::<Vec<String>>().join("\n");
+            let out_func_gates = module.gates_sequential.iter().enumerate().map(|(index,value)| format!("            this.b[{}] = !( this.b[{}] || this.b[{}] );{}",index,value.0,value.1,super::gate_comment(module,index))).collect::<Vec<String>>().join("\n");
             let constructor_func = format!(
                 "        constructor () {{\n{}\n        }}",
                 format!("            this.b = new Array({}).fill(false);",module.gates_sequential.len()+module.inputs as usize),
@@ -60,7 +60,7 @@ pub fn transpile(modules: Vec<Module>,header: bool) -> Result<String,String> {
             );
             let module = format!(
                 "    \"{}\": class {{\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}    }}",
-                module.name,
+                super::identifier(&module.name),
                 format!("        private b: boolean[];"),
                 format!("        static inputsLen: number = {};",module.inputs),
                 format!("        static outputsLen: number = {};",module.outputs.len()),
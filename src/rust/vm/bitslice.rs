@@ -0,0 +1,108 @@
+use super::types::*;
+
+/// 一度に動かすパターンの数 (1ワイヤのビット数)
+pub const LANES: usize = 64;
+
+/// 各ワイヤをu64にして、64個のパターンを同時に動かすVM
+/// ビットiがi番目のパターン (レーン) の値
+/// ゲートの並びや1tickの計算は `Module::next` と同じで、レーンごとに見れば同じ結果になる
+pub struct BitslicedModule {
+    pub inputs: u32,
+    pub outputs: Vec<u32>,
+    pub gates_sequential: Vec<NORGate>,
+    pub gates_symmetry: Vec<NORGate>,
+    pub cond: Vec<u64>,
+}
+
+impl BitslicedModule {
+    /// 全てのレーンをmoduleの今の状態にする
+    pub fn new(module: &Module) -> Self {
+        BitslicedModule {
+            inputs: module.inputs,
+            outputs: module.outputs.clone(),
+            gates_sequential: module.gates_sequential.clone(),
+            gates_symmetry: module.gates_symmetry.clone(),
+            cond: module.cond.iter().map(|value| if *value { !0 } else { 0 }).collect(),
+        }
+    }
+    /// 全てのレーンをcondの状態 (input付き) にする
+    pub fn load_all(&mut self, cond: &GatesCond) {
+        for (wire, value) in self.cond.iter_mut().zip(cond) {
+            *wire = if *value { !0 } else { 0 };
+        }
+    }
+    /// symmetryのゲートがなく、どのゲートも前のゲートかinputだけを入力にしているか
+    /// (1tick進めれば状態がinputだけで決まり、前の状態に関係なくなる)
+    pub fn feed_forward(&self) -> bool {
+        let gates_len = self.gates_len();
+        self.gates_symmetry.is_empty() && self.gates_sequential.iter().enumerate().all(|(index, gate)| {
+            let earlier = |wire: u32| (wire as usize) < index || wire as usize >= gates_len;
+            earlier(gate.0) && earlier(gate.1)
+        })
+    }
+    fn gates_len(&self) -> usize {
+        self.gates_sequential.len() + self.gates_symmetry.len()
+    }
+    /// レーンの状態 (input付き) を設定する
+    pub fn load(&mut self, lane: usize, cond: &GatesCond) {
+        let bit = 1u64 << lane;
+        for (wire, value) in self.cond.iter_mut().zip(cond) {
+            if *value { *wire |= bit } else { *wire &= !bit }
+        }
+    }
+    /// レーンの状態 (input付き) を取得する
+    pub fn state(&self, lane: usize) -> GatesCond {
+        self.cond.iter().map(|wire| (wire >> lane) & 1 == 1).collect()
+    }
+    /// maskのビットが立っているレーンのinputをまとめてvaluesのビットにする
+    pub fn set_lanes(&mut self, index: u32, mask: u64, values: u64) -> Result<(),String> {
+        let gates_len = self.gates_len();
+        let wire = self.cond.get_mut(index as usize + gates_len).ok_or("Index out of bounds")?;
+        *wire = (*wire & !mask) | (values & mask);
+        Ok(())
+    }
+    /// レーンの全てのoutputを取得する
+    pub fn get_output(&self, lane: usize) -> Result<GatesCond,String> {
+        self.outputs.iter().map(|i| match self.cond.get(*i as usize) {
+            Some(wire) => Ok((wire >> lane) & 1 == 1),
+            None => Err("Index out of bounds".to_string()),
+        }).collect()
+    }
+    /// 1tick進めると状態が変わるレーン (どれかのゲートが入力のNORになっていない)
+    pub fn unstable(&self) -> u64 {
+        self.gates_sequential.iter().chain(&self.gates_symmetry).enumerate().fold(0, |mask, (index, gate)| {
+            mask | (!(self.cond[gate.0 as usize] | self.cond[gate.1 as usize]) ^ self.cond[index])
+        })
+    }
+    /// activeのビットが立っているレーンだけgatesを1周更新する
+    /// @return 状態が変わったレーン
+    pub fn next(&mut self, active: u64) -> Result<u64,String> {
+        let mut changed = 0;
+        let mut gate_index = 0;
+        { // sequential
+            for gate in &self.gates_sequential {
+                let input1 = *self.cond.get(gate.0 as usize).ok_or("gates access error")?;
+                let input2 = *self.cond.get(gate.1 as usize).ok_or("gates access error")?;
+                let output = self.cond.get_mut(gate_index).ok_or("gates access error")?;
+                let value = (!(input1|input2) & active) | (*output & !active); // 64レーン分のNORを計算する
+                changed |= *output ^ value;
+                *output = value;
+                gate_index+=1;
+            }
+        }
+        let mut gate_index = self.gates_sequential.len();
+        { // symmetry
+            let before_cond = self.cond.clone();
+            for gate in &self.gates_symmetry {
+                let input1 = *before_cond.get(gate.0 as usize).ok_or("gates access error")?;
+                let input2 = *before_cond.get(gate.1 as usize).ok_or("gates access error")?;
+                let output = self.cond.get_mut(gate_index).ok_or("gates access error")?;
+                let value = (!(input1|input2) & active) | (*output & !active);
+                changed |= *output ^ value;
+                *output = value;
+                gate_index+=1;
+            }
+        }
+        Ok(changed)
+    }
+}
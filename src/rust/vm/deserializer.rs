@@ -1,107 +1,421 @@
 use super::types::*;
 
-pub fn deserialize_from_vec(data: &[u32]) -> Result<Module, String> {
-    let mut index = 0;
+// NCGBの形式の定数 (書き出す側のcompiler::ncgbもこれを使う)
 
+/// NCGBの先頭 ("NCGb")
+pub const MAGIC: u32 = 0x6247434e;
+/// コンテナ形式のバージョン (バージョン1はこの位置にデータサイズの32が入っている)
+pub const VERSION: u32 = 2;
+const LEGACY_DATA_SIZE: u32 = 32;
+/// 末尾にチェックサムがある
+pub const FLAG_CHECKSUM: u32 = 1;
+/// シンボルセクションのタグ ("symb")
+pub const SYMBOL_SECTION: u32 = 0x626d7973;
+/// graphicalの配置のセクションのタグ ("grph")
+pub const GRAPHICAL_SECTION: u32 = 0x68707267;
+/// テストのパターンのセクションのタグ ("test")
+pub const TEST_SECTION: u32 = 0x74736574;
+/// ゲートと入力の数の上限 (壊れたファイルで巨大な状態を確保しないため)
+const MAX_WIRES: usize = 1 << 26;
+
+/// NCGBが壊れている理由
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeserializeError {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    UnsupportedFlags(u32),
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// 何を読もうとしたところでデータが終わったか
+    TooShort(&'static str),
+    UnsupportedFuncFlag(u32),
+    /// 名前にUnicodeのスカラー値でない値がある
+    InvalidCharacter { value: u32, offset: usize },
+    TooManyWires(usize),
+    /// ゲートの入力がゲートと入力の数を超えている
+    GateInputOutOfBounds { gate: usize, input: u32, wires: usize },
+    OutputOutOfBounds { output: usize, index: u32, wires: usize },
+    UnsupportedTestValue(u32),
+    UnsupportedTestTicks(u32),
+    /// モジュールやセクションを読み終わった位置が、書かれた長さと合わない
+    LengthMismatch { what: &'static str, expected: usize, actual: usize },
+    /// 最後まで読んだ後に余っているデータ
+    TrailingData { offset: usize },
+    NoModule,
+}
+
+impl std::fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use DeserializeError::*;
+        match self {
+            InvalidMagic => write!(f, "Invalid magic number"),
+            UnsupportedVersion(v) => write!(f, "Unsupported NCGB version: {}", v),
+            UnsupportedFlags(v) => write!(f, "Unsupported flags: {:#x}", v),
+            ChecksumMismatch { expected, actual } => write!(f, "Checksum mismatch: expected {:#010x} but got {:#010x}", expected, actual),
+            TooShort(what) => write!(f, "Data is too short to contain {}", what),
+            UnsupportedFuncFlag(v) => write!(f, "Unsupported func module flag: {}", v),
+            InvalidCharacter { value, offset } => write!(f, "Invalid character {:#x} in a name at word {}", value, offset),
+            TooManyWires(n) => write!(f, "Too many gates and inputs: {}", n),
+            GateInputOutOfBounds { gate, input, wires } => write!(f, "Gate {} refers to wire {} but there are only {} gates and inputs", gate, input, wires),
+            OutputOutOfBounds { output, index, wires } => write!(f, "Output {} refers to wire {} but there are only {} gates and inputs", output, index, wires),
+            UnsupportedTestValue(v) => write!(f, "Unsupported test value: {}", v),
+            UnsupportedTestTicks(v) => write!(f, "Unsupported test ticks: {}", v),
+            LengthMismatch { what, expected, actual } => write!(f, "Length mismatch: {} is {} words but {} words were read", what, expected, actual),
+            TrailingData { offset } => write!(f, "Unexpected data after word {}", offset),
+            NoModule => write!(f, "The container has no module"),
+        }
+    }
+}
+
+impl From<DeserializeError> for String {
+    fn from(error: DeserializeError) -> String {
+        error.to_string()
+    }
+}
+
+/// u32の列を先頭から読む
+struct Reader<'a> {
+    data: &'a [u32],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn word(&mut self, what: &'static str) -> Result<u32, DeserializeError> {
+        let value = *self.data.get(self.index).ok_or(DeserializeError::TooShort(what))?;
+        self.index += 1;
+        Ok(value)
+    }
+    fn words(&mut self, len: usize, what: &'static str) -> Result<&'a [u32], DeserializeError> {
+        let end = self.index.checked_add(len).filter(|&end| end <= self.data.len()).ok_or(DeserializeError::TooShort(what))?;
+        let words = &self.data[self.index..end];
+        self.index = end;
+        Ok(words)
+    }
+    /// 長さ付きの文字列 (UTF-32)
+    fn string(&mut self, what: &'static str) -> Result<String, DeserializeError> {
+        let len = self.word(what)? as usize;
+        let offset = self.index;
+        self.words(len, what)?.iter().enumerate().map(|(i, &value)| {
+            std::char::from_u32(value).ok_or(DeserializeError::InvalidCharacter { value, offset: offset + i })
+        }).collect()
+    }
+    fn strings(&mut self, what: &'static str) -> Result<Vec<String>, DeserializeError> {
+        let len = self.word(what)?;
+        (0..len).map(|_| self.string(what)).collect()
+    }
+    fn finish(&self) -> Result<(), DeserializeError> {
+        if self.index != self.data.len() {
+            return Err(DeserializeError::TrailingData { offset: self.index });
+        }
+        Ok(())
+    }
+}
+
+/// NCGBの最初のモジュールを読む
+pub fn deserialize_from_vec(data: &[u32]) -> Result<Module, DeserializeError> {
+    deserialize_modules(data)?.into_iter().next().ok_or(DeserializeError::NoModule)
+}
+
+/// NCGBの全てのモジュールを読む (バージョン1の単一モジュールのファイルも読める)
+/// ゲートの入力や出力が範囲内にあることなど、VMで実行できることまで確かめる
+pub fn deserialize_modules(data: &[u32]) -> Result<Vec<Module>, DeserializeError> {
     // Check magic number
-    if data.len() < 2 || data[index] != 0x6247434e {
-        return Err("Invalid magic number".to_string());
+    if data.len() < 2 || data[0] != MAGIC {
+        return Err(DeserializeError::InvalidMagic);
     }
-    index += 1;
+    match data[1] {
+        LEGACY_DATA_SIZE => deserialize_legacy(data).map(|module| vec![module]),
+        VERSION => deserialize_container(data),
+        v => Err(DeserializeError::UnsupportedVersion(v)),
+    }
+}
 
-    // Check data size
-    if data[index] != 32 {
-        return Err("Unsupported data size".to_string());
+/// バージョン1: 本体の後にタグだけのシンボルセクションが続くことがある
+fn deserialize_legacy(data: &[u32]) -> Result<Module, DeserializeError> {
+    let mut reader = Reader { data, index: 2 };
+    let mut module = deserialize_module(&mut reader)?;
+    if reader.index < data.len() && data[reader.index] == SYMBOL_SECTION {
+        reader.index += 1;
+        module.symbols = Some(deserialize_symbols(&mut reader)?);
     }
-    index += 1;
+    reader.finish()?;
+    Ok(module)
+}
 
-    // Func Module flag
-    if index >= data.len() {
-        return Err("Data is too short to contain func module flag".to_string());
+fn deserialize_container(data: &[u32]) -> Result<Vec<Module>, DeserializeError> {
+    let mut reader = Reader { data, index: 2 };
+    let flags = reader.word("flags")?;
+    if flags & !FLAG_CHECKSUM != 0 {
+        return Err(DeserializeError::UnsupportedFlags(flags));
+    }
+    // チェックサムを確かめてから中身を読む
+    if flags & FLAG_CHECKSUM != 0 {
+        let body_len = data.len().checked_sub(1).filter(|&n| n >= reader.index).ok_or(DeserializeError::TooShort("checksum"))?;
+        let (body, checksum) = data.split_at(body_len);
+        let actual = crc32(body);
+        if actual != checksum[0] {
+            return Err(DeserializeError::ChecksumMismatch { expected: checksum[0], actual });
+        }
+        reader.data = body;
+    }
+    let module_count = reader.word("module count")?;
+    let mut modules = Vec::new();
+    for _ in 0..module_count {
+        let len = reader.word("module length")? as usize;
+        let mut record = Reader { data: reader.words(len, "module")?, index: 0 };
+        let mut module = deserialize_module(&mut record)?;
+        let section_count = record.word("section count")?;
+        for _ in 0..section_count {
+            let tag = record.word("section tag")?;
+            let section_len = record.word("section length")? as usize;
+            let mut section = Reader { data: record.words(section_len, "section")?, index: 0 };
+            match tag {
+                SYMBOL_SECTION => module.symbols = Some(deserialize_symbols(&mut section)?),
+                GRAPHICAL_SECTION => module.layout = Some(deserialize_layout(&mut section)?),
+                TEST_SECTION => module.test_vectors = deserialize_test_vectors(&mut section)?,
+                // 知らないセクションは読み飛ばす (新しいバージョンで増えたセクション)
+                _ => section.index = section_len,
+            }
+            if section.index != section_len {
+                return Err(DeserializeError::LengthMismatch { what: "section", expected: section_len, actual: section.index });
+            }
+        }
+        if record.index != len {
+            return Err(DeserializeError::LengthMismatch { what: "module", expected: len, actual: record.index });
+        }
+        modules.push(module);
     }
-    let func = match data[index] {
+    reader.finish()?;
+    Ok(modules)
+}
+
+/// モジュールの本体 (func,名前,入出力,ゲート)
+fn deserialize_module(reader: &mut Reader) -> Result<Module, DeserializeError> {
+    // Func Module flag
+    let func = match reader.word("func module flag")? {
         0 => false,
         1 => true,
-        _ => {return Err("Unsupported func module flag".to_string());},
+        v => return Err(DeserializeError::UnsupportedFuncFlag(v)),
     };
-    index += 1;
 
     // Deserialize name
-    if index >= data.len() {
-        return Err("Data is too short to contain name length".to_string());
-    }
-    let name_len = data[index] as usize;
-    index += 1;
-
-    if index + name_len > data.len() {
-        return Err("Data is too short to contain name".to_string());
-    }
-    let name = data[index..index + name_len].to_vec().into_iter().filter_map(std::char::from_u32).collect();
-    index += name_len;
+    let name = reader.string("name")?;
 
     // Deserialize inputs
-    if index >= data.len() {
-        return Err("Data is too short to contain inputs".to_string());
-    }
-    let inputs = data[index];
-    index += 1;
+    let inputs = reader.word("inputs")?;
 
     // Deserialize outputs
-    if index >= data.len() {
-        return Err("Data is too short to contain outputs length".to_string());
-    }
-    let outputs_len = data[index] as usize;
-    index += 1;
-
-    if index + outputs_len > data.len() {
-        return Err("Data is too short to contain outputs".to_string());
-    }
-    let outputs = data[index..index + outputs_len].to_vec();
-    index += outputs_len;
+    let outputs_len = reader.word("outputs length")? as usize;
+    let outputs = reader.words(outputs_len, "outputs")?.to_vec();
 
     // Deserialize gates
-    if index >= data.len() {
-        return Err("Data is too short to contain gates length".to_string());
-    }
-    let gates_len_sequential = data[index] as usize;
-    index += 1;
-    if index >= data.len() {
-        return Err("Data is too short to contain gates length".to_string());
-    }
-    let gates_len_symmetry = data[index] as usize;
-    index += 1;
+    let gates_len_sequential = reader.word("gates length")? as usize;
+    let gates_len_symmetry = reader.word("gates length")? as usize;
+    let mut gates = |len: usize| -> Result<Vec<NORGate>, DeserializeError> {
+        let words = reader.words(len.checked_mul(2).ok_or(DeserializeError::TooShort("gates"))?, "gates")?;
+        Ok(words.chunks(2).map(|gate| (gate[0], gate[1])).collect())
+    };
+    let gates_sequential = gates(gates_len_sequential)?;
+    let gates_symmetry = gates(gates_len_symmetry)?;
 
-    if index + gates_len_sequential * 2 > data.len() {
-        return Err("Data is too short to contain gates".to_string());
-    }
-    let mut gates_sequential = Vec::new();
-    for _ in 0..gates_len_sequential {
-        let gate: NORGate = (data[index], data[index + 1]);
-        gates_sequential.push(gate);
-        index += 2;
+    // 全てのゲートの入力と出力が、ゲートと入力のどれかを指していることを確かめる
+    let wires = gates_sequential.len() + gates_symmetry.len() + inputs as usize;
+    if wires > MAX_WIRES {
+        return Err(DeserializeError::TooManyWires(wires));
     }
-    if index + gates_len_symmetry * 2 > data.len() {
-        return Err("Data is too short to contain gates".to_string());
+    for (gate, &(a, b)) in gates_sequential.iter().chain(&gates_symmetry).enumerate() {
+        if let Some(&input) = [a, b].iter().find(|&&input| input as usize >= wires) {
+            return Err(DeserializeError::GateInputOutOfBounds { gate, input, wires });
+        }
     }
-    let mut gates_symmetry = Vec::new();
-    for _ in 0..gates_len_symmetry {
-        let gate: NORGate = (data[index], data[index + 1]);
-        gates_symmetry.push(gate);
-        index += 2;
+    if let Some((output, &index)) = outputs.iter().enumerate().find(|(_, &index)| index as usize >= wires) {
+        return Err(DeserializeError::OutputOutOfBounds { output, index, wires });
     }
 
     // init cond
     let mut cond = Vec::new();
-    cond.resize(gates_sequential.len()+gates_symmetry.len()+inputs as usize, false);
+    cond.resize(wires, false);
 
     Ok(Module {
         func,
         name,
         inputs,
         outputs,
-        gates_sequential: gates_sequential,
-        gates_symmetry: gates_symmetry,
+        gates_sequential,
+        gates_symmetry,
         cond,
         tick: 0,
+        symbols: None,
+        layout: None,
+        test_vectors: Vec::new(),
+        events: None, trace: None,
     })
-}
\ No newline at end of file
+}
+
+fn deserialize_symbols(reader: &mut Reader) -> Result<Symbols, DeserializeError> {
+    let inputs = reader.strings("symbols")?;
+    let outputs = reader.strings("symbols")?;
+    let gates_len = reader.word("symbols")? as usize;
+    let mut gates = Vec::new();
+    for _ in 0..gates_len {
+        let path = reader.string("symbols")?;
+        let wire = reader.string("symbols")?;
+        gates.push((path, wire));
+    }
+    Ok(Symbols {
+        inputs,
+        outputs,
+        gates,
+    })
+}
+
+fn deserialize_layout(reader: &mut Reader) -> Result<Layout, DeserializeError> {
+    let width = reader.word("graphical size")?;
+    let height = reader.word("graphical size")?;
+    let pixels_len = reader.word("pixels length")?;
+    let mut pixels = Vec::new();
+    for _ in 0..pixels_len {
+        let mut word = || reader.word("pixel");
+        pixels.push(LayoutPixel {
+            x: word()?,
+            y: word()?,
+            output: word()? != 0,
+            index: word()?,
+            on: word()?,
+            off: word()?,
+        });
+    }
+    Ok(Layout { width, height, pixels })
+}
+
+fn deserialize_test_vectors(reader: &mut Reader) -> Result<Vec<TestVector>, DeserializeError> {
+    fn values(reader: &mut Reader) -> Result<Vec<Option<bool>>, DeserializeError> {
+        let len = reader.word("test values length")?;
+        (0..len).map(|_| match reader.word("test value")? {
+            0 => Ok(Some(false)),
+            1 => Ok(Some(true)),
+            2 => Ok(None),
+            v => Err(DeserializeError::UnsupportedTestValue(v)),
+        }).collect()
+    }
+    let patterns_len = reader.word("test patterns length")?;
+    let mut vectors = Vec::new();
+    for _ in 0..patterns_len {
+        let kind = reader.word("test ticks")?;
+        let count = reader.word("test ticks")?;
+        let ticks = match kind {
+            0 => VectorTicks::Default,
+            1 => VectorTicks::Count(count),
+            2 => VectorTicks::Stable,
+            v => return Err(DeserializeError::UnsupportedTestTicks(v)),
+        };
+        let inputs = values(reader)?;
+        let outputs = values(reader)?;
+        vectors.push(TestVector { ticks, inputs, outputs });
+    }
+    Ok(vectors)
+}
+
+/// CRC-32 (IEEE) 各u32をリトルエンディアンのバイト列として計算する
+pub fn crc32(data: &[u32]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data.iter().flat_map(|word| word.to_le_bytes()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::vm::fuzz::{check_case, fuzz_case};
+
+    /// 壊す元にする、シンボル,graphical,テストのセクションのあるNCGB
+    fn sample() -> Vec<u32> {
+        let source = "
+            using nor:2->1;
+            module not (x)->(a) { a: nor <- x x; }
+            module sr_latch (s r)->(q nq) {
+                q: nor <- r nq;
+                nq: nor <- s q;
+            }
+            graphical sr_latch: 2x1 {
+                0,0: i0 <- #21cf4c #303050;
+                1,0: o0 <- #21af4c #303030;
+            }
+            test sr_latch:2->2 { 1 0 -> 1 0; 0 0 -> 1 0; }
+        ";
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        crate::compiler::serialize_modules(&products, &["not".to_string(),"sr_latch".to_string()], &crate::compiler::NcgbOptions::default()).unwrap()
+    }
+
+    /// ランダムなデータや壊れたデータを読んでもpanicせず、読めたものはVMで動かせる
+    #[test]
+    fn random_buffers() {
+        let samples = vec![sample()];
+        assert_eq!(deserialize_modules(&samples[0]).map(|modules| modules.len()), Ok(2));
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut accepted = 0;
+        for case in 0..2000 {
+            let data = fuzz_case(&mut rng, &samples);
+            let seed = rng.gen();
+            match std::panic::catch_unwind(|| check_case(&data, seed)) {
+                Ok(Ok(true)) => accepted += 1,
+                Ok(Ok(false)) => {},
+                Ok(Err(e)) => panic!("case {} was accepted but failed to run: {}: {:08x?}", case, e, data),
+                Err(_) => panic!("case {} panicked: {:08x?}", case, data),
+            }
+        }
+        assert!(accepted>0);
+    }
+
+    /// バージョン1の単一モジュールのNCGB (MAGIC 32 本体 [SYMBOL_SECTION シンボル]) も読める
+    #[test]
+    fn legacy_single_module() {
+        let text = |s: &str| std::iter::once(s.chars().count() as u32).chain(s.chars().map(|c| c as u32)).collect::<Vec<_>>();
+        // not: func, 入力1, 出力はゲート0, ゲート0は入力0 (ゲート数1の後の番号) 同士のnor
+        let mut data = vec![MAGIC, 32, 1];
+        data.extend(text("not"));
+        data.extend([1, 1, 0, 1, 0, 1, 1]);
+        let module = deserialize_from_vec(&data).unwrap();
+        assert_eq!((module.func, module.name.as_str(), module.inputs, module.outputs.clone()), (true, "not", 1, vec![0]));
+        assert_eq!(module.gates_sequential, vec![(1, 1)]);
+        assert!(module.symbols.is_none());
+        let mut vm = Module::new(data.clone()).unwrap();
+        vm.next(1).unwrap();
+        assert_eq!(vm.get_output(), Ok(vec![true]));
+        vm.set(0, true).unwrap();
+        vm.next(1).unwrap();
+        assert_eq!(vm.get_output(), Ok(vec![false]));
+        // タグだけのシンボルセクション (入力名[] 出力名[] ゲート( 階層 ワイヤ名 )[])
+        data.extend([SYMBOL_SECTION, 1]);
+        data.extend(text("x"));
+        data.push(1);
+        data.extend(text("a"));
+        data.push(1);
+        data.extend(text(""));
+        data.extend(text("a"));
+        let module = deserialize_from_vec(&data).unwrap();
+        let symbols = module.symbols.unwrap();
+        assert_eq!((symbols.inputs, symbols.outputs, symbols.gates), (vec!["x".to_string()], vec!["a".to_string()], vec![(String::new(), "a".to_string())]));
+        // 余分なデータがあれば読めない
+        data.push(0);
+        assert!(deserialize_modules(&data).is_err());
+    }
+
+    /// 1ワードずつ切り詰めても、余分なデータを付けてもpanicしない
+    #[test]
+    fn truncated_buffers() {
+        let data = sample();
+        for len in 0..data.len() {
+            assert!(deserialize_modules(&data[..len]).is_err());
+        }
+        let mut longer = data.clone();
+        longer.push(0);
+        assert!(deserialize_modules(&longer).is_err());
+    }
+}
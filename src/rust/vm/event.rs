@@ -0,0 +1,147 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use super::types::*;
+
+/// これ以上のゲートがあるモジュールは、テストをイベント駆動で動かす (`Module::new` では有効にしない)
+pub const EVENT_DRIVEN_MIN_GATES: usize = 4096;
+
+/// イベント駆動で動かすための状態
+/// 入力が変わったゲートだけを評価する
+/// 評価の順番とsequential,symmetryの違いは `Module::next` と同じなので、毎tick全てのゲートを評価した場合と同じ状態になる
+#[derive(Clone)]
+pub struct Events {
+    /// ワイヤ (ゲートとinput) ごとの、それを入力にするゲート `fanout[fanout_start[w]..fanout_start[w+1]]`
+    fanout_start: Vec<u32>,
+    fanout: Vec<u32>,
+    sequential_len: usize,
+    /// 次のtickで評価するゲート
+    pending: Vec<u32>,
+    pending_flag: Vec<bool>,
+    /// 今のtickのsequentialで評価するゲート (番号の小さい順に取り出す)
+    sequential: BinaryHeap<Reverse<u32>>,
+    /// 今のtickのsymmetryで評価するゲート
+    symmetry: Vec<u32>,
+    current_flag: Vec<bool>,
+}
+
+impl Events {
+    /// ゲートの入力からfan-outの一覧を作る (最初は全てのゲートを評価する)
+    pub fn new(module: &Module) -> Self {
+        let gates = module.gates_sequential.iter().chain(&module.gates_symmetry).collect::<Vec<_>>();
+        let mut counts = vec![0u32; module.cond.len() + 1];
+        for gate in &gates {
+            counts[gate.0 as usize + 1] += 1;
+            if gate.1!=gate.0 {
+                counts[gate.1 as usize + 1] += 1;
+            }
+        }
+        let mut fanout_start = counts;
+        for i in 1..fanout_start.len() {
+            fanout_start[i] += fanout_start[i-1];
+        }
+        let mut fill = fanout_start.clone();
+        let mut fanout = vec![0u32; gates.len()*2];
+        for (index, gate) in gates.iter().enumerate() {
+            for (n, wire) in [gate.0, gate.1].iter().enumerate() {
+                if n==1 && gate.1==gate.0 {
+                    continue;
+                }
+                fanout[fill[*wire as usize] as usize] = index as u32;
+                fill[*wire as usize] += 1;
+            }
+        }
+        fanout.truncate(*fanout_start.last().unwrap_or(&0) as usize);
+        let mut events = Events {
+            fanout_start,
+            fanout,
+            sequential_len: module.gates_sequential.len(),
+            pending: Vec::new(),
+            pending_flag: vec![false; gates.len()],
+            sequential: BinaryHeap::new(),
+            symmetry: Vec::new(),
+            current_flag: vec![false; gates.len()],
+        };
+        events.mark_all();
+        events
+    }
+    fn fanout_of(&self, wire: usize) -> std::ops::Range<usize> {
+        self.fanout_start[wire] as usize..self.fanout_start[wire+1] as usize
+    }
+    /// 全てのゲートを次のtickで評価する (状態を外から書き換えたとき)
+    pub fn mark_all(&mut self) {
+        for gate in 0..self.pending_flag.len() {
+            self.mark(gate as u32);
+        }
+    }
+    fn mark(&mut self, gate: u32) {
+        if !self.pending_flag[gate as usize] {
+            self.pending_flag[gate as usize] = true;
+            self.pending.push(gate);
+        }
+    }
+    /// ワイヤの値が変わったので、それを入力にするゲートを次のtickで評価する
+    pub fn mark_fanout(&mut self, wire: usize) {
+        for i in self.fanout_of(wire) {
+            self.mark(self.fanout[i]);
+        }
+    }
+    fn push_current(&mut self, gate: u32) {
+        if !self.current_flag[gate as usize] {
+            self.current_flag[gate as usize] = true;
+            if (gate as usize)<self.sequential_len { self.sequential.push(Reverse(gate)); }
+            else { self.symmetry.push(gate); }
+        }
+    }
+    /// 1tick進める
+    /// on_change: 値が変わったゲートごとに呼ぶ
+    /// @return 状態が変わったかどうか
+    pub fn step(&mut self, gates_sequential: &[NORGate], gates_symmetry: &[NORGate], cond: &mut GatesCond, mut on_change: impl FnMut(usize)) -> Result<bool,String> {
+        for gate in std::mem::take(&mut self.pending) {
+            self.pending_flag[gate as usize] = false;
+            self.push_current(gate);
+        }
+        let mut changed = false;
+        { // sequential: 後ろのゲートへの変化は同じtickで、前のゲートへの変化は次のtickで評価する
+            while let Some(Reverse(gate)) = self.sequential.pop() {
+                self.current_flag[gate as usize] = false;
+                let (input1, input2) = *gates_sequential.get(gate as usize).ok_or("gates access error")?;
+                let value = !(*cond.get(input1 as usize).ok_or("gates access error")? || *cond.get(input2 as usize).ok_or("gates access error")?);
+                if cond[gate as usize]==value {
+                    continue;
+                }
+                cond[gate as usize] = value;
+                changed = true;
+                on_change(gate as usize);
+                for i in self.fanout_of(gate as usize) {
+                    let target = self.fanout[i];
+                    if (target as usize)<self.sequential_len && target<=gate { self.mark(target); }
+                    else { self.push_current(target); }
+                }
+            }
+        }
+        { // symmetry: sequentialの後の状態から全て同時に評価し、変化は次のtickで評価する
+            let symmetry = std::mem::take(&mut self.symmetry);
+            let mut updates = Vec::new();
+            for &gate in &symmetry {
+                self.current_flag[gate as usize] = false;
+                let (input1, input2) = *gates_symmetry.get(gate as usize - self.sequential_len).ok_or("gates access error")?;
+                let value = !(*cond.get(input1 as usize).ok_or("gates access error")? || *cond.get(input2 as usize).ok_or("gates access error")?);
+                if cond[gate as usize]!=value {
+                    updates.push(gate);
+                }
+            }
+            for gate in updates {
+                cond[gate as usize] = !cond[gate as usize];
+                changed = true;
+                on_change(gate as usize);
+                for i in self.fanout_of(gate as usize) {
+                    self.mark(self.fanout[i]);
+                }
+            }
+            self.symmetry = symmetry;
+            self.symmetry.clear();
+        }
+        Ok(changed)
+    }
+}
@@ -0,0 +1,98 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::deserializer::{crc32, deserialize_modules};
+
+/// fuzzで実行まで確かめるモジュールのゲートと入力の数の上限
+const FUZZ_MAX_WIRES: usize = 1 << 16;
+
+/// 正しい構造のランダムな回路 (バージョン1の形式)
+pub fn random_module(rng: &mut StdRng) -> Vec<u32> {
+    let inputs = rng.gen_range(0..8);
+    let sequential = rng.gen_range(0..48);
+    let symmetry = rng.gen_range(0..48);
+    let wires = inputs + sequential + symmetry;
+    let mut data = vec![0x6247434e, 32, rng.gen_range(0..2), 1, 'r' as u32, inputs];
+    let outputs = if wires==0 { 0 } else { rng.gen_range(0..8) };
+    data.push(outputs);
+    data.extend((0..outputs).map(|_| rng.gen_range(0..wires)));
+    data.extend([sequential, symmetry]);
+    data.extend((0..(sequential+symmetry)*2).map(|_| rng.gen_range(0..wires)));
+    data
+}
+
+/// ランダムなデータを作る
+/// 正しい構造の回路、正しいファイルを少し壊したもの、ヘッダだけ正しいものを混ぜる
+#[allow(dead_code)] // circuitgame_binのverifyとテストだけが使う
+pub fn fuzz_case(rng: &mut StdRng, samples: &[Vec<u32>]) -> Vec<u32> {
+    if rng.gen_bool(0.3) {
+        random_module(rng)
+    }
+    else if !samples.is_empty() && rng.gen_bool(0.9) {
+        let mut data = samples[rng.gen_range(0..samples.len())].clone();
+        for _ in 0..rng.gen_range(1..=4) {
+            if data.is_empty() {
+                break;
+            }
+            let index = rng.gen_range(0..data.len());
+            match rng.gen_range(0..5) {
+                0 => data[index] ^= 1 << rng.gen_range(0..32),
+                1 => data[index] = rng.gen_range(0..64),
+                2 => data[index] = rng.gen(),
+                3 => data.truncate(index),
+                _ => data.insert(index, rng.gen_range(0..64)),
+            }
+        }
+        // チェックサムで弾かれるだけにならないよう、半分は付け直して中身まで読ませる
+        if data.len() > 3 && data[1] == 2 && data[2] & 1 != 0 && rng.gen_bool(0.5) {
+            let last = data.len() - 1;
+            data[last] = crc32(&data[..last]);
+        }
+        data
+    }
+    else {
+        let len = rng.gen_range(0..64);
+        let mut data = (0..len).map(|_| if rng.gen_bool(0.5) { rng.gen_range(0..8) } else { rng.gen() }).collect::<Vec<u32>>();
+        if rng.gen_bool(0.5) {
+            data.splice(0..0, [0x6247434e, if rng.gen_bool(0.5) { 32 } else { 2 }, 0]);
+        }
+        data
+    }
+}
+
+/// 読み込めたモジュールを実行して、失敗したケースの説明を返す
+#[allow(dead_code)]
+pub fn check_case(data: &[u32], rng_seed: u64) -> Result<bool, String> {
+    let modules = match deserialize_modules(data) {
+        Ok(v) => v,
+        Err(_) => return Ok(false),
+    };
+    let mut rng = StdRng::seed_from_u64(rng_seed);
+    for mut module in modules {
+        // 壊れて入力の数が大きくなっただけのモジュールは、動かしても時間がかかるだけなので読めることだけを確かめる
+        if module.cond.len()>FUZZ_MAX_WIRES {
+            continue;
+        }
+        // イベント駆動でも毎tick全てのゲートを評価した場合と同じ状態になるか
+        let mut events = module.clone();
+        events.set_event_driven(true);
+        for tick in 0..8 {
+            if module.inputs>0 && rng.gen_bool(0.5) {
+                let index = rng.gen_range(0..module.inputs);
+                let value = rng.gen();
+                module.set(index, value)?;
+                events.set(index, value)?;
+            }
+            module.next(1)?;
+            events.next(1)?;
+            if module.get_gates()!=events.get_gates() {
+                return Err(format!("the event-driven VM differs after {} ticks", tick+1));
+            }
+        }
+        if module.clone().run_until_stable(64)?!=events.clone().run_until_stable(64)? {
+            return Err("the event-driven VM detects a different stability".to_string());
+        }
+        module.get_output()?;
+        module.get_input()?;
+    }
+    Ok(true)
+}
@@ -1,5 +1,11 @@
 pub mod types;
 pub mod deserializer;
+pub mod bitslice;
+pub mod event;
+pub mod trace;
+/// 壊れたNCGBを読ませるためのデータ (`verify --fuzz` とテストで使う)
+#[cfg(any(feature = "native", test))]
+pub mod fuzz;
 
 use types::*;
 
@@ -8,30 +14,77 @@ use types::*;
 
 impl Module {
     pub fn new(data: Vec<u32>) -> Result<Self,String> {
-        deserializer::deserialize_from_vec(&data)
+        deserializer::deserialize_from_vec(&data).map_err(String::from)
     }
+    /// イベント駆動 (入力が変わったゲートだけを評価する) で動かすかどうか
+    /// どちらでも同じ状態になる (読み込んだときは毎tick全てのゲートを評価する)
+    pub fn set_event_driven(&mut self, enabled: bool) {
+        self.events = if enabled { Some(event::Events::new(self)) } else { None };
+    }
+    /// inputとoutputの値の変化の記録を始める (wiresなら名前の付いた全てのゲートも記録する)
+    #[allow(dead_code)] // circuitgame_binのrun,testだけが使う
+    pub fn start_trace(&mut self, wires: bool) {
+        self.trace = Some(trace::Trace::new(self, wires));
+    }
+    /// 記録を終えて取り出す
+    #[allow(dead_code)]
+    pub fn take_trace(&mut self) -> Option<trace::Trace> {
+        self.trace.take()
+    }
+    fn record(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            trace.record(self.tick, &self.cond);
+        }
+    }
+    #[allow(dead_code)]
     pub fn reset(&mut self) {
         self.cond.fill(false);
         self.tick=0;
+        if let Some(events) = &mut self.events {
+            events.mark_all();
+        }
+        self.record();
+    }
+    /// 全てのinput,gateの状態を設定する
+    pub fn set_state(&mut self, cond: GatesCond) {
+        self.cond = cond;
+        if let Some(events) = &mut self.events {
+            events.mark_all();
+        }
+        self.record();
     }
     /// inputを指定した値にする
     pub fn set(&mut self,index: u32,value: bool) -> Result<(),String> {
-        match self.cond.get_mut(index as usize + &self.gates_sequential.len() + &self.gates_symmetry.len()) {
+        let wire = index as usize + self.gates_sequential.len() + self.gates_symmetry.len();
+        match self.cond.get_mut(wire) {
             Some(v)=>{
+                if *v==value {
+                    return Ok(());
+                }
                 *v = value;
             },
             None =>{return Err(format!("Index out of bounds"));}
         };
+        if let Some(events) = &mut self.events {
+            events.mark_fanout(wire);
+        }
+        self.record();
         Ok(())
     }
     /// inputを反転する
+    #[allow(dead_code)]
     pub fn inv(&mut self,index: u32) -> Result<(),String> {
-        match self.cond.get_mut(index as usize + &self.gates_sequential.len() + &self.gates_symmetry.len()) {
+        let wire = index as usize + self.gates_sequential.len() + self.gates_symmetry.len();
+        match self.cond.get_mut(wire) {
             Some(v)=>{
                 *v = !*v;
             },
             None =>{return Err(format!("Index out of bounds"));}
         };
+        if let Some(events) = &mut self.events {
+            events.mark_fanout(wire);
+        }
+        self.record();
         Ok(())
     }
     /// 全てのoutputを取得する
@@ -48,6 +101,7 @@ impl Module {
         Ok(outputs)
     }
     /// 全てのinputを取得する
+    #[allow(dead_code)]
     pub fn get_input(&self) -> Result<GatesCond,String> {
         let mut inputs = Vec::new();
         let gates = self.gates_sequential.len() + &self.gates_symmetry.len();
@@ -65,36 +119,224 @@ impl Module {
     pub fn get_gates(&self) -> GatesCond {
         self.cond.clone()
     }
+    /// ゲートの名前 (シンボルがなければ `b{index}`)
+    pub fn gate_name(&self, index: usize) -> String {
+        match self.symbols.as_ref().and_then(|symbols| symbols.gates.get(index)) {
+            Some((path,wire)) if path.is_empty() => wire.clone(),
+            Some((path,wire)) => format!("{}.{}",path,wire),
+            None => format!("b{}",index),
+        }
+    }
+    /// inputの名前 (シンボルがなければ `i{index}`)
+    #[allow(dead_code)]
+    pub fn input_name(&self, index: usize) -> String {
+        self.symbols.as_ref().and_then(|symbols| symbols.inputs.get(index)).cloned().unwrap_or_else(|| format!("i{}",index))
+    }
+    /// outputの名前 (シンボルがなければ `o{index}`)
+    #[allow(dead_code)]
+    pub fn output_name(&self, index: usize) -> String {
+        self.symbols.as_ref().and_then(|symbols| symbols.outputs.get(index)).cloned().unwrap_or_else(|| format!("o{}",index))
+    }
+    /// 全てのinput,gateの状態を名前付きで一行ずつ出力する (webのツール向け)
+    #[allow(dead_code)]
+    pub fn dump_state(&self) -> String {
+        let gates_len = self.gates_sequential.len() + self.gates_symmetry.len();
+        self.cond.iter().enumerate().map(|(index,value)| {
+            let name = if index<gates_len { self.gate_name(index) } else { self.input_name(index-gates_len) };
+            format!("{} {}",if *value {"t"} else {"f"},name)
+        }).collect::<Vec<_>>().join("\n")
+    }
     /// 現在のtickを取得する
+    #[allow(dead_code)]
     pub fn get_tick(&self) -> u128 {
         self.tick
     }
     /// gatesをn周更新する
     pub fn next(&mut self,n: u32) -> Result<u128, String> {
         for _ in 0..n {
-            let mut gate_index = 0;
-            { // sequential
-                for gate in &self.gates_sequential {
-                    let input1 = *self.cond.get(gate.0 as usize).ok_or("gates access error")?;
-                    let input2 = *self.cond.get(gate.1 as usize).ok_or("gates access error")?;
-                    let output = self.cond.get_mut(gate_index).ok_or("gates access error")?;
-                    *output = !(input1||input2); // input1,input2のNORを計算する
-                    gate_index+=1;
+            self.step()?;
+        }
+        Ok(self.tick)
+    }
+    /// 状態が変わらなくなるまで、max_ticksまで進める
+    /// 前に通った状態に戻ったら (状態のハッシュで見つけて、もう一周して確かめる) 振動しているとして止める
+    /// ハッシュは値が変わったゲートの分だけ更新する (イベント駆動なら毎tick全てのワイヤを見ずに済む)
+    pub fn run_until_stable(&mut self, max_ticks: u64) -> Result<Stability,String> {
+        let mut hash = self.state_hash();
+        let mut seen = std::collections::HashMap::new();
+        seen.insert(hash, 0u64);
+        let mut ticks = 0;
+        while ticks<max_ticks {
+            ticks += 1;
+            if !self.step_with(|gate| hash ^= wire_key(gate))? {
+                return Ok(Stability::Stable { ticks });
+            }
+            if let Some(&before) = seen.get(&hash) {
+                let period = ticks-before;
+                if ticks+period>max_ticks {
+                    break;
+                }
+                ticks += period;
+                if let Some(toggling) = self.confirm_cycle(period, &mut hash)? {
+                    return Ok(Stability::Oscillating { ticks, period, toggling });
+                }
+            }
+            seen.insert(hash, ticks);
+        }
+        Ok(Stability::Unsettled { ticks })
+    }
+    /// periodだけ進めて今の状態に戻るかを確かめる (全てのゲートが偶数回変われば戻っている)
+    /// @return 戻ればその間に値が変わったゲート
+    fn confirm_cycle(&mut self, period: u64, hash: &mut u64) -> Result<Option<Vec<usize>>,String> {
+        let mut toggled = std::collections::HashSet::new();
+        let mut odd = std::collections::HashSet::new();
+        for _ in 0..period {
+            self.step_with(|gate| {
+                *hash ^= wire_key(gate);
+                toggled.insert(gate);
+                if !odd.remove(&gate) {
+                    odd.insert(gate);
+                }
+            })?;
+        }
+        if !odd.is_empty() {
+            return Ok(None);
+        }
+        let mut toggling = toggled.into_iter().collect::<Vec<_>>();
+        toggling.sort_unstable();
+        Ok(Some(toggling))
+    }
+    /// 値がtrueのワイヤのwire_keyのXOR (ワイヤの値が変わったらそのwire_keyをXORすれば更新できる)
+    fn state_hash(&self) -> u64 {
+        self.cond.iter().enumerate().filter(|(_, value)| **value).fold(0, |hash, (wire, _)| hash ^ wire_key(wire))
+    }
+    /// 今の状態で1tick進めても何も変わらないか (全てのゲートが入力のNORになっているか)
+    pub fn is_stable(&self) -> bool {
+        self.gates_sequential.iter().chain(&self.gates_symmetry).enumerate().all(|(index, gate)| {
+            match (self.cond.get(gate.0 as usize), self.cond.get(gate.1 as usize)) {
+                (Some(input1), Some(input2)) => self.cond[index] != (*input1||*input2),
+                _ => false,
+            }
+        })
+    }
+    /// gatesを1周更新する
+    /// @return 状態が変わったかどうか
+    fn step(&mut self) -> Result<bool,String> {
+        self.step_with(|_| {})
+    }
+    /// on_change: 値が変わったゲートごとに呼ぶ
+    fn step_with(&mut self, on_change: impl FnMut(usize)) -> Result<bool,String> {
+        let changed = self.evaluate(on_change)?;
+        self.record();
+        Ok(changed)
+    }
+    fn evaluate(&mut self, mut on_change: impl FnMut(usize)) -> Result<bool,String> {
+        self.tick+=1;
+        if let Some(events) = &mut self.events {
+            return events.step(&self.gates_sequential, &self.gates_symmetry, &mut self.cond, on_change);
+        }
+        let mut changed = false;
+        let mut gate_index = 0;
+        { // sequential
+            for gate in &self.gates_sequential {
+                let input1 = *self.cond.get(gate.0 as usize).ok_or("gates access error")?;
+                let input2 = *self.cond.get(gate.1 as usize).ok_or("gates access error")?;
+                let output = self.cond.get_mut(gate_index).ok_or("gates access error")?;
+                let value = !(input1||input2); // input1,input2のNORを計算する
+                if *output!=value {
+                    changed = true;
+                    on_change(gate_index);
                 }
+                *output = value;
+                gate_index+=1;
             }
-            let mut gate_index = 0;
-            { // symmetry
-                let before_cond = self.cond.clone();
-                for gate in &self.gates_symmetry {
-                    let input1 = *before_cond.get(gate.0 as usize).ok_or("gates access error")?;
-                    let input2 = *before_cond.get(gate.1 as usize).ok_or("gates access error")?;
-                    let output = self.cond.get_mut(gate_index).ok_or("gates access error")?;
-                    *output = !(input1||input2); // input1,input2のNORを計算する
-                    gate_index+=1;
+        }
+        let mut gate_index = self.gates_sequential.len();
+        { // symmetry
+            let before_cond = self.cond.clone();
+            for gate in &self.gates_symmetry {
+                let input1 = *before_cond.get(gate.0 as usize).ok_or("gates access error")?;
+                let input2 = *before_cond.get(gate.1 as usize).ok_or("gates access error")?;
+                let output = self.cond.get_mut(gate_index).ok_or("gates access error")?;
+                let value = !(input1||input2); // input1,input2のNORを計算する
+                if *output!=value {
+                    changed = true;
+                    on_change(gate_index);
                 }
+                *output = value;
+                gate_index+=1;
             }
-            self.tick+=1;
         }
-        Ok(self.tick)
+        Ok(changed)
+    }
+}
+
+/// 状態のハッシュに使うワイヤごとの値 (splitmix64)
+fn wire_key(wire: usize) -> u64 {
+    let mut z = (wire as u64).wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Stability {
+    /// 進めたtick数
+    pub fn ticks(&self) -> u64 {
+        match self {
+            Stability::Stable { ticks } | Stability::Oscillating { ticks, .. } | Stability::Unsettled { ticks } => *ticks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use super::deserializer::MAGIC;
+
+    /// 3つのnotの輪は2tickごとに同じ状態を繰り返す (イベント駆動でも同じ)
+    #[test]
+    fn ring_oscillates() {
+        let mut data = vec![MAGIC, 32, 1, 4];
+        data.extend("ring".chars().map(|c| c as u32));
+        data.extend([0, 1, 0, 3, 0, 2, 2, 0, 0, 1, 1]);
+        for event_driven in [false, true] {
+            let mut vm = Module::new(data.clone()).unwrap();
+            vm.set_event_driven(event_driven);
+            assert_eq!(vm.run_until_stable(64), Ok(Stability::Oscillating { ticks: 5, period: 2, toggling: vec![0, 1, 2] }));
+            assert_eq!(vm.run_until_stable(64), Ok(Stability::Oscillating { ticks: 4, period: 2, toggling: vec![0, 1, 2] }));
+        }
+    }
+
+    /// ランダムな回路をランダムに動かしても, イベント駆動と毎tick全てのゲートを評価した場合で同じ状態になる
+    #[test]
+    fn event_driven_matches_serial() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for case in 0..300 {
+            let mut serial = Module::new(super::fuzz::random_module(&mut rng)).unwrap();
+            let mut events = serial.clone();
+            events.set_event_driven(true);
+            for step in 0..16 {
+                match rng.gen_range(0..8) {
+                    0 => {
+                        let cond = (0..serial.cond.len()).map(|_| rng.gen()).collect::<GatesCond>();
+                        serial.set_state(cond.clone());
+                        events.set_state(cond);
+                    },
+                    1..=4 if serial.inputs>0 => {
+                        let (index, value) = (rng.gen_range(0..serial.inputs), rng.gen());
+                        serial.set(index, value).unwrap();
+                        events.set(index, value).unwrap();
+                    },
+                    _ => {},
+                }
+                let ticks = rng.gen_range(1..4);
+                assert_eq!(serial.next(ticks), events.next(ticks), "case {} step {}", case, step);
+                assert_eq!(serial.cond, events.cond, "case {} step {}", case, step);
+            }
+            assert_eq!(serial.run_until_stable(64), events.run_until_stable(64), "case {}", case);
+            assert_eq!(serial.cond, events.cond, "case {}", case);
+        }
     }
 }
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use super::types::*;
+
+/// VCD (Value Change Dump) に書き出すための、信号の値の変化の記録
+/// 1tickをVCDの1nsとして記録する
+#[derive(Clone)]
+pub struct Trace {
+    module: String,
+    /// 記録するワイヤ (condの番号), VCDの識別子はこの並びの番号から作る
+    wires: Vec<usize>,
+    /// VCDの変数 `( scope, 名前, wiresの番号 )`
+    signals: Vec<(Vec<String>,String,usize)>,
+    /// 記録を始めたtickとそのときの値
+    start: u128,
+    initial: Vec<bool>,
+    /// 最後に記録した値
+    last: Vec<bool>,
+    /// 値が変わったtickごとの `( wiresの番号, 値 )`
+    changes: Vec<(u128,Vec<(usize,bool)>)>,
+    /// 最後に記録したtick
+    end: u128,
+    /// drain_vcdでヘッダーと初期値を書き出したかどうか
+    header_written: bool,
+    /// drain_vcdで書き出した最後のtick
+    written_until: u128,
+}
+
+impl Trace {
+    /// inputとoutputを記録する (wiresなら名前の付いた全てのゲートも記録する)
+    pub fn new(module: &Module, wires: bool) -> Self {
+        let gates_len = module.gates_sequential.len() + module.gates_symmetry.len();
+        let mut trace = Trace {
+            module: module.name.clone(),
+            wires: Vec::new(),
+            signals: Vec::new(),
+            start: module.tick,
+            initial: Vec::new(),
+            last: Vec::new(),
+            changes: Vec::new(),
+            end: module.tick,
+            header_written: false,
+            written_until: module.tick,
+        };
+        // 同じワイヤは同じ識別子にする (outputはゲートでもある)
+        let mut ids = HashMap::new();
+        let mut add = |trace: &mut Trace, scope: Vec<String>, name: String, wire: usize| {
+            let id = *ids.entry(wire).or_insert_with(|| {
+                trace.wires.push(wire);
+                trace.wires.len()-1
+            });
+            trace.signals.push((scope,name,id));
+        };
+        for index in 0..module.inputs as usize {
+            add(&mut trace, Vec::new(), module.input_name(index), gates_len+index);
+        }
+        for (index, wire) in module.outputs.iter().enumerate() {
+            add(&mut trace, Vec::new(), module.output_name(index), *wire as usize);
+        }
+        if wires {
+            let mut named = Vec::new();
+            for (index, (path, wire)) in module.symbols.iter().flat_map(|symbols| symbols.gates.iter()).enumerate().take(gates_len) {
+                if wire.is_empty() {
+                    continue;
+                }
+                // 階層ごとにVCDのscopeにする (inputとoutputと名前が重ならないように `wires` の下に置く)
+                let scope = std::iter::once("wires").chain(path.split('.').filter(|s| !s.is_empty())).map(String::from).collect::<Vec<_>>();
+                named.push((scope, wire.clone(), index));
+            }
+            // 同じscopeの変数をまとめる
+            named.sort_by(|a, b| a.0.cmp(&b.0));
+            for (scope, name, wire) in named {
+                add(&mut trace, scope, name, wire);
+            }
+        }
+        trace.initial = trace.wires.iter().map(|wire| module.cond.get(*wire).copied().unwrap_or(false)).collect();
+        trace.last = trace.initial.clone();
+        trace
+    }
+    /// tickの時点の値を記録する (同じtickなら前の記録に加える)
+    pub fn record(&mut self, tick: u128, cond: &GatesCond) {
+        // resetなどでtickが戻ったら、その時点から記録し直す
+        if tick<self.end {
+            self.start = tick;
+            self.changes.clear();
+            for (id, wire) in self.wires.iter().enumerate() {
+                self.initial[id] = cond.get(*wire).copied().unwrap_or(false);
+            }
+            self.last = self.initial.clone();
+            self.end = tick;
+            return;
+        }
+        self.end = tick;
+        for (id, wire) in self.wires.iter().enumerate() {
+            let value = cond.get(*wire).copied().unwrap_or(false);
+            if self.last[id]==value {
+                continue;
+            }
+            self.last[id] = value;
+            if self.changes.last().is_none_or(|(t, _)| *t!=tick) {
+                // 初期値を書き出した後は、同じtickの変化も変化として書く
+                if tick==self.start && !self.header_written {
+                    self.initial[id] = value;
+                    continue;
+                }
+                self.changes.push((tick, Vec::new()));
+            }
+            if let Some((_, values)) = self.changes.last_mut() {
+                match values.iter_mut().find(|(i, _)| *i==id) {
+                    Some(v) => v.1 = value,
+                    None => values.push((id, value)),
+                }
+            }
+        }
+    }
+    /// 値が変わったtickの数
+    #[allow(dead_code)]
+    pub fn change_count(&self) -> usize {
+        self.changes.len()
+    }
+    /// VCDの文字列にする
+    pub fn to_vcd(&self) -> String {
+        let mut vcd = self.vcd_header();
+        self.push_vcd_changes(&mut vcd);
+        // 最後の値がどこまで続いたかが分かるように、最後のtickも書く
+        if self.changes.last().map_or(self.start, |(tick, _)| *tick)<self.end {
+            vcd.push_str(&format!("#{}\n",self.end));
+        }
+        vcd
+    }
+    /// まだ書き出していない変化をVCDの文字列にして記録から取り除く (長く動かすときにファイルへ少しずつ追記する用)
+    /// 初めて呼んだときはヘッダーと初期値も付ける, finishなら最後の値がどこまで続いたかも書く
+    #[allow(dead_code)] // circuitgame_binのrunだけが使う
+    pub fn drain_vcd(&mut self, finish: bool) -> String {
+        let mut vcd = String::new();
+        if !self.header_written {
+            vcd = self.vcd_header();
+            self.header_written = true;
+        }
+        self.push_vcd_changes(&mut vcd);
+        if let Some((tick, _)) = self.changes.last() {
+            self.written_until = *tick;
+        }
+        self.changes.clear();
+        if finish && self.written_until<self.end {
+            vcd.push_str(&format!("#{}\n",self.end));
+            self.written_until = self.end;
+        }
+        vcd
+    }
+    /// 変数の定義と初期値
+    fn vcd_header(&self) -> String {
+        let mut vcd = String::new();
+        vcd.push_str("$version Neknaj Circuit Game $end\n");
+        vcd.push_str("$comment 1 ns = 1 tick $end\n");
+        vcd.push_str("$timescale 1 ns $end\n");
+        vcd.push_str(&format!("$scope module {} $end\n",vcd_name(&self.module)));
+        let mut current: &[String] = &[];
+        for (scope, name, id) in &self.signals {
+            let common = current.iter().zip(scope).take_while(|(a, b)| a==b).count();
+            for _ in common..current.len() {
+                vcd.push_str("$upscope $end\n");
+            }
+            for s in &scope[common..] {
+                vcd.push_str(&format!("$scope module {} $end\n",vcd_name(s)));
+            }
+            current = scope;
+            vcd.push_str(&format!("$var wire 1 {} {} $end\n",vcd_id(*id),vcd_name(name)));
+        }
+        for _ in 0..current.len() {
+            vcd.push_str("$upscope $end\n");
+        }
+        vcd.push_str("$upscope $end\n");
+        vcd.push_str("$enddefinitions $end\n");
+        vcd.push_str(&format!("#{}\n$dumpvars\n",self.start));
+        for (id, value) in self.initial.iter().enumerate() {
+            vcd.push_str(&format!("{}{}\n",if *value {1} else {0},vcd_id(id)));
+        }
+        vcd.push_str("$end\n");
+        vcd
+    }
+    fn push_vcd_changes(&self, vcd: &mut String) {
+        for (tick, values) in &self.changes {
+            vcd.push_str(&format!("#{}\n",tick));
+            for (id, value) in values {
+                vcd.push_str(&format!("{}{}\n",if *value {1} else {0},vcd_id(*id)));
+            }
+        }
+    }
+}
+
+/// VCDの識別子 (`!` から `~` までの文字を使った94進数)
+fn vcd_id(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index%94) as u8) as char);
+        index /= 94;
+        if index==0 {
+            break;
+        }
+        index -= 1;
+    }
+    id
+}
+
+/// VCDの名前には空白を使えないので置き換える
+fn vcd_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sr_latch() -> Module {
+        let source = "using nor:2->1;\nmodule sr_latch (s r)->(q nq) { q: nor <- r nq; nq: nor <- s q; }\n";
+        let products = crate::compiler::intermediate_products_with_loader(source, "", &|path: &str| Err(format!("File loading is not supported in this test: {}",path)));
+        Module::new(crate::compiler::serialize(products, "sr_latch").unwrap()).unwrap()
+    }
+
+    /// sを立ててから下ろす: tick0のsの変化は初期値に入り, qだけが1tick遅れて変わる
+    fn run(vm: &mut Module, mut between: impl FnMut(&mut Module)) {
+        vm.set(0, true).unwrap();
+        vm.next(2).unwrap();
+        between(vm);
+        vm.set(0, false).unwrap();
+        vm.next(3).unwrap();
+    }
+
+    #[test]
+    fn vcd_of_latch() {
+        let mut vm = sr_latch();
+        vm.start_trace(false);
+        run(&mut vm, |_| {});
+        let vcd = vm.take_trace().unwrap().to_vcd();
+        let (header, changes) = vcd.split_once("$enddefinitions $end\n").unwrap();
+        assert!(header.starts_with("$version Neknaj Circuit Game $end\n"));
+        assert!(header.ends_with("$scope module sr_latch $end\n$var wire 1 ! s $end\n$var wire 1 \" r $end\n$var wire 1 # q $end\n$var wire 1 $ nq $end\n$upscope $end\n"), "{}", header);
+        assert_eq!(changes, "#0\n$dumpvars\n1!\n0\"\n0#\n0$\n$end\n#1\n1#\n#2\n0!\n#5\n");
+    }
+
+    /// 少しずつ書き出しても, まとめて書き出した場合と同じVCDになる
+    #[test]
+    fn drained_vcd_matches() {
+        let mut vm = sr_latch();
+        vm.start_trace(true);
+        let mut drained = vm.trace.as_mut().unwrap().drain_vcd(false);
+        let mut whole = vm.clone();
+        run(&mut vm, |vm| drained.push_str(&vm.trace.as_mut().unwrap().drain_vcd(false)));
+        run(&mut whole, |_| {});
+        drained.push_str(&vm.trace.as_mut().unwrap().drain_vcd(true));
+        assert_eq!(drained, whole.take_trace().unwrap().to_vcd());
+        assert!(drained.contains("$scope module wires $end\n"));
+    }
+}
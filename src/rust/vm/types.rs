@@ -2,6 +2,8 @@ pub type NORGate = (u32,u32);
 
 #[derive(Clone)]
 pub struct Module {
+    /// funcモジュールかどうか (VM自体は使わない, トランスパイラやverify向け)
+    #[allow(dead_code)]
     pub func: bool,
     pub name: String,
     pub inputs: u32,
@@ -10,6 +12,83 @@ pub struct Module {
     pub gates_symmetry: Vec<NORGate>,
     pub cond: GatesCond,
     pub tick: u128,
+    /// NCGBにシンボルセクションがあればゲートの名前
+    pub symbols: Option<Symbols>,
+    /// NCGBにgraphicalのセクションがあれば表示の配置 (VM自体は使わない, NCGBを読む側向け)
+    #[allow(dead_code)]
+    pub layout: Option<Layout>,
+    /// NCGBにテストのセクションがあればテストのパターン (VM自体は使わない, NCGBを読む側向け)
+    #[allow(dead_code)]
+    pub test_vectors: Vec<TestVector>,
+    /// イベント駆動で動かしているときの状態 (Noneなら毎tick全てのゲートを評価する)
+    pub events: Option<super::event::Events>,
+    /// 値の変化を記録しているときの記録 (VCDに書き出す)
+    pub trace: Option<super::trace::Trace>,
+}
+
+/// `run_until_stable` の結果 (ticksは進めたtick数)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stability {
+    /// 状態が変わらなくなった (変わらなかったtickを含む)
+    Stable { ticks: u64 },
+    /// 前に通った状態に戻った, periodごとに同じ状態を繰り返す
+    Oscillating {
+        ticks: u64,
+        period: u64,
+        /// 繰り返す間に値が変わるゲート
+        toggling: Vec<usize>,
+    },
+    /// max_ticksまでに止まらず、繰り返しも見つからなかった
+    Unsettled { ticks: u64 },
+}
+
+/// NCGBのシンボルセクション
+#[derive(Clone)]
+pub struct Symbols {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// ゲートの `( 階層, ワイヤ名 )`
+    pub gates: Vec<(String,String)>,
+}
+
+/// NCGBのgraphicalのセクション
+#[derive(Clone)]
+#[allow(dead_code)]
+pub struct Layout {
+    /// autoなら0
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<LayoutPixel>,
+}
+
+#[derive(Clone)]
+#[allow(dead_code)]
+pub struct LayoutPixel {
+    pub x: u32,
+    pub y: u32,
+    /// 出力のピクセルかどうか (falseなら入力)
+    pub output: bool,
+    pub index: u32,
+    /// 色 0xrrggbb
+    pub on: u32,
+    pub off: u32,
+}
+
+/// NCGBのテストのセクションのパターン (Noneは `x`)
+#[derive(Clone)]
+#[allow(dead_code)]
+pub struct TestVector {
+    pub ticks: VectorTicks,
+    pub inputs: Vec<Option<bool>>,
+    pub outputs: Vec<Option<bool>>,
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum VectorTicks {
+    Default,
+    Count(u32),
+    Stable,
 }
 
 pub type GatesCond = Vec<bool>;
@@ -1,4 +1,4 @@
-import init, { CompilerIntermediateProducts as CompilerIntermediateProducts_raw, Test as Test_raw, Compile, Module, VMreset, VMset, VMgetOutput, VMgetGates, VMgetTick, VMnext, TranspileTS, TranspileTSresId } from './circuitgame_lib.js';
+import init, { CompilerIntermediateProducts as CompilerIntermediateProducts_raw, Test as Test_raw, Compile, Module, VMreset, VMset, VMgetOutput, VMgetGates, VMgetTick, VMdumpState, VMnext, TranspileTS, TranspileTSresId } from './circuitgame_lib.js';
 import { IntermediateProducts, TestProducts } from './types.js';
 import { isIntermediateProducts, isTestProducts } from './typeGuards.js';
 
@@ -35,6 +35,7 @@ const VM = {
     getOutput: VMgetOutput,
     getGates: VMgetGates,
     getTick: VMgetTick,
+    dumpState: VMdumpState,
     next: VMnext,
 }
 
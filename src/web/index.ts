@@ -128,13 +128,20 @@ async function update() {
     updateTStranspile(input);
 }
 
+// エラーと警告を位置付きの一行にする
+function formatDiagnostic(diagnostic: Diagnostic): string {
+    if (!diagnostic.span) { return `${diagnostic.message} [${diagnostic.code}]`; }
+    const file = diagnostic.file ? `${diagnostic.file}:` : "line ";
+    return `${diagnostic.message} (${file}${diagnostic.span.line}:${diagnostic.span.column}) [${diagnostic.code}]`;
+}
+
 function setErrMsg(compiler_products: IntermediateProducts,test_products: TestProducts) {
     // 普通にリストで表示
     document.querySelector("#errMsgArea").Replace([
-        E("ul",{},compiler_products.errors.map(x=>E("li",{class:"error"},[T(x)]))),
-        E("ul",{},compiler_products.warns.map(x=>E("li",{class:"warn"},[T(x)]))),
-        E("ul",{},test_products.errors.map(x=>E("li",{class:"error"},[T(x)]))),
-        E("ul",{},test_products.warns.map(x=>E("li",{class:"warn"},[T(x)]))),
+        E("ul",{},compiler_products.errors.map(x=>E("li",{class:"error"},[T(formatDiagnostic(x))]))),
+        E("ul",{},compiler_products.warns.map(x=>E("li",{class:"warn"},[T(formatDiagnostic(x))]))),
+        E("ul",{},test_products.errors.map(x=>E("li",{class:"error"},[T(formatDiagnostic(x))]))),
+        E("ul",{},test_products.warns.map(x=>E("li",{class:"warn"},[T(formatDiagnostic(x))]))),
     ]);
     // テスト結果の表
     document.querySelector("#testResult").Replace(Object.keys(test_products.test_result).map(
@@ -156,7 +163,7 @@ function setErrMsg(compiler_products: IntermediateProducts,test_products: TestPr
                             E("td",{},[T(x.accept?"true":"false")]),
                             E("td",{class:"boolean"},[T(x.input.length>0?x.input.map(x=>x?"t":"f").join(" "):"-")]),
                             E("td",{class:"boolean"},[T(x.output.length>0?x.output.map(x=>x?"t":"f").join(" "):"-")]),
-                            E("td",{class:"boolean"},[T(x.expect.length>0?x.expect.map(x=>x?"t":"f").join(" "):"-")]),
+                            E("td",{class:"boolean"},[T(x.expect.length>0?x.expect.map((e,i)=>x.ignored[i]?"x":e?"t":"f").join(" "):"-")]),
                         ]))
                     ),
                 ])
@@ -249,7 +256,7 @@ ${output_wires}
     }
 }
 
-import { Module, Test, TestProducts } from './types.js';
+import { Diagnostic, Module, Test, TestProducts } from './types.js';
 function constructGraph(product: IntermediateProducts,module_name: string,offset: number=0,subgraph=0): [string,number,number] {
     if (module_name=="nor") { return [`nor${offset}\n`,offset+1,subgraph]; }
     const modulesAST = module_name!="nor"?(product.ast.components.filter(x=>x.type=="Module"&&x.name==module_name)[0] as Module):{name:"nor",inputs:["x","y"],outputs:["a"],gates:[{inputs:["x","y"],outputs:["a"],module_name:"nor"}]};
@@ -1,4 +1,4 @@
-import { Component, File, Gate, IntermediateProducts, Module, ModuleType, MType, NodeDepends, Test, TestProducts, Using } from './types';
+import { Component, Diagnostic, File, Gate, IntermediateProducts, Module, ModuleType, MType, NodeDepends, Test, TestProducts, Using } from './types';
 
 export function isIntermediateProducts(obj: any): obj is IntermediateProducts {
     if (!obj || typeof obj !== 'object') return false;
@@ -9,8 +9,8 @@ export function isIntermediateProducts(obj: any): obj is IntermediateProducts {
         'module_dependency_sorted' in obj)) return false;
 
     // 配列プロパティの型チェック
-    // if (!Array.isArray(obj.warns) || !obj.warns.every(w => typeof w === 'string')) return false;
-    // if (!Array.isArray(obj.errors) || !obj.errors.every(e => typeof e === 'string')) return false;
+    // if (!Array.isArray(obj.warns) || !obj.warns.every(isDiagnostic)) return false;
+    // if (!Array.isArray(obj.errors) || !obj.errors.every(isDiagnostic)) return false;
     // if (!Array.isArray(obj.module_type_list) || !obj.module_type_list.every(isModuleType)) return false;
     // if (!Array.isArray(obj.module_dependency) || !obj.module_dependency.every(isNodeDepends)) return false;
     // if (!Array.isArray(obj.module_dependency_sorted) || !obj.module_dependency_sorted.every(m => typeof m === 'string')) return false;
@@ -23,6 +23,14 @@ export function isIntermediateProducts(obj: any): obj is IntermediateProducts {
     return true;
 }
 
+export function isDiagnostic(obj: any): obj is Diagnostic {
+    return obj && typeof obj === 'object' &&
+        typeof obj.code === 'string' &&
+        (obj.severity === 'Error' || obj.severity === 'Warning') &&
+        typeof obj.message === 'string' &&
+        Array.isArray(obj.notes);
+}
+
 export function isModuleType(obj: any): obj is ModuleType {
     return obj && typeof obj === 'object' &&
         typeof obj.name === 'string' &&
@@ -103,8 +111,8 @@ export function isTestProducts(obj: any): obj is TestProducts {
     if (!('warns' in obj && 'errors' in obj && 'test_list' in obj && 'test_result' in obj)) return false;
 
     // 配列プロパティの型チェック
-    if (!Array.isArray(obj.warns) || !obj.warns.every(w => typeof w === 'string')) return false;
-    if (!Array.isArray(obj.errors) || !obj.errors.every(e => typeof e === 'string')) return false;
+    if (!Array.isArray(obj.warns) || !obj.warns.every(isDiagnostic)) return false;
+    if (!Array.isArray(obj.errors) || !obj.errors.every(isDiagnostic)) return false;
     if (!Array.isArray(obj.test_list) || !obj.test_list.every(t => typeof t === 'string')) return false;
 
     // test_resultの型チェック
@@ -120,6 +128,8 @@ export function isTestProducts(obj: any): obj is TestProducts {
             pattern.input.every(i => typeof i === 'boolean') &&
             Array.isArray(pattern.expect) &&
             pattern.expect.every(e => typeof e === 'boolean') &&
+            Array.isArray(pattern.ignored) &&
+            pattern.ignored.every(e => typeof e === 'boolean') &&
             Array.isArray(pattern.output) &&
             pattern.output.every(o => typeof o === 'boolean')
         );
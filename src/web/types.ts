@@ -3,23 +3,80 @@ export interface MType {
     output_count: number;
 }
 
+export interface Span {
+    start: number;
+    end: number;
+    line: number;
+    column: number;
+}
+
+export interface Diagnostic {
+    code: string;
+    severity: "Error" | "Warning";
+    file: string;
+    span: Span | null;
+    message: string;
+    notes: string[];
+}
+
 export interface Gate {
     outputs: string[];
     module_name: string;
     inputs: string[];
+    span: Span;
 }
 
 export interface Module {
+    func: boolean;
+    sync: boolean;
+    export: boolean;
     name: string;
+    file: string;
     inputs: string[];
     outputs: string[];
     gates: Gate[];
+    span: Span;
+}
+
+export type SizeExpr =
+    | { type: "Number", value: number }
+    | { type: "Param", name: string }
+    | { type: "Add" | "Sub" | "Mul" | "Div", left: SizeExpr, right: SizeExpr };
+
+export interface ArrSlice {
+    all: boolean;
+    start: SizeExpr;
+    end: SizeExpr;
+    step: SizeExpr;
+    lower_inclusive: boolean;
+    upper_inclusive: boolean;
+}
+
+export interface GenericModule {
+    func: boolean;
+    sync: boolean;
+    export: boolean;
+    name: string;
+    file: string;
+    params: string[];
+    inputs: { arr_name: string, arr_size: SizeExpr }[];
+    outputs: { arr_name: string, arr_slice: ArrSlice }[];
+    gates: {
+        outputs: { arr_name: string, arr_size: SizeExpr }[];
+        module_name: string;
+        module_params: SizeExpr[];
+        inputs: { arr_name: string, arr_slice: ArrSlice }[];
+        span: Span;
+    }[];
+    span: Span;
 }
 
 export interface Graphical {
     name: string;
+    file: string;
     size: ImgSize;
     pixels: Pixel[];
+    span: Span;
 }
 
 export type ImgSize =
@@ -30,6 +87,7 @@ export interface Pixel {
     coord: [number,number],
     io_index: IoIndex,
     color: PixelColor,
+    span: Span,
 }
 
 export interface IoIndex {
@@ -48,18 +106,33 @@ export interface Using {
 
 export interface Test {
     name: string;
+    file: string;
     type_sig: MType;
     patterns: {
-        inputs: boolean[];
-        outputs: boolean[];
+        inputs: (boolean | null)[];
+        ticks: { type: "Default" } | { type: "Count", count: number } | { type: "Stable" };
+        outputs: (boolean | null)[];
     }[];
+    span: Span;
+}
+
+export interface EquivalenceTest {
+    name: string;
+    file: string;
+    reference: string;
+    random: number | null;
+    seed: number;
+    prove: boolean;
+    span: Span;
 }
 
 export type Component =
     | { type: "Using" } & Using
     | { type: "Module" } & Module
+    | { type: "GenericModule" } & GenericModule
     | { type: "Graphical" } & Graphical
-    | { type: "Test" } & Test;
+    | { type: "Test" } & Test
+    | { type: "EquivalenceTest" } & EquivalenceTest;
 
 export interface File {
     components: Component[];
@@ -90,23 +163,51 @@ export interface CompiledModule {
     outputs: number[];
     gates_sequential: CompiledGate[];
     gates_symmetry: CompiledGate[];
+    gate_names: string[];
+}
+
+export interface GateSymbol {
+    path: string;
+    wire: string;
+}
+
+export interface SymbolTable {
+    inputs: string[];
+    outputs: string[];
+    gates: GateSymbol[];
+}
+
+export interface ModuleOptimization {
+    before: number;
+    after: number;
+}
+
+export interface ModuleDelay {
+    output_depths: number[];
+    critical_path: string[];
 }
 
 export interface IntermediateProducts {
     source: string;
-    warns: string[];
-    errors: string[];
+    source_files: string[];
+    warns: Diagnostic[];
+    errors: Diagnostic[];
     ast: File;
     module_type_list: ModuleType[];
     module_dependency: NodeDepends[];
     module_dependency_sorted: string[];
     expanded_modules: Map<string,CompiledModule>;
+    module_optimizations: Map<string,ModuleOptimization>;
+    module_delays: Map<string,ModuleDelay>;
+    symbol_tables: Map<string,SymbolTable>;
 }
 
 export type TestPattern = {
     accept: Boolean;
+    ticks: number;
     input: Boolean[];
     expect: Boolean[];
+    ignored: Boolean[];
     output: Boolean[];
 }
 
@@ -115,8 +216,8 @@ export type TestPatternMap = {
 }
 
 export type TestProducts = {
-    warns: string[];
-    errors: string[];
+    warns: Diagnostic[];
+    errors: Diagnostic[];
     test_list: string[];
     test_result: TestPatternMap
 }
\ No newline at end of file